@@ -0,0 +1,161 @@
+// Allocators for the resources handed out to devices, which get them back
+// when a device is unplugged.
+
+use std::collections::BTreeMap;
+
+/// Hands out aligned ranges of an address space.
+pub struct AddressAllocator {
+    // Start and size of each free range, sorted by start and never adjacent.
+    free: BTreeMap<u64, u64>,
+}
+
+impl AddressAllocator {
+    /// Manages the `size` bytes starting at `base`.
+    pub fn new(base: u64, size: u64) -> Self {
+        let mut free = BTreeMap::new();
+        free.insert(base, size);
+        AddressAllocator { free }
+    }
+
+    /// The lowest free range of `size` bytes aligned on `align`, a power of two.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        let (start, len, addr) = self.free.iter().find_map(|(&start, &len)| {
+            let addr = (start + align - 1) & !(align - 1);
+            if addr + size <= start + len {
+                Some((start, len, addr))
+            } else {
+                None
+            }
+        })?;
+
+        self.free.remove(&start);
+        if addr > start {
+            self.free.insert(start, addr - start);
+        }
+        if addr + size < start + len {
+            self.free.insert(addr + size, start + len - addr - size);
+        }
+        Some(addr)
+    }
+
+    /// Gives back the range of `size` bytes at `addr`.
+    pub fn free(&mut self, addr: u64, size: u64) {
+        let mut start = addr;
+        let mut len = size;
+        // Merge with the free ranges right before and after.
+        if let Some((&prev, &prev_len)) = self.free.range(..addr).next_back() {
+            if prev + prev_len == addr {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(addr + size)) {
+            len += next_len;
+        }
+        self.free.insert(start, len);
+    }
+}
+
+/// Hands out numbers in a range, like interrupt lines or KVM memory slots,
+/// reusing freed ones first.
+pub struct IdAllocator {
+    next: u32,
+    end: u32,
+    freed: Vec<u32>,
+}
+
+impl IdAllocator {
+    /// Manages the numbers from `first` to `last` included.
+    pub fn new(first: u32, last: u32) -> Self {
+        IdAllocator {
+            next: first,
+            end: last + 1,
+            freed: Vec::new(),
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<u32> {
+        if let Some(id) = self.freed.pop() {
+            return Some(id);
+        }
+        if self.next >= self.end {
+            return None;
+        }
+        self.next += 1;
+        Some(self.next - 1)
+    }
+
+    pub fn free(&mut self, id: u32) {
+        self.freed.push(id);
+    }
+
+    /// How many numbers can still be allocated.
+    pub fn available(&self) -> u32 {
+        self.end - self.next + self.freed.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_alignment_and_exhaustion() {
+        let mut allocator = AddressAllocator::new(0x1000, 0x10000);
+        assert_eq!(allocator.allocate(0x100, 0x100), Some(0x1000));
+        // Naturally aligned, like a BAR.
+        assert_eq!(allocator.allocate(0x4000, 0x4000), Some(0x4000));
+        // Fills the gap left by the alignment.
+        assert_eq!(allocator.allocate(0x2000, 0x1000), Some(0x2000));
+        assert_eq!(allocator.allocate(0x8000, 0x8000), Some(0x8000));
+        assert_eq!(allocator.allocate(0x1000, 0x1000), Some(0x10000));
+        assert_eq!(allocator.allocate(0x1000, 0x1000), None);
+        assert_eq!(allocator.allocate(0x100, 0x100), Some(0x1100));
+    }
+
+    #[test]
+    fn address_ranges_do_not_overlap() {
+        let mut allocator = AddressAllocator::new(0, 0x10_0000);
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for size in [0x1000u64, 0x4000, 0x1000, 0x10000, 0x2000, 0x1000].iter() {
+            let addr = allocator.allocate(*size, *size).unwrap();
+            assert_eq!(addr % size, 0);
+            for (start, len) in ranges.iter() {
+                assert!(addr + size <= *start || start + len <= addr);
+            }
+            ranges.push((addr, *size));
+        }
+    }
+
+    #[test]
+    fn address_free_merges() {
+        let mut allocator = AddressAllocator::new(0, 0x4000);
+        let a = allocator.allocate(0x1000, 0x1000).unwrap();
+        let b = allocator.allocate(0x1000, 0x1000).unwrap();
+        let c = allocator.allocate(0x2000, 0x1000).unwrap();
+        assert_eq!(allocator.allocate(0x1000, 0x1000), None);
+
+        // Freeing b then a and c gives back one range covering everything.
+        allocator.free(b, 0x1000);
+        assert_eq!(allocator.allocate(0x2000, 0x1000), None);
+        allocator.free(a, 0x1000);
+        allocator.free(c, 0x2000);
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.allocate(0x4000, 0x1000), Some(0));
+    }
+
+    #[test]
+    fn ids() {
+        let mut allocator = IdAllocator::new(32, 34);
+        assert_eq!(allocator.allocate(), Some(32));
+        assert_eq!(allocator.allocate(), Some(33));
+        assert_eq!(allocator.allocate(), Some(34));
+        assert_eq!(allocator.allocate(), None);
+
+        // Freed ids come back first.
+        allocator.free(33);
+        assert_eq!(allocator.allocate(), Some(33));
+        assert_eq!(allocator.allocate(), None);
+    }
+}
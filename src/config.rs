@@ -1,12 +1,645 @@
+use crate::devices::virtio::{self, p9::SecurityModel};
+use crate::error::*;
+use crate::memory::VmLayout;
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+const VHOST_USER_QUEUE_SIZE: u16 = 256;
+
+/// Splits a device option string like `tag=work,path=/src` into its key/value pairs.
+fn parse_options<'a>(value: &'a str, known: &[&str]) -> Result<HashMap<&'a str, &'a str>> {
+    let mut options = HashMap::new();
+    for option in value.split(',').filter(|o| !o.is_empty()) {
+        let mut kv = option.splitn(2, '=');
+        let key = kv.next().unwrap_or("");
+        let val = kv
+            .next()
+            .ok_or_else(|| Error::InvalidOption(format!("missing value in \"{}\"", option)))?;
+        if !known.contains(&key) {
+            return Err(Error::InvalidOption(format!("unknown option \"{}\"", key)));
+        }
+        options.insert(key, val);
+    }
+    Ok(options)
+}
+
+/// Parses the `<name>=on|off` option of `device`, off by default.
+fn parse_switch(device: &str, name: &str, options: &HashMap<&str, &str>) -> Result<bool> {
+    match options.get(name) {
+        None | Some(&"off") => Ok(false),
+        Some(&"on") => Ok(true),
+        Some(other) => Err(Error::InvalidOption(format!(
+            "{}: invalid {} value \"{}\"",
+            device, name, other
+        ))),
+    }
+}
+
+/// How a virtio device is exposed to the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VirtioTransport {
+    Mmio,
+    Pci,
+}
+
+impl VirtioTransport {
+    /// Parses the `transport=mmio|pci` option of `device`, virtio-mmio by default.
+    fn parse(device: &str, options: &HashMap<&str, &str>) -> Result<Self> {
+        match options.get("transport") {
+            None | Some(&"mmio") => Ok(VirtioTransport::Mmio),
+            Some(&"pci") => Ok(VirtioTransport::Pci),
+            Some(other) => Err(Error::InvalidOption(format!(
+                "{}: unknown transport \"{}\"",
+                device, other
+            ))),
+        }
+    }
+}
+
+/// A host directory shared with the guest through virtio-fs.
+pub struct FsConfig {
+    /// Mount tag the guest uses, e.g. `mount -t virtiofs work /mnt`.
+    pub tag: String,
+    /// Host directory to share.
+    pub path: PathBuf,
+    /// Socket of an already running vhost-user-fs backend. When not given,
+    /// glue starts virtiofsd for `path` itself.
+    pub socket: Option<PathBuf>,
+    pub transport: VirtioTransport,
+}
+
+impl FsConfig {
+    /// Parses `tag=<tag>,path=<dir>[,socket=<path>][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["tag", "path", "socket", "transport"])?;
+
+        let tag = options
+            .get("tag")
+            .ok_or_else(|| Error::InvalidOption("fs: missing \"tag\"".to_string()))?;
+        if tag.is_empty() || tag.len() > crate::devices::virtio::fs::FS_TAG_LEN {
+            return Err(Error::InvalidOption(format!("fs: invalid tag \"{}\"", tag)));
+        }
+        let socket = options.get("socket").map(PathBuf::from);
+        let path = match options.get("path") {
+            Some(path) => PathBuf::from(path),
+            None if socket.is_some() => PathBuf::new(),
+            None => return Err(Error::InvalidOption("fs: missing \"path\"".to_string())),
+        };
+        let transport = VirtioTransport::parse("fs", &options)?;
+
+        Ok(FsConfig {
+            tag: tag.to_string(),
+            path,
+            socket,
+            transport,
+        })
+    }
+}
+
+/// A host directory shared with the guest through virtio-9p.
+pub struct P9Config {
+    /// Mount tag the guest uses, e.g. `mount -t 9p -o trans=virtio work /mnt`.
+    pub tag: String,
+    /// Host directory to export, the guest cannot reach anything outside it.
+    pub path: PathBuf,
+    pub security: SecurityModel,
+    pub transport: VirtioTransport,
+}
+
+impl P9Config {
+    /// Parses `tag=<tag>,path=<dir>[,security=passthrough|mapped][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["tag", "path", "security", "transport"])?;
+
+        let tag = options
+            .get("tag")
+            .filter(|t| !t.is_empty() && t.len() <= u16::max_value() as usize)
+            .ok_or_else(|| Error::InvalidOption("9p: missing \"tag\"".to_string()))?;
+        let path = options
+            .get("path")
+            .ok_or_else(|| Error::InvalidOption("9p: missing \"path\"".to_string()))?;
+        let security = match options.get("security") {
+            None | Some(&"mapped") => SecurityModel::Mapped,
+            Some(&"passthrough") => SecurityModel::Passthrough,
+            Some(other) => {
+                return Err(Error::InvalidOption(format!(
+                    "9p: unknown security model \"{}\"",
+                    other
+                )))
+            }
+        };
+        let transport = VirtioTransport::parse("9p", &options)?;
+
+        Ok(P9Config {
+            tag: tag.to_string(),
+            path: PathBuf::from(path),
+            security,
+            transport,
+        })
+    }
+}
+
+/// A host file exposed to the guest as persistent memory through virtio-pmem.
+pub struct PmemConfig {
+    pub path: PathBuf,
+    /// Map the file read-only, e.g. for a shared root filesystem image.
+    pub readonly: bool,
+    pub transport: VirtioTransport,
+}
+
+impl PmemConfig {
+    /// Parses `path=<file>[,readonly=on|off][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["path", "readonly", "transport"])?;
+
+        let path = options
+            .get("path")
+            .ok_or_else(|| Error::InvalidOption("pmem: missing \"path\"".to_string()))?;
+        let readonly = parse_switch("pmem", "readonly", &options)?;
+        let transport = VirtioTransport::parse("pmem", &options)?;
+
+        Ok(PmemConfig {
+            path: PathBuf::from(path),
+            readonly,
+            transport,
+        })
+    }
+}
+
+/// Pages backing guest RAM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HugePages {
+    /// 2 MB hugetlbfs pages.
+    Size2M,
+    /// 1 GB hugetlbfs pages.
+    Size1G,
+    /// Normal pages the kernel may merge into transparent hugepages.
+    Transparent,
+}
+
+impl HugePages {
+    /// Size of a hugetlbfs page, None for transparent hugepages.
+    pub fn page_size(self) -> Option<u64> {
+        match self {
+            HugePages::Size2M => Some(2 << 20),
+            HugePages::Size1G => Some(1 << 30),
+            HugePages::Transparent => None,
+        }
+    }
+}
+
+/// How guest RAM is backed on the host.
+#[derive(Default)]
+pub struct MemoryConfig {
+    pub hugepages: Option<HugePages>,
+    /// A hugetlbfs mount to create the files backing RAM in, instead of memfds.
+    pub hugetlbfs: Option<PathBuf>,
+    /// A file backing RAM, the regions one after the other, created if missing.
+    ///
+    /// Without `shared`, the guest starts from the content of the file but its
+    /// writes stay private, like when restoring a snapshot.
+    pub file: Option<PathBuf>,
+    /// Map RAM shared, so its writes reach the backing file or memfd and other
+    /// processes mapping it see them.
+    pub shared: bool,
+    /// Fault all of DRAM in at startup, so the guest never waits on the host for it.
+    pub prefault: bool,
+    /// Lock DRAM in host memory, so it is never swapped out.
+    pub mlock: bool,
+    /// Let KSM merge identical pages with other guests. Only private memory
+    /// that is not on hugepages merges.
+    pub merge: bool,
+    /// Leave guest memory out of core dumps of glue.
+    pub dontdump: bool,
+}
+
+impl MemoryConfig {
+    /// Parses `[hugepages=2M|1G|thp][,hugetlbfs=<dir>][,file=<path>][,shared=on|off]
+    /// [,prefault=on|off][,mlock=on|off][,merge=on|off][,dontdump=on|off]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(
+            value,
+            &[
+                "hugepages",
+                "hugetlbfs",
+                "file",
+                "shared",
+                "prefault",
+                "mlock",
+                "merge",
+                "dontdump",
+            ],
+        )?;
+
+        let hugepages = match options.get("hugepages") {
+            None => None,
+            Some(&"2M") => Some(HugePages::Size2M),
+            Some(&"1G") => Some(HugePages::Size1G),
+            Some(&"thp") => Some(HugePages::Transparent),
+            Some(other) => {
+                return Err(Error::InvalidOption(format!(
+                    "memory: unknown hugepages \"{}\"",
+                    other
+                )))
+            }
+        };
+        let hugetlbfs = options.get("hugetlbfs").map(PathBuf::from);
+        if hugetlbfs.is_some() && hugepages.and_then(HugePages::page_size).is_none() {
+            return Err(Error::InvalidOption(
+                "memory: hugetlbfs needs hugepages=2M or hugepages=1G".to_string(),
+            ));
+        }
+        let file = options.get("file").map(PathBuf::from);
+        if file.is_some() && hugetlbfs.is_some() {
+            return Err(Error::InvalidOption(
+                "memory: file and hugetlbfs exclude each other, put the file on hugetlbfs"
+                    .to_string(),
+            ));
+        }
+        let shared = parse_switch("memory", "shared", &options)?;
+        let prefault = parse_switch("memory", "prefault", &options)?;
+        let mlock = parse_switch("memory", "mlock", &options)?;
+        let merge = parse_switch("memory", "merge", &options)?;
+        let dontdump = parse_switch("memory", "dontdump", &options)?;
+
+        Ok(MemoryConfig {
+            hugepages,
+            hugetlbfs,
+            file,
+            shared,
+            prefault,
+            mlock,
+            merge,
+            dontdump,
+        })
+    }
+}
+
+/// Memory the guest can be given and taken back while it runs, through virtio-mem.
+pub struct MemHotplugConfig {
+    /// Size of the hotplug region in bytes, the most memory that can be added.
+    pub size: u64,
+    /// Granularity memory is added and removed with, in bytes.
+    pub block_size: u64,
+    pub transport: VirtioTransport,
+}
+
+impl MemHotplugConfig {
+    /// Parses `size=<MB>[,block-size=<MB>][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["size", "block-size", "transport"])?;
+
+        let parse_mb = |name: &str, value: &str| {
+            value
+                .parse::<u64>()
+                .ok()
+                .filter(|&mb| mb > 0)
+                .map(|mb| mb << 20)
+                .ok_or_else(|| {
+                    Error::InvalidOption(format!("mem-hotplug: invalid {} \"{}\"", name, value))
+                })
+        };
+        let size = options
+            .get("size")
+            .ok_or_else(|| Error::InvalidOption("mem-hotplug: missing \"size\"".to_string()))?;
+        let size = parse_mb("size", size)?;
+        let block_size = match options.get("block-size") {
+            Some(block_size) => parse_mb("block-size", block_size)?,
+            None => virtio::mem::DEFAULT_BLOCK_SIZE,
+        };
+        // Linux plugs at least a pageblock, 2 MB with 4K pages.
+        if !block_size.is_power_of_two() || block_size < virtio::mem::MIN_BLOCK_SIZE {
+            return Err(Error::InvalidOption(format!(
+                "mem-hotplug: block-size must be a power of two of at least {} MB",
+                virtio::mem::MIN_BLOCK_SIZE >> 20
+            )));
+        }
+        if size % block_size != 0 || size > VmLayout::MEM_HOTPLUG_SIZE {
+            return Err(Error::InvalidOption(format!(
+                "mem-hotplug: size must be a multiple of block-size and at most {} MB",
+                VmLayout::MEM_HOTPLUG_SIZE >> 20
+            )));
+        }
+        let transport = VirtioTransport::parse("mem-hotplug", &options)?;
+
+        Ok(MemHotplugConfig {
+            size,
+            block_size,
+            transport,
+        })
+    }
+}
+
+/// Whether to hand the queues of a device to an in-kernel vhost backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VhostMode {
+    On,
+    Off,
+    /// Use vhost when the host has it, process the queues in glue otherwise.
+    Auto,
+}
+
+/// A network interface connected to a host tap interface through virtio-net.
+pub struct NetConfig {
+    /// Name of the tap interface, created if it does not exist.
+    pub tap: String,
+    pub mac: [u8; 6],
+    pub vhost: VhostMode,
+    pub transport: VirtioTransport,
+}
+
+impl NetConfig {
+    /// Parses `tap=<name>[,mac=<mac>][,vhost=on|off|auto][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["tap", "mac", "vhost", "transport"])?;
+
+        let tap = options
+            .get("tap")
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| Error::InvalidOption("net: missing \"tap\"".to_string()))?;
+        let mac = match options.get("mac") {
+            Some(mac) => parse_mac(mac)
+                .ok_or_else(|| Error::InvalidOption(format!("net: invalid mac \"{}\"", mac)))?,
+            None => random_mac(),
+        };
+        let vhost = match options.get("vhost") {
+            None | Some(&"auto") => VhostMode::Auto,
+            Some(&"on") => VhostMode::On,
+            Some(&"off") => VhostMode::Off,
+            Some(other) => {
+                return Err(Error::InvalidOption(format!(
+                    "net: invalid vhost value \"{}\"",
+                    other
+                )))
+            }
+        };
+        let transport = VirtioTransport::parse("net", &options)?;
+
+        Ok(NetConfig {
+            tap: tap.to_string(),
+            mac,
+            vhost,
+            transport,
+        })
+    }
+}
+
+fn parse_mac(value: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut bytes = value.split(':');
+    for b in mac.iter_mut() {
+        *b = u8::from_str_radix(bytes.next()?, 16).ok()?;
+    }
+    if bytes.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+// A locally administered address in the range QEMU uses, different for each VM.
+fn random_mac() -> [u8; 6] {
+    let mut bytes = [0u8; 3];
+    // Safe because the buffer is large enough for the requested length.
+    let ret = unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, 3, 0) };
+    if ret != 3 {
+        bytes = [0, 0, 1];
+    }
+    [0x52, 0x54, 0x00, bytes[0], bytes[1], bytes[2]]
+}
+
+/// A vsock device, served by vhost-vsock on the host.
+pub struct VsockConfig {
+    /// Context ID of the guest, host tools connect to it with AF_VSOCK.
+    pub cid: u64,
+    pub transport: VirtioTransport,
+}
+
+impl VsockConfig {
+    /// Parses `cid=<context id>[,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["cid", "transport"])?;
+
+        let cid = options
+            .get("cid")
+            .ok_or_else(|| Error::InvalidOption("vsock: missing \"cid\"".to_string()))?;
+        // 0 to 2 are reserved for the hypervisor, the loopback and the host.
+        let cid = cid
+            .parse::<u64>()
+            .ok()
+            .filter(|&cid| cid > 2 && cid < u64::from(u32::max_value()))
+            .ok_or_else(|| Error::InvalidOption(format!("vsock: invalid cid \"{}\"", cid)))?;
+        let transport = VirtioTransport::parse("vsock", &options)?;
+
+        Ok(VsockConfig { cid, transport })
+    }
+}
+
+/// A device implemented by an external vhost-user backend.
+pub struct VhostUserConfig {
+    /// Socket the backend listens on.
+    pub socket: PathBuf,
+    /// The virtio device type, e.g. 2 for a block device.
+    pub device_type: u32,
+    /// Number of queues, asked from the backend when not given.
+    pub num_queues: Option<usize>,
+    pub queue_size: u16,
+    pub transport: VirtioTransport,
+}
+
+impl VhostUserConfig {
+    /// Parses `socket=<path>,type=net|blk|fs|rng|<id>[,queues=<n>][,queue-size=<n>][,transport=mmio|pci]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(
+            value,
+            &["socket", "type", "queues", "queue-size", "transport"],
+        )?;
+
+        let socket = options
+            .get("socket")
+            .ok_or_else(|| Error::InvalidOption("vhost-user: missing \"socket\"".to_string()))?;
+        let device_type = match options.get("type") {
+            Some(&"net") => virtio::TYPE_NET,
+            Some(&"blk") => virtio::TYPE_BLOCK,
+            Some(&"fs") => virtio::TYPE_FS,
+            Some(&"rng") => virtio::TYPE_RNG,
+            Some(other) => other.parse().map_err(|_| {
+                Error::InvalidOption(format!("vhost-user: unknown type \"{}\"", other))
+            })?,
+            None => {
+                return Err(Error::InvalidOption(
+                    "vhost-user: missing \"type\"".to_string(),
+                ))
+            }
+        };
+        let num_queues = match options.get("queues") {
+            Some(queues) => Some(queues.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                Error::InvalidOption(format!("vhost-user: invalid queues \"{}\"", queues))
+            })?),
+            None => None,
+        };
+        let queue_size = match options.get("queue-size") {
+            Some(size) => size
+                .parse::<u16>()
+                .ok()
+                .filter(|s| s.is_power_of_two())
+                .ok_or_else(|| {
+                    Error::InvalidOption(format!("vhost-user: invalid queue-size \"{}\"", size))
+                })?,
+            None => VHOST_USER_QUEUE_SIZE,
+        };
+        let transport = VirtioTransport::parse("vhost-user", &options)?;
+
+        Ok(VhostUserConfig {
+            socket: PathBuf::from(socket),
+            device_type,
+            num_queues,
+            queue_size,
+            transport,
+        })
+    }
+}
+
+/// What happens when the guest stops feeding the watchdog.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchdogAction {
+    /// Reboot the VM.
+    Reset,
+    /// Kill the VM.
+    Poweroff,
+    /// Pause the vcpus and leave the VM around for inspection.
+    Pause,
+    /// Write the memory and vcpu registers to an ELF core file, then kill the VM.
+    Dump(PathBuf),
+}
+
+/// A watchdog the guest must keep feeding.
+pub struct WatchdogConfig {
+    pub action: WatchdogAction,
+}
+
+impl WatchdogConfig {
+    /// Parses `action=reset|poweroff|pause|dump[,file=<dump file>]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["action", "file"])?;
+
+        let action = match options.get("action") {
+            None | Some(&"reset") => WatchdogAction::Reset,
+            Some(&"poweroff") => WatchdogAction::Poweroff,
+            Some(&"pause") => WatchdogAction::Pause,
+            Some(&"dump") => {
+                let file = options.get("file").ok_or_else(|| {
+                    Error::InvalidOption("watchdog: missing \"file\" to dump to".to_string())
+                })?;
+                WatchdogAction::Dump(PathBuf::from(file))
+            }
+            Some(other) => {
+                return Err(Error::InvalidOption(format!(
+                    "watchdog: unknown action \"{}\"",
+                    other
+                )))
+            }
+        };
+
+        Ok(WatchdogConfig { action })
+    }
+}
+
+/// Parses a list of numbers and ranges like `0-3:8`, with `separator` between items,
+/// none of them above `max`.
+pub fn parse_ranges(list: &str, separator: char, max: usize) -> Option<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for item in list.trim().split(separator).filter(|i| !i.is_empty()) {
+        let mut bounds = item.splitn(2, '-');
+        let first = bounds.next()?.parse::<usize>().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.parse::<usize>().ok()?,
+            None => first,
+        };
+        if last < first || last > max {
+            return None;
+        }
+        numbers.extend(first..=last);
+    }
+    Some(numbers)
+}
+
+/// A NUMA node of the guest, with its share of memory and vcpus.
+pub struct NumaConfig {
+    /// Memory size in MB.
+    pub mem_size: u64,
+    pub cpus: Vec<u8>,
+    /// Distance to each node in order, this one included. Left empty, nodes are
+    /// 10 from themselves and 20 from the others.
+    pub distances: Vec<u8>,
+    /// Host node the memory comes from and the vcpus run on.
+    pub host_node: Option<u32>,
+}
+
+impl NumaConfig {
+    /// Parses `mem=<MB>,cpus=<first>[-<last>][:...][,distances=<d0>:<d1>...][,host-node=<n>]`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let options = parse_options(value, &["mem", "cpus", "distances", "host-node"])?;
+
+        let mem_size = match options.get("mem").map(|m| m.parse::<u64>()) {
+            Some(Ok(mem_size)) if mem_size > 0 => mem_size,
+            _ => return Err(Error::InvalidOption("numa: invalid \"mem\"".to_string())),
+        };
+        let cpus = options
+            .get("cpus")
+            .and_then(|c| parse_ranges(c, ':', u8::MAX as usize))
+            .filter(|c| !c.is_empty())
+            .ok_or_else(|| Error::InvalidOption("numa: invalid \"cpus\"".to_string()))?
+            .into_iter()
+            .map(|cpu| cpu as u8)
+            .collect();
+        let distances = match options.get("distances") {
+            Some(distances) => distances
+                .split(':')
+                .map(|d| d.parse::<u8>())
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .map_err(|_| Error::InvalidOption("numa: invalid \"distances\"".to_string()))?,
+            None => Vec::new(),
+        };
+        let host_node = match options.get("host-node").map(|n| n.parse::<u32>()) {
+            None => None,
+            Some(Ok(host_node)) => Some(host_node),
+            Some(Err(_)) => {
+                return Err(Error::InvalidOption(
+                    "numa: invalid \"host-node\"".to_string(),
+                ))
+            }
+        };
+
+        Ok(NumaConfig {
+            mem_size,
+            cpus,
+            distances,
+            host_node,
+        })
+    }
+}
+
 pub struct VmConfig {
     pub boot_vcpus: u8,
     pub max_vcpus: u8,
     pub memory_size: u64,
+    pub memory: MemoryConfig,
     pub kernel_path: PathBuf,
-    //pub kernel_args: CString,
+    pub kernel_args: String,
     pub disk_path: PathBuf,
+    pub fs: Vec<FsConfig>,
+    pub p9: Vec<P9Config>,
+    pub pmem: Vec<PmemConfig>,
+    pub net: Vec<NetConfig>,
+    pub vsock: Option<VsockConfig>,
+    pub vhost_user: Vec<VhostUserConfig>,
+    pub watchdog: Option<WatchdogConfig>,
+    pub mem_hotplug: Option<MemHotplugConfig>,
+    /// Guest NUMA nodes, from node 0, none for a single node.
+    pub numa: Vec<NumaConfig>,
+    /// Arguments of the `glue run` giving this configuration, to start the VM
+    /// over on reset or to restore it from a snapshot.
+    pub run_args: Vec<String>,
 }
 
 impl VmConfig {
@@ -14,16 +647,127 @@ impl VmConfig {
         cpus: u8,
         mem: u64,
         kernel_path: PathBuf,
-        //kernel_args: CString,
+        kernel_args: String,
         disk_path: PathBuf,
     ) -> Self {
         VmConfig {
             boot_vcpus: cpus,
             max_vcpus: cpus,
             memory_size: mem,
+            memory: MemoryConfig::default(),
             kernel_path,
-            //kernel_args,
+            kernel_args,
             disk_path,
+            fs: Vec::new(),
+            p9: Vec::new(),
+            pmem: Vec::new(),
+            net: Vec::new(),
+            vsock: None,
+            vhost_user: Vec::new(),
+            watchdog: None,
+            mem_hotplug: None,
+            numa: Vec::new(),
+            run_args: Vec::new(),
+        }
+    }
+
+    /// Checks the NUMA nodes share out all the memory and vcpus, and that
+    /// their distances are ones Linux accepts.
+    pub fn check_numa(&self) -> Result<()> {
+        if self.numa.is_empty() {
+            return Ok(());
+        }
+        let invalid = |reason: &str| Err(Error::InvalidOption(format!("numa: {}", reason)));
+
+        if self.numa.iter().map(|n| n.mem_size).sum::<u64>() != self.memory_size {
+            return invalid("the memory of the nodes does not add up to the VM memory");
         }
+        for cpu in 0..self.boot_vcpus {
+            match self.numa.iter().filter(|n| n.cpus.contains(&cpu)).count() {
+                0 => return invalid(&format!("vcpu {} is in no node", cpu)),
+                1 => {}
+                _ => return invalid(&format!("vcpu {} is in several nodes", cpu)),
+            }
+        }
+        if self
+            .numa
+            .iter()
+            .any(|n| n.cpus.iter().any(|c| *c >= self.boot_vcpus))
+        {
+            return invalid("a node has a vcpu the VM does not have");
+        }
+        for (index, node) in self.numa.iter().enumerate() {
+            if node.distances.is_empty() {
+                continue;
+            }
+            if node.distances.len() != self.numa.len() {
+                return invalid(&format!("node {} needs a distance to each node", index));
+            }
+            for (to, distance) in node.distances.iter().enumerate() {
+                if (to == index) != (*distance == 10) || *distance < 10 {
+                    return invalid(&format!(
+                        "distance from node {} to itself must be 10, to other nodes more",
+                        index
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether guest memory is mapped shared, because it was asked for or
+    /// because external vhost-user backends need to map it.
+    pub fn shares_memory(&self) -> bool {
+        self.memory.shared || !self.fs.is_empty() || !self.vhost_user.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ranges_lists() {
+        assert_eq!(parse_ranges("0-3:8", ':', 8), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_ranges("0-1,4\n", ',', 255), Some(vec![0, 1, 4]));
+        assert_eq!(parse_ranges("5", ',', 5), Some(vec![5]));
+        assert_eq!(parse_ranges("", ',', 5), Some(vec![]));
+    }
+
+    #[test]
+    fn parse_ranges_rejects_invalid() {
+        assert_eq!(parse_ranges("3-1", ':', 8), None);
+        assert_eq!(parse_ranges("0-x", ':', 8), None);
+        assert_eq!(parse_ranges("-1", ':', 8), None);
+        assert_eq!(parse_ranges("0-9", ':', 8), None);
+        assert_eq!(parse_ranges("9", ':', 8), None);
+        // Rejected before anything is allocated for it.
+        assert_eq!(parse_ranges(&format!("0-{}", usize::MAX), ':', 255), None);
+    }
+
+    #[test]
+    fn numa_parse() {
+        let numa = NumaConfig::parse("mem=512,cpus=0-1:3,distances=10:20,host-node=1").unwrap();
+        assert_eq!(numa.mem_size, 512);
+        assert_eq!(numa.cpus, vec![0, 1, 3]);
+        assert_eq!(numa.distances, vec![10, 20]);
+        assert_eq!(numa.host_node, Some(1));
+
+        let numa = NumaConfig::parse("mem=256,cpus=2").unwrap();
+        assert_eq!(numa.cpus, vec![2]);
+        assert!(numa.distances.is_empty());
+        assert_eq!(numa.host_node, None);
+    }
+
+    #[test]
+    fn numa_parse_rejects_invalid() {
+        assert!(NumaConfig::parse("cpus=0").is_err());
+        assert!(NumaConfig::parse("mem=0,cpus=0").is_err());
+        assert!(NumaConfig::parse("mem=512").is_err());
+        assert!(NumaConfig::parse("mem=512,cpus=0-256").is_err());
+        assert!(NumaConfig::parse("mem=512,cpus=0-18446744073709551615").is_err());
+        assert!(NumaConfig::parse("mem=512,cpus=0,distances=10:x").is_err());
+        assert!(NumaConfig::parse("mem=512,cpus=0,host-node=-1").is_err());
+        assert!(NumaConfig::parse("mem=512,cpus=0,nodes=1").is_err());
     }
 }
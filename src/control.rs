@@ -0,0 +1,92 @@
+// Control socket of a running VM.
+//
+// There is no daemon: each `glue run` process listens on a socket named after
+// its VM, and the other subcommands (`glue stop -n <name>`, ...) connect to it,
+// the way kvmtool does. Requests and replies are single lines of text.
+
+use crate::error::*;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+
+/// Where the VM called `name` listens for commands.
+pub fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join("glue")
+        .join(format!("{}.sock", name))
+}
+
+/// Serves commands for the VM until dropped.
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Listens for commands; `handler` gets each request line and returns the reply.
+    pub fn start<F>(name: &str, handler: F) -> Result<ControlServer>
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let path = socket_path(name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(Error::Control)?;
+        }
+        if is_running(name) {
+            return Err(Error::VmExists(name.to_string()));
+        }
+        // Left behind by a VM that did not exit cleanly.
+        fs::remove_file(&path).ok();
+
+        let listener = UnixListener::bind(&path).map_err(Error::Control)?;
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Ok(stream) = stream {
+                        serve(stream, &handler);
+                    }
+                }
+            })
+            .map_err(Error::SpawnThread)?;
+
+        Ok(ControlServer { path })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+fn serve<F: Fn(&str) -> String>(stream: UnixStream, handler: &F) {
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    if reader.read_line(&mut request).is_err() {
+        return;
+    }
+    let reply = handler(request.trim());
+    (&stream).write_all(format!("{}\n", reply).as_bytes()).ok();
+}
+
+/// Sends `command` to the VM called `name` and returns its reply.
+pub fn send_command(name: &str, command: &str) -> Result<String> {
+    let stream =
+        UnixStream::connect(socket_path(name)).map_err(|_| Error::VmNotFound(name.to_string()))?;
+    (&stream)
+        .write_all(format!("{}\n", command).as_bytes())
+        .map_err(Error::Control)?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .map_err(Error::Control)?;
+    Ok(reply.trim().to_string())
+}
+
+/// Whether the VM called `name` is still running.
+pub fn is_running(name: &str) -> bool {
+    UnixStream::connect(socket_path(name)).is_ok()
+}
@@ -0,0 +1,119 @@
+// Guest memory and vcpu registers as an ELF core file, the way QEMU's
+// dump-guest-memory writes them, which crash and gdb can read.
+// Format: https://refspecs.linuxfoundation.org/elf/gabi4+/contents.html
+
+use crate::error::*;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_AARCH64: u16 = 183;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 0x7;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const NT_PRSTATUS: u32 = 1;
+// "CORE" and its terminating zero, padded to 4 bytes.
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_NAME_SIZE: u32 = 5;
+const NOTE_HDR_SIZE: usize = 12;
+
+// struct elf_prstatus of arm64: pr_pid at 32, pr_reg (user_pt_regs) at 112.
+const PRSTATUS_SIZE: usize = 392;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REG: usize = 112;
+
+/// x0 to x30, SP, PC and PSTATE, as in `user_pt_regs`.
+pub type CoreRegs = [u64; 34];
+
+/// Memory starts on a page boundary in the file, so it can be mapped.
+const MEMORY_ALIGNMENT: usize = 0x1000;
+
+/// Writes the guest memory `ranges`, given as guest address and size, and the
+/// registers of each vcpu to an ELF core file at `path`.
+pub fn write_core(
+    guest_mem: &GuestMemoryMmap,
+    ranges: &[(GuestAddress, u64)],
+    vcpu_regs: &[CoreRegs],
+    path: &Path,
+) -> Result<()> {
+    let mut notes = Vec::new();
+    for (index, regs) in vcpu_regs.iter().enumerate() {
+        let mut prstatus = [0u8; PRSTATUS_SIZE];
+        // crash numbers the cpus after the pid, from 1.
+        prstatus[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        for (i, reg) in regs.iter().enumerate() {
+            let offset = PRSTATUS_REG + i * 8;
+            prstatus[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+        }
+        notes.extend_from_slice(&NOTE_NAME_SIZE.to_le_bytes());
+        notes.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+        notes.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+        notes.extend_from_slice(NOTE_NAME);
+        notes.extend_from_slice(&prstatus);
+    }
+    debug_assert_eq!(
+        notes.len(),
+        vcpu_regs.len() * (NOTE_HDR_SIZE + NOTE_NAME.len() + PRSTATUS_SIZE)
+    );
+
+    let phnum = 1 + ranges.len();
+    let notes_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let memory_offset =
+        (notes_offset + notes.len() + MEMORY_ALIGNMENT - 1) / MEMORY_ALIGNMENT * MEMORY_ALIGNMENT;
+
+    let mut header = Vec::with_capacity(memory_offset);
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    header.resize(16, 0);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&EM_AARCH64.to_le_bytes());
+    header.extend_from_slice(&u32::from(EV_CURRENT).to_le_bytes());
+    // Entry point, program and section header offsets, flags.
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(phnum as u16).to_le_bytes());
+    // No sections.
+    header.extend_from_slice(&[0u8; 6]);
+
+    let mut phdr = |p_type: u32, offset: u64, paddr: u64, size: u64| {
+        header.extend_from_slice(&p_type.to_le_bytes());
+        header.extend_from_slice(&PF_RWX.to_le_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        // Guest virtual addresses are unknown, tools go by the physical ones.
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&paddr.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+    };
+    phdr(PT_NOTE, notes_offset as u64, 0, notes.len() as u64);
+    let mut offset = memory_offset as u64;
+    for (addr, size) in ranges {
+        phdr(PT_LOAD, offset, addr.0, *size);
+        offset += size;
+    }
+    header.extend_from_slice(&notes);
+    header.resize(memory_offset, 0);
+
+    let mut file = File::create(path).map_err(Error::Dump)?;
+    file.write_all(&header).map_err(Error::Dump)?;
+    for (addr, size) in ranges {
+        guest_mem
+            .write_all_to(*addr, &mut file, *size as usize)
+            .map_err(|e| Error::Dump(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+    }
+    Ok(())
+}
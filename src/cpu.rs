@@ -1,24 +1,88 @@
+use crate::config;
+use crate::devices::Bus;
 use crate::error::*;
 use crate::regs;
 use kvm_bindings;
-use kvm_ioctls::{VcpuFd, VmFd};
-use std::sync::{Arc, Barrier};
+use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
+use std::fs;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::thread::JoinHandleExt;
+use std::ptr;
+use std::sync::{Arc, Barrier, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+/// The kvm_run structure of a vcpu, mapped again next to the mapping kvm-ioctls
+/// keeps to itself, to ask KVM_RUN to return right away.
+struct KvmRun {
+    run: *mut kvm_bindings::kvm_run,
+    size: usize,
+}
+
+// Safe because only the vcpu thread writes to the mapping, which lives as long as the vcpu.
+unsafe impl Send for KvmRun {}
+unsafe impl Sync for KvmRun {}
+
+impl KvmRun {
+    fn new(vcpu: &VcpuFd) -> Result<Self> {
+        // Safe because sysconf has no pointer arguments.
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        // Safe because the result is checked and the mapping is owned by the returned struct.
+        let run = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                0,
+            )
+        };
+        if run == libc::MAP_FAILED {
+            return Err(Error::MapKvmRun(std::io::Error::last_os_error()));
+        }
+        Ok(KvmRun {
+            run: run as *mut kvm_bindings::kvm_run,
+            size,
+        })
+    }
+
+    fn set_immediate_exit(&self, value: u8) {
+        // Safe because the mapping is valid and starts with the kvm_run structure.
+        unsafe { ptr::write_volatile(&mut (*self.run).immediate_exit, value) };
+    }
+}
+
+impl Drop for KvmRun {
+    fn drop(&mut self) {
+        // Safe because the mapping is ours and nothing uses it anymore.
+        unsafe { libc::munmap(self.run as *mut libc::c_void, self.size) };
+    }
+}
 
 pub struct Vcpu {
     fd: VcpuFd,
+    kvm_run: KvmRun,
     id: u8,
     mpidr: u64,
+    // Host CPUs the vcpu thread may run on, any when empty.
+    host_cpus: Vec<usize>,
 }
 
 impl Vcpu {
     pub fn new(id: u8, vm_fd: &VmFd) -> Result<Self> {
         let kvm_vcpu = vm_fd.create_vcpu(id).unwrap(); //.map_err(Error::X)?;
+        let kvm_run = KvmRun::new(&kvm_vcpu)?;
         Ok(Vcpu {
             fd: kvm_vcpu,
+            kvm_run,
             id,
             mpidr: 0,
+            host_cpus: Vec::new(),
         })
     }
 
@@ -47,27 +111,203 @@ impl Vcpu {
         Ok(())
     }
 
-    /*
-        pub fn run(&self) -> Result<()> {
-            Ok(())
+    /// Finishes handling the exit KVM_RUN last returned with, without running
+    /// the guest: the data of an MMIO read only gets to its register, and the
+    /// PC past the access, once KVM_RUN is called again.
+    fn complete_exit(&self) {
+        self.kvm_run.set_immediate_exit(1);
+        // Returns EINTR once the exit is complete.
+        self.fd.run().ok();
+        self.kvm_run.set_immediate_exit(0);
+    }
+
+    /// Runs the vcpu until the next exit that needs handling.
+    ///
+    /// Returns false once the guest powered off or reset the VM.
+    pub fn run(&self, mmio_bus: &Bus) -> Result<bool> {
+        match self.fd.run() {
+            Ok(VcpuExit::MmioRead(addr, data)) => {
+                if !mmio_bus.read(addr, data) {
+                    println!("vcpu{}: unhandled MMIO read at {:#x}", self.id, addr);
+                }
+                Ok(true)
+            }
+            Ok(VcpuExit::MmioWrite(addr, data)) => {
+                if !mmio_bus.write(addr, data) {
+                    println!("vcpu{}: unhandled MMIO write at {:#x}", self.id, addr);
+                }
+                Ok(true)
+            }
+            Ok(VcpuExit::SystemEvent(event_type, _flags)) => {
+                println!("vcpu{}: system event {}", self.id, event_type);
+                Ok(false)
+            }
+            Ok(VcpuExit::Shutdown) => Ok(false),
+            Ok(_) => Ok(true),
+            Err(e) => {
+                if e.errno() == libc::EINTR || e.errno() == libc::EAGAIN {
+                    Ok(true)
+                } else {
+                    println!("vcpu{}: KVM_RUN failed: {:?}", self.id, e);
+                    Ok(false)
+                }
+            }
         }
-    */
+    }
+}
 
-    pub fn fake_run() -> Result<()> {
-        Ok(())
+/// Core registers of each of `cpus`, which have to be paused.
+pub fn core_regs(cpus: &[Arc<Vcpu>]) -> Result<Vec<[u64; 34]>> {
+    cpus.iter()
+        .map(|cpu| regs::read_core_regs(&cpu.fd))
+        .collect()
+}
+
+/// The CPUs of host NUMA node `node`.
+pub fn host_node_cpus(node: u32) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|list| config::parse_ranges(&list, ',', libc::CPU_SETSIZE as usize - 1))
+        .filter(|cpus| !cpus.is_empty())
+        .ok_or(Error::HostNumaNode(node))
+}
+
+/// Restricts the calling thread to `cpus`.
+fn pin_current_thread(cpus: &[usize]) -> std::io::Result<()> {
+    // Safe because an all zero cpu_set_t is an empty set.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for cpu in cpus {
+        // Safe because CPU_SET checks the cpu fits in the set.
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    // Safe because set outlives the call, 0 is the calling thread.
+    let ret = unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Only there to make KVM_RUN return with EINTR.
+extern "C" fn handle_kick_signal(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {}
+
+struct RunState {
+    pause: bool,
+    // The VM is going away, the threads have to exit.
+    stop: bool,
+    // Threads waiting in `park_if_paused`, and threads still running the guest.
+    parked: usize,
+    alive: usize,
+}
+
+/// Lets other threads pause and resume the vcpu threads.
+pub struct VcpuControl {
+    state: Mutex<RunState>,
+    cond: Condvar,
+    threads: Mutex<Vec<libc::pthread_t>>,
+}
+
+impl VcpuControl {
+    fn new() -> Self {
+        VcpuControl {
+            state: Mutex::new(RunState {
+                pause: false,
+                stop: false,
+                parked: 0,
+                alive: 0,
+            }),
+            cond: Condvar::new(),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Waits while the vcpus are paused, returns false once they have to exit.
+    ///
+    /// The registers of `cpu` are complete while it waits, ready to be saved.
+    fn park_if_paused(&self, cpu: &Vcpu) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.pause && !state.stop {
+            cpu.complete_exit();
+            state.parked += 1;
+            self.cond.notify_all();
+            while state.pause && !state.stop {
+                state = self.cond.wait(state).unwrap();
+            }
+            state.parked -= 1;
+        }
+        !state.stop
+    }
+
+    fn exited(&self) {
+        self.state.lock().unwrap().alive -= 1;
+        self.cond.notify_all();
+    }
+
+    fn kick(&self) {
+        for thread in self.threads.lock().unwrap().iter() {
+            // Safe because the join handles are kept until the VM goes away, so the id is valid.
+            unsafe { libc::pthread_kill(*thread, SIGRTMIN()) };
+        }
+    }
+
+    /// Stops all vcpus, returns once none of them is running guest code.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.pause = true;
+        // A kick arriving right before KVM_RUN is lost, so keep kicking.
+        while state.parked < state.alive {
+            self.kick();
+            state = self
+                .cond
+                .wait_timeout(state, Duration::from_millis(10))
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Makes all vcpu threads exit, returns once they did.
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stop = true;
+        self.cond.notify_all();
+        while state.alive > 0 {
+            self.kick();
+            state = self
+                .cond
+                .wait_timeout(state, Duration::from_millis(10))
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn resume(&self) {
+        self.state.lock().unwrap().pause = false;
+        self.cond.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().pause
     }
 }
 
 pub struct VmCpu {
     cpus: Option<Vec<Vcpu>>,
+    // The vcpus once their threads run.
+    running: Vec<Arc<Vcpu>>,
     cpu_count: usize,
+    handles: Vec<thread::JoinHandle<()>>,
+    control: Arc<VcpuControl>,
 }
 
 impl VmCpu {
     pub fn new() -> Result<Self> {
         Ok(VmCpu {
             cpus: None,
+            running: Vec::new(),
             cpu_count: 0,
+            handles: Vec::new(),
+            control: Arc::new(VcpuControl::new()),
         })
     }
 
@@ -95,30 +335,121 @@ impl VmCpu {
         Ok(())
     }
 
-    pub fn start_vcpus(&mut self) -> Result<()> {
-        // TODO
-        // Totally not ready.
-        let vcpu_thread_barrier = Arc::new(Barrier::new(self.cpu_count));
-        for cpu in self.cpus.as_ref().unwrap() {
+    /// Runs the vcpus `ids` on the host CPUs `host_cpus` only, once started.
+    pub fn set_affinity(&mut self, ids: &[u8], host_cpus: &[usize]) {
+        for cpu in self.cpus.iter_mut().flatten() {
+            if ids.contains(&cpu.id) {
+                cpu.host_cpus = host_cpus.to_vec();
+            }
+        }
+    }
+
+    /// MPIDR of each vcpu, in creation order.
+    pub fn mpidrs(&self) -> Vec<u64> {
+        match self.cpus.as_ref() {
+            Some(cpus) => cpus.iter().map(|cpu| cpu.mpidr).collect(),
+            None => self.running.iter().map(|cpu| cpu.mpidr).collect(),
+        }
+    }
+
+    pub fn control(&self) -> Arc<VcpuControl> {
+        self.control.clone()
+    }
+
+    /// The vcpus whose threads run, for reading their registers while paused.
+    pub fn running(&self) -> Vec<Arc<Vcpu>> {
+        self.running.clone()
+    }
+
+    /// Core registers of each running vcpu, which have to be paused.
+    pub fn core_regs(&self) -> Result<Vec<[u64; 34]>> {
+        core_regs(&self.running)
+    }
+
+    /// Power state and registers of each running vcpu, which have to be paused.
+    pub fn save_state(&self) -> Result<Vec<(u32, regs::VcpuState)>> {
+        self.running
+            .iter()
+            .map(|cpu| {
+                let mp_state = cpu.fd.get_mp_state().map_err(Error::VcpuMpState)?;
+                Ok((mp_state.mp_state, regs::save_vcpu_state(&cpu.fd)?))
+            })
+            .collect()
+    }
+
+    /// Puts the vcpus, created but not started, in the state `save_state` returned.
+    pub fn restore_state(&mut self, state: &[(u32, regs::VcpuState)]) -> Result<()> {
+        let cpus = self.cpus.as_mut().unwrap();
+        if cpus.len() != state.len() {
+            return Err(Error::InvalidSnapshot(format!(
+                "{} vcpus saved, the VM has {}",
+                state.len(),
+                cpus.len()
+            )));
+        }
+        for (cpu, (mp_state, vcpu_state)) in cpus.iter_mut().zip(state.iter()) {
+            regs::check_vcpu_state(&cpu.fd, vcpu_state)?;
+            regs::restore_vcpu_state(&cpu.fd, vcpu_state)?;
+            cpu.fd
+                .set_mp_state(kvm_bindings::kvm_mp_state {
+                    mp_state: *mp_state,
+                })
+                .map_err(Error::VcpuMpState)?;
+            cpu.mpidr = regs::read_mpidr(&cpu.fd)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a thread per vcpu; `exit_evt` is signaled once the guest powers off.
+    pub fn start_vcpus(&mut self, mmio_bus: Arc<Bus>, exit_evt: &EventFd) -> Result<()> {
+        register_signal_handler(SIGRTMIN(), handle_kick_signal)
+            .map_err(Error::RegisterSignalHandler)?;
+
+        let vcpu_thread_barrier = Arc::new(Barrier::new(self.cpu_count + 1));
+        self.control.state.lock().unwrap().alive = self.cpu_count;
+        for cpu in self.cpus.take().unwrap() {
+            let cpu = Arc::new(cpu);
+            self.running.push(cpu.clone());
             let vcpu_thread_barrier = vcpu_thread_barrier.clone();
-            let _handle = Some(
-                thread::Builder::new()
-                    .name(format!("vcpu{}", cpu.id))
-                    .spawn(move || {
-                        // Block until all CPUs are ready.
-                        vcpu_thread_barrier.wait();
-                        loop {
-                            match Vcpu::fake_run() {
-                                Err(_e) => {
-                                    break;
-                                }
-                                Ok(()) => {}
-                            }
+            let mmio_bus = mmio_bus.clone();
+            let exit_evt = exit_evt.try_clone().map_err(Error::EventFd)?;
+            let control = self.control.clone();
+            let handle = thread::Builder::new()
+                .name(format!("vcpu{}", cpu.id))
+                .spawn(move || {
+                    if !cpu.host_cpus.is_empty() {
+                        if let Err(e) = pin_current_thread(&cpu.host_cpus) {
+                            println!("vcpu{}: failed to pin to host CPUs: {}", cpu.id, e);
                         }
-                    }),
-            );
+                    }
+                    // Block until all CPUs are ready.
+                    vcpu_thread_barrier.wait();
+                    while control.park_if_paused(&cpu) {
+                        match cpu.run(&mmio_bus) {
+                            Ok(true) => {}
+                            _ => break,
+                        }
+                    }
+                    control.exited();
+                    exit_evt.write(1).ok();
+                })
+                .map_err(Error::SpawnThread)?;
+            self.control
+                .threads
+                .lock()
+                .unwrap()
+                .push(handle.as_pthread_t());
+            self.handles.push(handle);
         }
         vcpu_thread_barrier.wait();
         Ok(())
     }
+
+    /// Stops the vcpu threads still running the guest and waits for all of them to exit.
+    pub fn join_vcpus(&mut self) {
+        self.control.stop();
+        for handle in self.handles.drain(..) {
+            handle.join().ok();
+        }
+    }
 }
@@ -0,0 +1,761 @@
+use crate::allocator::{AddressAllocator, IdAllocator};
+use crate::config::{MemHotplugConfig, PmemConfig, VirtioTransport};
+use crate::devices::pci::{PciBarRegionType, PciDevice, PciInterruptPin, PciRoot, PciRootPort};
+use crate::devices::pl061::{Pl061, PL061_LEN};
+use crate::devices::sp805::{Sp805, SP805_LEN};
+use crate::devices::virtio::{self, MmioTransport, VirtioDevice, VirtioPciDevice};
+use crate::devices::Bus;
+use crate::error::*;
+use crate::event_manager::EventManager;
+use crate::fdt::{self, FdtWriter};
+use crate::irqchip::GsiRouting;
+use crate::memory::{DeviceDirtyLog, MemorySlots, VmLayout};
+use kvm_ioctls::{IoEventAddress, VmFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Every MMIO device gets a page of its own.
+const MMIO_SLOT_SIZE: u64 = 0x1000;
+
+/// Root ports devices can be hotplugged behind.
+const PCI_HOTPLUG_PORTS: usize = 4;
+
+/// Where a virtio-mmio device was placed, for describing it to the guest.
+pub struct MmioDeviceInfo {
+    pub addr: u64,
+    pub len: u64,
+    pub irq: u32,
+    pub device_type: u32,
+}
+
+// GPIO line of the power button and the key it reports, see linux/input-event-codes.h.
+const POWER_BUTTON_LINE: u32 = 3;
+const KEY_POWER: u32 = 116;
+
+// Address space codes in the first cell of a PCI address, see the PCI bus binding.
+const PCI_RANGE_MMIO32: u32 = 0x0200_0000;
+const PCI_RANGE_MMIO64: u32 = 0x4300_0000;
+
+/// The power button wired to the GPIO controller.
+#[derive(Clone)]
+pub struct PowerButton {
+    gpio: Arc<Mutex<Pl061>>,
+}
+
+impl PowerButton {
+    /// Presses and releases the button, asking the guest to shut down.
+    pub fn press(&self) {
+        self.gpio.lock().unwrap().set_input(POWER_BUTTON_LINE, true);
+        thread::sleep(Duration::from_millis(100));
+        self.gpio
+            .lock()
+            .unwrap()
+            .set_input(POWER_BUTTON_LINE, false);
+    }
+}
+
+struct GpioInfo {
+    addr: u64,
+    irq: u32,
+    device: Arc<Mutex<Pl061>>,
+}
+
+struct WatchdogInfo {
+    addr: u64,
+    irq: u32,
+    device: Arc<Mutex<Sp805>>,
+}
+
+/// A device added while the guest runs, and what it took from the allocators.
+struct HotpluggedDevice {
+    id: String,
+    // Slot of the root port the device sits behind.
+    port: u8,
+    bars: Vec<(u64, u64, PciBarRegionType)>,
+    // Guest address, size and KVM slot of the memory of a pmem device.
+    device_mem: Option<(u64, u64, u32)>,
+}
+
+/// Allocates MMIO space and interrupt lines to devices and puts them on the MMIO bus.
+pub struct DeviceManager {
+    mmio_bus: Arc<Bus>,
+    mmio_space: AddressAllocator,
+    irqs: IdAllocator,
+    device_mem: AddressAllocator,
+    // Shared with guest memory and virtio-mem, which takes slots as the guest plugs memory.
+    mem_slots: Arc<Mutex<MemorySlots>>,
+    // Where virtio queues mark the guest memory devices write to.
+    dirty_log: Arc<DeviceDirtyLog>,
+    pub mmio_devices: Vec<MmioDeviceInfo>,
+    gpio: Option<GpioInfo>,
+    watchdog: Option<WatchdogInfo>,
+    pci_root: Arc<Mutex<PciRoot>>,
+    pci_mmio32: AddressAllocator,
+    pci_mmio64: AddressAllocator,
+    // INTx line of each populated PCI slot.
+    pci_irqs: Vec<(u8, u32)>,
+    // Slots of the root ports for hotplug.
+    hotplug_ports: Vec<u8>,
+    hotplugged: Vec<HotpluggedDevice>,
+    gsi_routing: Arc<Mutex<GsiRouting>>,
+    event_manager: Arc<EventManager>,
+    mem_resizer: Option<virtio::mem::MemResizer>,
+}
+
+impl DeviceManager {
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        mem_slots: Arc<Mutex<MemorySlots>>,
+        event_manager: Arc<EventManager>,
+    ) -> Self {
+        let mmio_bus = Arc::new(Bus::new());
+        let pci_root = Arc::new(Mutex::new(PciRoot::new(mmio_bus.clone(), vm_fd.clone())));
+        // The bus is empty, nothing to overlap with.
+        mmio_bus
+            .insert(
+                pci_root.clone(),
+                VmLayout::PCI_ECAM_START,
+                VmLayout::PCI_ECAM_SIZE,
+            )
+            .unwrap();
+
+        DeviceManager {
+            mmio_bus,
+            mmio_space: AddressAllocator::new(
+                VmLayout::MAPPED_IO_START,
+                VmLayout::PCI_MMIO32_START - VmLayout::MAPPED_IO_START,
+            ),
+            irqs: IdAllocator::new(VmLayout::IRQ_BASE, VmLayout::IRQ_MAX),
+            device_mem: AddressAllocator::new(
+                VmLayout::DEVICE_MEM_START,
+                VmLayout::DEVICE_MEM_SIZE,
+            ),
+            dirty_log: mem_slots.lock().unwrap().device_log(),
+            mem_slots,
+            mmio_devices: Vec::new(),
+            gpio: None,
+            watchdog: None,
+            pci_root,
+            pci_mmio32: AddressAllocator::new(
+                VmLayout::PCI_MMIO32_START,
+                VmLayout::PCI_MMIO32_SIZE,
+            ),
+            pci_mmio64: AddressAllocator::new(
+                VmLayout::PCI_MMIO64_START,
+                VmLayout::PCI_MMIO64_SIZE,
+            ),
+            pci_irqs: Vec::new(),
+            hotplug_ports: Vec::new(),
+            hotplugged: Vec::new(),
+            gsi_routing: Arc::new(Mutex::new(GsiRouting::new(vm_fd))),
+            event_manager,
+            mem_resizer: None,
+        }
+    }
+
+    /// The event loop devices wait on their fds with.
+    pub fn event_manager(&self) -> Arc<EventManager> {
+        self.event_manager.clone()
+    }
+
+    /// Stops the devices writing to guest memory, for a snapshot, until `resume`.
+    pub fn pause(&self) -> Result<()> {
+        self.event_manager.pause();
+        self.mmio_bus.pause()
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        let resumed = self.mmio_bus.resume();
+        self.event_manager.resume();
+        resumed
+    }
+
+    /// Stops the device workers, for when the VM goes away.
+    pub fn shutdown(&self) {
+        self.event_manager.shutdown();
+    }
+
+    /// The bus vcpus dispatch MMIO exits to.
+    pub fn mmio_bus(&self) -> Arc<Bus> {
+        self.mmio_bus.clone()
+    }
+
+    /// Allocates `len` bytes of MMIO space, rounded up to whole slots.
+    pub fn allocate_mmio(&mut self, len: u64) -> Result<u64> {
+        let len = (len + MMIO_SLOT_SIZE - 1) / MMIO_SLOT_SIZE * MMIO_SLOT_SIZE;
+        self.mmio_space
+            .allocate(len, MMIO_SLOT_SIZE)
+            .ok_or(Error::MmioExhausted)
+    }
+
+    /// Allocates a shared peripheral interrupt.
+    pub fn allocate_irq(&mut self) -> Result<u32> {
+        self.irqs.allocate().ok_or(Error::IrqsExhausted)
+    }
+
+    /// Allocates a guest physical range for device memory, aligned on `align`.
+    pub fn allocate_device_memory(&mut self, size: u64, align: u64) -> Result<u64> {
+        self.device_mem
+            .allocate(size, align)
+            .ok_or(Error::DeviceMemoryExhausted)
+    }
+
+    /// Allocates a naturally aligned BAR in one of the PCI MMIO windows.
+    pub fn allocate_pci_bar(&mut self, size: u64, region_type: PciBarRegionType) -> Result<u64> {
+        let window = match region_type {
+            PciBarRegionType::Memory32 => &mut self.pci_mmio32,
+            PciBarRegionType::Memory64 => &mut self.pci_mmio64,
+        };
+        window.allocate(size, size).ok_or(Error::PciMemoryExhausted)
+    }
+
+    fn free_pci_bar(&mut self, addr: u64, size: u64, region_type: PciBarRegionType) {
+        match region_type {
+            PciBarRegionType::Memory32 => self.pci_mmio32.free(addr, size),
+            PciBarRegionType::Memory64 => self.pci_mmio64.free(addr, size),
+        }
+    }
+
+    /// Creates an irqfd for `irq`; writing to it raises the interrupt.
+    pub fn irqfd(vm_fd: &VmFd, irq: u32) -> Result<EventFd> {
+        let irq_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+        // KVM routes GSI n to SPI n on arm64.
+        vm_fd
+            .register_irqfd(&irq_evt, irq - VmLayout::IRQ_BASE)
+            .map_err(Error::RegisterIrqfd)?;
+        Ok(irq_evt)
+    }
+
+    /// Exposes `device` to the guest over virtio-mmio.
+    pub fn register_virtio_mmio(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        device: Box<dyn VirtioDevice>,
+    ) -> Result<()> {
+        let device_type = device.device_type();
+        let irq = self.allocate_irq()?;
+        let irq_evt = DeviceManager::irqfd(vm_fd, irq)?;
+        let addr = self.allocate_mmio(virtio::mmio::MMIO_LEN)?;
+
+        let transport = MmioTransport::new(mem.clone(), device, irq_evt, self.dirty_log.clone())?;
+        // Queue notifications wake up the device thread without a trip through the
+        // vcpu thread. Should registering fail, the transport still signals the queue.
+        for (index, evt) in transport.queue_evts().iter().enumerate() {
+            let notify_addr = IoEventAddress::Mmio(addr + virtio::mmio::QUEUE_NOTIFY_OFFSET);
+            if let Err(e) = vm_fd.register_ioevent(evt, &notify_addr, index as u32) {
+                println!("virtio-mmio: failed to register ioeventfd: {:?}", e);
+            }
+        }
+        self.mmio_bus.insert(
+            Arc::new(Mutex::new(transport)),
+            addr,
+            virtio::mmio::MMIO_LEN,
+        )?;
+
+        self.mmio_devices.push(MmioDeviceInfo {
+            addr,
+            len: virtio::mmio::MMIO_LEN,
+            irq,
+            device_type,
+        });
+        Ok(())
+    }
+
+    /// Puts `device` in a free slot of the PCI root bus, with its BARs and INTx line.
+    pub fn register_pci(&mut self, vm_fd: &VmFd, device: Arc<Mutex<dyn PciDevice>>) -> Result<u8> {
+        let slot = self
+            .pci_root
+            .lock()
+            .unwrap()
+            .next_free_slot()
+            .ok_or(Error::PciSlotsExhausted)?;
+        let irq = {
+            let mut device = device.lock().unwrap();
+            self.allocate_bars(&mut *device)?;
+            self.wire_intx(vm_fd, &mut *device)?
+        };
+
+        self.pci_root.lock().unwrap().add_device(slot, device)?;
+        self.pci_irqs.push((slot, irq));
+        Ok(slot)
+    }
+
+    // Allocates and sets the BARs `device` asks for, returns their address, size and type.
+    fn allocate_bars(
+        &mut self,
+        device: &mut dyn PciDevice,
+    ) -> Result<Vec<(u64, u64, PciBarRegionType)>> {
+        let mut bars = Vec::new();
+        for bar in device.bar_requests() {
+            let addr = self.allocate_pci_bar(bar.size, bar.region_type)?;
+            bars.push((addr, bar.size, bar.region_type));
+            device
+                .config_mut()
+                .add_pci_bar(bar.bar_idx, addr, bar.size, bar.region_type)?;
+        }
+        Ok(bars)
+    }
+
+    // Wires INTA of `device` to a new interrupt line and returns it.
+    fn wire_intx(&mut self, vm_fd: &VmFd, device: &mut dyn PciDevice) -> Result<u32> {
+        let irq = self.allocate_irq()?;
+        let irq_evt = DeviceManager::irqfd(vm_fd, irq)?;
+        device
+            .config_mut()
+            .set_interrupt(PciInterruptPin::IntA, irq as u8);
+        device.assign_intx(irq_evt);
+        Ok(irq)
+    }
+
+    /// Adds the root ports devices are hotplugged behind, each with a slot of its own.
+    pub fn register_hotplug_ports(&mut self, vm_fd: &VmFd) -> Result<()> {
+        for _ in 0..PCI_HOTPLUG_PORTS {
+            let slot = self
+                .pci_root
+                .lock()
+                .unwrap()
+                .next_free_slot()
+                .ok_or(Error::PciSlotsExhausted)?;
+            let mut port = PciRootPort::new(slot);
+            let irq = self.wire_intx(vm_fd, &mut port)?;
+            self.pci_root
+                .lock()
+                .unwrap()
+                .add_port(slot, Arc::new(Mutex::new(port)))?;
+            self.pci_irqs.push((slot, irq));
+            self.hotplug_ports.push(slot);
+        }
+        Ok(())
+    }
+
+    /// Exposes `device` to the guest over virtio-pci, interrupting through MSI-X.
+    pub fn register_virtio_pci(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        device: Box<dyn VirtioDevice>,
+    ) -> Result<()> {
+        let slot = self
+            .pci_root
+            .lock()
+            .unwrap()
+            .next_free_slot()
+            .ok_or(Error::PciSlotsExhausted)?;
+        // The requester ID of bus 0, function 0 of the slot.
+        let devid = u32::from(slot) << 3;
+        let device = VirtioPciDevice::new(
+            mem.clone(),
+            device,
+            self.gsi_routing.clone(),
+            devid,
+            self.dirty_log.clone(),
+        )?;
+        self.register_pci(vm_fd, Arc::new(Mutex::new(device)))?;
+        Ok(())
+    }
+
+    /// Exposes `device` to the guest over `transport`.
+    pub fn register_virtio(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        device: Box<dyn VirtioDevice>,
+        transport: VirtioTransport,
+    ) -> Result<()> {
+        match transport {
+            VirtioTransport::Mmio => self.register_virtio_mmio(vm_fd, mem, device),
+            VirtioTransport::Pci => self.register_virtio_pci(vm_fd, mem, device),
+        }
+    }
+
+    /// Maps the file of `config` into device memory and exposes it over virtio-pmem.
+    pub fn register_pmem(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        config: &PmemConfig,
+    ) -> Result<()> {
+        let mut pmem = virtio::pmem::Pmem::new(config, self.event_manager.clone())?;
+        let addr = self.allocate_device_memory(pmem.size(), virtio::pmem::PMEM_ALIGNMENT)?;
+        pmem.map_to_guest(&mut self.mem_slots.lock().unwrap(), addr)?;
+        self.register_virtio(vm_fd, mem, Box::new(pmem), config.transport)
+    }
+
+    /// Exposes the hotplug `region` of `mem` over virtio-mem, with nothing plugged.
+    pub fn register_virtio_mem(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        config: &MemHotplugConfig,
+        region: (GuestAddress, usize),
+    ) -> Result<()> {
+        let device = virtio::mem::Mem::new(
+            config,
+            mem,
+            region,
+            self.mem_slots.clone(),
+            self.event_manager.clone(),
+        )?;
+        self.mem_resizer = Some(device.resizer());
+        self.register_virtio(vm_fd, mem, Box::new(device), config.transport)
+    }
+
+    /// Asks the guest to have `size` bytes of the hotplug region plugged.
+    pub fn resize_memory(&self, size: u64) -> Result<()> {
+        self.mem_resizer
+            .as_ref()
+            .ok_or(Error::NoMemoryHotplug)?
+            .resize(size)
+    }
+
+    /// Adds `device` behind a free root port while the guest runs.
+    ///
+    /// Returns the id to unplug it with, `kind` followed by a number.
+    pub fn hotplug_virtio(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        kind: &str,
+        device: Box<dyn VirtioDevice>,
+        transport: VirtioTransport,
+    ) -> Result<String> {
+        self.hotplug(vm_fd, mem, kind, device, transport, None)
+    }
+
+    /// Maps the file of `config` into device memory and hotplugs it over virtio-pmem.
+    pub fn hotplug_pmem(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        config: &PmemConfig,
+    ) -> Result<String> {
+        let mut pmem = virtio::pmem::Pmem::new(config, self.event_manager.clone())?;
+        let size = pmem.size();
+        let addr = self.allocate_device_memory(size, virtio::pmem::PMEM_ALIGNMENT)?;
+        let slot = match pmem.map_to_guest(&mut self.mem_slots.lock().unwrap(), addr) {
+            Ok(slot) => slot,
+            Err(e) => {
+                self.device_mem.free(addr, size);
+                return Err(e);
+            }
+        };
+        let device_mem = (addr, size, slot);
+        let result = self.hotplug(
+            vm_fd,
+            mem,
+            "pmem",
+            Box::new(pmem),
+            config.transport,
+            Some(device_mem),
+        );
+        if result.is_err() {
+            self.release_device_mem(device_mem);
+        }
+        result
+    }
+
+    fn hotplug(
+        &mut self,
+        vm_fd: &VmFd,
+        mem: &GuestMemoryMmap,
+        kind: &str,
+        device: Box<dyn VirtioDevice>,
+        transport: VirtioTransport,
+        device_mem: Option<(u64, u64, u32)>,
+    ) -> Result<String> {
+        if transport == VirtioTransport::Mmio {
+            return Err(Error::HotplugUnsupported);
+        }
+        self.reap_ejected();
+        let port = self
+            .hotplug_ports
+            .iter()
+            .copied()
+            .find(|port| !self.hotplugged.iter().any(|d| d.port == *port))
+            .ok_or(Error::HotplugSlotsExhausted)?;
+
+        // The requester ID of device 0 on the bus behind the port.
+        let bus = match self.pci_root.lock().unwrap().port(port) {
+            Some(port) => port.lock().unwrap().secondary_bus(),
+            None => 0,
+        };
+        let devid = u32::from(bus) << 8;
+        let mut device = VirtioPciDevice::new(
+            mem.clone(),
+            device,
+            self.gsi_routing.clone(),
+            devid,
+            self.dirty_log.clone(),
+        )?;
+        let bars = self.allocate_bars(&mut device)?;
+        // The guest swizzles INTA behind the port to the INTA of the port.
+        if let Some((_, irq)) = self.pci_irqs.iter().find(|(slot, _)| *slot == port) {
+            device
+                .config_mut()
+                .set_interrupt(PciInterruptPin::IntA, *irq as u8);
+        }
+        let plugged = self
+            .pci_root
+            .lock()
+            .unwrap()
+            .plug(port, Arc::new(Mutex::new(device)));
+        if let Err(e) = plugged {
+            for (addr, size, region_type) in bars {
+                self.free_pci_bar(addr, size, region_type);
+            }
+            return Err(e);
+        }
+
+        let id = (0..)
+            .map(|n| format!("{}{}", kind, n))
+            .find(|id| !self.hotplugged.iter().any(|d| d.id == *id))
+            .unwrap();
+        println!("Hotplugged {} behind the root port in slot {}", id, port);
+        self.hotplugged.push(HotpluggedDevice {
+            id: id.clone(),
+            port,
+            bars,
+            device_mem,
+        });
+        Ok(id)
+    }
+
+    /// Asks the guest to release the hotplugged device `id`.
+    ///
+    /// The device is removed once the guest ejected it, see `is_plugged`.
+    pub fn unplug(&mut self, id: &str) -> Result<()> {
+        self.reap_ejected();
+        let port = self
+            .hotplugged
+            .iter()
+            .find(|d| d.id == id)
+            .and_then(|d| self.pci_root.lock().unwrap().port(d.port))
+            .ok_or_else(|| Error::DeviceNotFound(id.to_string()))?;
+
+        port.lock().unwrap().request_unplug();
+        Ok(())
+    }
+
+    /// Whether the hotplugged device `id` is still there, removes it if the guest ejected it.
+    pub fn is_plugged(&mut self, id: &str) -> bool {
+        self.reap_ejected();
+        self.hotplugged.iter().any(|d| d.id == id)
+    }
+
+    /// State of the devices for a snapshot, see `Bus::snapshot`.
+    ///
+    /// Hotplugged devices are not part of the configuration a restore starts
+    /// from, so they have to be unplugged first.
+    pub fn snapshot(&mut self) -> Result<Vec<(u64, Vec<u8>)>> {
+        self.reap_ejected();
+        if let Some(device) = self.hotplugged.first() {
+            return Err(Error::SnapshotUnsupported(format!(
+                "hotplugged device {} has to be unplugged first",
+                device.id
+            )));
+        }
+        self.mmio_bus.snapshot()
+    }
+
+    /// Restores the devices, set up from the configuration of the VM the
+    /// states were taken from.
+    pub fn restore(&self, states: &[(u64, Vec<u8>)]) -> Result<()> {
+        self.mmio_bus.restore(states)
+    }
+
+    // Removes the devices the guest ejected, asked to or not, and frees what they took.
+    fn reap_ejected(&mut self) {
+        let ejected: Vec<HotpluggedDevice> = {
+            let pci_root = self.pci_root.lock().unwrap();
+            let (ejected, plugged): (Vec<_>, Vec<_>) = self.hotplugged.drain(..).partition(|d| {
+                pci_root
+                    .port(d.port)
+                    .map_or(true, |port| port.lock().unwrap().is_ejected())
+            });
+            self.hotplugged = plugged;
+            ejected
+        };
+
+        for device in ejected {
+            // The mapping of a pmem device goes away with it, KVM must not use it anymore.
+            if let Some(device_mem) = device.device_mem {
+                self.release_device_mem(device_mem);
+            }
+            let child = self.pci_root.lock().unwrap().remove_child(device.port);
+            for (addr, size, region_type) in device.bars {
+                self.free_pci_bar(addr, size, region_type);
+            }
+            drop(child);
+            println!("Unplugged {}", device.id);
+        }
+    }
+
+    // Deletes the KVM slot of a device memory range and frees both.
+    fn release_device_mem(&mut self, (addr, size, slot): (u64, u64, u32)) {
+        self.mem_slots.lock().unwrap().remove(slot);
+        self.device_mem.free(addr, size);
+    }
+
+    /// Adds the PL061 GPIO controller the power button is wired to.
+    pub fn register_gpio(&mut self, vm_fd: &VmFd) -> Result<()> {
+        let irq = self.allocate_irq()?;
+        let irq_evt = DeviceManager::irqfd(vm_fd, irq)?;
+        let addr = self.allocate_mmio(PL061_LEN)?;
+
+        let device = Arc::new(Mutex::new(Pl061::new(irq_evt)));
+        self.mmio_bus.insert(device.clone(), addr, PL061_LEN)?;
+        self.gpio = Some(GpioInfo { addr, irq, device });
+        Ok(())
+    }
+
+    pub fn power_button(&self) -> Option<PowerButton> {
+        self.gpio.as_ref().map(|gpio| PowerButton {
+            gpio: gpio.device.clone(),
+        })
+    }
+
+    /// Adds the SP805 watchdog.
+    pub fn register_watchdog(&mut self, vm_fd: &VmFd) -> Result<()> {
+        let irq = self.allocate_irq()?;
+        let irq_evt = DeviceManager::irqfd(vm_fd, irq)?;
+        let addr = self.allocate_mmio(SP805_LEN)?;
+
+        let device = Arc::new(Mutex::new(Sp805::new(
+            irq_evt,
+            u64::from(fdt::APB_PCLK_FREQUENCY),
+        )));
+        self.mmio_bus.insert(device.clone(), addr, SP805_LEN)?;
+        self.watchdog = Some(WatchdogInfo { addr, irq, device });
+        Ok(())
+    }
+
+    pub fn watchdog(&self) -> Option<Arc<Mutex<Sp805>>> {
+        self.watchdog
+            .as_ref()
+            .map(|watchdog| watchdog.device.clone())
+    }
+
+    fn create_watchdog_fdt_node(watchdog: &WatchdogInfo, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("watchdog@{:x}", watchdog.addr));
+        fdt.property_string_list("compatible", &["arm,sp805", "arm,primecell"]);
+        fdt.property_array_u64("reg", &[watchdog.addr, SP805_LEN]);
+        fdt.property_array_u32(
+            "interrupts",
+            &fdt::spi(watchdog.irq, fdt::IRQ_TYPE_EDGE_RISING),
+        );
+        // The counter runs off the bus clock.
+        fdt.property_array_u32("clocks", &[fdt::APB_PCLK_PHANDLE, fdt::APB_PCLK_PHANDLE]);
+        fdt.property_string_list("clock-names", &["wdog_clk", "apb_pclk"]);
+        fdt.end_node();
+    }
+
+    fn create_gpio_fdt_nodes(gpio: &GpioInfo, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("pl061@{:x}", gpio.addr));
+        fdt.property_string_list("compatible", &["arm,pl061", "arm,primecell"]);
+        fdt.property_array_u64("reg", &[gpio.addr, PL061_LEN]);
+        fdt.property_array_u32("interrupts", &fdt::spi(gpio.irq, fdt::IRQ_TYPE_EDGE_RISING));
+        fdt.property_null("gpio-controller");
+        fdt.property_u32("#gpio-cells", 2);
+        fdt.property_u32("clocks", fdt::APB_PCLK_PHANDLE);
+        fdt.property_string("clock-names", "apb_pclk");
+        fdt.property_u32("phandle", fdt::GPIO_PHANDLE);
+        fdt.end_node();
+
+        fdt.begin_node("gpio-keys");
+        fdt.property_string("compatible", "gpio-keys");
+        fdt.begin_node("poweroff");
+        fdt.property_string("label", "GPIO Key Poweroff");
+        fdt.property_u32("linux,code", KEY_POWER);
+        fdt.property_array_u32("gpios", &[fdt::GPIO_PHANDLE, POWER_BUTTON_LINE, 0]);
+        fdt.end_node();
+        fdt.end_node();
+    }
+
+    fn create_pci_fdt_node(&self, fdt: &mut FdtWriter, msi_parent: Option<u32>) {
+        let range = |space: u32, start: u64, size: u64| {
+            [
+                space,
+                (start >> 32) as u32,
+                start as u32,
+                (start >> 32) as u32,
+                start as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ]
+        };
+        let mut ranges = Vec::new();
+        ranges.extend_from_slice(&range(
+            PCI_RANGE_MMIO32,
+            VmLayout::PCI_MMIO32_START,
+            VmLayout::PCI_MMIO32_SIZE,
+        ));
+        ranges.extend_from_slice(&range(
+            PCI_RANGE_MMIO64,
+            VmLayout::PCI_MMIO64_START,
+            VmLayout::PCI_MMIO64_SIZE,
+        ));
+
+        fdt.begin_node(&format!("pcie@{:x}", VmLayout::PCI_ECAM_START));
+        fdt.property_string("compatible", "pci-host-ecam-generic");
+        fdt.property_string("device_type", "pci");
+        fdt.property_array_u64("reg", &[VmLayout::PCI_ECAM_START, VmLayout::PCI_ECAM_SIZE]);
+        let last_bus = (VmLayout::PCI_ECAM_SIZE >> 20) as u32 - 1;
+        fdt.property_array_u32("bus-range", &[0, last_bus]);
+        fdt.property_u32("#address-cells", 3);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_array_u32("ranges", &ranges);
+        fdt.property_null("dma-coherent");
+        if let Some(msi_parent) = msi_parent {
+            fdt.property_u32("msi-parent", msi_parent);
+        }
+
+        if !self.pci_irqs.is_empty() {
+            // INTx is level triggered, but an irqfd only pulses the line.
+            let mut interrupt_map = Vec::new();
+            for (slot, irq) in self.pci_irqs.iter() {
+                interrupt_map.extend_from_slice(&[
+                    u32::from(*slot) << 11,
+                    0,
+                    0,
+                    PciInterruptPin::IntA as u32,
+                    fdt::GIC_PHANDLE,
+                    0,
+                    0,
+                ]);
+                interrupt_map.extend_from_slice(&fdt::spi(*irq, fdt::IRQ_TYPE_EDGE_RISING));
+            }
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_array_u32("interrupt-map", &interrupt_map);
+            fdt.property_array_u32("interrupt-map-mask", &[0xf800, 0, 0, 7]);
+        }
+        fdt.end_node();
+    }
+
+    /// Describes all the devices in the device tree.
+    ///
+    /// PCI devices send their MSIs to `msi_parent`, without one they use INTx.
+    pub fn create_fdt_nodes(&self, fdt: &mut FdtWriter, msi_parent: Option<u32>) {
+        self.create_pci_fdt_node(fdt, msi_parent);
+        if let Some(gpio) = &self.gpio {
+            DeviceManager::create_gpio_fdt_nodes(gpio, fdt);
+        }
+        if let Some(watchdog) = &self.watchdog {
+            DeviceManager::create_watchdog_fdt_node(watchdog, fdt);
+        }
+        for device in self.mmio_devices.iter() {
+            fdt.begin_node(&format!("virtio_mmio@{:x}", device.addr));
+            fdt.property_string("compatible", "virtio,mmio");
+            fdt.property_array_u64("reg", &[device.addr, device.len]);
+            fdt.property_array_u32(
+                "interrupts",
+                &fdt::spi(device.irq, fdt::IRQ_TYPE_EDGE_RISING),
+            );
+            fdt.property_null("dma-coherent");
+            fdt.end_node();
+        }
+    }
+}
@@ -0,0 +1,212 @@
+pub mod pci;
+pub mod pl061;
+pub mod sp805;
+pub mod virtio;
+
+use crate::error::*;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Trait for devices that respond to reads or writes in an arbitrary address space.
+///
+/// The `offset` is relative to the base address the device was inserted at.
+pub trait BusDevice: Send {
+    fn read(&mut self, _offset: u64, _data: &mut [u8]) {}
+    fn write(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// State of the device for a snapshot, taken while the vcpus are paused.
+    ///
+    /// None for devices without state, which are left out of snapshots.
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Puts the device, set up the same way as the one `state` was taken from,
+    /// back in that state before the vcpus start.
+    fn restore(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops work done outside of the device threads, like by vhost in the
+    /// host kernel, so guest memory stays as it is until `resume`.
+    fn pause(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BusRange {
+    base: u64,
+    len: u64,
+}
+
+impl Eq for BusRange {}
+
+impl PartialEq for BusRange {
+    fn eq(&self, other: &BusRange) -> bool {
+        self.base == other.base
+    }
+}
+
+impl Ord for BusRange {
+    fn cmp(&self, other: &BusRange) -> Ordering {
+        self.base.cmp(&other.base)
+    }
+}
+
+impl PartialOrd for BusRange {
+    fn partial_cmp(&self, other: &BusRange) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A device container for routing reads and writes over some address space.
+///
+/// The bus is shared by all vcpu threads, so devices can be added and removed
+/// while the VM is running.
+#[derive(Default)]
+pub struct Bus {
+    devices: RwLock<BTreeMap<BusRange, Arc<Mutex<dyn BusDevice>>>>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            devices: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    fn first_before(&self, addr: u64) -> Option<(BusRange, Arc<Mutex<dyn BusDevice>>)> {
+        let devices = self.devices.read().unwrap();
+        let (range, dev) = devices
+            .range(..=BusRange { base: addr, len: 1 })
+            .rev()
+            .next()?;
+        Some((*range, dev.clone()))
+    }
+
+    fn get_device(&self, addr: u64) -> Option<(u64, Arc<Mutex<dyn BusDevice>>)> {
+        if let Some((range, dev)) = self.first_before(addr) {
+            let offset = addr - range.base;
+            if offset < range.len {
+                return Some((offset, dev));
+            }
+        }
+        None
+    }
+
+    /// Puts the given device at the given address space.
+    pub fn insert(&self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
+        if len == 0 {
+            return Err(Error::BusOverlap);
+        }
+
+        let mut devices = self.devices.write().unwrap();
+        // Reject the range if it overlaps with anything already inserted.
+        let overlaps = devices
+            .keys()
+            .any(|r| base < r.base + r.len && r.base < base + len);
+        if overlaps {
+            return Err(Error::BusOverlap);
+        }
+
+        devices.insert(BusRange { base, len }, device);
+        Ok(())
+    }
+
+    /// Removes the device inserted at `base`, returning it.
+    pub fn remove(&self, base: u64) -> Option<Arc<Mutex<dyn BusDevice>>> {
+        self.devices
+            .write()
+            .unwrap()
+            .remove(&BusRange { base, len: 1 })
+    }
+
+    /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
+    ///
+    /// Returns true on success, otherwise `data` is untouched.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> bool {
+        if let Some((offset, dev)) = self.get_device(addr) {
+            dev.lock().unwrap().read(offset, data);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Writes `data` to the device that owns the range containing `addr`.
+    ///
+    /// Returns true on success, otherwise `data` is untouched.
+    pub fn write(&self, addr: u64, data: &[u8]) -> bool {
+        if let Some((offset, dev)) = self.get_device(addr) {
+            dev.lock().unwrap().write(offset, data);
+            true
+        } else {
+            false
+        }
+    }
+
+    // The devices and the address they sit at, without holding the lock
+    // while calling into them.
+    fn device_list(&self) -> Vec<(u64, Arc<Mutex<dyn BusDevice>>)> {
+        self.devices
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(range, dev)| (range.base, dev.clone()))
+            .collect()
+    }
+
+    /// State of each device that has some, by the address it sits at.
+    pub fn snapshot(&self) -> Result<Vec<(u64, Vec<u8>)>> {
+        let mut states = Vec::new();
+        for (base, dev) in self.device_list() {
+            if let Some(state) = dev.lock().unwrap().snapshot()? {
+                states.push((base, state));
+            }
+        }
+        Ok(states)
+    }
+
+    /// Pauses all devices, see `BusDevice::pause`.
+    pub fn pause(&self) -> Result<()> {
+        for (_, dev) in self.device_list() {
+            dev.lock().unwrap().pause()?;
+        }
+        Ok(())
+    }
+
+    /// Resumes all devices, also the ones that were not paused. Returns the
+    /// first error, after trying every device.
+    pub fn resume(&self) -> Result<()> {
+        let mut result = Ok(());
+        for (_, dev) in self.device_list() {
+            let resumed = dev.lock().unwrap().resume();
+            result = result.and(resumed);
+        }
+        result
+    }
+
+    /// Restores the devices from the states `snapshot` returned.
+    ///
+    /// Devices may move others on the bus when restored, like PCI BARs, which
+    /// only sit there to route accesses and have no state.
+    pub fn restore(&self, states: &[(u64, Vec<u8>)]) -> Result<()> {
+        let devices = self.device_list();
+        for (base, state) in states {
+            let dev = devices
+                .iter()
+                .find(|(addr, _)| addr == base)
+                .ok_or_else(|| {
+                    Error::InvalidSnapshot(format!("no device at {:#x} to restore", base))
+                })?;
+            dev.1.lock().unwrap().restore(state)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,474 @@
+// Configuration space header of a PCI function, type 0 or the type 1 of a bridge.
+// Spec: PCI Local Bus Specification 3.0, chapter 6, and the PCI-to-PCI Bridge
+// Architecture Specification 1.2, chapter 3.
+
+use super::PciBarRegionType;
+use crate::error::*;
+use crate::snapshot::{StateReader, StateWriter};
+
+/// Number of 32-bit registers in the conventional configuration space.
+pub const NUM_CONFIGURATION_REGISTERS: usize = 64;
+
+pub const NUM_BAR_REGS: usize = 6;
+
+// Command in the low half, status in the high half.
+const COMMAND_REG: usize = 1;
+const COMMAND_REG_WRITABLE: u32 = 0x0000_ffff;
+const STATUS_REG_CAPABILITIES_USED: u32 = 0x0010_0000;
+const BAR0_REG: usize = 4;
+const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
+const BAR_MEM_64BIT: u32 = 0x04;
+const BAR_MEM_PREFETCHABLE: u32 = 0x08;
+const CAPABILITY_LIST_HEAD_OFFSET: usize = 0x34;
+const FIRST_CAPABILITY_OFFSET: usize = 0x40;
+const INTERRUPT_LINE_PIN_REG: usize = 15;
+
+// Type 1 header registers.
+const HEADER_TYPE_BRIDGE: u32 = 0x0001_0000;
+const BUS_NUMBERS_REG: usize = 6;
+const MEMORY_WINDOW_REG: usize = 8;
+const PREFETCH_WINDOW_REG: usize = 9;
+const PREFETCH_BASE_UPPER_REG: usize = 10;
+const PREFETCH_LIMIT_UPPER_REG: usize = 11;
+const WINDOW_WRITABLE: u32 = 0xfff0_fff0;
+const PREFETCH_WINDOW_64BIT: u32 = 0x0001_0001;
+// Interrupt line in the low byte, bridge control in the upper half.
+const BRIDGE_CONTROL_WRITABLE: u32 = 0xffff_00ff;
+const PCI_CLASS_BRIDGE: u8 = 0x06;
+const PCI_SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+/// Interrupt pin a function uses for INTx.
+#[derive(Clone, Copy, Debug)]
+pub enum PciInterruptPin {
+    IntA = 1,
+    IntB,
+    IntC,
+    IntD,
+}
+
+/// Contents of the configuration space of a function, and which bits the guest may write.
+pub struct PciConfiguration {
+    registers: [u32; NUM_CONFIGURATION_REGISTERS],
+    writable_bits: [u32; NUM_CONFIGURATION_REGISTERS],
+    bar_sizes: [u64; NUM_BAR_REGS],
+    bar_used: [bool; NUM_BAR_REGS],
+    // Offset of the last capability added, 0 if none.
+    last_capability: usize,
+    next_capability: usize,
+}
+
+impl PciConfiguration {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        vendor_id: u16,
+        device_id: u16,
+        revision_id: u8,
+        class_code: u8,
+        subclass: u8,
+        prog_if: u8,
+        subsystem_vendor_id: u16,
+        subsystem_id: u16,
+    ) -> Self {
+        let mut registers = [0u32; NUM_CONFIGURATION_REGISTERS];
+        let mut writable_bits = [0u32; NUM_CONFIGURATION_REGISTERS];
+        registers[0] = u32::from(device_id) << 16 | u32::from(vendor_id);
+        writable_bits[COMMAND_REG] = COMMAND_REG_WRITABLE;
+        registers[2] = u32::from(class_code) << 24
+            | u32::from(subclass) << 16
+            | u32::from(prog_if) << 8
+            | u32::from(revision_id);
+        // Header type 0, single function.
+        registers[3] = 0;
+        registers[11] = u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+        // The interrupt line is scratch space for the guest.
+        writable_bits[INTERRUPT_LINE_PIN_REG] = 0x0000_00ff;
+
+        PciConfiguration {
+            registers,
+            writable_bits,
+            bar_sizes: [0; NUM_BAR_REGS],
+            bar_used: [false; NUM_BAR_REGS],
+            last_capability: 0,
+            next_capability: FIRST_CAPABILITY_OFFSET,
+        }
+    }
+
+    /// A PCI-to-PCI bridge with a type 1 header.
+    ///
+    /// The guest numbers the bus behind it and sets its memory windows; there is
+    /// no I/O window.
+    pub fn new_bridge(vendor_id: u16, device_id: u16) -> Self {
+        let mut config = PciConfiguration::new(
+            vendor_id,
+            device_id,
+            0,
+            PCI_CLASS_BRIDGE,
+            PCI_SUBCLASS_PCI_BRIDGE,
+            0,
+            0,
+            0,
+        );
+        config.registers[3] = HEADER_TYPE_BRIDGE;
+        config.writable_bits[BUS_NUMBERS_REG] = 0x00ff_ffff;
+        config.writable_bits[MEMORY_WINDOW_REG] = WINDOW_WRITABLE;
+        config.registers[PREFETCH_WINDOW_REG] = PREFETCH_WINDOW_64BIT;
+        config.writable_bits[PREFETCH_WINDOW_REG] = WINDOW_WRITABLE;
+        config.writable_bits[PREFETCH_BASE_UPPER_REG] = 0xffff_ffff;
+        config.writable_bits[PREFETCH_LIMIT_UPPER_REG] = 0xffff_ffff;
+        config.writable_bits[INTERRUPT_LINE_PIN_REG] = BRIDGE_CONTROL_WRITABLE;
+        config
+    }
+
+    /// Number of the bus behind a bridge, 0 until the guest assigned it.
+    pub fn secondary_bus(&self) -> u8 {
+        (self.registers[BUS_NUMBERS_REG] >> 8) as u8
+    }
+
+    pub fn read_reg(&self, reg_idx: usize) -> u32 {
+        self.registers.get(reg_idx).copied().unwrap_or(0xffff_ffff)
+    }
+
+    /// Writes `data` at byte `offset` of register `reg_idx`, leaving read-only bits alone.
+    pub fn write_reg(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if reg_idx >= NUM_CONFIGURATION_REGISTERS || offset as usize + data.len() > 4 {
+            return;
+        }
+        let shift = offset * 8;
+        let (value, mask) = match data.len() {
+            1 => (u32::from(data[0]), 0xff),
+            2 => (u32::from(u16::from_le_bytes([data[0], data[1]])), 0xffff),
+            4 => (
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                0xffff_ffff,
+            ),
+            _ => return,
+        };
+        let mask = (mask << shift) & self.writable_bits[reg_idx];
+        self.registers[reg_idx] = (self.registers[reg_idx] & !mask) | ((value << shift) & mask);
+    }
+
+    /// Saves the registers, the layout of the space comes with the function.
+    pub fn snapshot(&self, state: &mut StateWriter) {
+        for reg in self.registers.iter() {
+            state.u32(*reg);
+        }
+    }
+
+    pub fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        for reg in self.registers.iter_mut() {
+            *reg = state.u32()?;
+        }
+        Ok(())
+    }
+
+    /// Sets register `reg_idx` regardless of the writable bits, for registers
+    /// whose writes have side effects.
+    pub fn set_reg(&mut self, reg_idx: usize, value: u32) {
+        self.registers[reg_idx] = value;
+    }
+
+    /// Lets the guest write the bits in `mask` of register `reg_idx`.
+    pub fn set_writable_bits(&mut self, reg_idx: usize, mask: u32) {
+        self.writable_bits[reg_idx] = mask;
+    }
+
+    /// Wires the function to `pin`, routed to interrupt `line`.
+    pub fn set_interrupt(&mut self, pin: PciInterruptPin, line: u8) {
+        self.registers[INTERRUPT_LINE_PIN_REG] = (pin as u32) << 8 | u32::from(line);
+    }
+
+    /// Adds a memory BAR of `size` bytes at `addr`.
+    ///
+    /// A 64-bit BAR takes register `bar_idx + 1` as well.
+    pub fn add_pci_bar(
+        &mut self,
+        bar_idx: usize,
+        addr: u64,
+        size: u64,
+        region_type: PciBarRegionType,
+    ) -> Result<()> {
+        let is_64bit = region_type == PciBarRegionType::Memory64;
+        let last_idx = if is_64bit { bar_idx + 1 } else { bar_idx };
+        if last_idx >= NUM_BAR_REGS
+            || self.bar_used[bar_idx]
+            || self.bar_used[last_idx]
+            || !size.is_power_of_two()
+            || size < 0x10
+            || addr % size != 0
+            || (!is_64bit && (size > u64::from(u32::max_value()) || addr + size > 1 << 32))
+        {
+            return Err(Error::PciBarInvalid(bar_idx));
+        }
+
+        let mask = !(size - 1);
+        let reg_idx = BAR0_REG + bar_idx;
+        self.registers[reg_idx] = addr as u32 & BAR_MEM_ADDR_MASK;
+        self.writable_bits[reg_idx] = mask as u32 & BAR_MEM_ADDR_MASK;
+        if is_64bit {
+            self.registers[reg_idx] |= BAR_MEM_64BIT | BAR_MEM_PREFETCHABLE;
+            self.registers[reg_idx + 1] = (addr >> 32) as u32;
+            self.writable_bits[reg_idx + 1] = (mask >> 32) as u32;
+        }
+
+        self.bar_sizes[bar_idx] = size;
+        self.bar_used[bar_idx] = true;
+        self.bar_used[last_idx] = true;
+        Ok(())
+    }
+
+    /// Address the guest currently put BAR `bar_idx` at, 0 if the BAR is not used.
+    pub fn get_bar_addr(&self, bar_idx: usize) -> u64 {
+        if bar_idx >= NUM_BAR_REGS || self.bar_sizes[bar_idx] == 0 {
+            return 0;
+        }
+        let reg = self.registers[BAR0_REG + bar_idx];
+        let mut addr = u64::from(reg & BAR_MEM_ADDR_MASK);
+        if reg & BAR_MEM_64BIT != 0 {
+            addr |= u64::from(self.registers[BAR0_REG + bar_idx + 1]) << 32;
+        }
+        addr
+    }
+
+    pub fn get_bar_size(&self, bar_idx: usize) -> u64 {
+        self.bar_sizes.get(bar_idx).copied().unwrap_or(0)
+    }
+
+    /// Whether the guest wrote all ones to BAR `bar_idx` to learn its size.
+    pub fn is_bar_sizing(&self, bar_idx: usize) -> bool {
+        let reg_idx = BAR0_REG + bar_idx;
+        let sizing =
+            |idx: usize| self.registers[idx] & self.writable_bits[idx] == self.writable_bits[idx];
+        if self.registers[reg_idx] & BAR_MEM_64BIT != 0 {
+            sizing(reg_idx) || sizing(reg_idx + 1)
+        } else {
+            sizing(reg_idx)
+        }
+    }
+
+    /// Register index of BAR `bar_idx`.
+    pub fn bar_reg_idx(bar_idx: usize) -> usize {
+        BAR0_REG + bar_idx
+    }
+
+    /// Appends a capability with the given `id` to the capability list.
+    ///
+    /// `data` follows the ID and next pointer bytes. Returns the offset of the capability.
+    pub fn add_capability(&mut self, id: u8, data: &[u8]) -> Result<usize> {
+        let offset = self.next_capability;
+        let len = 2 + data.len();
+        if offset + len > NUM_CONFIGURATION_REGISTERS * 4 {
+            return Err(Error::PciCapabilitySpace);
+        }
+
+        self.write_byte(offset, id);
+        self.write_byte(offset + 1, 0);
+        for (i, b) in data.iter().enumerate() {
+            self.write_byte(offset + 2 + i, *b);
+        }
+
+        if self.last_capability == 0 {
+            self.write_byte(CAPABILITY_LIST_HEAD_OFFSET, offset as u8);
+            self.registers[COMMAND_REG] |= STATUS_REG_CAPABILITIES_USED;
+        } else {
+            self.write_byte(self.last_capability + 1, offset as u8);
+        }
+        self.last_capability = offset;
+        self.next_capability = (offset + len + 3) & !3;
+        Ok(offset)
+    }
+
+    fn write_byte(&mut self, offset: usize, value: u8) {
+        let reg = &mut self.registers[offset / 4];
+        let shift = (offset % 4) * 8;
+        *reg = (*reg & !(0xff << shift)) | (u32::from(value) << shift);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> PciConfiguration {
+        PciConfiguration::new(0x1af4, 0x1042, 1, 0x02, 0x00, 0x00, 0x1af4, 0x40)
+    }
+
+    #[test]
+    fn header_registers() {
+        let config = config();
+        assert_eq!(config.read_reg(0), 0x1042_1af4);
+        assert_eq!(config.read_reg(2), 0x0200_0001);
+        assert_eq!(config.read_reg(11), 0x0040_1af4);
+        assert_eq!(config.read_reg(NUM_CONFIGURATION_REGISTERS), 0xffff_ffff);
+    }
+
+    #[test]
+    fn write_reg_keeps_read_only_bits() {
+        let mut config = config();
+        config.write_reg(0, 0, &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(config.read_reg(0), 0x1042_1af4);
+
+        // Only the command half of the register is writable.
+        config.write_reg(COMMAND_REG, 0, &[0x06, 0x00, 0xff, 0xff]);
+        assert_eq!(config.read_reg(COMMAND_REG), 0x0006);
+        config.write_reg(COMMAND_REG, 1, &[0x04]);
+        assert_eq!(config.read_reg(COMMAND_REG), 0x0406);
+
+        config.set_interrupt(PciInterruptPin::IntA, 5);
+        config.write_reg(INTERRUPT_LINE_PIN_REG, 0, &[0x22, 0x00]);
+        assert_eq!(config.read_reg(INTERRUPT_LINE_PIN_REG), 0x0122);
+
+        // Accesses past the end of the register are dropped.
+        config.write_reg(COMMAND_REG, 3, &[0, 0]);
+        config.write_reg(COMMAND_REG, 0, &[0, 0, 0]);
+        assert_eq!(config.read_reg(COMMAND_REG), 0x0406);
+    }
+
+    #[test]
+    fn bar_32bit_sizing_and_move() {
+        let mut config = config();
+        config
+            .add_pci_bar(0, 0x1000_0000, 0x1000, PciBarRegionType::Memory32)
+            .unwrap();
+        assert_eq!(config.get_bar_addr(0), 0x1000_0000);
+        assert_eq!(config.get_bar_size(0), 0x1000);
+        assert!(!config.is_bar_sizing(0));
+
+        let reg_idx = PciConfiguration::bar_reg_idx(0);
+        config.write_reg(reg_idx, 0, &[0xff; 4]);
+        assert!(config.is_bar_sizing(0));
+        assert_eq!(config.read_reg(reg_idx), 0xffff_f000);
+
+        config.write_reg(reg_idx, 0, &0x2000_0000u32.to_le_bytes());
+        assert!(!config.is_bar_sizing(0));
+        assert_eq!(config.get_bar_addr(0), 0x2000_0000);
+
+        // Unused BARs read as zero.
+        assert_eq!(config.get_bar_addr(1), 0);
+        assert_eq!(config.get_bar_size(1), 0);
+        assert_eq!(config.get_bar_addr(NUM_BAR_REGS), 0);
+    }
+
+    #[test]
+    fn bar_64bit() {
+        let mut config = config();
+        config
+            .add_pci_bar(2, 0x80_0000_0000, 0x4000, PciBarRegionType::Memory64)
+            .unwrap();
+        let reg_idx = PciConfiguration::bar_reg_idx(2);
+        assert_eq!(
+            config.read_reg(reg_idx),
+            BAR_MEM_64BIT | BAR_MEM_PREFETCHABLE
+        );
+        assert_eq!(config.read_reg(reg_idx + 1), 0x80);
+        assert_eq!(config.get_bar_addr(2), 0x80_0000_0000);
+
+        // Sizing the upper half alone counts too.
+        config.write_reg(reg_idx + 1, 0, &[0xff; 4]);
+        assert!(config.is_bar_sizing(2));
+        assert_eq!(config.read_reg(reg_idx + 1), 0xffff_ffff);
+    }
+
+    #[test]
+    fn bar_rejects_invalid() {
+        let mut config = config();
+        let invalid = |r: Result<()>| match r {
+            Err(Error::PciBarInvalid(_)) => true,
+            _ => false,
+        };
+        // Not a power of two, too small, misaligned.
+        assert!(invalid(config.add_pci_bar(
+            0,
+            0,
+            0x3000,
+            PciBarRegionType::Memory32
+        )));
+        assert!(invalid(config.add_pci_bar(
+            0,
+            0,
+            0x8,
+            PciBarRegionType::Memory32
+        )));
+        assert!(invalid(config.add_pci_bar(
+            0,
+            0x800,
+            0x1000,
+            PciBarRegionType::Memory32
+        )));
+        // A 32-bit BAR past 4 GB.
+        assert!(invalid(config.add_pci_bar(
+            0,
+            0x1_0000_0000,
+            0x1000,
+            PciBarRegionType::Memory32
+        )));
+        // A 64-bit BAR needs a second register.
+        assert!(invalid(config.add_pci_bar(
+            NUM_BAR_REGS - 1,
+            0,
+            0x1000,
+            PciBarRegionType::Memory64
+        )));
+        assert!(invalid(config.add_pci_bar(
+            NUM_BAR_REGS,
+            0,
+            0x1000,
+            PciBarRegionType::Memory32
+        )));
+
+        // BARs can't share registers.
+        config
+            .add_pci_bar(1, 0, 0x1000, PciBarRegionType::Memory64)
+            .unwrap();
+        assert!(invalid(config.add_pci_bar(
+            1,
+            0,
+            0x1000,
+            PciBarRegionType::Memory32
+        )));
+        assert!(invalid(config.add_pci_bar(
+            2,
+            0,
+            0x1000,
+            PciBarRegionType::Memory32
+        )));
+        assert!(invalid(config.add_pci_bar(
+            0,
+            0,
+            0x1000,
+            PciBarRegionType::Memory64
+        )));
+        config
+            .add_pci_bar(0, 0, 0x1000, PciBarRegionType::Memory32)
+            .unwrap();
+    }
+
+    #[test]
+    fn capability_list() {
+        let mut config = config();
+        let first = config.add_capability(0x11, &[1, 2, 3]).unwrap();
+        assert_eq!(first, FIRST_CAPABILITY_OFFSET);
+        assert_eq!(
+            config.read_reg(CAPABILITY_LIST_HEAD_OFFSET / 4) & 0xff,
+            first as u32
+        );
+        assert_ne!(
+            config.read_reg(COMMAND_REG) & STATUS_REG_CAPABILITIES_USED,
+            0
+        );
+        assert_eq!(config.read_reg(first / 4), 0x0201_0011);
+
+        // The next capability starts on a dword boundary and is linked in.
+        let second = config.add_capability(0x09, &[7]).unwrap();
+        assert_eq!(second, first + 8);
+        assert_eq!(config.read_reg(first / 4) >> 8 & 0xff, second as u32);
+        assert_eq!(config.read_reg(second / 4), 0x0007_0009);
+
+        // Fill up the rest of the configuration space.
+        let rest = NUM_CONFIGURATION_REGISTERS * 4 - second - 4;
+        assert!(match config.add_capability(0x09, &vec![0; rest - 1]) {
+            Err(Error::PciCapabilitySpace) => true,
+            _ => false,
+        });
+        config.add_capability(0x09, &vec![0; rest - 2]).unwrap();
+    }
+}
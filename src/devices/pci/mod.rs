@@ -0,0 +1,91 @@
+// PCI emulation: a root bus behind a generic ECAM host bridge, with root ports
+// for hotplug.
+
+mod configuration;
+mod msix;
+mod root;
+mod root_port;
+
+pub use self::configuration::{
+    PciConfiguration, PciInterruptPin, NUM_BAR_REGS, NUM_CONFIGURATION_REGISTERS,
+};
+pub use self::msix::{MsixConfig, MSIX_MSG_CTL_WRITABLE, PCI_CAP_ID_MSIX};
+pub use self::root::{PciRoot, PCI_SLOTS};
+pub use self::root_port::PciRootPort;
+
+use crate::error::*;
+use crate::snapshot::{StateReader, StateWriter};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Kind of address space a BAR decodes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBarRegionType {
+    /// Memory below 4 GB.
+    Memory32,
+    /// Prefetchable memory anywhere, taking two BAR registers.
+    Memory64,
+}
+
+/// A BAR a function needs, the device manager picks its address.
+pub struct PciBarRequest {
+    pub bar_idx: usize,
+    pub size: u64,
+    pub region_type: PciBarRegionType,
+}
+
+/// A function on the root bus, or behind a root port.
+///
+/// Config space accesses come in through the ECAM window, BAR accesses through the
+/// MMIO bus at whatever address the guest programmed the BAR to.
+pub trait PciDevice: Send {
+    fn config(&self) -> &PciConfiguration;
+    fn config_mut(&mut self) -> &mut PciConfiguration;
+
+    /// BARs to allocate before the function is put on the bus.
+    fn bar_requests(&self) -> Vec<PciBarRequest> {
+        Vec::new()
+    }
+
+    /// Hands over the irqfd raising the INTx line of the function.
+    fn assign_intx(&mut self, _irq_evt: EventFd) {}
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.config().read_reg(reg_idx)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config_mut().write_reg(reg_idx, offset, data)
+    }
+
+    /// Eventfds KVM signals on writes to a register of BAR `bar_idx`, keyed by
+    /// the offset of the register in the BAR.
+    ///
+    /// Such writes never reach `write_bar` unless registering the eventfd failed.
+    fn ioeventfds(&self, _bar_idx: usize) -> Vec<(u64, &EventFd)> {
+        Vec::new()
+    }
+
+    fn read_bar(&mut self, _bar_idx: usize, _offset: u64, _data: &mut [u8]) {}
+    fn write_bar(&mut self, _bar_idx: usize, _offset: u64, _data: &[u8]) {}
+
+    /// State of the function for a snapshot, its config space unless it has more.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let mut state = StateWriter::new();
+        self.config().snapshot(&mut state);
+        Ok(state.into_inner())
+    }
+
+    /// Restores the state `snapshot` returned, the BARs are remapped afterwards.
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        self.config_mut().restore(&mut StateReader::new(state))
+    }
+
+    /// See `BusDevice::pause`.
+    fn pause(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
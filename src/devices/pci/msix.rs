@@ -0,0 +1,375 @@
+// MSI-X capability, vector table and pending bit array.
+// Spec: PCI Local Bus Specification 3.0, 6.8.2.
+
+use crate::error::*;
+use crate::irqchip::GsiRouting;
+use crate::snapshot::{StateReader, StateWriter};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::eventfd::EventFd;
+
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// Size of an entry in the vector table.
+const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+
+// Bits of the message control register.
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// Bits of the message control register the guest can write, as config register bits.
+pub const MSIX_MSG_CTL_WRITABLE: u32 = ((MSIX_ENABLE | MSIX_FUNCTION_MASK) as u32) << 16;
+
+const VECTOR_CTL_MASKED: u32 = 1;
+
+#[derive(Clone, Copy)]
+struct MsixTableEntry {
+    msg_addr_lo: u32,
+    msg_addr_hi: u32,
+    msg_data: u32,
+    vector_ctl: u32,
+}
+
+impl MsixTableEntry {
+    fn masked(&self) -> bool {
+        self.vector_ctl & VECTOR_CTL_MASKED != 0
+    }
+}
+
+impl Default for MsixTableEntry {
+    fn default() -> Self {
+        // All vectors are masked after reset.
+        MsixTableEntry {
+            msg_addr_lo: 0,
+            msg_addr_hi: 0,
+            msg_data: 0,
+            vector_ctl: VECTOR_CTL_MASKED,
+        }
+    }
+}
+
+/// The MSI-X state of a function, each vector delivered through its own irqfd.
+pub struct MsixConfig {
+    table: Vec<MsixTableEntry>,
+    pba: Vec<u64>,
+    gsis: Vec<u32>,
+    irq_evts: Vec<EventFd>,
+    routing: Arc<Mutex<GsiRouting>>,
+    // Requester ID of the function, the ITS tells devices apart by it.
+    devid: u32,
+    enabled: bool,
+    masked: bool,
+}
+
+impl MsixConfig {
+    pub fn new(num_vectors: u16, routing: Arc<Mutex<GsiRouting>>, devid: u32) -> Result<Self> {
+        let mut gsis = Vec::new();
+        let mut irq_evts = Vec::new();
+        {
+            let mut routing = routing.lock().unwrap();
+            for _ in 0..num_vectors {
+                let gsi = routing.allocate_gsi()?;
+                let irq_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+                routing
+                    .vm_fd()
+                    .register_irqfd(&irq_evt, gsi)
+                    .map_err(Error::RegisterIrqfd)?;
+                gsis.push(gsi);
+                irq_evts.push(irq_evt);
+            }
+        }
+
+        Ok(MsixConfig {
+            table: vec![MsixTableEntry::default(); num_vectors as usize],
+            pba: vec![0; (num_vectors as usize + 63) / 64],
+            gsis,
+            irq_evts,
+            routing,
+            devid,
+            enabled: false,
+            masked: true,
+        })
+    }
+
+    /// Body of the MSI-X capability, with the table and the PBA in `bar`.
+    pub fn capability(num_vectors: u16, bar: u8, table_offset: u32, pba_offset: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(num_vectors - 1).to_le_bytes());
+        data.extend_from_slice(&(table_offset | u32::from(bar)).to_le_bytes());
+        data.extend_from_slice(&(pba_offset | u32::from(bar)).to_le_bytes());
+        data
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Follows the guest writing the message control register.
+    pub fn set_msg_ctl(&mut self, msg_ctl: u16) {
+        self.enabled = msg_ctl & MSIX_ENABLE != 0;
+        self.masked = msg_ctl & MSIX_FUNCTION_MASK != 0;
+        if self.enabled && !self.masked {
+            for vector in 0..self.table.len() {
+                self.inject_pending(vector);
+            }
+        }
+    }
+
+    pub fn read_table(&self, offset: u64, data: &mut [u8]) {
+        let index = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+        let entry = match self.table.get(index) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let value = match offset % MSIX_TABLE_ENTRY_SIZE {
+            0x0 => entry.msg_addr_lo,
+            0x4 => entry.msg_addr_hi,
+            0x8 => entry.msg_data,
+            0xc => entry.vector_ctl,
+            _ => return,
+        };
+        if data.len() == 4 {
+            data.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    pub fn write_table(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 {
+            return;
+        }
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let index = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+        let entry = match self.table.get_mut(index) {
+            Some(entry) => entry,
+            None => return,
+        };
+        match offset % MSIX_TABLE_ENTRY_SIZE {
+            0x0 => entry.msg_addr_lo = value,
+            0x4 => entry.msg_addr_hi = value,
+            0x8 => entry.msg_data = value,
+            0xc => entry.vector_ctl = value & VECTOR_CTL_MASKED,
+            _ => return,
+        }
+
+        let entry = *entry;
+        let addr = u64::from(entry.msg_addr_hi) << 32 | u64::from(entry.msg_addr_lo);
+        if let Err(e) = self.routing.lock().unwrap().set_msi_route(
+            self.gsis[index],
+            addr,
+            entry.msg_data,
+            self.devid,
+        ) {
+            println!("msix: failed to route vector {}: {:?}", index, e);
+        }
+        if !entry.masked() {
+            self.inject_pending(index);
+        }
+    }
+
+    pub fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        let value = self.pba.get((offset / 8) as usize).copied().unwrap_or(0);
+        let value = value >> ((offset % 8) * 8);
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        let bit = 1u64 << (vector % 64);
+        if pending {
+            self.pba[vector / 64] |= bit;
+        } else {
+            self.pba[vector / 64] &= !bit;
+        }
+    }
+
+    fn inject_pending(&mut self, vector: usize) {
+        if self.pba[vector / 64] & (1 << (vector % 64)) != 0 {
+            self.set_pending(vector, false);
+            self.trigger(vector as u16);
+        }
+    }
+
+    /// A copy of the irqfd of `vector`, for backends sending its message themselves.
+    ///
+    /// Writes to it bypass the mask bits, so the guest masking the vector only
+    /// holds back interrupts sent through `trigger`.
+    pub fn irq_evt(&self, vector: u16) -> Option<EventFd> {
+        self.irq_evts.get(vector as usize)?.try_clone().ok()
+    }
+
+    /// Sends the message of `vector`, or leaves it pending while the vector is masked.
+    pub fn trigger(&mut self, vector: u16) {
+        let vector = vector as usize;
+        let masked = match self.table.get(vector) {
+            Some(entry) => entry.masked(),
+            None => return,
+        };
+        if self.masked || masked {
+            self.set_pending(vector, true);
+            return;
+        }
+        self.irq_evts[vector].write(1).unwrap_or(());
+    }
+}
+
+impl MsixConfig {
+    pub fn snapshot(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.masked);
+        for entry in self.table.iter() {
+            state.u32(entry.msg_addr_lo);
+            state.u32(entry.msg_addr_hi);
+            state.u32(entry.msg_data);
+            state.u32(entry.vector_ctl);
+        }
+        for pending in self.pba.iter() {
+            state.u64(*pending);
+        }
+    }
+
+    /// Restores the vectors and routes them again, for a function with as
+    /// many vectors as the one `snapshot` saved.
+    pub fn restore(&mut self, state: &mut StateReader) -> Result<()> {
+        self.enabled = state.bool()?;
+        self.masked = state.bool()?;
+        for entry in self.table.iter_mut() {
+            entry.msg_addr_lo = state.u32()?;
+            entry.msg_addr_hi = state.u32()?;
+            entry.msg_data = state.u32()?;
+            entry.vector_ctl = state.u32()?;
+        }
+        for pending in self.pba.iter_mut() {
+            *pending = state.u64()?;
+        }
+        let mut routing = self.routing.lock().unwrap();
+        for (entry, gsi) in self.table.iter().zip(self.gsis.iter()) {
+            let addr = u64::from(entry.msg_addr_hi) << 32 | u64::from(entry.msg_addr_lo);
+            routing.set_msi_route(*gsi, addr, entry.msg_data, self.devid)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MsixConfig {
+    fn drop(&mut self) {
+        // A backend may still hold a copy of an irqfd, detach them before the
+        // GSIs go to another device.
+        let mut routing = self.routing.lock().unwrap();
+        for (gsi, irq_evt) in self.gsis.iter().zip(self.irq_evts.iter()) {
+            routing.vm_fd().unregister_irqfd(irq_evt, *gsi).ok();
+            routing.release_gsi(*gsi).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    // The vectors are backed by irqfds, so this needs /dev/kvm.
+    fn msix(num_vectors: u16) -> MsixConfig {
+        let vm_fd = Kvm::new().unwrap().create_vm().unwrap();
+        let routing = Arc::new(Mutex::new(GsiRouting::new(Arc::new(vm_fd))));
+        MsixConfig::new(num_vectors, routing, 0).unwrap()
+    }
+
+    fn write(msix: &mut MsixConfig, offset: u64, v: u32) {
+        msix.write_table(offset, &v.to_le_bytes());
+    }
+
+    fn read(msix: &MsixConfig, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        msix.read_table(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn pba(msix: &MsixConfig) -> u64 {
+        let mut data = [0u8; 8];
+        msix.read_pba(0, &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    #[test]
+    fn capability_body() {
+        assert_eq!(
+            MsixConfig::capability(4, 1, 0x2000, 0x3000),
+            vec![3, 0, 0x01, 0x20, 0, 0, 0x01, 0x30, 0, 0]
+        );
+    }
+
+    #[test]
+    fn table_entries() {
+        let mut msix = msix(2);
+        // Masked after reset.
+        assert_eq!(read(&msix, 0xc), VECTOR_CTL_MASKED);
+        assert_eq!(read(&msix, 0x1c), VECTOR_CTL_MASKED);
+
+        write(&mut msix, 0x10, 0x0809_0040);
+        write(&mut msix, 0x14, 0x1);
+        write(&mut msix, 0x18, 0x23);
+        write(&mut msix, 0x1c, 0xffff_fffe);
+        assert_eq!(read(&msix, 0x10), 0x0809_0040);
+        assert_eq!(read(&msix, 0x14), 0x1);
+        assert_eq!(read(&msix, 0x18), 0x23);
+        // Only the mask bit is kept.
+        assert_eq!(read(&msix, 0x1c), 0);
+        assert_eq!(read(&msix, 0x0), 0);
+
+        // Past the end of the table, or not a whole register.
+        write(&mut msix, 0x20, 0x1234);
+        assert_eq!(read(&msix, 0x20), 0);
+        msix.write_table(0x10, &[0xff, 0xff]);
+        assert_eq!(read(&msix, 0x10), 0x0809_0040);
+    }
+
+    #[test]
+    fn masked_vectors_stay_pending() {
+        let mut msix = msix(2);
+        msix.set_msg_ctl(MSIX_ENABLE);
+        assert!(msix.enabled());
+
+        msix.trigger(1);
+        assert_eq!(pba(&msix), 0x2);
+        assert!(msix.irq_evts[1].read().is_err());
+
+        // Unmasking the vector sends the pending message.
+        write(&mut msix, 0x1c, 0);
+        assert_eq!(pba(&msix), 0);
+        assert_eq!(msix.irq_evts[1].read().unwrap(), 1);
+
+        msix.trigger(1);
+        assert_eq!(pba(&msix), 0);
+        assert_eq!(msix.irq_evts[1].read().unwrap(), 1);
+
+        // Vectors that don't exist are ignored.
+        msix.trigger(2);
+        assert_eq!(pba(&msix), 0);
+    }
+
+    #[test]
+    fn irq_evt_bypasses_the_mask() {
+        let mut msix = msix(1);
+        let irq_evt = msix.irq_evt(0).unwrap();
+        assert!(msix.irq_evt(1).is_none());
+
+        msix.trigger(0);
+        assert!(irq_evt.read().is_err());
+        irq_evt.write(1).unwrap();
+        assert_eq!(msix.irq_evts[0].read().unwrap(), 1);
+    }
+
+    #[test]
+    fn function_mask() {
+        let mut msix = msix(1);
+        write(&mut msix, 0xc, 0);
+        msix.set_msg_ctl(MSIX_ENABLE | MSIX_FUNCTION_MASK);
+
+        msix.trigger(0);
+        assert_eq!(pba(&msix), 0x1);
+        assert!(msix.irq_evts[0].read().is_err());
+
+        msix.set_msg_ctl(MSIX_ENABLE);
+        assert_eq!(pba(&msix), 0);
+        assert_eq!(msix.irq_evts[0].read().unwrap(), 1);
+    }
+}
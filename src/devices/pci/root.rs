@@ -0,0 +1,390 @@
+use super::{PciConfiguration, PciDevice, PciRootPort, NUM_BAR_REGS};
+use crate::devices::{Bus, BusDevice};
+use crate::error::*;
+use crate::snapshot::{StateReader, StateWriter};
+use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Number of device slots on a bus.
+pub const PCI_SLOTS: u8 = 32;
+
+// The host bridge QEMU uses for its generic PCIe machine, known to every guest.
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0008;
+const PCI_CLASS_BRIDGE: u8 = 0x06;
+const PCI_SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+
+struct PciHostBridge {
+    config: PciConfiguration,
+}
+
+impl PciDevice for PciHostBridge {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+}
+
+// Routes accesses to a BAR to the function owning it.
+struct PciBarDevice {
+    device: Arc<Mutex<dyn PciDevice>>,
+    bar_idx: usize,
+}
+
+impl BusDevice for PciBarDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.device
+            .lock()
+            .unwrap()
+            .read_bar(self.bar_idx, offset, data)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.device
+            .lock()
+            .unwrap()
+            .write_bar(self.bar_idx, offset, data)
+    }
+}
+
+// Where a function sits.
+#[derive(Clone, Copy, PartialEq)]
+enum Location {
+    // A slot of bus 0.
+    Root(u8),
+    // Behind the root port in a slot of bus 0.
+    Port(u8),
+}
+
+struct PciBarMapping {
+    location: Location,
+    bar_idx: usize,
+    size: u64,
+    // Where the BAR sits on the MMIO bus, if anywhere.
+    addr: Option<u64>,
+    bar_device: Arc<Mutex<PciBarDevice>>,
+}
+
+// Registers or unregisters the ioeventfds of BAR `bar_idx` of `device`, mapped at `addr`.
+// Writes to a register without one still get to the device through the MMIO bus.
+fn set_ioeventfds(vm_fd: &VmFd, device: &dyn PciDevice, bar_idx: usize, addr: u64, on: bool) {
+    for (offset, evt) in device.ioeventfds(bar_idx) {
+        let io_addr = IoEventAddress::Mmio(addr + offset);
+        let ret = if on {
+            vm_fd.register_ioevent(evt, &io_addr, NoDatamatch)
+        } else {
+            vm_fd.unregister_ioevent(evt, &io_addr, NoDatamatch)
+        };
+        if let Err(e) = ret {
+            println!(
+                "pci: failed to update ioeventfd at {:#x}: {:?}",
+                addr + offset,
+                e
+            );
+        }
+    }
+}
+
+/// Bus 0, with the host bridge in slot 0, and the buses behind its root ports.
+///
+/// Sits on the MMIO bus as the ECAM window, and moves BARs and their
+/// ioeventfds around as the guest programs them.
+pub struct PciRoot {
+    devices: BTreeMap<u8, Arc<Mutex<dyn PciDevice>>>,
+    // Root ports by slot, also in devices.
+    ports: BTreeMap<u8, Arc<Mutex<PciRootPort>>>,
+    // Device 0 on the secondary bus of each occupied root port, by port slot.
+    children: BTreeMap<u8, Arc<Mutex<dyn PciDevice>>>,
+    bars: Vec<PciBarMapping>,
+    mmio_bus: Arc<Bus>,
+    vm_fd: Arc<VmFd>,
+}
+
+impl PciRoot {
+    pub fn new(mmio_bus: Arc<Bus>, vm_fd: Arc<VmFd>) -> Self {
+        let host_bridge = PciHostBridge {
+            config: PciConfiguration::new(
+                HOST_BRIDGE_VENDOR_ID,
+                HOST_BRIDGE_DEVICE_ID,
+                0,
+                PCI_CLASS_BRIDGE,
+                PCI_SUBCLASS_HOST_BRIDGE,
+                0,
+                0,
+                0,
+            ),
+        };
+        let mut devices: BTreeMap<u8, Arc<Mutex<dyn PciDevice>>> = BTreeMap::new();
+        devices.insert(0, Arc::new(Mutex::new(host_bridge)));
+
+        PciRoot {
+            devices,
+            ports: BTreeMap::new(),
+            children: BTreeMap::new(),
+            bars: Vec::new(),
+            mmio_bus,
+            vm_fd,
+        }
+    }
+
+    pub fn next_free_slot(&self) -> Option<u8> {
+        (0..PCI_SLOTS).find(|slot| !self.devices.contains_key(slot))
+    }
+
+    /// Puts `device` in `slot` and maps the BARs already set in its config space.
+    pub fn add_device(&mut self, slot: u8, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
+        if slot >= PCI_SLOTS || self.devices.contains_key(&slot) {
+            return Err(Error::PciSlotsExhausted);
+        }
+        self.map_bars(Location::Root(slot), &device)?;
+        self.devices.insert(slot, device);
+        Ok(())
+    }
+
+    /// Puts `port` in `slot`, with an empty hotplug slot behind it.
+    pub fn add_port(&mut self, slot: u8, port: Arc<Mutex<PciRootPort>>) -> Result<()> {
+        self.add_device(slot, port.clone())?;
+        self.ports.insert(slot, port);
+        Ok(())
+    }
+
+    /// The root port in `slot`.
+    pub fn port(&self, slot: u8) -> Option<Arc<Mutex<PciRootPort>>> {
+        self.ports.get(&slot).cloned()
+    }
+
+    /// Puts `device` behind the empty root port in `port_slot` and tells the guest.
+    pub fn plug(&mut self, port_slot: u8, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
+        let port = self
+            .ports
+            .get(&port_slot)
+            .cloned()
+            .filter(|port| !port.lock().unwrap().is_present())
+            .ok_or(Error::HotplugSlotsExhausted)?;
+        if let Some(intx_evt) = port.lock().unwrap().intx_evt() {
+            device.lock().unwrap().assign_intx(intx_evt);
+        }
+        self.map_bars(Location::Port(port_slot), &device)?;
+        self.children.insert(port_slot, device);
+        port.lock().unwrap().plug();
+        Ok(())
+    }
+
+    /// Takes the device behind the root port in `port_slot` off the MMIO bus and
+    /// empties the slot.
+    ///
+    /// Meant for once the guest ejected the device, see `PciRootPort::is_ejected`.
+    pub fn remove_child(&mut self, port_slot: u8) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        let device = self.children.remove(&port_slot)?;
+        let location = Location::Port(port_slot);
+        {
+            let device = device.lock().unwrap();
+            for bar in self.bars.iter().filter(|bar| bar.location == location) {
+                if let Some(addr) = bar.addr {
+                    self.mmio_bus.remove(addr);
+                    set_ioeventfds(&self.vm_fd, &*device, bar.bar_idx, addr, false);
+                }
+            }
+        }
+        self.bars.retain(|bar| bar.location != location);
+        if let Some(port) = self.ports.get(&port_slot) {
+            port.lock().unwrap().clear();
+        }
+        Some(device)
+    }
+
+    fn device(&self, location: Location) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        match location {
+            Location::Root(slot) => self.devices.get(&slot).cloned(),
+            Location::Port(slot) => self.children.get(&slot).cloned(),
+        }
+    }
+
+    // Maps the BARs set in the config space of `device`.
+    fn map_bars(&mut self, location: Location, device: &Arc<Mutex<dyn PciDevice>>) -> Result<()> {
+        let bars: Vec<(usize, u64, u64)> = {
+            let device = device.lock().unwrap();
+            let config = device.config();
+            (0..NUM_BAR_REGS)
+                .filter(|idx| config.get_bar_size(*idx) != 0)
+                .map(|idx| (idx, config.get_bar_addr(idx), config.get_bar_size(idx)))
+                .collect()
+        };
+        for (bar_idx, addr, size) in bars {
+            let bar_device = Arc::new(Mutex::new(PciBarDevice {
+                device: device.clone(),
+                bar_idx,
+            }));
+            self.mmio_bus.insert(bar_device.clone(), addr, size)?;
+            set_ioeventfds(&self.vm_fd, &*device.lock().unwrap(), bar_idx, addr, true);
+            self.bars.push(PciBarMapping {
+                location,
+                bar_idx,
+                size,
+                addr: Some(addr),
+                bar_device,
+            });
+        }
+        Ok(())
+    }
+
+    // Follows the guest reprogramming the BARs of the function at `location`.
+    fn update_bars(&mut self, location: Location) {
+        let device = match self.device(location) {
+            Some(device) => device,
+            None => return,
+        };
+        let device = device.lock().unwrap();
+        let config = device.config();
+
+        for bar in self.bars.iter_mut().filter(|bar| bar.location == location) {
+            if config.is_bar_sizing(bar.bar_idx) {
+                continue;
+            }
+            let new_addr = config.get_bar_addr(bar.bar_idx);
+            if bar.addr == Some(new_addr) {
+                continue;
+            }
+            if let Some(old_addr) = bar.addr.take() {
+                self.mmio_bus.remove(old_addr);
+                set_ioeventfds(&self.vm_fd, &*device, bar.bar_idx, old_addr, false);
+            }
+            // The upper half of a 64-bit BAR is still stale after the guest wrote the
+            // lower one, the range may well overlap something until both are written.
+            if self
+                .mmio_bus
+                .insert(bar.bar_device.clone(), new_addr, bar.size)
+                .is_ok()
+            {
+                set_ioeventfds(&self.vm_fd, &*device, bar.bar_idx, new_addr, true);
+                bar.addr = Some(new_addr);
+            }
+        }
+    }
+
+    // Splits an ECAM offset into the function it addresses and the register.
+    fn decode(&self, offset: u64) -> Option<(Location, usize, u64)> {
+        let bus = (offset >> 20) as u8;
+        let slot = ((offset >> 15) & 0x1f) as u8;
+        let function = (offset >> 12) & 0x7;
+        // All functions are single function devices.
+        if function != 0 {
+            return None;
+        }
+        let location = if bus == 0 {
+            Location::Root(slot)
+        } else {
+            // Behind a root port there is only device 0.
+            if slot != 0 {
+                return None;
+            }
+            let port_slot = self
+                .ports
+                .iter()
+                .find(|(_, port)| port.lock().unwrap().secondary_bus() == bus)
+                .map(|(port_slot, _)| *port_slot)?;
+            Location::Port(port_slot)
+        };
+        Some((location, ((offset & 0xfff) >> 2) as usize, offset & 0x3))
+    }
+}
+
+impl BusDevice for PciRoot {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = self
+            .decode(offset)
+            .and_then(|(location, reg_idx, reg_offset)| {
+                let device = self.device(location)?;
+                let value = device.lock().unwrap().read_config_register(reg_idx);
+                Some(value >> (reg_offset * 8))
+            })
+            .unwrap_or(0xffff_ffff);
+
+        for (i, b) in data.iter_mut().enumerate().take(4) {
+            *b = (value >> (i * 8)) as u8;
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let (location, reg_idx, reg_offset) = match self.decode(offset) {
+            Some(decoded) => decoded,
+            None => return,
+        };
+        let is_bar = match self.device(location) {
+            Some(device) => {
+                device
+                    .lock()
+                    .unwrap()
+                    .write_config_register(reg_idx, reg_offset, data);
+                (PciConfiguration::bar_reg_idx(0)..PciConfiguration::bar_reg_idx(NUM_BAR_REGS))
+                    .contains(&reg_idx)
+            }
+            None => return,
+        };
+        if is_bar {
+            self.update_bars(location);
+        }
+    }
+
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        let mut state = StateWriter::new();
+        state.u32(self.devices.len() as u32);
+        for (slot, device) in self.devices.iter() {
+            state.u8(*slot);
+            state.bytes(&device.lock().unwrap().snapshot()?);
+        }
+        Ok(Some(state.into_inner()))
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        for device in self.devices.values() {
+            device.lock().unwrap().pause()?;
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for device in self.devices.values() {
+            let resumed = device.lock().unwrap().resume();
+            result = result.and(resumed);
+        }
+        result
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state);
+        for _ in 0..state.u32()? {
+            let slot = state.u8()?;
+            let device = self.devices.get(&slot).ok_or_else(|| {
+                Error::InvalidSnapshot(format!("no PCI device in slot {} to restore", slot))
+            })?;
+            device.lock().unwrap().restore(state.bytes()?)?;
+        }
+
+        // Take all BARs off the bus before mapping them where the guest put
+        // them, which may be where those of another device sit now.
+        for bar in self.bars.iter_mut() {
+            if let Some(addr) = bar.addr.take() {
+                self.mmio_bus.remove(addr);
+                let device = bar.bar_device.lock().unwrap().device.clone();
+                set_ioeventfds(
+                    &self.vm_fd,
+                    &*device.lock().unwrap(),
+                    bar.bar_idx,
+                    addr,
+                    false,
+                );
+            }
+        }
+        let slots: Vec<u8> = self.devices.keys().copied().collect();
+        for slot in slots {
+            self.update_bars(Location::Root(slot));
+        }
+        Ok(())
+    }
+}
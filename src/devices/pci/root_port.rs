@@ -0,0 +1,263 @@
+// PCI Express root port with a hotplug slot, driven by the pciehp driver of the guest.
+// Spec: PCI Express Base Specification 3.0, sections 6.7 and 7.8.
+
+use super::{PciConfiguration, PciDevice};
+use crate::error::*;
+use crate::snapshot::{StateReader, StateWriter};
+use vmm_sys_util::eventfd::EventFd;
+
+// The root port QEMU uses, known to every guest.
+const ROOT_PORT_VENDOR_ID: u16 = 0x1b36;
+const ROOT_PORT_DEVICE_ID: u16 = 0x000c;
+
+const PCI_CAP_ID_EXP: u8 = 0x10;
+// Size of a version 2 capability, without the ID and next pointer.
+const PCI_EXP_CAP_LEN: usize = 0x3a;
+const PCI_EXP_FLAGS_VERSION: u16 = 2;
+const PCI_EXP_TYPE_ROOT_PORT: u16 = 4 << 4;
+const PCI_EXP_FLAGS_SLOT: u16 = 1 << 8;
+
+// Registers, as offsets in the capability.
+const PCI_EXP_LNKCAP: usize = 0x0c;
+const PCI_EXP_LNKCTL: usize = 0x10;
+const PCI_EXP_SLTCAP: usize = 0x14;
+const PCI_EXP_SLTCTL: usize = 0x18;
+
+// 2.5 GT/s, x1, and the port reports when the link is up.
+const LNKCAP_SPEED_WIDTH: u32 = 0x1 | 0x1 << 4;
+const LNKCAP_DLLLARC: u32 = 1 << 20;
+const LNKSTA_SPEED_WIDTH: u16 = 0x1 | 0x1 << 4;
+const LNKSTA_DLLLA: u16 = 1 << 13;
+
+// Attention button, power controller, hotplug capable, no command completed events.
+const SLTCAP_ABP: u32 = 1 << 0;
+const SLTCAP_PCP: u32 = 1 << 1;
+const SLTCAP_HPC: u32 = 1 << 6;
+const SLTCAP_NCCS: u32 = 1 << 18;
+const SLTCAP_PSN_SHIFT: u32 = 19;
+
+const SLTCTL_ABPE: u16 = 1 << 0;
+const SLTCTL_PDCE: u16 = 1 << 3;
+const SLTCTL_HPIE: u16 = 1 << 5;
+// Set when the slot is powered off.
+const SLTCTL_PCC: u16 = 1 << 10;
+const SLTCTL_DLLSCE: u16 = 1 << 12;
+const SLTCTL_WRITABLE: u16 = 0x1fff;
+
+const SLTSTA_ABP: u16 = 1 << 0;
+const SLTSTA_PDC: u16 = 1 << 3;
+const SLTSTA_PDS: u16 = 1 << 6;
+const SLTSTA_DLLSC: u16 = 1 << 8;
+// Event bits the guest clears by writing 1.
+const SLTSTA_RW1C: u16 = 0x011f;
+
+/// A root port on bus 0 with a single slot behind it.
+///
+/// Plugging a device sets presence detect, the guest then powers the slot on and
+/// scans the bus. Unplugging presses the attention button, the guest releases the
+/// device and powers the slot off, which ejects it.
+pub struct PciRootPort {
+    config: PciConfiguration,
+    // Offset of the PCI Express capability.
+    cap: usize,
+    slot_ctl: u16,
+    slot_sta: u16,
+    link_sta: u16,
+    present: bool,
+    ejected: bool,
+    intx_evt: Option<EventFd>,
+}
+
+impl PciRootPort {
+    /// A root port with physical slot number `slot`.
+    pub fn new(slot: u8) -> Self {
+        let mut config = PciConfiguration::new_bridge(ROOT_PORT_VENDOR_ID, ROOT_PORT_DEVICE_ID);
+
+        let mut data = [0u8; PCI_EXP_CAP_LEN];
+        let flags = PCI_EXP_FLAGS_VERSION | PCI_EXP_TYPE_ROOT_PORT | PCI_EXP_FLAGS_SLOT;
+        data[0..2].copy_from_slice(&flags.to_le_bytes());
+        let link_cap = LNKCAP_SPEED_WIDTH | LNKCAP_DLLLARC | u32::from(slot) << 24;
+        data[PCI_EXP_LNKCAP - 2..PCI_EXP_LNKCAP + 2].copy_from_slice(&link_cap.to_le_bytes());
+        let slot_cap = SLTCAP_ABP
+            | SLTCAP_PCP
+            | SLTCAP_HPC
+            | SLTCAP_NCCS
+            | u32::from(slot) << SLTCAP_PSN_SHIFT;
+        data[PCI_EXP_SLTCAP - 2..PCI_EXP_SLTCAP + 2].copy_from_slice(&slot_cap.to_le_bytes());
+        // The capability list of a fresh configuration space has room for it.
+        let cap = config.add_capability(PCI_CAP_ID_EXP, &data).unwrap();
+
+        let mut port = PciRootPort {
+            config,
+            cap,
+            // Empty slots are powered off.
+            slot_ctl: SLTCTL_PCC,
+            slot_sta: 0,
+            link_sta: LNKSTA_SPEED_WIDTH,
+            present: false,
+            ejected: false,
+            intx_evt: None,
+        };
+        port.update_registers();
+        port
+    }
+
+    /// Number of the bus behind the port, 0 until the guest assigned it.
+    pub fn secondary_bus(&self) -> u8 {
+        self.config.secondary_bus()
+    }
+
+    /// A clone of the INTx irqfd of the port, for the device behind it.
+    ///
+    /// The guest swizzles the INTA of device 0 on the secondary bus to the INTA of the port.
+    pub fn intx_evt(&self) -> Option<EventFd> {
+        self.intx_evt.as_ref().and_then(|evt| evt.try_clone().ok())
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    /// Whether the guest powered the slot off since its device was unplugged.
+    pub fn is_ejected(&self) -> bool {
+        self.ejected
+    }
+
+    /// Tells the guest a device was put in the slot.
+    pub fn plug(&mut self) {
+        self.present = true;
+        self.ejected = false;
+        self.slot_sta |= SLTSTA_PDS | SLTSTA_PDC;
+        self.update_registers();
+        self.notify();
+    }
+
+    /// Presses the attention button, asking the guest to release the device.
+    pub fn request_unplug(&mut self) {
+        self.slot_sta |= SLTSTA_ABP;
+        self.update_registers();
+        self.notify();
+    }
+
+    /// Empties the slot once the device behind it is gone.
+    pub fn clear(&mut self) {
+        self.present = false;
+        self.ejected = false;
+    }
+
+    fn reg_idx(&self, offset: usize) -> usize {
+        (self.cap + offset) / 4
+    }
+
+    // Mirrors the link and slot state into the configuration space.
+    fn update_registers(&mut self) {
+        let link_reg = self.reg_idx(PCI_EXP_LNKCTL);
+        let link_ctl = self.config.read_reg(link_reg) & 0xffff;
+        self.config
+            .set_reg(link_reg, u32::from(self.link_sta) << 16 | link_ctl);
+        let slot_reg = self.reg_idx(PCI_EXP_SLTCTL);
+        self.config.set_reg(
+            slot_reg,
+            u32::from(self.slot_sta) << 16 | u32::from(self.slot_ctl),
+        );
+    }
+
+    fn write_slot_ctl(&mut self, value: u16) {
+        let old = self.slot_ctl;
+        self.slot_ctl = value & SLTCTL_WRITABLE;
+        if !self.present {
+            return;
+        }
+        if old & SLTCTL_PCC != 0 && self.slot_ctl & SLTCTL_PCC == 0 {
+            // Powered on, the link comes up right away.
+            self.link_sta |= LNKSTA_DLLLA;
+            self.slot_sta |= SLTSTA_DLLSC;
+            self.notify();
+        } else if old & SLTCTL_PCC == 0 && self.slot_ctl & SLTCTL_PCC != 0 {
+            // Powered off: the device is ejected.
+            self.link_sta &= !LNKSTA_DLLLA;
+            self.slot_sta &= !SLTSTA_PDS;
+            self.slot_sta |= SLTSTA_PDC | SLTSTA_DLLSC;
+            self.ejected = true;
+            self.notify();
+        }
+    }
+
+    // Raises the INTx line if hotplug interrupts are on and an enabled event is pending.
+    fn notify(&self) {
+        if self.slot_ctl & SLTCTL_HPIE == 0 {
+            return;
+        }
+        let enabled = [
+            (SLTSTA_ABP, SLTCTL_ABPE),
+            (SLTSTA_PDC, SLTCTL_PDCE),
+            (SLTSTA_DLLSC, SLTCTL_DLLSCE),
+        ];
+        if enabled
+            .iter()
+            .any(|(sta, ctl)| self.slot_sta & sta != 0 && self.slot_ctl & ctl != 0)
+        {
+            if let Some(intx_evt) = &self.intx_evt {
+                intx_evt.write(1).ok();
+            }
+        }
+    }
+}
+
+impl PciDevice for PciRootPort {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn assign_intx(&mut self, irq_evt: EventFd) {
+        self.intx_evt = Some(irq_evt);
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if reg_idx != self.reg_idx(PCI_EXP_SLTCTL) {
+            self.config.write_reg(reg_idx, offset, data);
+            return;
+        }
+
+        let mut value = u32::from(self.slot_sta) << 16 | u32::from(self.slot_ctl);
+        let mut written = 0u32;
+        for (i, b) in data.iter().enumerate() {
+            let shift = (offset as usize + i) * 8;
+            value = (value & !(0xff << shift)) | u32::from(*b) << shift;
+            written |= 0xff << shift;
+        }
+        if written & 0xffff != 0 {
+            self.write_slot_ctl(value as u16);
+        }
+        if written >> 16 != 0 {
+            self.slot_sta &= !((value >> 16) as u16 & SLTSTA_RW1C);
+        }
+        self.update_registers();
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let mut state = StateWriter::new();
+        self.config.snapshot(&mut state);
+        state.u16(self.slot_ctl);
+        state.u16(self.slot_sta);
+        state.u16(self.link_sta);
+        state.bool(self.present);
+        state.bool(self.ejected);
+        Ok(state.into_inner())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state);
+        self.config.restore(&mut state)?;
+        self.slot_ctl = state.u16()?;
+        self.slot_sta = state.u16()?;
+        self.link_sta = state.u16()?;
+        self.present = state.bool()?;
+        self.ejected = state.bool()?;
+        Ok(())
+    }
+}
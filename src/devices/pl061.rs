@@ -0,0 +1,177 @@
+// ARM PrimeCell PL061 GPIO controller.
+// Spec: https://developer.arm.com/documentation/ddi0190/b
+
+use super::BusDevice;
+use crate::error::*;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the PL061 MMIO window.
+pub const PL061_LEN: u64 = 0x1000;
+
+/// Number of GPIO lines.
+pub const PL061_NGPIO: u32 = 8;
+
+// Register offsets.
+const GPIODATA_END: u64 = 0x3fc;
+const GPIODIR: u64 = 0x400;
+const GPIOIS: u64 = 0x404;
+const GPIOIBE: u64 = 0x408;
+const GPIOIEV: u64 = 0x40c;
+const GPIOIE: u64 = 0x410;
+const GPIORIS: u64 = 0x414;
+const GPIOMIS: u64 = 0x418;
+const GPIOIC: u64 = 0x41c;
+const GPIOAFSEL: u64 = 0x420;
+const GPIO_ID_START: u64 = 0xfe0;
+
+// PeriphID0-3 and PCellID0-3, read by the AMBA bus driver to probe the device.
+const PL061_ID: [u8; 8] = [0x61, 0x10, 0x04, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+pub struct Pl061 {
+    data: u8,
+    dir: u8,
+    is: u8,
+    ibe: u8,
+    iev: u8,
+    ie: u8,
+    ris: u8,
+    afsel: u8,
+    // Levels driven on the input lines from outside the VM.
+    input: u8,
+    irq_evt: EventFd,
+}
+
+impl Pl061 {
+    pub fn new(irq_evt: EventFd) -> Self {
+        Pl061 {
+            data: 0,
+            dir: 0,
+            is: 0,
+            ibe: 0,
+            iev: 0,
+            ie: 0,
+            ris: 0,
+            afsel: 0,
+            input: 0,
+            irq_evt,
+        }
+    }
+
+    /// Drives input `line` to `level`, as a button or sensor wired to the controller would.
+    pub fn set_input(&mut self, line: u32, level: bool) {
+        if line >= PL061_NGPIO {
+            return;
+        }
+        let bit = 1u8 << line;
+        if level {
+            self.input |= bit;
+        } else {
+            self.input &= !bit;
+        }
+        self.update();
+    }
+
+    /// Recomputes input levels and interrupt status, raising the interrupt if needed.
+    fn update(&mut self) {
+        let old_mis = self.ris & self.ie;
+
+        // Only lines configured as inputs follow the external level.
+        let old_data = self.data;
+        self.data = (self.data & self.dir) | (self.input & !self.dir);
+        let changed = (old_data ^ self.data) & !self.dir;
+
+        // Edge sensitive lines latch an event on the configured edge(s).
+        let rising = changed & self.data;
+        let falling = changed & !self.data;
+        let edges = (changed & self.ibe)
+            | (rising & self.iev & !self.ibe)
+            | (falling & !self.iev & !self.ibe);
+        self.ris |= edges & !self.is;
+
+        // Level sensitive lines report the current level.
+        let levels = !(self.data ^ self.iev);
+        self.ris = (self.ris & !self.is) | (levels & self.is);
+
+        let mis = self.ris & self.ie;
+        if old_mis == 0 && mis != 0 {
+            self.irq_evt.write(1).unwrap_or(());
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u8 {
+        match offset {
+            // Bits [9:2] of the address mask the data bits being accessed.
+            0..=GPIODATA_END => self.data & (offset >> 2) as u8,
+            GPIODIR => self.dir,
+            GPIOIS => self.is,
+            GPIOIBE => self.ibe,
+            GPIOIEV => self.iev,
+            GPIOIE => self.ie,
+            GPIORIS => self.ris,
+            GPIOMIS => self.ris & self.ie,
+            GPIOAFSEL => self.afsel,
+            GPIO_ID_START..=0xffc => PL061_ID[((offset - GPIO_ID_START) >> 2) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, v: u8) {
+        match offset {
+            0..=GPIODATA_END => {
+                let mask = (offset >> 2) as u8 & self.dir;
+                self.data = (self.data & !mask) | (v & mask);
+            }
+            GPIODIR => self.dir = v,
+            GPIOIS => self.is = v,
+            GPIOIBE => self.ibe = v,
+            GPIOIEV => self.iev = v,
+            GPIOIE => self.ie = v,
+            GPIOIC => self.ris &= !v,
+            GPIOAFSEL => self.afsel = v,
+            _ => return,
+        }
+        self.update();
+    }
+}
+
+// The driver uses byte accesses, registers are only 8 bits wide anyway.
+impl BusDevice for Pl061 {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            *b = 0;
+        }
+        if let Some(b) = data.first_mut() {
+            *b = self.read_reg(offset);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let Some(&v) = data.first() {
+            self.write_reg(offset, v);
+        }
+    }
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        Ok(Some(vec![
+            self.data, self.dir, self.is, self.ibe, self.iev, self.ie, self.ris, self.afsel,
+            self.input,
+        ]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        match *state {
+            [data, dir, is, ibe, iev, ie, ris, afsel, input] => {
+                self.data = data;
+                self.dir = dir;
+                self.is = is;
+                self.ibe = ibe;
+                self.iev = iev;
+                self.ie = ie;
+                self.ris = ris;
+                self.afsel = afsel;
+                self.input = input;
+                Ok(())
+            }
+            _ => Err(Error::InvalidSnapshot("bad PL061 state".to_string())),
+        }
+    }
+}
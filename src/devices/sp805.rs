@@ -0,0 +1,288 @@
+// ARM PrimeCell SP805 watchdog.
+// Spec: https://developer.arm.com/documentation/ddi0270/b
+
+use super::BusDevice;
+use crate::error::*;
+use crate::snapshot::{StateReader, StateWriter};
+use std::convert::TryInto;
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the SP805 MMIO window.
+pub const SP805_LEN: u64 = 0x1000;
+
+// Register offsets.
+const WDOGLOAD: u64 = 0x000;
+const WDOGVALUE: u64 = 0x004;
+const WDOGCONTROL: u64 = 0x008;
+const WDOGINTCLR: u64 = 0x00c;
+const WDOGRIS: u64 = 0x010;
+const WDOGMIS: u64 = 0x014;
+const WDOGLOCK: u64 = 0xc00;
+const WDOG_ID_START: u64 = 0xfe0;
+
+const WDOGCONTROL_INTEN: u32 = 1 << 0;
+const WDOGCONTROL_RESEN: u32 = 1 << 1;
+const WDOGLOCK_UNLOCK_KEY: u32 = 0x1acc_e551;
+
+// PeriphID0-3 and PCellID0-3, read by the AMBA bus driver to probe the device.
+const SP805_ID: [u8; 8] = [0x05, 0x18, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// What happened when the counter reached zero.
+#[derive(Debug, PartialEq)]
+pub enum Expiry {
+    /// The first time, the interrupt was raised for the guest to handle.
+    Interrupt,
+    /// Again before the guest cleared the interrupt, the VM has to be reset.
+    /// Only with the reset output enabled, as Linux does.
+    Reset,
+}
+
+pub struct Sp805 {
+    load: u32,
+    control: u32,
+    ris: bool,
+    locked: bool,
+    // When the counter was last reloaded, it counts down from `load` since then.
+    loaded_at: Instant,
+    clock_hz: u64,
+    irq_evt: EventFd,
+}
+
+impl Sp805 {
+    pub fn new(irq_evt: EventFd, clock_hz: u64) -> Self {
+        Sp805 {
+            load: u32::max_value(),
+            control: 0,
+            ris: false,
+            locked: false,
+            loaded_at: Instant::now(),
+            clock_hz,
+            irq_evt,
+        }
+    }
+
+    fn period(&self) -> Duration {
+        Duration::from_nanos((u64::from(self.load) + 1) * 1_000_000_000 / self.clock_hz)
+    }
+
+    fn value(&self) -> u32 {
+        if self.control & WDOGCONTROL_INTEN == 0 {
+            return self.load;
+        }
+        let elapsed =
+            self.loaded_at.elapsed().as_nanos() * u128::from(self.clock_hz) / 1_000_000_000;
+        u64::from(self.load).saturating_sub(elapsed as u64) as u32
+    }
+
+    /// Restarts the count down, e.g. while the vcpus are not running.
+    pub fn reload(&mut self) {
+        self.loaded_at = Instant::now();
+    }
+
+    /// Advances the counter to the current time.
+    ///
+    /// Returns which stage of expiry was reached, if the counter reached zero.
+    pub fn tick(&mut self) -> Option<Expiry> {
+        if self.control & WDOGCONTROL_INTEN == 0 {
+            return None;
+        }
+        let period = self.period();
+        if self.loaded_at.elapsed() < period {
+            return None;
+        }
+        self.loaded_at += period;
+
+        if !self.ris {
+            self.ris = true;
+            self.irq_evt.write(1).unwrap_or(());
+            return Some(Expiry::Interrupt);
+        }
+        if self.control & WDOGCONTROL_RESEN == 0 {
+            return None;
+        }
+        // Give the guest a fresh start once the VM recovered from the reset.
+        self.ris = false;
+        self.reload();
+        Some(Expiry::Reset)
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            WDOGLOAD => self.load,
+            WDOGVALUE => self.value(),
+            WDOGCONTROL => self.control,
+            WDOGRIS => self.ris as u32,
+            WDOGMIS => (self.ris && self.control & WDOGCONTROL_INTEN != 0) as u32,
+            WDOGLOCK => self.locked as u32,
+            WDOG_ID_START..=0xffc => u32::from(SP805_ID[((offset - WDOG_ID_START) >> 2) as usize]),
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, v: u32) {
+        if offset == WDOGLOCK {
+            self.locked = v != WDOGLOCK_UNLOCK_KEY;
+            return;
+        }
+        if self.locked {
+            return;
+        }
+        match offset {
+            WDOGLOAD => {
+                self.load = v;
+                self.reload();
+            }
+            WDOGCONTROL => {
+                if self.control & WDOGCONTROL_INTEN == 0 && v & WDOGCONTROL_INTEN != 0 {
+                    self.reload();
+                }
+                self.control = v & (WDOGCONTROL_INTEN | WDOGCONTROL_RESEN);
+            }
+            WDOGINTCLR => {
+                self.ris = false;
+                self.reload();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Sp805 {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() == 4 {
+            data.copy_from_slice(&self.read_reg(offset).to_le_bytes());
+        } else {
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let Ok(bytes) = data.try_into() {
+            self.write_reg(offset, u32::from_le_bytes(bytes));
+        }
+    }
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        let mut state = StateWriter::new();
+        state.u32(self.load);
+        state.u32(self.control);
+        state.bool(self.ris);
+        state.bool(self.locked);
+        state.u32(self.value());
+        Ok(Some(state.into_inner()))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state);
+        self.load = state.u32()?;
+        self.control = state.u32()?;
+        self.ris = state.bool()?;
+        self.locked = state.bool()?;
+        // Carry on counting down from the saved value.
+        let counted = u64::from(self.load.saturating_sub(state.u32()?));
+        let elapsed = Duration::from_nanos(counted * 1_000_000_000 / self.clock_hz);
+        self.loaded_at = Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(wdt: &mut Sp805, offset: u64, v: u32) {
+        wdt.write(offset, &v.to_le_bytes());
+    }
+
+    fn read(wdt: &mut Sp805, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        wdt.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    // A 10ms period; `expire` moves the count down to zero without sleeping.
+    fn watchdog(control: u32) -> Sp805 {
+        let mut wdt = Sp805::new(EventFd::new(libc::EFD_NONBLOCK).unwrap(), 1000);
+        write(&mut wdt, WDOGLOAD, 9);
+        write(&mut wdt, WDOGCONTROL, control);
+        wdt
+    }
+
+    fn expire(wdt: &mut Sp805) {
+        wdt.loaded_at -= wdt.period();
+    }
+
+    #[test]
+    fn disabled_never_expires() {
+        let mut wdt = watchdog(0);
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), None);
+        assert_eq!(read(&mut wdt, WDOGRIS), 0);
+        assert_eq!(read(&mut wdt, WDOGVALUE), 9);
+    }
+
+    #[test]
+    fn second_expiry_resets() {
+        let mut wdt = watchdog(WDOGCONTROL_INTEN | WDOGCONTROL_RESEN);
+        assert_eq!(wdt.tick(), None);
+
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), Some(Expiry::Interrupt));
+        assert_eq!(wdt.irq_evt.read().unwrap(), 1);
+        assert_eq!(read(&mut wdt, WDOGRIS), 1);
+        assert_eq!(read(&mut wdt, WDOGMIS), 1);
+
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), Some(Expiry::Reset));
+        assert_eq!(read(&mut wdt, WDOGRIS), 0);
+    }
+
+    #[test]
+    fn no_reset_without_resen() {
+        let mut wdt = watchdog(WDOGCONTROL_INTEN);
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), Some(Expiry::Interrupt));
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), None);
+        assert_eq!(read(&mut wdt, WDOGRIS), 1);
+    }
+
+    #[test]
+    fn clearing_the_interrupt_restarts() {
+        let mut wdt = watchdog(WDOGCONTROL_INTEN | WDOGCONTROL_RESEN);
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), Some(Expiry::Interrupt));
+        write(&mut wdt, WDOGINTCLR, 1);
+        assert_eq!(read(&mut wdt, WDOGRIS), 0);
+
+        expire(&mut wdt);
+        assert_eq!(wdt.tick(), Some(Expiry::Interrupt));
+        assert_eq!(read(&mut wdt, WDOGRIS), 1);
+    }
+
+    #[test]
+    fn lock_ignores_writes() {
+        let mut wdt = watchdog(0);
+        write(&mut wdt, WDOGLOCK, 0);
+        assert_eq!(read(&mut wdt, WDOGLOCK), 1);
+        write(&mut wdt, WDOGLOAD, 100);
+        assert_eq!(read(&mut wdt, WDOGLOAD), 9);
+
+        write(&mut wdt, WDOGLOCK, WDOGLOCK_UNLOCK_KEY);
+        assert_eq!(read(&mut wdt, WDOGLOCK), 0);
+        write(&mut wdt, WDOGLOAD, 100);
+        assert_eq!(read(&mut wdt, WDOGLOAD), 100);
+    }
+
+    #[test]
+    fn id_registers() {
+        let mut wdt = watchdog(0);
+        for (i, id) in SP805_ID.iter().enumerate() {
+            assert_eq!(read(&mut wdt, WDOG_ID_START + 4 * i as u64), u32::from(*id));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// virtio-fs device, driven by an external vhost-user-fs backend such as virtiofsd.
+
+use super::vhost_user::{self, Backend};
+use super::*;
+use crate::config::FsConfig;
+use crate::error::*;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::Mutex;
+use vm_memory::GuestMemoryMmap;
+
+const QUEUE_SIZE: u16 = 1024;
+// One high priority queue plus one request queue.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+
+/// Length of the `tag` field in the configuration space.
+pub const FS_TAG_LEN: usize = 36;
+
+/// A virtio-fs device, sharing a host directory with the guest under a mount tag.
+pub struct Fs {
+    tag: String,
+    backend: Arc<Mutex<Backend>>,
+    event_manager: Arc<EventManager>,
+    subscriptions: Vec<Subscription>,
+    // virtiofsd instance started by glue, if the user did not pass a socket.
+    child: Option<Child>,
+}
+
+impl Fs {
+    pub fn new(config: &FsConfig, event_manager: Arc<EventManager>) -> Result<Fs> {
+        let (socket, child) = match &config.socket {
+            Some(socket) => (socket.clone(), None),
+            None => {
+                let socket = backend_socket_path(&config.tag);
+                let child = Command::new("virtiofsd")
+                    .arg(format!("--socket-path={}", socket.display()))
+                    .arg(format!("--shared-dir={}", config.path.display()))
+                    .spawn()
+                    .map_err(Error::SpawnBackend)?;
+                (socket, Some(child))
+            }
+        };
+
+        let backend = match Backend::connect(&socket) {
+            Ok(backend) => backend,
+            Err(e) => {
+                if let Some(mut child) = child {
+                    stop_backend(&mut child, &socket);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Fs {
+            tag: config.tag.clone(),
+            backend: Arc::new(Mutex::new(backend)),
+            event_manager,
+            subscriptions: Vec::new(),
+            child,
+        })
+    }
+}
+
+/// The socket a virtiofsd started by glue listens on.
+fn backend_socket_path(tag: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("glue-{}-fs-{}.sock", std::process::id(), tag))
+}
+
+/// Kills and reaps a virtiofsd started by glue, and removes its socket.
+fn stop_backend(child: &mut Child, socket: &Path) {
+    child.kill().unwrap_or(());
+    child.wait().ok();
+    std::fs::remove_file(socket).ok();
+}
+
+impl VirtioDevice for Fs {
+    fn device_type(&self) -> u32 {
+        TYPE_FS
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.backend.lock().unwrap().features()
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.backend.lock().unwrap().ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_fs_config { u8 tag[36]; le32 num_request_queues; }
+        let mut config = [0u8; FS_TAG_LEN + 4];
+        let tag = self.tag.as_bytes();
+        config[..tag.len()].copy_from_slice(tag);
+        config[FS_TAG_LEN..].copy_from_slice(&(QUEUE_SIZES.len() as u32 - 1).to_le_bytes());
+
+        let offset = offset as usize;
+        if offset + data.len() <= config.len() {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.subscriptions = vhost_user::activate(
+            &self.backend,
+            &self.event_manager,
+            mem,
+            interrupt,
+            queues,
+            queue_evts,
+        )?;
+        Ok(())
+    }
+
+    // The open files are in virtiofsd, a new instance would not know them.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Err(Error::SnapshotUnsupported(
+            "virtio-fs devices keep their state in virtiofsd".to_string(),
+        ))
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.backend.lock().unwrap().pause()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.backend.lock().unwrap().resume()
+    }
+}
+
+impl Drop for Fs {
+    fn drop(&mut self) {
+        // Not to reconnect to the virtiofsd going away.
+        self.subscriptions.clear();
+        if let Some(child) = self.child.as_mut() {
+            stop_backend(child, &backend_socket_path(&self.tag));
+        }
+    }
+}
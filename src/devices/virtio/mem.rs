@@ -0,0 +1,393 @@
+// virtio-mem device, plugging and unplugging blocks of the hotplug region at the
+// request of the guest, which is asked to plug a given amount of memory.
+// Spec: virtio 1.2, section 5.15.
+
+use super::*;
+use crate::config::MemHotplugConfig;
+use crate::error::*;
+use crate::memory::MemorySlots;
+use crate::snapshot::{StateReader, StateWriter};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// Block size when the configuration does not give one, the size of a Linux
+/// memory block on arm64 with 4K pages.
+pub const DEFAULT_BLOCK_SIZE: u64 = 128 << 20;
+pub const MIN_BLOCK_SIZE: u64 = 2 << 20;
+
+// The device never touches unplugged memory, and the guest must not either.
+const VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE: u64 = 1;
+
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+// struct virtio_mem_req { le16 type; le16 padding[3]; le64 addr; le16 nb_blocks; le16 padding[3]; }
+const REQ_LEN: usize = 24;
+// struct virtio_mem_resp { le16 type; le16 padding[3]; le16 state; }
+const RESP_LEN: usize = 10;
+
+// The hotplug region and the blocks plugged in it, shared by the device and its resizer.
+struct MemState {
+    addr: u64,
+    region_size: u64,
+    block_size: u64,
+    // Host address the region is mapped at.
+    host_addr: u64,
+    requested_size: u64,
+    // KVM slot of each plugged block, by block index.
+    plugged: BTreeMap<u64, u32>,
+    slots: Arc<Mutex<MemorySlots>>,
+    interrupt: Option<Arc<dyn VirtioInterrupt>>,
+}
+
+impl MemState {
+    fn plugged_size(&self) -> u64 {
+        self.plugged.len() as u64 * self.block_size
+    }
+
+    // Indexes of the `nb_blocks` blocks at `addr`, if they all lie in the region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<Range<u64>> {
+        if nb_blocks == 0 || addr < self.addr || (addr - self.addr) % self.block_size != 0 {
+            return None;
+        }
+        let first = (addr - self.addr) / self.block_size;
+        let end = first + u64::from(nb_blocks);
+        if end > self.region_size / self.block_size {
+            return None;
+        }
+        Some(first..end)
+    }
+
+    // Handles a request and returns the response type and block state.
+    fn handle(&mut self, req_type: u16, addr: u64, nb_blocks: u16) -> (u16, u16) {
+        if req_type == VIRTIO_MEM_REQ_UNPLUG_ALL {
+            let blocks: Vec<u64> = self.plugged.keys().copied().collect();
+            for block in blocks {
+                self.unplug_block(block);
+            }
+            return (VIRTIO_MEM_RESP_ACK, 0);
+        }
+
+        let blocks = match self.blocks(addr, nb_blocks) {
+            Some(blocks) => blocks,
+            None => return (VIRTIO_MEM_RESP_ERROR, 0),
+        };
+        let num_plugged = blocks
+            .clone()
+            .filter(|block| self.plugged.contains_key(block))
+            .count() as u16;
+        match req_type {
+            VIRTIO_MEM_REQ_PLUG => {
+                if num_plugged != 0 {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                if self.plugged_size() + u64::from(nb_blocks) * self.block_size
+                    > self.requested_size
+                {
+                    return (VIRTIO_MEM_RESP_NACK, 0);
+                }
+                for block in blocks.clone() {
+                    if let Err(e) = self.plug_block(block) {
+                        println!("virtio-mem: failed to plug block {}: {:?}", block, e);
+                        for block in blocks.start..block {
+                            self.unplug_block(block);
+                        }
+                        return (VIRTIO_MEM_RESP_ERROR, 0);
+                    }
+                }
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_UNPLUG => {
+                if num_plugged != nb_blocks {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                for block in blocks {
+                    self.unplug_block(block);
+                }
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_STATE => {
+                let state = if num_plugged == nb_blocks {
+                    VIRTIO_MEM_STATE_PLUGGED
+                } else if num_plugged == 0 {
+                    VIRTIO_MEM_STATE_UNPLUGGED
+                } else {
+                    VIRTIO_MEM_STATE_MIXED
+                };
+                (VIRTIO_MEM_RESP_ACK, state)
+            }
+            _ => (VIRTIO_MEM_RESP_ERROR, 0),
+        }
+    }
+
+    // Maps `block` into the guest with a KVM slot of its own.
+    fn plug_block(&mut self, block: u64) -> Result<()> {
+        let offset = block * self.block_size;
+        // Safe because the hotplug region stays mapped as long as the VM exists.
+        let slot = unsafe {
+            self.slots.lock().unwrap().add(
+                self.addr + offset,
+                self.block_size,
+                self.host_addr + offset,
+                false,
+            )
+        }?;
+        self.plugged.insert(block, slot);
+        Ok(())
+    }
+
+    // Takes `block` away from the guest and gives its memory back to the host.
+    fn unplug_block(&mut self, block: u64) {
+        let slot = match self.plugged.remove(&block) {
+            Some(slot) => slot,
+            None => return,
+        };
+        self.slots.lock().unwrap().remove(slot);
+
+        let offset = block * self.block_size;
+        let host_addr = (self.host_addr + offset) as *mut libc::c_void;
+        let len = self.block_size as usize;
+        // Safe because the range is part of the hotplug region, which the guest
+        // cannot reach anymore. MADV_REMOVE frees the pages of a memfd, anonymous
+        // memory needs MADV_DONTNEED.
+        unsafe {
+            if libc::madvise(host_addr, len, libc::MADV_REMOVE) != 0 {
+                libc::madvise(host_addr, len, libc::MADV_DONTNEED);
+            }
+        }
+    }
+}
+
+/// Changes the amount of memory the guest is asked to plug, while it runs.
+#[derive(Clone)]
+pub struct MemResizer {
+    state: Arc<Mutex<MemState>>,
+}
+
+impl MemResizer {
+    /// Asks the guest to plug or unplug blocks until `size` bytes are plugged.
+    pub fn resize(&self, size: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if size % state.block_size != 0 || size > state.region_size {
+            return Err(Error::InvalidMemorySize(size));
+        }
+        state.requested_size = size;
+        if let Some(interrupt) = &state.interrupt {
+            interrupt
+                .trigger(VirtioInterruptType::Config, 0)
+                .unwrap_or(());
+        }
+        println!(
+            "virtio-mem: requested {} MB, {} MB plugged",
+            size >> 20,
+            state.plugged_size() >> 20
+        );
+        Ok(())
+    }
+}
+
+/// A virtio-mem device managing the hotplug region of guest memory.
+pub struct Mem {
+    state: Arc<Mutex<MemState>>,
+    acked_features: u64,
+    event_manager: Arc<EventManager>,
+    subscription: Option<Subscription>,
+}
+
+impl Mem {
+    /// Manages the `region` of `mem` reserved for hotplug, with nothing plugged.
+    ///
+    /// Plugged blocks get KVM slots of their own from `slots`, which must have
+    /// enough left for the whole region to be plugged.
+    pub fn new(
+        config: &MemHotplugConfig,
+        mem: &GuestMemoryMmap,
+        region: (GuestAddress, usize),
+        slots: Arc<Mutex<MemorySlots>>,
+        event_manager: Arc<EventManager>,
+    ) -> Result<Mem> {
+        let (addr, size) = region;
+        let num_blocks = size as u64 / config.block_size;
+        let available = slots.lock().unwrap().available();
+        if num_blocks > u64::from(available) {
+            return Err(Error::InvalidOption(format!(
+                "mem-hotplug: {} blocks need more than the {} free memory slots, use a larger block-size",
+                num_blocks, available
+            )));
+        }
+        // The region is part of guest memory.
+        let host_addr = mem.get_host_address(addr).unwrap();
+
+        let state = MemState {
+            addr: addr.0,
+            region_size: size as u64,
+            block_size: config.block_size,
+            host_addr: host_addr as u64,
+            requested_size: 0,
+            plugged: BTreeMap::new(),
+            slots,
+            interrupt: None,
+        };
+        Ok(Mem {
+            state: Arc::new(Mutex::new(state)),
+            acked_features: 0,
+            event_manager,
+            subscription: None,
+        })
+    }
+
+    pub fn resizer(&self) -> MemResizer {
+        MemResizer {
+            state: self.state.clone(),
+        }
+    }
+}
+
+fn process_queue(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    state: &Mutex<MemState>,
+    interrupt: &dyn VirtioInterrupt,
+) {
+    let mut used = false;
+    while let Some(head) = queue.pop(mem) {
+        let index = head.index;
+        let mut len = 0;
+
+        let mut req = [0u8; REQ_LEN];
+        let valid = !head.is_write_only()
+            && head.len as usize >= REQ_LEN
+            && mem.read_slice(&mut req, head.addr).is_ok();
+        let resp = head
+            .next_descriptor()
+            .filter(|d| d.is_write_only() && d.len as usize >= RESP_LEN);
+
+        if let Some(resp) = resp {
+            let (resp_type, block_state) = if valid {
+                let req_type = u16::from_le_bytes([req[0], req[1]]);
+                let mut addr = [0u8; 8];
+                addr.copy_from_slice(&req[8..16]);
+                let nb_blocks = u16::from_le_bytes([req[16], req[17]]);
+                state
+                    .lock()
+                    .unwrap()
+                    .handle(req_type, u64::from_le_bytes(addr), nb_blocks)
+            } else {
+                (VIRTIO_MEM_RESP_ERROR, 0)
+            };
+            let mut buf = [0u8; RESP_LEN];
+            buf[0..2].copy_from_slice(&resp_type.to_le_bytes());
+            buf[8..10].copy_from_slice(&block_state.to_le_bytes());
+            if mem.write_slice(&buf, resp.addr).is_ok() {
+                len = RESP_LEN as u32;
+            }
+        }
+
+        queue.add_used(mem, index, len);
+        used = true;
+    }
+
+    if used {
+        interrupt
+            .trigger(VirtioInterruptType::Queue, 0)
+            .unwrap_or(());
+    }
+}
+
+impl VirtioDevice for Mem {
+    fn device_type(&self) -> u32 {
+        TYPE_MEM
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_MEM_F_UNPLUGGED_INACCESSIBLE
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features();
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_mem_config { le64 block_size; le16 node_id; u8 padding[6];
+        // le64 addr; le64 region_size; le64 usable_region_size; le64 plugged_size;
+        // le64 requested_size; }
+        let state = self.state.lock().unwrap();
+        let mut config = [0u8; 56];
+        config[0..8].copy_from_slice(&state.block_size.to_le_bytes());
+        config[16..24].copy_from_slice(&state.addr.to_le_bytes());
+        config[24..32].copy_from_slice(&state.region_size.to_le_bytes());
+        config[32..40].copy_from_slice(&state.region_size.to_le_bytes());
+        config[40..48].copy_from_slice(&state.plugged_size().to_le_bytes());
+        config[48..56].copy_from_slice(&state.requested_size.to_le_bytes());
+
+        let offset = offset as usize;
+        if offset + data.len() <= config.len() {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.state.lock().unwrap().interrupt = Some(interrupt.clone());
+        let state = self.state.clone();
+        self.subscription = Some(subscribe_queue(
+            &self.event_manager,
+            queues.remove(0),
+            queue_evts.remove(0),
+            move |queue| process_queue(&mem, queue, &state, interrupt.as_ref()),
+        )?);
+        Ok(())
+    }
+
+    // The content of the plugged blocks is saved with the rest of guest memory.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let mut snapshot = StateWriter::new();
+        snapshot.u64(state.requested_size);
+        snapshot.u32(state.plugged.len() as u32);
+        for block in state.plugged.keys() {
+            snapshot.u64(*block);
+        }
+        Ok(snapshot.into_inner())
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<()> {
+        let mut snapshot = StateReader::new(snapshot);
+        let mut state = self.state.lock().unwrap();
+        state.requested_size = snapshot.u64()?;
+        for _ in 0..snapshot.u32()? {
+            let block = snapshot.u64()?;
+            if block >= state.region_size / state.block_size {
+                return Err(Error::InvalidSnapshot(format!(
+                    "virtio-mem block {} out of the region",
+                    block
+                )));
+            }
+            state.plug_block(block)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+// Virtio over MMIO, see "4.2 Virtio Over MMIO" of the virtio spec.
+
+use super::*;
+use crate::devices::BusDevice;
+use crate::error::*;
+use crate::memory::DeviceDirtyLog;
+use crate::snapshot::{StateReader, StateWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the MMIO window of a virtio-mmio device, registers plus configuration space.
+pub const MMIO_LEN: u64 = 0x200;
+/// Offset of the `QueueNotify` register, the driver writes the index of a queue to it.
+pub const QUEUE_NOTIFY_OFFSET: u64 = REG_QUEUE_NOTIFY;
+
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976; // "virt"
+const MMIO_VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0;
+
+// Register offsets.
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_AVAIL_LOW: u64 = 0x090;
+const REG_QUEUE_AVAIL_HIGH: u64 = 0x094;
+const REG_QUEUE_USED_LOW: u64 = 0x0a0;
+const REG_QUEUE_USED_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+const REG_CONFIG: u64 = 0x100;
+
+fn set_low(addr: &mut GuestAddress, v: u32) {
+    addr.0 = (addr.0 & 0xffff_ffff_0000_0000) | u64::from(v);
+}
+
+fn set_high(addr: &mut GuestAddress, v: u32) {
+    addr.0 = (addr.0 & 0x0000_0000_ffff_ffff) | (u64::from(v) << 32);
+}
+
+/// Implements the virtio-mmio register layout on top of a `VirtioDevice`.
+pub struct MmioTransport {
+    device: Box<dyn VirtioDevice>,
+    mem: GuestMemoryMmap,
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt: Arc<dyn VirtioInterrupt>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+    features_select: u32,
+    acked_features_select: u32,
+    // Features the driver acked, to ack them again on restore.
+    acked_features: u64,
+    queue_select: u32,
+    driver_status: u32,
+    config_generation: u32,
+    activated: bool,
+}
+
+impl MmioTransport {
+    /// Wraps `device`; `irq_evt` is the irqfd of the interrupt line given to the device.
+    ///
+    /// The queues mark what the device writes to guest memory in `dirty_log`.
+    pub fn new(
+        mem: GuestMemoryMmap,
+        device: Box<dyn VirtioDevice>,
+        irq_evt: EventFd,
+        dirty_log: Arc<DeviceDirtyLog>,
+    ) -> Result<MmioTransport> {
+        let mut queue_evts = Vec::new();
+        for _ in device.queue_max_sizes().iter() {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|&s| {
+                let mut queue = Queue::new(s);
+                queue.set_dirty_log(dirty_log.clone());
+                queue
+            })
+            .collect();
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let interrupt = Arc::new(LegacyIrq::new(interrupt_status.clone(), irq_evt));
+
+        Ok(MmioTransport {
+            device,
+            mem,
+            interrupt_status,
+            interrupt,
+            queues,
+            queue_evts,
+            features_select: 0,
+            acked_features_select: 0,
+            acked_features: 0,
+            queue_select: 0,
+            driver_status: 0,
+            config_generation: 0,
+            activated: false,
+        })
+    }
+
+    /// The eventfds signalled when the driver notifies each queue.
+    pub fn queue_evts(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn check_driver_status(&self, set: u32, clr: u32) -> bool {
+        self.driver_status & (set | clr) == set
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+            f(queue);
+        }
+    }
+
+    fn activate(&mut self) {
+        let mut queue_evts = Vec::new();
+        for evt in self.queue_evts.iter() {
+            match evt.try_clone() {
+                Ok(evt) => queue_evts.push(evt),
+                Err(e) => {
+                    println!("virtio-mmio: failed to clone queue eventfd: {:?}", e);
+                    return;
+                }
+            }
+        }
+
+        match self.device.activate(
+            self.mem.clone(),
+            self.interrupt.clone(),
+            self.queues.clone(),
+            queue_evts,
+        ) {
+            Ok(()) => self.activated = true,
+            Err(e) => {
+                println!("virtio-mmio: failed to activate device: {:?}", e);
+                self.driver_status |= DEVICE_FAILED;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.activated && !self.device.reset() {
+            // The device keeps running; the driver has to treat it as broken.
+            self.driver_status |= DEVICE_FAILED;
+            return;
+        }
+        self.activated = false;
+        self.features_select = 0;
+        self.acked_features_select = 0;
+        self.acked_features = 0;
+        self.queue_select = 0;
+        self.driver_status = 0;
+        self.interrupt_status.store(0, Ordering::SeqCst);
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            REG_MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            REG_VERSION => MMIO_VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => {
+                let features = self.device.features() | (1 << VIRTIO_F_VERSION_1);
+                match self.features_select {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            REG_QUEUE_NUM_MAX => self
+                .queues
+                .get(self.queue_select as usize)
+                .map_or(0, |q| u32::from(q.max_size)),
+            REG_QUEUE_READY => self
+                .queues
+                .get(self.queue_select as usize)
+                .map_or(0, |q| q.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst) as u32,
+            REG_STATUS => self.driver_status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            _ => {
+                println!("virtio-mmio: unknown register read at {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, v: u32) {
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.features_select = v,
+            REG_DRIVER_FEATURES => {
+                if self.check_driver_status(DEVICE_DRIVER, DEVICE_FEATURES_OK | DEVICE_FAILED) {
+                    let features = match self.acked_features_select {
+                        0 => u64::from(v),
+                        1 => u64::from(v) << 32,
+                        _ => 0,
+                    };
+                    self.acked_features |= features;
+                    self.device.ack_features(features);
+                }
+            }
+            REG_DRIVER_FEATURES_SEL => self.acked_features_select = v,
+            REG_QUEUE_SEL => self.queue_select = v,
+            REG_QUEUE_NUM => self.with_queue_mut(|q| q.size = v as u16),
+            REG_QUEUE_READY => self.with_queue_mut(|q| q.ready = v == 1),
+            REG_QUEUE_NOTIFY => {
+                if let Some(evt) = self.queue_evts.get(v as usize) {
+                    evt.write(1).unwrap_or(());
+                }
+            }
+            REG_INTERRUPT_ACK => {
+                self.interrupt_status
+                    .fetch_and(!(v as usize), Ordering::SeqCst);
+            }
+            REG_STATUS => {
+                if v == 0 {
+                    self.reset();
+                    return;
+                }
+                self.driver_status = v;
+                if !self.activated && self.check_driver_status(DEVICE_DRIVER_OK, DEVICE_FAILED) {
+                    self.activate();
+                }
+            }
+            REG_QUEUE_DESC_LOW => self.with_queue_mut(|q| set_low(&mut q.desc_table, v)),
+            REG_QUEUE_DESC_HIGH => self.with_queue_mut(|q| set_high(&mut q.desc_table, v)),
+            REG_QUEUE_AVAIL_LOW => self.with_queue_mut(|q| set_low(&mut q.avail_ring, v)),
+            REG_QUEUE_AVAIL_HIGH => self.with_queue_mut(|q| set_high(&mut q.avail_ring, v)),
+            REG_QUEUE_USED_LOW => self.with_queue_mut(|q| set_low(&mut q.used_ring, v)),
+            REG_QUEUE_USED_HIGH => self.with_queue_mut(|q| set_high(&mut q.used_ring, v)),
+            _ => println!("virtio-mmio: unknown register write at {:#x}", offset),
+        }
+    }
+}
+
+impl BusDevice for MmioTransport {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= REG_CONFIG {
+            self.device.read_config(offset - REG_CONFIG, data);
+        } else if data.len() == 4 {
+            let v = self.read_reg(offset);
+            data.copy_from_slice(&v.to_le_bytes());
+        } else {
+            println!("virtio-mmio: invalid {} byte register read", data.len());
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if offset >= REG_CONFIG {
+            if self.check_driver_status(DEVICE_DRIVER, DEVICE_FAILED) {
+                self.device.write_config(offset - REG_CONFIG, data);
+                self.config_generation = self.config_generation.wrapping_add(1);
+            }
+        } else if data.len() == 4 {
+            let mut v = [0u8; 4];
+            v.copy_from_slice(data);
+            self.write_reg(offset, u32::from_le_bytes(v));
+        } else {
+            println!("virtio-mmio: invalid {} byte register write", data.len());
+        }
+    }
+
+    fn snapshot(&self) -> Result<Option<Vec<u8>>> {
+        let mut state = StateWriter::new();
+        state.u32(self.interrupt_status.load(Ordering::SeqCst) as u32);
+        state.u32(self.features_select);
+        state.u32(self.acked_features_select);
+        state.u64(self.acked_features);
+        state.u32(self.queue_select);
+        state.u32(self.driver_status);
+        state.u32(self.config_generation);
+        state.bool(self.activated);
+        for queue in self.queues.iter() {
+            queue.snapshot(&mut state);
+        }
+        state.bytes(&self.device.snapshot()?);
+        Ok(Some(state.into_inner()))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state);
+        self.interrupt_status
+            .store(state.u32()? as usize, Ordering::SeqCst);
+        self.features_select = state.u32()?;
+        self.acked_features_select = state.u32()?;
+        self.acked_features = state.u64()?;
+        self.queue_select = state.u32()?;
+        self.driver_status = state.u32()?;
+        self.config_generation = state.u32()?;
+        let activated = state.bool()?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(&mut state, &self.mem)?;
+        }
+        self.device.ack_features(self.acked_features);
+        self.device.restore(state.bytes()?)?;
+        if activated {
+            self.activate();
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        self.device.pause()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        self.device.resume()
+    }
+}
@@ -0,0 +1,305 @@
+// Virtio device model, shared by all the virtio devices and transports.
+// Spec: https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html
+
+pub mod fs;
+pub mod mem;
+pub mod mmio;
+pub mod net;
+pub mod p9;
+pub mod pci;
+pub mod pmem;
+pub mod queue;
+pub mod vhost_kernel;
+pub mod vhost_user;
+pub mod vhost_user_device;
+pub mod vsock;
+
+pub use self::mmio::MmioTransport;
+pub use self::pci::VirtioPciDevice;
+pub use self::queue::{DescriptorChain, Queue};
+
+use crate::error::*;
+use crate::event_manager::{EventManager, EventOps, Subscriber, Subscription};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+// Device types, see "5 Device Types" of the spec.
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_RNG: u32 = 4;
+pub const TYPE_9P: u32 = 9;
+pub const TYPE_VSOCK: u32 = 19;
+pub const TYPE_MEM: u32 = 24;
+pub const TYPE_FS: u32 = 26;
+pub const TYPE_PMEM: u32 = 27;
+
+// Device status bits, see "2.1 Device Status Field".
+pub const DEVICE_ACKNOWLEDGE: u32 = 0x01;
+pub const DEVICE_DRIVER: u32 = 0x02;
+pub const DEVICE_DRIVER_OK: u32 = 0x04;
+pub const DEVICE_FEATURES_OK: u32 = 0x08;
+pub const DEVICE_FAILED: u32 = 0x80;
+
+/// The device conforms to the virtio 1.x (modern) specification.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// What caused an interrupt to be raised by a device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VirtioInterruptType {
+    /// Buffers were used in one of the queues.
+    Queue,
+    /// The device configuration space changed.
+    Config,
+}
+
+/// Interrupt delivery, implemented by each transport.
+pub trait VirtioInterrupt: Send + Sync {
+    /// Notifies the driver about used buffers on `queue_index` or a configuration change.
+    fn trigger(&self, int_type: VirtioInterruptType, queue_index: u16) -> io::Result<()>;
+
+    /// An irqfd raising the interrupt of `queue_index` without going through
+    /// `trigger`, for backends signalling used buffers on their own.
+    ///
+    /// None when the interrupt needs help from glue, like setting a status bit.
+    fn notifier(&self, _queue_index: u16) -> Option<EventFd> {
+        None
+    }
+}
+
+// Interrupt status bits of the virtio-mmio `InterruptStatus` register.
+pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
+pub const VIRTIO_MMIO_INT_CONFIG: u32 = 0x02;
+
+/// A level interrupt line backed by a KVM irqfd, with the status register virtio-mmio requires.
+pub struct LegacyIrq {
+    status: Arc<AtomicUsize>,
+    irq_evt: EventFd,
+}
+
+impl LegacyIrq {
+    pub fn new(status: Arc<AtomicUsize>, irq_evt: EventFd) -> Self {
+        LegacyIrq { status, irq_evt }
+    }
+}
+
+impl VirtioInterrupt for LegacyIrq {
+    fn trigger(&self, int_type: VirtioInterruptType, _queue_index: u16) -> io::Result<()> {
+        let bit = match int_type {
+            VirtioInterruptType::Queue => VIRTIO_MMIO_INT_VRING,
+            VirtioInterruptType::Config => VIRTIO_MMIO_INT_CONFIG,
+        };
+        self.status.fetch_or(bit as usize, Ordering::SeqCst);
+        self.irq_evt.write(1)
+    }
+}
+
+/// Eventfds a backend processing `num_queues` queues outside of glue, like
+/// vhost, signals when it used buffers.
+///
+/// Queues whose interrupt has an irqfd get it directly, so the notification
+/// goes straight to KVM; the others are relayed to `interrupt` by `event_manager`
+/// for as long as the returned subscription is kept.
+pub fn call_evts(
+    event_manager: &EventManager,
+    interrupt: Arc<dyn VirtioInterrupt>,
+    num_queues: usize,
+) -> Result<(Vec<EventFd>, Option<Subscription>)> {
+    let mut call_evts = Vec::new();
+    let mut relay_evts = Vec::new();
+    for index in 0..num_queues {
+        match interrupt.notifier(index as u16) {
+            Some(evt) => call_evts.push(evt),
+            None => {
+                let evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
+                relay_evts.push((index as u16, evt.try_clone().map_err(Error::EventFd)?));
+                call_evts.push(evt);
+            }
+        }
+    }
+    if relay_evts.is_empty() {
+        return Ok((call_evts, None));
+    }
+
+    let fds: Vec<RawFd> = relay_evts.iter().map(|(_, evt)| evt.as_raw_fd()).collect();
+    let relay = CallRelay {
+        relay_evts,
+        interrupt,
+    };
+    let mut subscription = Subscription::new(event_manager.subscribe(Arc::new(Mutex::new(relay))));
+    for fd in fds {
+        subscription.add(fd, libc::EPOLLIN as u32)?;
+    }
+    Ok((call_evts, Some(subscription)))
+}
+
+// Relays each eventfd of `relay_evts` to the interrupt of the queue paired with it.
+struct CallRelay {
+    relay_evts: Vec<(u16, EventFd)>,
+    interrupt: Arc<dyn VirtioInterrupt>,
+}
+
+impl Subscriber for CallRelay {
+    fn process(&mut self, fd: RawFd, _events: u32, _ops: &EventOps) {
+        for (queue_index, evt) in self.relay_evts.iter() {
+            if evt.as_raw_fd() == fd && evt.read().is_ok() {
+                self.interrupt
+                    .trigger(VirtioInterruptType::Queue, *queue_index)
+                    .unwrap_or(());
+            }
+        }
+    }
+}
+
+/// Processes a single queue on the event manager, for devices like virtio-pmem.
+///
+/// `process` is called with the queue every time the driver notifies it, until
+/// the returned subscription is dropped.
+pub fn subscribe_queue<F>(
+    event_manager: &EventManager,
+    queue: Queue,
+    queue_evt: EventFd,
+    process: F,
+) -> Result<Subscription>
+where
+    F: FnMut(&mut Queue) + Send + 'static,
+{
+    let fd = queue_evt.as_raw_fd();
+    let handler = QueueHandler {
+        queue,
+        queue_evt,
+        process,
+    };
+    let mut subscription =
+        Subscription::new(event_manager.subscribe(Arc::new(Mutex::new(handler))));
+    subscription.add(fd, libc::EPOLLIN as u32)?;
+    Ok(subscription)
+}
+
+struct QueueHandler<F> {
+    queue: Queue,
+    queue_evt: EventFd,
+    process: F,
+}
+
+impl<F: FnMut(&mut Queue) + Send> Subscriber for QueueHandler<F> {
+    fn process(&mut self, _fd: RawFd, _events: u32, _ops: &EventOps) {
+        if self.queue_evt.read().is_ok() {
+            (self.process)(&mut self.queue);
+        }
+    }
+}
+
+/// Trait for virtio devices, independent of the transport they are exposed on.
+pub trait VirtioDevice: Send {
+    /// The virtio device type.
+    fn device_type(&self) -> u32;
+
+    /// The maximum size of each queue this device supports.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// The set of feature bits this device offers.
+    fn features(&self) -> u64;
+
+    /// Acknowledges the features the driver accepted.
+    fn ack_features(&mut self, value: u64);
+
+    /// Reads from this device configuration space at `offset`.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Writes to this device configuration space at `offset`.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+
+    /// Activates the device once the driver set `DRIVER_OK`.
+    ///
+    /// `queue_evts` are signalled whenever the driver notifies the matching queue.
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()>;
+
+    /// Resets the device, stopping any processing. Returns false if the device cannot be reset.
+    fn reset(&mut self) -> bool {
+        false
+    }
+
+    /// State of the device for a snapshot, besides the features and queues
+    /// the transport saves. Taken while the vcpus are paused.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(Vec::new())
+    }
+
+    /// Restores the state `snapshot` returned, after the features were acked
+    /// again and before the device is activated.
+    fn restore(&mut self, _state: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Stops a vhost backend processing the queues of the activated device,
+    /// until `resume`. The event loop is paused separately.
+    fn pause(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Queue 0 has an irqfd, the others need their interrupt relayed.
+    struct TestInterrupt {
+        irq_evt: EventFd,
+        triggered: Mutex<Sender<u16>>,
+    }
+
+    impl VirtioInterrupt for TestInterrupt {
+        fn trigger(&self, int_type: VirtioInterruptType, queue_index: u16) -> io::Result<()> {
+            assert_eq!(int_type, VirtioInterruptType::Queue);
+            self.triggered.lock().unwrap().send(queue_index).unwrap();
+            Ok(())
+        }
+
+        fn notifier(&self, queue_index: u16) -> Option<EventFd> {
+            if queue_index == 0 {
+                self.irq_evt.try_clone().ok()
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn call_evts_use_irqfds_or_relay() {
+        let (tx, rx) = channel();
+        let irq_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let interrupt = Arc::new(TestInterrupt {
+            irq_evt: irq_evt.try_clone().unwrap(),
+            triggered: Mutex::new(tx),
+        });
+        let call_evts = call_evts("test_call_relay", interrupt, 3).unwrap();
+        assert_eq!(call_evts.len(), 3);
+
+        call_evts[0].write(1).unwrap();
+        assert_eq!(irq_evt.read().unwrap(), 1);
+
+        call_evts[2].write(1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+        call_evts[1].write(1).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        // The irqfd went straight to its consumer.
+        assert!(rx.try_recv().is_err());
+    }
+}
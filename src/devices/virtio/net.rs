@@ -0,0 +1,400 @@
+// virtio-net device on top of a host tap interface.
+//
+// The queues are processed by vhost-net in the host kernel when it is
+// available, or on the device event loop of glue otherwise.
+
+use super::vhost_kernel::VhostKernel;
+use super::*;
+use crate::config::{NetConfig, VhostMode};
+use crate::error::*;
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use vm_memory::{Bytes, GuestMemoryMmap};
+use vmm_sys_util::ioctl::ioctl_with_ref;
+use vmm_sys_util::ioctl_iow_nr;
+
+const QUEUE_SIZE: u16 = 256;
+// One receive queue plus one transmit queue.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE];
+const RX_INDEX: usize = 0;
+const TX_INDEX: usize = 1;
+
+/// The device has a MAC address in its configuration space.
+const VIRTIO_NET_F_MAC: u32 = 5;
+
+// struct virtio_net_hdr_v1, in front of every frame on the queues and the tap.
+const VNET_HDR_LEN: usize = 12;
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+// The largest frame a tap hands out, with its header.
+const MAX_FRAME_LEN: usize = 65562;
+
+const TUNTAP: u32 = b'T' as u32;
+ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, libc::c_int);
+ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, libc::c_uint);
+ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, libc::c_int);
+const IFF_TAP: i16 = 0x0002;
+const IFF_NO_PI: i16 = 0x1000;
+const IFF_VNET_HDR: i16 = 0x4000;
+
+// struct ifreq, with the flags member of the union.
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: i16,
+    padding: [u8; 22],
+}
+
+/// A host tap interface, carrying frames with a virtio-net header.
+struct Tap {
+    file: File,
+}
+
+impl Tap {
+    fn open(name: &str) -> Result<Tap> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open("/dev/net/tun")
+            .map_err(Error::Tap)?;
+
+        let mut ifreq = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+            padding: [0; 22],
+        };
+        if name.len() >= ifreq.name.len() {
+            return Err(Error::InvalidOption(format!(
+                "net: tap name too long: {}",
+                name
+            )));
+        }
+        ifreq.name[..name.len()].copy_from_slice(name.as_bytes());
+        let hdr_len = VNET_HDR_LEN as libc::c_int;
+        let offload: libc::c_uint = 0;
+        // Safe because each ioctl only reads the argument passed to it.
+        unsafe {
+            check_ret(ioctl_with_ref(&file, TUNSETIFF(), &ifreq))?;
+            check_ret(ioctl_with_ref(&file, TUNSETVNETHDRSZ(), &hdr_len))?;
+            check_ret(ioctl_with_ref(&file, TUNSETOFFLOAD(), &offload))?;
+        }
+        Ok(Tap { file })
+    }
+}
+
+fn check_ret(ret: i32) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::Tap(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// A network interface, connected to a host tap interface.
+pub struct Net {
+    tap: Tap,
+    mac: [u8; 6],
+    vhost: Option<VhostKernel>,
+    avail_features: u64,
+    acked_features: u64,
+    event_manager: Arc<EventManager>,
+    // Keeps the datapath, or the interrupt relay of vhost-net, on the event loop.
+    subscription: Option<Subscription>,
+}
+
+impl Net {
+    pub fn new(config: &NetConfig, event_manager: Arc<EventManager>) -> Result<Net> {
+        let tap = Tap::open(&config.tap)?;
+
+        let vhost = match config.vhost {
+            VhostMode::Off => None,
+            VhostMode::On => Some(VhostKernel::open(Path::new("/dev/vhost-net"))?),
+            VhostMode::Auto => match VhostKernel::open(Path::new("/dev/vhost-net")) {
+                Ok(vhost) => Some(vhost),
+                Err(e) => {
+                    println!("net {}: vhost-net not available: {:?}", config.tap, e);
+                    None
+                }
+            },
+        };
+
+        println!(
+            "net {}: {} datapath",
+            config.tap,
+            if vhost.is_some() {
+                "vhost-net"
+            } else {
+                "userspace"
+            }
+        );
+
+        Ok(Net {
+            tap,
+            mac: config.mac,
+            vhost,
+            avail_features: 1 << VIRTIO_NET_F_MAC,
+            acked_features: 0,
+            event_manager,
+            subscription: None,
+        })
+    }
+
+    fn activate_vhost(
+        &self,
+        vhost: &VhostKernel,
+        mem: &GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: &[Queue],
+        queue_evts: Vec<EventFd>,
+    ) -> Result<Option<Subscription>> {
+        // The MAC is no business of vhost-net. Both transports only speak
+        // virtio 1.x, which vhost-net has to know to use the header length of the tap.
+        let features = self.acked_features & !(1 << VIRTIO_NET_F_MAC) | 1 << VIRTIO_F_VERSION_1;
+        vhost.set_features(features)?;
+
+        let (call_evts, relay) = call_evts(&self.event_manager, interrupt, queues.len())?;
+        vhost.setup_vrings(mem, queues, &queue_evts, &call_evts)?;
+        for index in 0..queues.len() {
+            vhost.net_set_backend(index as u32, self.tap.file.as_raw_fd())?;
+        }
+        // vhost-net holds references to the eventfds, they can be closed here.
+        Ok(relay)
+    }
+}
+
+// Copies frames from the tap to the receive queue until either runs out.
+// Returns a frame read but not delivered for lack of buffers.
+fn process_rx(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    tap: &mut File,
+    mut pending: Option<Vec<u8>>,
+    interrupt: &dyn VirtioInterrupt,
+) -> Option<Vec<u8>> {
+    let mut used = false;
+    loop {
+        let mut frame = match pending.take() {
+            Some(frame) => frame,
+            None => {
+                let mut frame = vec![0u8; MAX_FRAME_LEN];
+                match tap.read(&mut frame) {
+                    Ok(len) if len >= VNET_HDR_LEN => frame.truncate(len),
+                    _ => break,
+                }
+                frame
+            }
+        };
+        // The tap leaves num_buffers alone, the frame always fits in one chain.
+        frame[VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_LEN].copy_from_slice(&1u16.to_le_bytes());
+
+        let head = match queue.pop(mem) {
+            Some(head) => head,
+            None => {
+                pending = Some(frame);
+                break;
+            }
+        };
+        let index = head.index;
+        let mut written = 0;
+        let mut desc = Some(head);
+        while let Some(d) = desc {
+            if written == frame.len() || !d.is_write_only() {
+                break;
+            }
+            let len = cmp::min(d.len as usize, frame.len() - written);
+            if mem
+                .write_slice(&frame[written..written + len], d.addr)
+                .is_err()
+            {
+                break;
+            }
+            written += len;
+            desc = d.next_descriptor();
+        }
+        queue.add_used(mem, index, written as u32);
+        used = true;
+    }
+
+    if used {
+        interrupt
+            .trigger(VirtioInterruptType::Queue, RX_INDEX as u16)
+            .unwrap_or(());
+    }
+    pending
+}
+
+// Sends every frame on the transmit queue to the tap.
+fn process_tx(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    tap: &mut File,
+    interrupt: &dyn VirtioInterrupt,
+) {
+    let mut used = false;
+    while let Some(head) = queue.pop(mem) {
+        let index = head.index;
+        let mut frame = Vec::new();
+        let mut desc = Some(head);
+        while let Some(d) = desc {
+            if d.is_write_only() || frame.len() + d.len as usize > MAX_FRAME_LEN {
+                break;
+            }
+            let start = frame.len();
+            frame.resize(start + d.len as usize, 0);
+            if mem.read_slice(&mut frame[start..], d.addr).is_err() {
+                break;
+            }
+            desc = d.next_descriptor();
+        }
+        // A full tap drops the frame, as a real link would.
+        tap.write_all(&frame).unwrap_or(());
+        queue.add_used(mem, index, 0);
+        used = true;
+    }
+
+    if used {
+        interrupt
+            .trigger(VirtioInterruptType::Queue, TX_INDEX as u16)
+            .unwrap_or(());
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.avail_features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_net_config { u8 mac[6]; ... }, only the MAC is offered.
+        let offset = offset as usize;
+        if offset + data.len() <= self.mac.len() {
+            data.copy_from_slice(&self.mac[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        if let Some(vhost) = &self.vhost {
+            self.subscription = self.activate_vhost(vhost, &mem, interrupt, &queues, queue_evts)?;
+            return Ok(());
+        }
+
+        let tap = self.tap.file.try_clone().map_err(Error::Tap)?;
+        let tx_evt = queue_evts.remove(TX_INDEX);
+        let rx_evt = queue_evts.remove(RX_INDEX);
+        // The tap is edge triggered: frames left in it for lack of receive
+        // buffers are picked up on the next receive queue notification.
+        let fds = [
+            (tap.as_raw_fd(), libc::EPOLLIN | libc::EPOLLET),
+            (rx_evt.as_raw_fd(), libc::EPOLLIN),
+            (tx_evt.as_raw_fd(), libc::EPOLLIN),
+        ];
+        let datapath = NetDatapath {
+            mem,
+            interrupt,
+            tap,
+            tx_queue: queues.remove(TX_INDEX),
+            rx_queue: queues.remove(RX_INDEX),
+            tx_evt,
+            rx_evt,
+            pending: None,
+        };
+
+        let ops = self.event_manager.subscribe(Arc::new(Mutex::new(datapath)));
+        let mut subscription = Subscription::new(ops);
+        for (fd, events) in fds.iter() {
+            subscription.add(*fd, *events as u32)?;
+        }
+        self.subscription = Some(subscription);
+        Ok(())
+    }
+
+    // The MAC may be a random one, the new process would pick another.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.mac.to_vec())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        if state.len() != self.mac.len() {
+            return Err(Error::InvalidSnapshot("bad virtio-net state".to_string()));
+        }
+        self.mac.copy_from_slice(state);
+        Ok(())
+    }
+
+    // vhost-net stops processing the queues once the tap is taken away from them.
+    fn pause(&mut self) -> Result<()> {
+        if let Some(vhost) = &self.vhost {
+            for index in 0..QUEUE_SIZES.len() {
+                vhost.net_set_backend(index as u32, -1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if let Some(vhost) = &self.vhost {
+            for index in 0..QUEUE_SIZES.len() {
+                vhost.net_set_backend(index as u32, self.tap.file.as_raw_fd())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The userspace datapath, moving frames between the tap and the queues.
+struct NetDatapath {
+    mem: GuestMemoryMmap,
+    interrupt: Arc<dyn VirtioInterrupt>,
+    tap: File,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    rx_evt: EventFd,
+    tx_evt: EventFd,
+    // A frame read from the tap but not delivered for lack of receive buffers.
+    pending: Option<Vec<u8>>,
+}
+
+impl Subscriber for NetDatapath {
+    fn process(&mut self, fd: RawFd, _events: u32, _ops: &EventOps) {
+        if fd == self.tx_evt.as_raw_fd() {
+            self.tx_evt.read().ok();
+            process_tx(
+                &self.mem,
+                &mut self.tx_queue,
+                &mut self.tap,
+                self.interrupt.as_ref(),
+            );
+            return;
+        }
+        if fd == self.rx_evt.as_raw_fd() {
+            self.rx_evt.read().ok();
+        }
+        self.pending = process_rx(
+            &self.mem,
+            &mut self.rx_queue,
+            &mut self.tap,
+            self.pending.take(),
+            self.interrupt.as_ref(),
+        );
+    }
+}
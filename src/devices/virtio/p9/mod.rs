@@ -0,0 +1,149 @@
+// virtio-9p device, exporting a host directory with the 9P2000.L protocol.
+
+mod server;
+mod wire;
+
+pub use self::server::SecurityModel;
+
+use self::server::Server;
+use super::*;
+use crate::config::P9Config;
+use crate::error::*;
+use std::cmp;
+use vm_memory::{Bytes, GuestMemoryMmap};
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// The mount tag is present in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u32 = 0;
+
+/// A virtio-9p device, sharing a host directory with the guest under a mount tag.
+pub struct P9 {
+    tag: String,
+    server: Option<Server>,
+    acked_features: u64,
+    event_manager: Arc<EventManager>,
+    subscription: Option<Subscription>,
+}
+
+impl P9 {
+    pub fn new(config: &P9Config, event_manager: Arc<EventManager>) -> Result<P9> {
+        let server = Server::new(&config.path, config.security).map_err(Error::P9)?;
+        Ok(P9 {
+            tag: config.tag.clone(),
+            server: Some(server),
+            acked_features: 0,
+            event_manager,
+            subscription: None,
+        })
+    }
+}
+
+/// Serves all the requests available on the queue.
+fn process_queue(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    server: &mut Server,
+    interrupt: &dyn VirtioInterrupt,
+) {
+    let mut used = false;
+    while let Some(head) = queue.pop(mem) {
+        let index = head.index;
+        let mut request = Vec::new();
+        let mut writable = Vec::new();
+
+        // Requests come in the readable descriptors, replies go to the writable ones.
+        let mut desc = Some(head);
+        while let Some(d) = desc {
+            if d.is_write_only() {
+                writable.push((d.addr, d.len as usize));
+            } else {
+                // Nothing past msize is part of a valid request.
+                let start = request.len();
+                let len = cmp::min(d.len as usize, server.msize() as usize - start);
+                request.resize(start + len, 0);
+                if mem.read_slice(&mut request[start..], d.addr).is_err() {
+                    break;
+                }
+            }
+            desc = d.next_descriptor();
+        }
+
+        let reply = server.handle(&request);
+        let mut written = 0;
+        for (addr, len) in writable {
+            let len = cmp::min(len, reply.len() - written);
+            if mem
+                .write_slice(&reply[written..written + len], addr)
+                .is_err()
+            {
+                break;
+            }
+            written += len;
+        }
+
+        queue.add_used(mem, index, written as u32);
+        used = true;
+    }
+
+    if used {
+        interrupt
+            .trigger(VirtioInterruptType::Queue, 0)
+            .unwrap_or(());
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn device_type(&self) -> u32 {
+        TYPE_9P
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features();
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_9p_config { le16 tag_len; u8 tag[]; }
+        let mut config = Vec::new();
+        config.extend_from_slice(&(self.tag.len() as u16).to_le_bytes());
+        config.extend_from_slice(self.tag.as_bytes());
+
+        let offset = offset as usize;
+        if offset + data.len() <= config.len() {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let mut server = self.server.take().ok_or(Error::DeviceActivated)?;
+        self.subscription = Some(subscribe_queue(
+            &self.event_manager,
+            queues.remove(0),
+            queue_evts.remove(0),
+            move |queue| process_queue(&mem, queue, &mut server, interrupt.as_ref()),
+        )?);
+        Ok(())
+    }
+
+    // The fids of the guest refer to host files the new process would not have open.
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        Err(Error::SnapshotUnsupported(
+            "virtio-9p devices have host files open for the guest".to_string(),
+        ))
+    }
+}
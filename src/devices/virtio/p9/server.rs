@@ -0,0 +1,1027 @@
+// A 9P2000.L file server exporting a host directory.
+// Protocol: https://github.com/chaos/diod/blob/master/protocol.md
+
+use super::wire::*;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+// Message types; every T-message is answered with type + 1, or Rlerror.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION_9P2000_L: &str = "9P2000.L";
+const MAX_MSIZE: u32 = 128 * 1024;
+// Enough for any fixed size reply, smaller sizes only come from a broken guest.
+const MIN_MSIZE: u32 = 4096;
+const MAX_WALK_ELEMENTS: u16 = 16;
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+// Open flags as sent by the guest, which do not always match the host values.
+const P9_DOTL_ACCMODE: u32 = 0o3;
+const P9_DOTL_RDONLY: u32 = 0o0;
+const P9_DOTL_WRONLY: u32 = 0o1;
+const P9_DOTL_TRUNC: u32 = 0o1000;
+const P9_DOTL_APPEND: u32 = 0o2000;
+const P9_DOTL_EXCL: u32 = 0o200;
+
+// Tsetattr valid bits.
+const P9_SETATTR_MODE: u32 = 0x1;
+const P9_SETATTR_UID: u32 = 0x2;
+const P9_SETATTR_GID: u32 = 0x4;
+const P9_SETATTR_SIZE: u32 = 0x8;
+const P9_SETATTR_ATIME: u32 = 0x10;
+const P9_SETATTR_MTIME: u32 = 0x20;
+const P9_SETATTR_ATIME_SET: u32 = 0x80;
+const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Fields filled in by Rgetattr, mode through blocks.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+const AT_REMOVEDIR: u32 = 0x200;
+
+// Where the mapped security model keeps the guest's view of file ownership.
+const XATTR_UID: &str = "user.virtfs.uid";
+const XATTR_GID: &str = "user.virtfs.gid";
+const XATTR_MODE: &str = "user.virtfs.mode";
+
+/// How guest file ownership and permissions are stored on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurityModel {
+    /// Files are created with the guest's credentials; glue needs the privileges to do so.
+    Passthrough,
+    /// Files are owned by the user running glue, the guest's credentials are kept in xattrs.
+    Mapped,
+}
+
+struct Fid {
+    /// Path relative to the exported root, made of normal components only.
+    path: PathBuf,
+    uid: u32,
+    file: Option<File>,
+    dir_entries: Vec<(Qid, u8, String)>,
+}
+
+fn errno(e: i32) -> io::Error {
+    io::Error::from_raw_os_error(e)
+}
+
+fn cstr(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| errno(libc::EINVAL))
+}
+
+fn qid(md: &Metadata) -> Qid {
+    let ty = if md.is_dir() {
+        QTDIR
+    } else if md.file_type().is_symlink() {
+        QTSYMLINK
+    } else {
+        QTFILE
+    };
+    Qid {
+        ty,
+        version: 0,
+        path: md.ino(),
+    }
+}
+
+fn dirent_type(md: &Metadata) -> u8 {
+    let ft = md.file_type();
+    if ft.is_dir() {
+        libc::DT_DIR
+    } else if ft.is_symlink() {
+        libc::DT_LNK
+    } else if ft.is_file() {
+        libc::DT_REG
+    } else if ft.is_fifo() {
+        libc::DT_FIFO
+    } else {
+        libc::DT_UNKNOWN
+    }
+}
+
+/// Validates a name the guest wants to create, it must stay in its directory.
+fn new_name(name: &str) -> io::Result<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok(name)
+}
+
+/// Steps from `path` into `name`; `..` never leaves the exported root.
+fn walk_name(path: &Path, name: &str) -> io::Result<PathBuf> {
+    if name == ".." {
+        let mut parent = path.to_path_buf();
+        parent.pop();
+        return Ok(parent);
+    }
+    Ok(path.join(new_name(name)?))
+}
+
+fn set_xattr(path: &Path, name: &str, value: u32) -> io::Result<()> {
+    let path = cstr(path)?;
+    let name = CString::new(name).unwrap();
+    let value = value.to_ne_bytes();
+    // Safe because all the buffers are valid for the duration of the call.
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn get_xattr(path: &Path, name: &str) -> Option<u32> {
+    let path = cstr(path).ok()?;
+    let name = CString::new(name).unwrap();
+    let mut value = [0u8; 4];
+    // Safe because all the buffers are valid for the duration of the call.
+    let ret = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    if ret != value.len() as isize {
+        return None;
+    }
+    Some(u32::from_ne_bytes(value))
+}
+
+fn lchown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let path = cstr(path)?;
+    // Safe because path is a valid C string; -1 leaves the id unchanged.
+    let ret = unsafe {
+        libc::lchown(
+            path.as_ptr(),
+            uid.unwrap_or(u32::max_value()),
+            gid.unwrap_or(u32::max_value()),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Opens `path` itself, not what it links to, without access to its content.
+fn open_path(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_NOFOLLOW | libc::O_CLOEXEC)
+        .open(path)
+}
+
+/// Like chmod, but fails with ELOOP on a symlink instead of following it out
+/// of the exported root.
+fn chmod_nofollow(path: &Path, mode: u32) -> io::Result<()> {
+    let file = open_path(path)?;
+    if file.metadata()?.file_type().is_symlink() {
+        return Err(errno(libc::ELOOP));
+    }
+    fs::set_permissions(
+        format!("/proc/self/fd/{}", file.as_raw_fd()),
+        fs::Permissions::from_mode(mode),
+    )
+}
+
+pub struct Server {
+    root: PathBuf,
+    security: SecurityModel,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(root: &Path, security: SecurityModel) -> io::Result<Server> {
+        Ok(Server {
+            root: fs::canonicalize(root)?,
+            security,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Largest message the guest may send or receive.
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Handles one T-message and returns the reply.
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut r = Reader::new(request);
+        let (ty, tag) = match Server::read_header(&mut r) {
+            Ok(header) => header,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut w = Writer::new(ty.wrapping_add(1), tag);
+        match self.dispatch(ty, &mut r, &mut w) {
+            Ok(()) => w.finish(),
+            Err(e) => {
+                let mut w = Writer::new(RLERROR, tag);
+                w.write_u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+                w.finish()
+            }
+        }
+    }
+
+    fn read_header(r: &mut Reader) -> io::Result<(u8, u16)> {
+        let _size = r.read_u32()?;
+        Ok((r.read_u8()?, r.read_u16()?))
+    }
+
+    fn dispatch(&mut self, ty: u8, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        match ty {
+            TVERSION => self.version(r, w),
+            TATTACH => self.attach(r, w),
+            TWALK => self.walk(r, w),
+            TLOPEN => self.lopen(r, w),
+            TLCREATE => self.lcreate(r, w),
+            TREAD => self.read(r, w),
+            TWRITE => self.write(r, w),
+            TCLUNK => self.clunk(r),
+            TREMOVE => self.remove(r),
+            TGETATTR => self.getattr(r, w),
+            TSETATTR => self.setattr(r),
+            TREADDIR => self.readdir(r, w),
+            TSTATFS => self.statfs(r, w),
+            TMKDIR => self.mkdir(r, w),
+            TSYMLINK => self.symlink(r, w),
+            TREADLINK => self.readlink(r, w),
+            TLINK => self.link(r),
+            TRENAME => self.rename(r),
+            TRENAMEAT => self.renameat(r),
+            TUNLINKAT => self.unlinkat(r),
+            TFSYNC => self.fsync(r),
+            // Requests are served synchronously, there is never anything to flush.
+            TFLUSH => Ok(()),
+            _ => Err(errno(libc::EOPNOTSUPP)),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> io::Result<&Fid> {
+        self.fids.get(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> io::Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or_else(|| errno(libc::EBADF))
+    }
+
+    /// Host path of `rel`, with every component but the last resolved and
+    /// checked to be under the exported root. The last one is never followed.
+    fn host_path(&self, rel: &Path) -> io::Result<PathBuf> {
+        let name = match rel.file_name() {
+            Some(name) => name,
+            None => return Ok(self.root.clone()),
+        };
+        let parent = fs::canonicalize(self.root.join(rel.parent().unwrap_or(rel)))?;
+        if !parent.starts_with(&self.root) {
+            return Err(errno(libc::EACCES));
+        }
+        Ok(parent.join(name))
+    }
+
+    fn metadata(&self, rel: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(self.host_path(rel)?)
+    }
+
+    /// Records the guest's view of ownership for a newly created file.
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32, mode: Option<u32>) -> io::Result<()> {
+        match self.security {
+            SecurityModel::Passthrough => lchown(path, Some(uid), Some(gid)),
+            SecurityModel::Mapped => {
+                // User xattrs are not allowed on symlinks, they keep the host owner.
+                if let Some(mode) = mode {
+                    set_xattr(path, XATTR_UID, uid)?;
+                    set_xattr(path, XATTR_GID, gid)?;
+                    set_xattr(path, XATTR_MODE, mode)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.read_u32()?;
+        let version = r.read_str()?;
+        if msize < MIN_MSIZE {
+            return Err(errno(libc::EINVAL));
+        }
+
+        // A version request aborts everything in flight and resets the session.
+        self.fids.clear();
+        self.msize = std::cmp::min(msize, MAX_MSIZE);
+        w.write_u32(self.msize);
+        if version.starts_with(VERSION_9P2000_L) {
+            w.write_str(VERSION_9P2000_L);
+        } else {
+            w.write_str("unknown");
+        }
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let _afid = r.read_u32()?;
+        let _uname = r.read_str()?;
+        let _aname = r.read_str()?;
+        let uid = r.read_u32()?;
+
+        let md = fs::symlink_metadata(&self.root)?;
+        self.fids.insert(
+            fid,
+            Fid {
+                path: PathBuf::new(),
+                uid,
+                file: None,
+                dir_entries: Vec::new(),
+            },
+        );
+        w.write_qid(&qid(&md));
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let newfid = r.read_u32()?;
+        let nwname = r.read_u16()?;
+        if nwname > MAX_WALK_ELEMENTS {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let (mut path, uid) = {
+            let fid = self.fid(fid)?;
+            (fid.path.clone(), fid.uid)
+        };
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(errno(libc::EBADF));
+        }
+
+        let mut qids = Vec::new();
+        for _ in 0..nwname {
+            let name = r.read_str()?;
+            let next = walk_name(&path, &name)?;
+            match self.metadata(&next) {
+                Ok(md) => {
+                    qids.push(qid(&md));
+                    path = next;
+                }
+                // Only the first element failing is an error, otherwise the
+                // partial walk is returned and newfid is left alone.
+                Err(e) if qids.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == nwname as usize {
+            self.fids.insert(
+                newfid,
+                Fid {
+                    path,
+                    uid,
+                    file: None,
+                    dir_entries: Vec::new(),
+                },
+            );
+        }
+
+        w.write_u16(qids.len() as u16);
+        for qid in qids.iter() {
+            w.write_qid(qid);
+        }
+        Ok(())
+    }
+
+    fn open_options(flags: u32) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        match flags & P9_DOTL_ACCMODE {
+            P9_DOTL_RDONLY => options.read(true),
+            P9_DOTL_WRONLY => options.write(true),
+            _ => options.read(true).write(true),
+        };
+        options
+            .truncate(flags & P9_DOTL_TRUNC != 0)
+            .append(flags & P9_DOTL_APPEND != 0)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC);
+        options
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let flags = r.read_u32()?;
+
+        let path = self.host_path(&self.fid(fid)?.path)?;
+        let md = fs::symlink_metadata(&path)?;
+        let file = if md.is_dir() {
+            Server::open_options(P9_DOTL_RDONLY).open(&path)?
+        } else {
+            Server::open_options(flags).open(&path)?
+        };
+
+        let fid = self.fid_mut(fid)?;
+        fid.file = Some(file);
+        fid.dir_entries.clear();
+        w.write_qid(&qid(&md));
+        w.write_u32(0); // iounit, let the guest use msize.
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let name = r.read_str()?;
+        let flags = r.read_u32()?;
+        let mode = r.read_u32()?;
+        let gid = r.read_u32()?;
+
+        let (rel, uid) = {
+            let fid = self.fid(fid)?;
+            (fid.path.join(new_name(&name)?), fid.uid)
+        };
+        let path = self.host_path(&rel)?;
+        let host_mode = match self.security {
+            SecurityModel::Passthrough => mode & 0o7777,
+            SecurityModel::Mapped => 0o600,
+        };
+        let mut options = Server::open_options(flags);
+        options.mode(host_mode);
+        if flags & P9_DOTL_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+        let file = options.open(&path)?;
+        self.set_owner(&path, uid, gid, Some(libc::S_IFREG | (mode & 0o7777)))?;
+
+        let md = file.metadata()?;
+        let fid = self.fid_mut(fid)?;
+        fid.path = rel;
+        fid.file = Some(file);
+        w.write_qid(&qid(&md));
+        w.write_u32(0);
+        Ok(())
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let offset = r.read_u64()?;
+        let count = r.read_u32()?;
+
+        let max = self.msize as usize - HEADER_SIZE - 4;
+        let mut buf = vec![0u8; std::cmp::min(count as usize, max)];
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let len = file.read_at(&mut buf, offset)?;
+
+        w.write_u32(len as u32);
+        w.write_bytes(&buf[..len]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let offset = r.read_u64()?;
+        let count = r.read_u32()?;
+        let data = r.read_bytes(count as usize)?;
+
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        let len = file.write_at(data, offset)?;
+        w.write_u32(len as u32);
+        Ok(())
+    }
+
+    fn clunk(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        // The fid is clunked even if the removal fails.
+        let fid = self.fids.remove(&fid).ok_or_else(|| errno(libc::EBADF))?;
+        if fid.path.as_os_str().is_empty() {
+            return Err(errno(libc::EBUSY));
+        }
+        let path = self.host_path(&fid.path)?;
+        if fs::symlink_metadata(&path)?.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let _request_mask = r.read_u64()?;
+
+        let path = self.host_path(&self.fid(fid)?.path)?;
+        let md = fs::symlink_metadata(&path)?;
+        let (mut mode, mut uid, mut gid) = (md.mode(), md.uid(), md.gid());
+        if self.security == SecurityModel::Mapped {
+            uid = get_xattr(&path, XATTR_UID).unwrap_or(uid);
+            gid = get_xattr(&path, XATTR_GID).unwrap_or(gid);
+            if let Some(mapped) = get_xattr(&path, XATTR_MODE) {
+                mode = (mode & libc::S_IFMT) | (mapped & !libc::S_IFMT);
+            }
+        }
+
+        w.write_u64(P9_GETATTR_BASIC);
+        w.write_qid(&qid(&md));
+        w.write_u32(mode);
+        w.write_u32(uid);
+        w.write_u32(gid);
+        w.write_u64(md.nlink());
+        w.write_u64(md.rdev());
+        w.write_u64(md.size());
+        w.write_u64(md.blksize());
+        w.write_u64(md.blocks());
+        w.write_u64(md.atime() as u64);
+        w.write_u64(md.atime_nsec() as u64);
+        w.write_u64(md.mtime() as u64);
+        w.write_u64(md.mtime_nsec() as u64);
+        w.write_u64(md.ctime() as u64);
+        w.write_u64(md.ctime_nsec() as u64);
+        // btime, gen and data_version are not provided.
+        for _ in 0..4 {
+            w.write_u64(0);
+        }
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let valid = r.read_u32()?;
+        let mode = r.read_u32()?;
+        let uid = r.read_u32()?;
+        let gid = r.read_u32()?;
+        let size = r.read_u64()?;
+        let atime = (r.read_u64()?, r.read_u64()?);
+        let mtime = (r.read_u64()?, r.read_u64()?);
+
+        let path = self.host_path(&self.fid(fid)?.path)?;
+
+        if valid & P9_SETATTR_MODE != 0 {
+            match self.security {
+                SecurityModel::Passthrough => chmod_nofollow(&path, mode & 0o7777)?,
+                SecurityModel::Mapped => set_xattr(&path, XATTR_MODE, mode)?,
+            }
+        }
+
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if valid & P9_SETATTR_UID != 0 {
+                Some(uid)
+            } else {
+                None
+            };
+            let gid = if valid & P9_SETATTR_GID != 0 {
+                Some(gid)
+            } else {
+                None
+            };
+            match self.security {
+                SecurityModel::Passthrough => lchown(&path, uid, gid)?,
+                SecurityModel::Mapped => {
+                    if let Some(uid) = uid {
+                        set_xattr(&path, XATTR_UID, uid)?;
+                    }
+                    if let Some(gid) = gid {
+                        set_xattr(&path, XATTR_GID, gid)?;
+                    }
+                }
+            }
+        }
+
+        if valid & P9_SETATTR_SIZE != 0 {
+            Server::open_options(P9_DOTL_WRONLY)
+                .open(&path)?
+                .set_len(size)?;
+        }
+
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let timespec = |set: bool, explicit: bool, (sec, nsec): (u64, u64)| {
+                let mut ts: libc::timespec = unsafe { mem::zeroed() };
+                if !set {
+                    ts.tv_nsec = libc::UTIME_OMIT;
+                } else if !explicit {
+                    ts.tv_nsec = libc::UTIME_NOW;
+                } else {
+                    ts.tv_sec = sec as libc::time_t;
+                    ts.tv_nsec = nsec as libc::c_long;
+                }
+                ts
+            };
+            let times = [
+                timespec(
+                    valid & P9_SETATTR_ATIME != 0,
+                    valid & P9_SETATTR_ATIME_SET != 0,
+                    atime,
+                ),
+                timespec(
+                    valid & P9_SETATTR_MTIME != 0,
+                    valid & P9_SETATTR_MTIME_SET != 0,
+                    mtime,
+                ),
+            ];
+            let c_path = cstr(&path)?;
+            // Safe because c_path and times are valid for the duration of the call.
+            let ret = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    c_path.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let offset = r.read_u64()?;
+        let count = r.read_u32()?;
+
+        // Snapshot the directory on the first read, offsets index into it.
+        if offset == 0 {
+            let rel = self.fid(fid)?.path.clone();
+            let path = self.host_path(&rel)?;
+            let mut entries = Vec::new();
+            let md = fs::symlink_metadata(&path)?;
+            entries.push((qid(&md), libc::DT_DIR, ".".to_string()));
+            let parent = self.metadata(&walk_name(&rel, "..")?)?;
+            entries.push((qid(&parent), libc::DT_DIR, "..".to_string()));
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                let md = entry.metadata()?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                entries.push((qid(&md), dirent_type(&md), name));
+            }
+            self.fid_mut(fid)?.dir_entries = entries;
+        }
+
+        let max = std::cmp::min(count, self.msize - HEADER_SIZE as u32 - 4) as usize;
+        let mut data = Writer::payload();
+        for (index, (qid, ty, name)) in self
+            .fid(fid)?
+            .dir_entries
+            .iter()
+            .enumerate()
+            .skip(offset as usize)
+        {
+            // qid[13] offset[8] type[1] name[s]
+            if data.len() + 24 + name.len() > max {
+                break;
+            }
+            data.write_qid(qid);
+            data.write_u64(index as u64 + 1);
+            data.write_u8(*ty);
+            data.write_str(name);
+        }
+
+        w.write_u32(data.len() as u32);
+        w.write_bytes(&data.into_bytes());
+        Ok(())
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        // A symlink is not followed, its own file system is the one reported.
+        let file = open_path(&self.host_path(&self.fid(fid)?.path)?)?;
+        let mut st: libc::statvfs = unsafe { mem::zeroed() };
+        // Safe because file is open and st is large enough.
+        if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut st) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        w.write_u32(V9FS_MAGIC);
+        w.write_u32(st.f_bsize as u32);
+        w.write_u64(st.f_blocks as u64);
+        w.write_u64(st.f_bfree as u64);
+        w.write_u64(st.f_bavail as u64);
+        w.write_u64(st.f_files as u64);
+        w.write_u64(st.f_ffree as u64);
+        w.write_u64(st.f_fsid as u64);
+        w.write_u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.read_u32()?;
+        let name = r.read_str()?;
+        let mode = r.read_u32()?;
+        let gid = r.read_u32()?;
+
+        let (rel, uid) = {
+            let fid = self.fid(dfid)?;
+            (fid.path.join(new_name(&name)?), fid.uid)
+        };
+        let path = self.host_path(&rel)?;
+        let host_mode = match self.security {
+            SecurityModel::Passthrough => mode & 0o7777,
+            SecurityModel::Mapped => 0o700,
+        };
+        fs::DirBuilder::new().mode(host_mode).create(&path)?;
+        self.set_owner(&path, uid, gid, Some(libc::S_IFDIR | (mode & 0o7777)))?;
+
+        w.write_qid(&qid(&fs::symlink_metadata(&path)?));
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let dfid = r.read_u32()?;
+        let name = r.read_str()?;
+        let target = r.read_str()?;
+        let gid = r.read_u32()?;
+
+        let (rel, uid) = {
+            let fid = self.fid(dfid)?;
+            (fid.path.join(new_name(&name)?), fid.uid)
+        };
+        let path = self.host_path(&rel)?;
+        // The target is stored as is, the guest resolves it and walks are confined anyway.
+        std::os::unix::fs::symlink(&target, &path)?;
+        self.set_owner(&path, uid, gid, None)?;
+
+        w.write_qid(&qid(&fs::symlink_metadata(&path)?));
+        Ok(())
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let target = fs::read_link(self.host_path(&self.fid(fid)?.path)?)?;
+        w.write_str(&target.to_string_lossy());
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.read_u32()?;
+        let fid = r.read_u32()?;
+        let name = r.read_str()?;
+
+        let target = self.host_path(&self.fid(fid)?.path)?;
+        let path = self.host_path(&self.fid(dfid)?.path.join(new_name(&name)?))?;
+        fs::hard_link(target, path)
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let dfid = r.read_u32()?;
+        let name = r.read_str()?;
+
+        let new_rel = self.fid(dfid)?.path.join(new_name(&name)?);
+        let old = self.host_path(&self.fid(fid)?.path)?;
+        fs::rename(old, self.host_path(&new_rel)?)?;
+        self.fid_mut(fid)?.path = new_rel;
+        Ok(())
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let olddirfid = r.read_u32()?;
+        let oldname = r.read_str()?;
+        let newdirfid = r.read_u32()?;
+        let newname = r.read_str()?;
+
+        let old = self.host_path(&self.fid(olddirfid)?.path.join(new_name(&oldname)?))?;
+        let new = self.host_path(&self.fid(newdirfid)?.path.join(new_name(&newname)?))?;
+        fs::rename(old, new)
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dfid = r.read_u32()?;
+        let name = r.read_str()?;
+        let flags = r.read_u32()?;
+
+        let path = self.host_path(&self.fid(dfid)?.path.join(new_name(&name)?))?;
+        if flags & AT_REMOVEDIR != 0 {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.read_u32()?;
+        let datasync = r.read_u32()?;
+        let file = self
+            .fid(fid)?
+            .file
+            .as_ref()
+            .ok_or_else(|| errno(libc::EBADF))?;
+        if datasync != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    // A scratch directory holding the exported `root` and a file outside of it.
+    struct TestDir {
+        path: PathBuf,
+    }
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let path =
+                std::env::temp_dir().join(format!("glue-p9-{}-{}", std::process::id(), name));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(path.join("root/dir")).unwrap();
+            fs::write(path.join("root/dir/file"), b"inside").unwrap();
+            fs::write(path.join("outside"), b"outside").unwrap();
+            fs::set_permissions(path.join("outside"), fs::Permissions::from_mode(0o600)).unwrap();
+            TestDir {
+                path: fs::canonicalize(path).unwrap(),
+            }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.path.join("root")
+        }
+
+        fn server(&self) -> Server {
+            Server::new(&self.root(), SecurityModel::Passthrough).unwrap()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.path).ok();
+        }
+    }
+
+    fn request<F: FnOnce(&mut Writer)>(server: &mut Server, ty: u8, body: F) -> Vec<u8> {
+        let mut w = Writer::new(ty, 1);
+        body(&mut w);
+        server.handle(&w.finish())
+    }
+
+    // The errno of an Rlerror reply, None for any other reply.
+    fn error(reply: &[u8]) -> Option<i32> {
+        if reply[4] != RLERROR {
+            return None;
+        }
+        let mut r = Reader::new(&reply[HEADER_SIZE..]);
+        Some(r.read_u32().unwrap() as i32)
+    }
+
+    // Attaches fid 1 to the root and walks `newfid` from it to `names`.
+    fn walk(server: &mut Server, newfid: u32, names: &[&str]) -> Vec<u8> {
+        request(server, TATTACH, |w| {
+            w.write_u32(1);
+            w.write_u32(u32::max_value());
+            w.write_str("");
+            w.write_str("");
+            w.write_u32(0);
+        });
+        request(server, TWALK, |w| {
+            w.write_u32(1);
+            w.write_u32(newfid);
+            w.write_u16(names.len() as u16);
+            for name in names {
+                w.write_str(name);
+            }
+        })
+    }
+
+    fn chmod(server: &mut Server, fid: u32, mode: u32) -> Vec<u8> {
+        request(server, TSETATTR, |w| {
+            w.write_u32(fid);
+            w.write_u32(P9_SETATTR_MODE);
+            w.write_u32(mode);
+            w.write_u32(0);
+            w.write_u32(0);
+            for _ in 0..5 {
+                w.write_u64(0);
+            }
+        })
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().mode() & 0o7777
+    }
+
+    #[test]
+    fn walk_name_stays_in_root() {
+        assert_eq!(walk_name(Path::new(""), "..").unwrap(), PathBuf::new());
+        assert_eq!(walk_name(Path::new("dir"), "..").unwrap(), PathBuf::new());
+        assert_eq!(
+            walk_name(Path::new("dir"), "file").unwrap(),
+            PathBuf::from("dir/file")
+        );
+        for name in &["", ".", "a/b", "/etc"] {
+            assert!(walk_name(Path::new("dir"), name).is_err());
+        }
+    }
+
+    #[test]
+    fn host_path_confined() {
+        let dir = TestDir::new("host-path");
+        let root = dir.root();
+        symlink("dir", root.join("inside")).unwrap();
+        symlink(&dir.path, root.join("escape")).unwrap();
+        symlink(dir.path.join("outside"), root.join("absolute")).unwrap();
+        let server = dir.server();
+
+        assert_eq!(server.host_path(Path::new("")).unwrap(), root);
+        assert_eq!(
+            server.host_path(Path::new("dir/file")).unwrap(),
+            root.join("dir/file")
+        );
+        // Symlinked parents are resolved, and must stay in the root.
+        assert_eq!(
+            server.host_path(Path::new("inside/file")).unwrap(),
+            root.join("dir/file")
+        );
+        let err = server.host_path(Path::new("escape/outside")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        // The last component is never followed.
+        assert_eq!(
+            server.host_path(Path::new("absolute")).unwrap(),
+            root.join("absolute")
+        );
+    }
+
+    #[test]
+    fn setattr_does_not_follow_symlinks() {
+        let dir = TestDir::new("setattr");
+        let outside = dir.path.join("outside");
+        symlink(&outside, dir.root().join("absolute")).unwrap();
+        let mut server = dir.server();
+
+        assert_eq!(error(&walk(&mut server, 2, &["absolute"])), None);
+        assert_eq!(error(&chmod(&mut server, 2, 0o777)), Some(libc::ELOOP));
+        assert_eq!(mode(&outside), 0o600);
+
+        // Walking up from the root stays there, and regular files still work.
+        assert_eq!(error(&walk(&mut server, 3, &["..", "dir", "file"])), None);
+        assert_eq!(error(&chmod(&mut server, 3, 0o640)), None);
+        assert_eq!(mode(&dir.root().join("dir/file")), 0o640);
+    }
+
+    #[test]
+    fn version_rejects_small_msize() {
+        let dir = TestDir::new("version");
+        let mut server = dir.server();
+        let reply = request(&mut server, TVERSION, |w| {
+            w.write_u32(HEADER_SIZE as u32);
+            w.write_str(VERSION_9P2000_L);
+        });
+        assert_eq!(error(&reply), Some(libc::EINVAL));
+        assert_eq!(server.msize(), MAX_MSIZE);
+    }
+}
@@ -0,0 +1,135 @@
+// Encoding of 9P messages: little-endian integers, strings prefixed with a 16 bit length.
+
+use std::io;
+
+/// Length of the `size[4] type[1] tag[2]` header of every message.
+pub const HEADER_SIZE: usize = 7;
+
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// Unique identifier of a file on the server.
+#[derive(Clone, Copy, Default)]
+pub struct Qid {
+    pub ty: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+fn short_message() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return Err(short_message());
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        let mut v = [0u8; 2];
+        v.copy_from_slice(self.read_bytes(2)?);
+        Ok(u16::from_le_bytes(v))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        let mut v = [0u8; 4];
+        v.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(v))
+    }
+
+    pub fn read_u64(&mut self) -> io::Result<u64> {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(v))
+    }
+
+    pub fn read_str(&mut self) -> io::Result<String> {
+        let len = self.read_u16()? as usize;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| short_message())
+    }
+}
+
+/// Builds a reply message; the size field is filled in by `finish`.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(ty: u8, tag: u16) -> Self {
+        let mut w = Writer { buf: Vec::new() };
+        w.write_u32(0);
+        w.write_u8(ty);
+        w.write_u16(tag);
+        w
+    }
+
+    /// A writer for a payload nested in a message, without header.
+    pub fn payload() -> Self {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        self.write_u16(s.len() as u16);
+        self.write_bytes(s.as_bytes());
+    }
+
+    pub fn write_qid(&mut self, qid: &Qid) {
+        self.write_u8(qid.ty);
+        self.write_u32(qid.version);
+        self.write_u64(qid.path);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = (self.buf.len() as u32).to_le_bytes();
+        self.buf[..4].copy_from_slice(&size);
+        self.buf
+    }
+}
@@ -0,0 +1,630 @@
+// Virtio over PCI, see "4.1 Virtio Over PCI Bus" of the virtio spec.
+// Only the modern (virtio 1.x) interface is implemented.
+
+use super::*;
+use crate::devices::pci::{
+    MsixConfig, PciBarRegionType, PciBarRequest, PciConfiguration, PciDevice,
+    MSIX_MSG_CTL_WRITABLE, PCI_CAP_ID_MSIX,
+};
+use crate::error::*;
+use crate::irqchip::GsiRouting;
+use crate::memory::DeviceDirtyLog;
+use crate::snapshot::{StateReader, StateWriter};
+use std::io;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use vm_memory::{GuestAddress, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+// Modern devices have ID 0x1040 + device type.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_REVISION_ID: u8 = 1;
+const PCI_CLASS_OTHERS: u8 = 0xff;
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// Type of the structure a virtio capability points to.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+/// The driver does not want an interrupt for this queue or configuration change.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+// Layout of the single BAR, every structure in its own page.
+const SETTINGS_BAR: usize = 0;
+const SETTINGS_BAR_SIZE: u64 = 0x8000;
+const COMMON_CONFIG_OFFSET: u64 = 0x0000;
+const COMMON_CONFIG_SIZE: u64 = 0x38;
+const ISR_CONFIG_OFFSET: u64 = 0x1000;
+const ISR_CONFIG_SIZE: u64 = 1;
+const DEVICE_CONFIG_OFFSET: u64 = 0x2000;
+const DEVICE_CONFIG_SIZE: u64 = 0x1000;
+const NOTIFY_OFFSET: u64 = 0x3000;
+const NOTIFY_SIZE: u64 = 0x1000;
+// Each queue is notified at its own address: NOTIFY_OFFSET + queue index * multiplier.
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+// Room for 512 vectors.
+const MSIX_TABLE_OFFSET: u64 = 0x4000;
+const MSIX_PBA_OFFSET: u64 = 0x6000;
+
+// Offsets in the common configuration structure.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_MSIX_CONFIG: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC_LOW: u64 = 0x20;
+const COMMON_QUEUE_DESC_HIGH: u64 = 0x24;
+const COMMON_QUEUE_AVAIL_LOW: u64 = 0x28;
+const COMMON_QUEUE_AVAIL_HIGH: u64 = 0x2c;
+const COMMON_QUEUE_USED_LOW: u64 = 0x30;
+const COMMON_QUEUE_USED_HIGH: u64 = 0x34;
+
+// Bits of the ISR status, the same as the virtio-mmio interrupt status.
+const VIRTIO_PCI_ISR_QUEUE: usize = VIRTIO_MMIO_INT_VRING as usize;
+const VIRTIO_PCI_ISR_CONFIG: usize = VIRTIO_MMIO_INT_CONFIG as usize;
+
+fn set_low(addr: &mut GuestAddress, v: u32) {
+    addr.0 = (addr.0 & 0xffff_ffff_0000_0000) | u64::from(v);
+}
+
+fn set_high(addr: &mut GuestAddress, v: u32) {
+    addr.0 = (addr.0 & 0x0000_0000_ffff_ffff) | (u64::from(v) << 32);
+}
+
+// Body of a virtio vendor capability pointing at `length` bytes at `offset` in the BAR.
+fn virtio_capability(cfg_type: u8, offset: u64, length: u64, extra: &[u8]) -> Vec<u8> {
+    // cap_len counts the ID and next pointer too.
+    let cap_len = 16 + extra.len() as u8;
+    let mut data = vec![cap_len, cfg_type, SETTINGS_BAR as u8, 0, 0, 0];
+    data.extend_from_slice(&(offset as u32).to_le_bytes());
+    data.extend_from_slice(&(length as u32).to_le_bytes());
+    data.extend_from_slice(extra);
+    data
+}
+
+/// Interrupts of a virtio-pci device: MSI-X once the driver enabled it, INTx before.
+struct VirtioPciInterrupt {
+    msix: Arc<Mutex<MsixConfig>>,
+    config_vector: Arc<AtomicU16>,
+    queue_vectors: Arc<Mutex<Vec<u16>>>,
+    isr: Arc<AtomicUsize>,
+    intx_evt: Option<EventFd>,
+}
+
+impl VirtioInterrupt for VirtioPciInterrupt {
+    fn trigger(&self, int_type: VirtioInterruptType, queue_index: u16) -> io::Result<()> {
+        let mut msix = self.msix.lock().unwrap();
+        if msix.enabled() {
+            let vector = match int_type {
+                VirtioInterruptType::Config => self.config_vector.load(Ordering::SeqCst),
+                VirtioInterruptType::Queue => self
+                    .queue_vectors
+                    .lock()
+                    .unwrap()
+                    .get(queue_index as usize)
+                    .copied()
+                    .unwrap_or(VIRTIO_MSI_NO_VECTOR),
+            };
+            if vector != VIRTIO_MSI_NO_VECTOR {
+                msix.trigger(vector);
+            }
+            return Ok(());
+        }
+
+        let bit = match int_type {
+            VirtioInterruptType::Queue => VIRTIO_PCI_ISR_QUEUE,
+            VirtioInterruptType::Config => VIRTIO_PCI_ISR_CONFIG,
+        };
+        self.isr.fetch_or(bit, Ordering::SeqCst);
+        match &self.intx_evt {
+            Some(evt) => evt.write(1),
+            None => Ok(()),
+        }
+    }
+
+    fn notifier(&self, queue_index: u16) -> Option<EventFd> {
+        // INTx needs the ISR bit set first, only MSI-X vectors go straight to KVM.
+        let msix = self.msix.lock().unwrap();
+        if !msix.enabled() {
+            return None;
+        }
+        let vector = *self
+            .queue_vectors
+            .lock()
+            .unwrap()
+            .get(queue_index as usize)?;
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            return None;
+        }
+        msix.irq_evt(vector)
+    }
+}
+
+/// Implements the virtio-pci capabilities and BAR layout on top of a `VirtioDevice`.
+pub struct VirtioPciDevice {
+    config: PciConfiguration,
+    device: Box<dyn VirtioDevice>,
+    mem: GuestMemoryMmap,
+    msix: Arc<Mutex<MsixConfig>>,
+    // Config space register holding the MSI-X message control.
+    msix_cap_reg_idx: usize,
+    config_vector: Arc<AtomicU16>,
+    queue_vectors: Arc<Mutex<Vec<u16>>>,
+    isr: Arc<AtomicUsize>,
+    intx_evt: Option<EventFd>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+    features_select: u32,
+    acked_features_select: u32,
+    // Features the driver acked, to ack them again on restore.
+    acked_features: u64,
+    queue_select: u16,
+    driver_status: u8,
+    config_generation: u8,
+    activated: bool,
+}
+
+impl VirtioPciDevice {
+    /// Wraps `device`; `devid` is the requester ID of the PCI function the device gets.
+    ///
+    /// The queues mark what the device writes to guest memory in `dirty_log`.
+    pub fn new(
+        mem: GuestMemoryMmap,
+        device: Box<dyn VirtioDevice>,
+        routing: Arc<Mutex<GsiRouting>>,
+        devid: u32,
+        dirty_log: Arc<DeviceDirtyLog>,
+    ) -> Result<VirtioPciDevice> {
+        let num_queues = device.queue_max_sizes().len();
+        let mut queue_evts = Vec::new();
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+        let queues = device
+            .queue_max_sizes()
+            .iter()
+            .map(|&s| {
+                let mut queue = Queue::new(s);
+                queue.set_dirty_log(dirty_log.clone());
+                queue
+            })
+            .collect();
+
+        let device_type = device.device_type();
+        let mut config = PciConfiguration::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+            VIRTIO_PCI_REVISION_ID,
+            PCI_CLASS_OTHERS,
+            0,
+            0,
+            VIRTIO_PCI_VENDOR_ID,
+            device_type as u16,
+        );
+
+        config.add_capability(
+            PCI_CAP_ID_VENDOR,
+            &virtio_capability(
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CONFIG_OFFSET,
+                COMMON_CONFIG_SIZE,
+                &[],
+            ),
+        )?;
+        config.add_capability(
+            PCI_CAP_ID_VENDOR,
+            &virtio_capability(
+                VIRTIO_PCI_CAP_ISR_CFG,
+                ISR_CONFIG_OFFSET,
+                ISR_CONFIG_SIZE,
+                &[],
+            ),
+        )?;
+        config.add_capability(
+            PCI_CAP_ID_VENDOR,
+            &virtio_capability(
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_CONFIG_OFFSET,
+                DEVICE_CONFIG_SIZE,
+                &[],
+            ),
+        )?;
+        config.add_capability(
+            PCI_CAP_ID_VENDOR,
+            &virtio_capability(
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                NOTIFY_OFFSET,
+                NOTIFY_SIZE,
+                &NOTIFY_OFF_MULTIPLIER.to_le_bytes(),
+            ),
+        )?;
+
+        // One vector per queue, plus one for configuration changes.
+        let num_vectors = num_queues as u16 + 1;
+        let msix_cap_offset = config.add_capability(
+            PCI_CAP_ID_MSIX,
+            &MsixConfig::capability(
+                num_vectors,
+                SETTINGS_BAR as u8,
+                MSIX_TABLE_OFFSET as u32,
+                MSIX_PBA_OFFSET as u32,
+            ),
+        )?;
+        let msix_cap_reg_idx = msix_cap_offset / 4;
+        config.set_writable_bits(msix_cap_reg_idx, MSIX_MSG_CTL_WRITABLE);
+        let msix = MsixConfig::new(num_vectors, routing, devid)?;
+
+        Ok(VirtioPciDevice {
+            config,
+            device,
+            mem,
+            msix: Arc::new(Mutex::new(msix)),
+            msix_cap_reg_idx,
+            config_vector: Arc::new(AtomicU16::new(VIRTIO_MSI_NO_VECTOR)),
+            queue_vectors: Arc::new(Mutex::new(vec![VIRTIO_MSI_NO_VECTOR; num_queues])),
+            isr: Arc::new(AtomicUsize::new(0)),
+            intx_evt: None,
+            queues,
+            queue_evts,
+            features_select: 0,
+            acked_features_select: 0,
+            acked_features: 0,
+            queue_select: 0,
+            driver_status: 0,
+            config_generation: 0,
+            activated: false,
+        })
+    }
+
+    /// The eventfds signalled when the driver notifies each queue.
+    pub fn queue_evts(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn check_driver_status(&self, set: u8, clr: u8) -> bool {
+        self.driver_status & (set | clr) == set
+    }
+
+    fn with_queue<T, F: FnOnce(&Queue) -> T>(&self, f: F) -> Option<T> {
+        self.queues.get(self.queue_select as usize).map(f)
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+            f(queue);
+        }
+    }
+
+    fn activate(&mut self) {
+        let mut queue_evts = Vec::new();
+        for evt in self.queue_evts.iter() {
+            match evt.try_clone() {
+                Ok(evt) => queue_evts.push(evt),
+                Err(e) => {
+                    println!("virtio-pci: failed to clone queue eventfd: {:?}", e);
+                    return;
+                }
+            }
+        }
+        let interrupt = VirtioPciInterrupt {
+            msix: self.msix.clone(),
+            config_vector: self.config_vector.clone(),
+            queue_vectors: self.queue_vectors.clone(),
+            isr: self.isr.clone(),
+            intx_evt: self.intx_evt.as_ref().and_then(|evt| evt.try_clone().ok()),
+        };
+
+        match self.device.activate(
+            self.mem.clone(),
+            Arc::new(interrupt),
+            self.queues.clone(),
+            queue_evts,
+        ) {
+            Ok(()) => self.activated = true,
+            Err(e) => {
+                println!("virtio-pci: failed to activate device: {:?}", e);
+                self.driver_status |= DEVICE_FAILED as u8;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.activated && !self.device.reset() {
+            // The device keeps running; the driver has to treat it as broken.
+            self.driver_status |= DEVICE_FAILED as u8;
+            return;
+        }
+        self.activated = false;
+        self.features_select = 0;
+        self.acked_features_select = 0;
+        self.acked_features = 0;
+        self.queue_select = 0;
+        self.driver_status = 0;
+        self.isr.store(0, Ordering::SeqCst);
+        self.config_vector
+            .store(VIRTIO_MSI_NO_VECTOR, Ordering::SeqCst);
+        for vector in self.queue_vectors.lock().unwrap().iter_mut() {
+            *vector = VIRTIO_MSI_NO_VECTOR;
+        }
+        for queue in self.queues.iter_mut() {
+            queue.reset();
+        }
+    }
+
+    fn read_common_config(&self, offset: u64) -> u32 {
+        match offset {
+            COMMON_DEVICE_FEATURE_SELECT => self.features_select,
+            COMMON_DEVICE_FEATURE => {
+                let features = self.device.features() | (1 << VIRTIO_F_VERSION_1);
+                match self.features_select {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            COMMON_DRIVER_FEATURE_SELECT => self.acked_features_select,
+            COMMON_MSIX_CONFIG => u32::from(self.config_vector.load(Ordering::SeqCst)),
+            COMMON_NUM_QUEUES => self.queues.len() as u32,
+            COMMON_DEVICE_STATUS => u32::from(self.driver_status),
+            COMMON_CONFIG_GENERATION => u32::from(self.config_generation),
+            COMMON_QUEUE_SELECT => u32::from(self.queue_select),
+            COMMON_QUEUE_SIZE => self.with_queue(|q| u32::from(q.size)).unwrap_or(0),
+            COMMON_QUEUE_MSIX_VECTOR => self
+                .queue_vectors
+                .lock()
+                .unwrap()
+                .get(self.queue_select as usize)
+                .map_or(u32::from(VIRTIO_MSI_NO_VECTOR), |v| u32::from(*v)),
+            COMMON_QUEUE_ENABLE => self.with_queue(|q| q.ready as u32).unwrap_or(0),
+            COMMON_QUEUE_NOTIFY_OFF => u32::from(self.queue_select),
+            COMMON_QUEUE_DESC_LOW => self.with_queue(|q| q.desc_table.0 as u32).unwrap_or(0),
+            COMMON_QUEUE_DESC_HIGH => self
+                .with_queue(|q| (q.desc_table.0 >> 32) as u32)
+                .unwrap_or(0),
+            COMMON_QUEUE_AVAIL_LOW => self.with_queue(|q| q.avail_ring.0 as u32).unwrap_or(0),
+            COMMON_QUEUE_AVAIL_HIGH => self
+                .with_queue(|q| (q.avail_ring.0 >> 32) as u32)
+                .unwrap_or(0),
+            COMMON_QUEUE_USED_LOW => self.with_queue(|q| q.used_ring.0 as u32).unwrap_or(0),
+            COMMON_QUEUE_USED_HIGH => self
+                .with_queue(|q| (q.used_ring.0 >> 32) as u32)
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn write_common_config(&mut self, offset: u64, v: u32) {
+        match offset {
+            COMMON_DEVICE_FEATURE_SELECT => self.features_select = v,
+            COMMON_DRIVER_FEATURE_SELECT => self.acked_features_select = v,
+            COMMON_DRIVER_FEATURE => {
+                if self.check_driver_status(
+                    DEVICE_DRIVER as u8,
+                    (DEVICE_FEATURES_OK | DEVICE_FAILED) as u8,
+                ) {
+                    let features = match self.acked_features_select {
+                        0 => u64::from(v),
+                        1 => u64::from(v) << 32,
+                        _ => 0,
+                    };
+                    self.acked_features |= features;
+                    self.device.ack_features(features);
+                }
+            }
+            COMMON_MSIX_CONFIG => self.config_vector.store(v as u16, Ordering::SeqCst),
+            COMMON_DEVICE_STATUS => {
+                if v == 0 {
+                    self.reset();
+                    return;
+                }
+                self.driver_status = v as u8;
+                if !self.activated
+                    && self.check_driver_status(DEVICE_DRIVER_OK as u8, DEVICE_FAILED as u8)
+                {
+                    self.activate();
+                }
+            }
+            COMMON_QUEUE_SELECT => self.queue_select = v as u16,
+            COMMON_QUEUE_SIZE => self.with_queue_mut(|q| q.size = v as u16),
+            COMMON_QUEUE_MSIX_VECTOR => {
+                let queue_select = self.queue_select as usize;
+                if let Some(vector) = self.queue_vectors.lock().unwrap().get_mut(queue_select) {
+                    *vector = v as u16;
+                }
+            }
+            COMMON_QUEUE_ENABLE => self.with_queue_mut(|q| q.ready = v == 1),
+            COMMON_QUEUE_DESC_LOW => self.with_queue_mut(|q| set_low(&mut q.desc_table, v)),
+            COMMON_QUEUE_DESC_HIGH => self.with_queue_mut(|q| set_high(&mut q.desc_table, v)),
+            COMMON_QUEUE_AVAIL_LOW => self.with_queue_mut(|q| set_low(&mut q.avail_ring, v)),
+            COMMON_QUEUE_AVAIL_HIGH => self.with_queue_mut(|q| set_high(&mut q.avail_ring, v)),
+            COMMON_QUEUE_USED_LOW => self.with_queue_mut(|q| set_low(&mut q.used_ring, v)),
+            COMMON_QUEUE_USED_HIGH => self.with_queue_mut(|q| set_high(&mut q.used_ring, v)),
+            _ => println!("virtio-pci: unknown common config write at {:#x}", offset),
+        }
+    }
+}
+
+impl PciDevice for VirtioPciDevice {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn bar_requests(&self) -> Vec<PciBarRequest> {
+        vec![PciBarRequest {
+            bar_idx: SETTINGS_BAR,
+            size: SETTINGS_BAR_SIZE,
+            region_type: PciBarRegionType::Memory64,
+        }]
+    }
+
+    fn assign_intx(&mut self, irq_evt: EventFd) {
+        self.intx_evt = Some(irq_evt);
+    }
+
+    fn ioeventfds(&self, bar_idx: usize) -> Vec<(u64, &EventFd)> {
+        if bar_idx != SETTINGS_BAR {
+            return Vec::new();
+        }
+        self.queue_evts
+            .iter()
+            .enumerate()
+            .map(|(queue, evt)| {
+                let offset = NOTIFY_OFFSET + queue as u64 * u64::from(NOTIFY_OFF_MULTIPLIER);
+                (offset, evt)
+            })
+            .collect()
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config.write_reg(reg_idx, offset, data);
+        if reg_idx == self.msix_cap_reg_idx {
+            let msg_ctl = (self.config.read_reg(reg_idx) >> 16) as u16;
+            self.msix.lock().unwrap().set_msg_ctl(msg_ctl);
+        }
+    }
+
+    fn read_bar(&mut self, _bar_idx: usize, offset: u64, data: &mut [u8]) {
+        match offset {
+            o if o < COMMON_CONFIG_OFFSET + COMMON_CONFIG_SIZE => {
+                let v = self.read_common_config(o - COMMON_CONFIG_OFFSET);
+                for (i, b) in data.iter_mut().enumerate().take(4) {
+                    *b = (v >> (i * 8)) as u8;
+                }
+            }
+            o if o >= ISR_CONFIG_OFFSET && o < ISR_CONFIG_OFFSET + ISR_CONFIG_SIZE => {
+                // Reading the ISR status acknowledges the interrupt.
+                if let Some(b) = data.first_mut() {
+                    *b = self.isr.swap(0, Ordering::SeqCst) as u8;
+                }
+            }
+            o if o >= DEVICE_CONFIG_OFFSET && o < DEVICE_CONFIG_OFFSET + DEVICE_CONFIG_SIZE => {
+                self.device.read_config(o - DEVICE_CONFIG_OFFSET, data);
+            }
+            o if o >= MSIX_TABLE_OFFSET && o < MSIX_PBA_OFFSET => {
+                self.msix
+                    .lock()
+                    .unwrap()
+                    .read_table(o - MSIX_TABLE_OFFSET, data);
+            }
+            o if o >= MSIX_PBA_OFFSET && o < SETTINGS_BAR_SIZE => {
+                self.msix
+                    .lock()
+                    .unwrap()
+                    .read_pba(o - MSIX_PBA_OFFSET, data);
+            }
+            _ => {}
+        }
+    }
+
+    fn write_bar(&mut self, _bar_idx: usize, offset: u64, data: &[u8]) {
+        let mut bytes = [0u8; 4];
+        for (i, b) in data.iter().enumerate().take(4) {
+            bytes[i] = *b;
+        }
+        let v = u32::from_le_bytes(bytes);
+
+        match offset {
+            o if o < COMMON_CONFIG_OFFSET + COMMON_CONFIG_SIZE => {
+                self.write_common_config(o - COMMON_CONFIG_OFFSET, v);
+            }
+            o if o >= DEVICE_CONFIG_OFFSET && o < DEVICE_CONFIG_OFFSET + DEVICE_CONFIG_SIZE => {
+                if self.check_driver_status(DEVICE_DRIVER as u8, DEVICE_FAILED as u8) {
+                    self.device.write_config(o - DEVICE_CONFIG_OFFSET, data);
+                    self.config_generation = self.config_generation.wrapping_add(1);
+                }
+            }
+            o if o >= NOTIFY_OFFSET && o < NOTIFY_OFFSET + NOTIFY_SIZE => {
+                let queue = (o - NOTIFY_OFFSET) / u64::from(NOTIFY_OFF_MULTIPLIER);
+                if let Some(evt) = self.queue_evts.get(queue as usize) {
+                    evt.write(1).unwrap_or(());
+                }
+            }
+            o if o >= MSIX_TABLE_OFFSET && o < MSIX_PBA_OFFSET => {
+                self.msix
+                    .lock()
+                    .unwrap()
+                    .write_table(o - MSIX_TABLE_OFFSET, data);
+            }
+            _ => {}
+        }
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>> {
+        let mut state = StateWriter::new();
+        self.config.snapshot(&mut state);
+        self.msix.lock().unwrap().snapshot(&mut state);
+        state.u16(self.config_vector.load(Ordering::SeqCst));
+        for vector in self.queue_vectors.lock().unwrap().iter() {
+            state.u16(*vector);
+        }
+        state.u8(self.isr.load(Ordering::SeqCst) as u8);
+        state.u32(self.features_select);
+        state.u32(self.acked_features_select);
+        state.u64(self.acked_features);
+        state.u16(self.queue_select);
+        state.u8(self.driver_status);
+        state.u8(self.config_generation);
+        state.bool(self.activated);
+        for queue in self.queues.iter() {
+            queue.snapshot(&mut state);
+        }
+        state.bytes(&self.device.snapshot()?);
+        Ok(state.into_inner())
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<()> {
+        let mut state = StateReader::new(state);
+        self.config.restore(&mut state)?;
+        self.msix.lock().unwrap().restore(&mut state)?;
+        self.config_vector.store(state.u16()?, Ordering::SeqCst);
+        for vector in self.queue_vectors.lock().unwrap().iter_mut() {
+            *vector = state.u16()?;
+        }
+        self.isr.store(usize::from(state.u8()?), Ordering::SeqCst);
+        self.features_select = state.u32()?;
+        self.acked_features_select = state.u32()?;
+        self.acked_features = state.u64()?;
+        self.queue_select = state.u16()?;
+        self.driver_status = state.u8()?;
+        self.config_generation = state.u8()?;
+        let activated = state.bool()?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(&mut state, &self.mem)?;
+        }
+        self.device.ack_features(self.acked_features);
+        self.device.restore(state.bytes()?)?;
+        if activated {
+            self.activate();
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        self.device.pause()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        if !self.activated {
+            return Ok(());
+        }
+        self.device.resume()
+    }
+}
@@ -0,0 +1,197 @@
+// virtio-pmem device, exposing a host file as a DAX capable persistent memory range.
+
+use super::*;
+use crate::config::PmemConfig;
+use crate::error::*;
+use crate::memory::MemorySlots;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr::null_mut;
+use vm_memory::{Bytes, GuestMemoryMmap};
+
+const QUEUE_SIZE: u16 = 256;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// The guest maps the range with 2M pages, so it has to be aligned on them.
+pub const PMEM_ALIGNMENT: u64 = 0x20_0000;
+
+const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
+const VIRTIO_PMEM_RESP_TYPE_OK: u32 = 0;
+const VIRTIO_PMEM_RESP_TYPE_EIO: u32 = 1;
+
+/// A virtio-pmem device backed by a file mapped into this process.
+pub struct Pmem {
+    file: File,
+    host_addr: *mut u8,
+    size: u64,
+    readonly: bool,
+    guest_addr: u64,
+    acked_features: u64,
+    event_manager: Arc<EventManager>,
+    subscription: Option<Subscription>,
+}
+
+// The mapping is only handed to KVM, it is never accessed through the pointer.
+unsafe impl Send for Pmem {}
+
+impl Pmem {
+    pub fn new(config: &PmemConfig, event_manager: Arc<EventManager>) -> Result<Pmem> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!config.readonly)
+            .open(&config.path)
+            .map_err(Error::Pmem)?;
+        let size = file.metadata().map_err(Error::Pmem)?.len();
+        if size == 0 || size % PMEM_ALIGNMENT != 0 {
+            return Err(Error::PmemUnaligned(size));
+        }
+
+        let prot = if config.readonly {
+            libc::PROT_READ
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        // Safe because we check the result and unmap the range in drop.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                size as usize,
+                prot,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::Pmem(io::Error::last_os_error()));
+        }
+
+        Ok(Pmem {
+            file,
+            host_addr: addr as *mut u8,
+            size,
+            readonly: config.readonly,
+            guest_addr: 0,
+            acked_features: 0,
+            event_manager,
+            subscription: None,
+        })
+    }
+
+    /// Size of the guest physical range the device needs.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Makes the file visible to the guest at `guest_addr`, returns the KVM
+    /// memory slot it took.
+    ///
+    /// The slot has to be removed before the device is dropped.
+    pub fn map_to_guest(&mut self, slots: &mut MemorySlots, guest_addr: u64) -> Result<u32> {
+        // Safe because the mapping stays valid as long as the device exists.
+        let slot =
+            unsafe { slots.add(guest_addr, self.size, self.host_addr as u64, self.readonly) }?;
+        self.guest_addr = guest_addr;
+        Ok(slot)
+    }
+}
+
+impl Drop for Pmem {
+    fn drop(&mut self) {
+        // Safe because host_addr and size describe the mapping created in new().
+        unsafe { libc::munmap(self.host_addr as *mut libc::c_void, self.size as usize) };
+    }
+}
+
+/// Serves flush requests: the guest's writes through the mapping reach the file with fsync.
+fn process_queue(
+    mem: &GuestMemoryMmap,
+    queue: &mut Queue,
+    file: &File,
+    interrupt: &dyn VirtioInterrupt,
+) {
+    let mut used = false;
+    while let Some(head) = queue.pop(mem) {
+        let index = head.index;
+        let mut len = 0;
+
+        // struct virtio_pmem_req { le32 type; } followed by struct virtio_pmem_resp { le32 ret; }
+        let req_type: Option<u32> = if head.is_write_only() {
+            None
+        } else {
+            mem.read_obj(head.addr).ok()
+        };
+        let resp = head
+            .next_descriptor()
+            .filter(|d| d.is_write_only() && d.len >= 4);
+
+        if let Some(resp) = resp {
+            let ret = match req_type {
+                Some(VIRTIO_PMEM_REQ_TYPE_FLUSH) if file.sync_all().is_ok() => {
+                    VIRTIO_PMEM_RESP_TYPE_OK
+                }
+                _ => VIRTIO_PMEM_RESP_TYPE_EIO,
+            };
+            if mem.write_obj(ret, resp.addr).is_ok() {
+                len = 4;
+            }
+        }
+
+        queue.add_used(mem, index, len);
+        used = true;
+    }
+
+    if used {
+        interrupt
+            .trigger(VirtioInterruptType::Queue, 0)
+            .unwrap_or(());
+    }
+}
+
+impl VirtioDevice for Pmem {
+    fn device_type(&self) -> u32 {
+        TYPE_PMEM
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features();
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_pmem_config { le64 start; le64 size; }
+        let mut config = [0u8; 16];
+        config[..8].copy_from_slice(&self.guest_addr.to_le_bytes());
+        config[8..].copy_from_slice(&self.size.to_le_bytes());
+
+        let offset = offset as usize;
+        if offset + data.len() <= config.len() {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        mut queues: Vec<Queue>,
+        mut queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        let file = self.file.try_clone().map_err(Error::Pmem)?;
+        self.subscription = Some(subscribe_queue(
+            &self.event_manager,
+            queues.remove(0),
+            queue_evts.remove(0),
+            move |queue| process_queue(&mem, queue, &file, interrupt.as_ref()),
+        )?);
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+// Split virtqueue, see "2.6 Split Virtqueues" of the virtio spec.
+
+use crate::error::*;
+use crate::memory::DeviceDirtyLog;
+use crate::snapshot::{StateReader, StateWriter};
+use std::num::Wrapping;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+/// A virtio descriptor chain, walked one descriptor at a time.
+pub struct DescriptorChain<'a> {
+    mem: &'a GuestMemoryMmap,
+    desc_table: GuestAddress,
+    queue_size: u16,
+    ttl: u16, // used to prevent infinite chain cycles
+
+    /// Index into the descriptor table.
+    pub index: u16,
+    /// Guest physical address of the buffer.
+    pub addr: GuestAddress,
+    /// Length of the buffer.
+    pub len: u32,
+    /// Flags of the descriptor, `VIRTQ_DESC_F_*`.
+    pub flags: u16,
+    /// Index of the next descriptor in the chain, valid if `has_next()`.
+    pub next: u16,
+}
+
+impl<'a> DescriptorChain<'a> {
+    fn checked_new(
+        mem: &'a GuestMemoryMmap,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        index: u16,
+    ) -> Option<Self> {
+        if index >= queue_size {
+            return None;
+        }
+
+        let desc_head = desc_table.checked_add(u64::from(index) * 16)?;
+        let addr: u64 = mem.read_obj(desc_head).ok()?;
+        let len: u32 = mem.read_obj(desc_head.unchecked_add(8)).ok()?;
+        let flags: u16 = mem.read_obj(desc_head.unchecked_add(12)).ok()?;
+        let next: u16 = mem.read_obj(desc_head.unchecked_add(14)).ok()?;
+
+        let chain = DescriptorChain {
+            mem,
+            desc_table,
+            queue_size,
+            ttl: queue_size,
+            index,
+            addr: GuestAddress(addr),
+            len,
+            flags,
+            next,
+        };
+
+        if chain.is_valid() {
+            Some(chain)
+        } else {
+            None
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        !(self.len > 0
+            && self
+                .mem
+                .checked_offset(self.addr, self.len as usize - 1)
+                .is_none()
+            || self.has_next() && self.next >= self.queue_size)
+    }
+
+    /// Whether there is a following descriptor in the chain.
+    pub fn has_next(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_NEXT != 0 && self.ttl > 1
+    }
+
+    /// Whether the device may write to this descriptor's buffer.
+    pub fn is_write_only(&self) -> bool {
+        self.flags & VIRTQ_DESC_F_WRITE != 0
+    }
+
+    /// Gets the next descriptor in this chain, if there is one.
+    pub fn next_descriptor(&self) -> Option<DescriptorChain<'a>> {
+        if self.has_next() {
+            DescriptorChain::checked_new(self.mem, self.desc_table, self.queue_size, self.next).map(
+                |mut c| {
+                    c.ttl = self.ttl - 1;
+                    c
+                },
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// A virtqueue, as configured by the driver through the transport.
+#[derive(Clone)]
+pub struct Queue {
+    /// The maximal size in elements offered by the device.
+    pub max_size: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// Whether the driver finished setting up the queue.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc_table: GuestAddress,
+    /// Guest physical address of the available ring.
+    pub avail_ring: GuestAddress,
+    /// Guest physical address of the used ring.
+    pub used_ring: GuestAddress,
+
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    dirty_log: Option<Arc<DeviceDirtyLog>>,
+}
+
+impl Queue {
+    pub fn new(max_size: u16) -> Queue {
+        Queue {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            dirty_log: None,
+        }
+    }
+
+    /// Marks the buffers the device fills and the used ring in `dirty_log`.
+    pub fn set_dirty_log(&mut self, dirty_log: Arc<DeviceDirtyLog>) {
+        self.dirty_log = Some(dirty_log);
+    }
+
+    /// The queue size actually in use, bounded by `max_size`.
+    pub fn actual_size(&self) -> u16 {
+        std::cmp::min(self.size, self.max_size)
+    }
+
+    /// Index of the next available descriptor the device will process.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Sets where processing resumes, e.g. after the queue was handed back by a backend.
+    pub fn set_next_avail(&mut self, index: u16) {
+        self.next_avail = Wrapping(index);
+        self.next_used = Wrapping(index);
+    }
+
+    pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
+        let queue_size = u64::from(self.actual_size());
+        let desc_table_size = 16 * queue_size;
+        let avail_ring_size = 6 + 2 * queue_size;
+        let used_ring_size = 6 + 8 * queue_size;
+        if !self.ready {
+            false
+        } else if self.size > self.max_size || self.size == 0 || (self.size & (self.size - 1)) != 0
+        {
+            false
+        } else if self.desc_table.0 & 0xf != 0
+            || self.avail_ring.0 & 0x1 != 0
+            || self.used_ring.0 & 0x3 != 0
+        {
+            false
+        } else {
+            mem.checked_offset(self.desc_table, desc_table_size as usize)
+                .is_some()
+                && mem
+                    .checked_offset(self.avail_ring, avail_ring_size as usize)
+                    .is_some()
+                && mem
+                    .checked_offset(self.used_ring, used_ring_size as usize)
+                    .is_some()
+        }
+    }
+
+    /// Pops the next available descriptor chain head, if any.
+    pub fn pop<'a>(&mut self, mem: &'a GuestMemoryMmap) -> Option<DescriptorChain<'a>> {
+        if !self.is_valid(mem) {
+            return None;
+        }
+
+        let queue_size = self.actual_size();
+        let avail_idx: u16 = mem.read_obj(self.avail_ring.unchecked_add(2)).ok()?;
+        if Wrapping(avail_idx) == self.next_avail {
+            return None;
+        }
+        // Make sure the ring entry is read after the index that published it.
+        fence(Ordering::Acquire);
+
+        let index_offset = 4 + 2 * u64::from(self.next_avail.0 % queue_size);
+        let desc_index: u16 = mem
+            .read_obj(self.avail_ring.unchecked_add(index_offset))
+            .ok()?;
+        self.next_avail += Wrapping(1);
+
+        DescriptorChain::checked_new(mem, self.desc_table, queue_size, desc_index)
+    }
+
+    /// Puts the descriptor chain `desc_index` on the used ring, with `len` bytes written to it.
+    pub fn add_used(&mut self, mem: &GuestMemoryMmap, desc_index: u16, len: u32) {
+        if desc_index >= self.actual_size() {
+            return;
+        }
+
+        let used_ring = self.used_ring;
+        let next_used = u64::from(self.next_used.0 % self.actual_size());
+        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+
+        if mem.write_obj(u32::from(desc_index), used_elem).is_err()
+            || mem.write_obj(len, used_elem.unchecked_add(4)).is_err()
+        {
+            return;
+        }
+
+        // The guest may reuse the descriptors once the index is updated.
+        if let Some(dirty_log) = self.dirty_log.as_ref().filter(|log| log.is_enabled()) {
+            let mut desc =
+                DescriptorChain::checked_new(mem, self.desc_table, self.actual_size(), desc_index);
+            while let Some(d) = desc {
+                if d.is_write_only() {
+                    dirty_log.mark(d.addr, d.len as usize);
+                }
+                desc = d.next_descriptor();
+            }
+            dirty_log.mark(used_elem, 8);
+            dirty_log.mark(used_ring.unchecked_add(2), 2);
+        }
+
+        self.next_used += Wrapping(1);
+
+        // The used element must be visible before the index is updated.
+        fence(Ordering::Release);
+
+        mem.write_obj(self.next_used.0, used_ring.unchecked_add(2))
+            .unwrap_or(());
+    }
+
+    /// Saves the driver configuration, the indexes are in guest memory.
+    pub fn snapshot(&self, state: &mut StateWriter) {
+        state.u16(self.size);
+        state.bool(self.ready);
+        state.u64(self.desc_table.0);
+        state.u64(self.avail_ring.0);
+        state.u64(self.used_ring.0);
+    }
+
+    /// Restores the driver configuration once guest memory is restored.
+    ///
+    /// Processing resumes after the last buffer on the used ring, so the
+    /// buffers the device had taken but not used yet are processed again.
+    pub fn restore(&mut self, state: &mut StateReader, mem: &GuestMemoryMmap) -> Result<()> {
+        self.size = state.u16()?;
+        self.ready = state.bool()?;
+        self.desc_table = GuestAddress(state.u64()?);
+        self.avail_ring = GuestAddress(state.u64()?);
+        self.used_ring = GuestAddress(state.u64()?);
+        if self.is_valid(mem) {
+            let used_idx: u16 = mem
+                .read_obj(self.used_ring.unchecked_add(2))
+                .map_err(|_| Error::InvalidSnapshot("used ring out of memory".to_string()))?;
+            self.set_next_avail(used_idx);
+        }
+        Ok(())
+    }
+
+    /// Forgets the driver configuration, as on a device reset.
+    pub fn reset(&mut self) {
+        let dirty_log = self.dirty_log.take();
+        *self = Queue::new(self.max_size);
+        self.dirty_log = dirty_log;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+
+    fn setup(size: u16) -> (GuestMemoryMmap, Queue) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(size);
+        queue.desc_table = GuestAddress(DESC_TABLE);
+        queue.avail_ring = GuestAddress(AVAIL_RING);
+        queue.used_ring = GuestAddress(USED_RING);
+        queue.ready = true;
+        (mem, queue)
+    }
+
+    fn write_desc(mem: &GuestMemoryMmap, index: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = GuestAddress(DESC_TABLE + u64::from(index) * 16);
+        mem.write_obj(addr, desc).unwrap();
+        mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+        mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+        mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+    }
+
+    // Publishes `head` in avail ring slot `slot` and sets the avail index to `idx`.
+    fn offer(mem: &GuestMemoryMmap, size: u16, slot: u16, head: u16, idx: u16) {
+        let entry = GuestAddress(AVAIL_RING + 4 + 2 * u64::from(slot % size));
+        mem.write_obj(head, entry).unwrap();
+        mem.write_obj(idx, GuestAddress(AVAIL_RING + 2)).unwrap();
+    }
+
+    fn used_idx(mem: &GuestMemoryMmap) -> u16 {
+        mem.read_obj(GuestAddress(USED_RING + 2)).unwrap()
+    }
+
+    fn used_elem(mem: &GuestMemoryMmap, slot: u64) -> (u32, u32) {
+        let elem = GuestAddress(USED_RING + 4 + slot * 8);
+        (
+            mem.read_obj(elem).unwrap(),
+            mem.read_obj(elem.unchecked_add(4)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn pop_walks_the_chain() {
+        let (mem, mut queue) = setup(4);
+        write_desc(&mem, 0, 0x4000, 0x100, VIRTQ_DESC_F_NEXT, 2);
+        write_desc(&mem, 2, 0x5000, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        offer(&mem, 4, 0, 0, 1);
+
+        let head = queue.pop(&mem).unwrap();
+        assert_eq!(head.index, 0);
+        assert_eq!(head.addr, GuestAddress(0x4000));
+        assert_eq!(head.len, 0x100);
+        assert!(!head.is_write_only());
+        let next = head.next_descriptor().unwrap();
+        assert_eq!(next.index, 2);
+        assert_eq!(next.addr, GuestAddress(0x5000));
+        assert!(next.is_write_only());
+        assert!(next.next_descriptor().is_none());
+
+        // Nothing else is available.
+        assert!(queue.pop(&mem).is_none());
+        assert_eq!(queue.next_avail(), 1);
+    }
+
+    #[test]
+    fn add_used_publishes_the_element() {
+        let (mem, mut queue) = setup(4);
+        write_desc(&mem, 3, 0x4000, 0x10, 0, 0);
+        offer(&mem, 4, 0, 3, 1);
+
+        let head = queue.pop(&mem).unwrap();
+        queue.add_used(&mem, head.index, 0x10);
+        assert_eq!(used_idx(&mem), 1);
+        assert_eq!(used_elem(&mem, 0), (3, 0x10));
+
+        // Out of range heads are ignored.
+        queue.add_used(&mem, 4, 0);
+        assert_eq!(used_idx(&mem), 1);
+    }
+
+    #[test]
+    fn pop_rejects_invalid_queue_and_descriptors() {
+        let (mem, mut queue) = setup(4);
+        write_desc(&mem, 0, 0x4000, 0x10, 0, 0);
+        offer(&mem, 4, 0, 0, 1);
+
+        queue.ready = false;
+        assert!(queue.pop(&mem).is_none());
+        queue.ready = true;
+        queue.size = 3;
+        assert!(queue.pop(&mem).is_none());
+        queue.size = 4;
+        queue.desc_table = GuestAddress(DESC_TABLE + 8);
+        assert!(queue.pop(&mem).is_none());
+        queue.desc_table = GuestAddress(DESC_TABLE);
+
+        // Head index past the end of the table.
+        offer(&mem, 4, 0, 4, 1);
+        assert!(queue.pop(&mem).is_none());
+
+        // Buffer past the end of guest memory.
+        write_desc(&mem, 1, 0xfff0, 0x20, 0, 0);
+        offer(&mem, 4, 1, 1, 2);
+        assert!(queue.pop(&mem).is_none());
+
+        // Next index past the end of the table.
+        write_desc(&mem, 2, 0x4000, 0x10, VIRTQ_DESC_F_NEXT, 4);
+        offer(&mem, 4, 2, 2, 3);
+        assert!(queue.pop(&mem).is_none());
+    }
+
+    #[test]
+    fn chain_cycles_end() {
+        let (mem, mut queue) = setup(2);
+        write_desc(&mem, 0, 0x4000, 0x10, VIRTQ_DESC_F_NEXT, 1);
+        write_desc(&mem, 1, 0x4000, 0x10, VIRTQ_DESC_F_NEXT, 0);
+        offer(&mem, 2, 0, 0, 1);
+
+        let head = queue.pop(&mem).unwrap();
+        let next = head.next_descriptor().unwrap();
+        assert!(next.next_descriptor().is_none());
+    }
+
+    #[test]
+    fn indexes_wrap_around() {
+        let (mem, mut queue) = setup(4);
+        queue.set_next_avail(u16::MAX);
+        mem.write_obj(u16::MAX, GuestAddress(USED_RING + 2))
+            .unwrap();
+        write_desc(&mem, 1, 0x4000, 0x10, 0, 0);
+        write_desc(&mem, 2, 0x5000, 0x10, 0, 0);
+        offer(&mem, 4, u16::MAX, 1, 0);
+        offer(&mem, 4, 0, 2, 1);
+
+        let head = queue.pop(&mem).unwrap();
+        assert_eq!(head.index, 1);
+        queue.add_used(&mem, head.index, 1);
+        assert_eq!(used_idx(&mem), 0);
+        assert_eq!(used_elem(&mem, 3), (1, 1));
+
+        let head = queue.pop(&mem).unwrap();
+        assert_eq!(head.index, 2);
+        queue.add_used(&mem, head.index, 2);
+        assert_eq!(used_idx(&mem), 1);
+        assert_eq!(used_elem(&mem, 0), (2, 2));
+
+        assert!(queue.pop(&mem).is_none());
+        assert_eq!(queue.next_avail(), 1);
+    }
+
+    #[test]
+    fn restore_resumes_after_the_used_ring() {
+        let (mem, mut queue) = setup(4);
+        write_desc(&mem, 0, 0x4000, 0x10, 0, 0);
+        write_desc(&mem, 1, 0x5000, 0x10, 0, 0);
+        offer(&mem, 4, 0, 0, 1);
+        offer(&mem, 4, 1, 1, 2);
+
+        // The first buffer is used, the second only taken.
+        let head = queue.pop(&mem).unwrap();
+        queue.add_used(&mem, head.index, 0);
+        queue.pop(&mem).unwrap();
+
+        let mut state = StateWriter::new();
+        queue.snapshot(&mut state);
+        let state = state.into_inner();
+
+        let mut restored = Queue::new(4);
+        restored
+            .restore(&mut StateReader::new(&state), &mem)
+            .unwrap();
+        assert!(restored.ready);
+        assert_eq!(restored.desc_table, GuestAddress(DESC_TABLE));
+        assert_eq!(restored.avail_ring, GuestAddress(AVAIL_RING));
+        assert_eq!(restored.used_ring, GuestAddress(USED_RING));
+        assert_eq!(restored.next_avail(), 1);
+        assert_eq!(restored.pop(&mem).unwrap().index, 1);
+
+        // A queue the driver did not set up has nothing to resume.
+        let mut state = StateWriter::new();
+        Queue::new(4).snapshot(&mut state);
+        let state = state.into_inner();
+        let mut restored = Queue::new(4);
+        restored
+            .restore(&mut StateReader::new(&state), &mem)
+            .unwrap();
+        assert!(!restored.ready);
+        assert_eq!(restored.next_avail(), 0);
+
+        let mut restored = Queue::new(4);
+        assert!(
+            match restored.restore(&mut StateReader::new(&state[..4]), &mem) {
+                Err(Error::InvalidSnapshot(_)) => true,
+                _ => false,
+            }
+        );
+    }
+}
@@ -0,0 +1,214 @@
+// In-kernel vhost backends, like vhost-net and vhost-vsock.
+// Interface: include/uapi/linux/vhost.h
+
+use super::Queue;
+use crate::error::*;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ior_nr, ioctl_iow_nr};
+
+const VHOST_VIRTIO: u32 = 0xaf;
+
+ioctl_ior_nr!(VHOST_GET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_iow_nr!(VHOST_SET_FEATURES, VHOST_VIRTIO, 0x00, u64);
+ioctl_io_nr!(VHOST_SET_OWNER, VHOST_VIRTIO, 0x01);
+// The size is that of the header of struct vhost_memory, the regions follow it.
+ioctl_iow_nr!(VHOST_SET_MEM_TABLE, VHOST_VIRTIO, 0x03, u64);
+ioctl_iow_nr!(VHOST_SET_VRING_NUM, VHOST_VIRTIO, 0x10, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_ADDR, VHOST_VIRTIO, 0x11, VhostVringAddr);
+ioctl_iow_nr!(VHOST_SET_VRING_BASE, VHOST_VIRTIO, 0x12, VhostVringState);
+ioctl_iow_nr!(VHOST_SET_VRING_KICK, VHOST_VIRTIO, 0x20, VhostVringFile);
+ioctl_iow_nr!(VHOST_SET_VRING_CALL, VHOST_VIRTIO, 0x21, VhostVringFile);
+ioctl_iow_nr!(VHOST_NET_SET_BACKEND, VHOST_VIRTIO, 0x30, VhostVringFile);
+ioctl_iow_nr!(VHOST_VSOCK_SET_GUEST_CID, VHOST_VIRTIO, 0x60, u64);
+ioctl_iow_nr!(VHOST_VSOCK_SET_RUNNING, VHOST_VIRTIO, 0x61, libc::c_int);
+
+#[repr(C)]
+struct VhostMemoryRegion {
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    flags_padding: u64,
+}
+
+#[repr(C)]
+struct VhostVringState {
+    index: u32,
+    num: u32,
+}
+
+#[repr(C)]
+struct VhostVringAddr {
+    index: u32,
+    flags: u32,
+    desc_user_addr: u64,
+    used_user_addr: u64,
+    avail_user_addr: u64,
+    log_guest_addr: u64,
+}
+
+#[repr(C)]
+struct VhostVringFile {
+    index: u32,
+    fd: RawFd,
+}
+
+/// A vhost device of the host kernel, processing the queues of a virtio device.
+pub struct VhostKernel {
+    file: File,
+}
+
+impl VhostKernel {
+    /// Opens `path`, like /dev/vhost-net, and takes ownership of the backend.
+    pub fn open(path: &Path) -> Result<VhostKernel> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+            .open(path)
+            .map_err(Error::VhostKernel)?;
+        let vhost = VhostKernel { file };
+        // Safe because the ioctl takes no argument.
+        let ret = unsafe { ioctl(&vhost.file, VHOST_SET_OWNER()) };
+        vhost.check(ret)?;
+        Ok(vhost)
+    }
+
+    fn check(&self, ret: i32) -> Result<()> {
+        if ret < 0 {
+            return Err(Error::VhostKernel(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// The virtio features the backend supports.
+    pub fn get_features(&self) -> Result<u64> {
+        let mut features = 0u64;
+        // Safe because the kernel only writes a u64.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, VHOST_GET_FEATURES(), &mut features) };
+        self.check(ret)?;
+        Ok(features)
+    }
+
+    pub fn set_features(&self, features: u64) -> Result<()> {
+        // Safe because the kernel only reads a u64.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_SET_FEATURES(), &features) };
+        self.check(ret)
+    }
+
+    /// Tells the backend where guest memory is mapped in this process.
+    pub fn set_mem_table(&self, mem: &GuestMemoryMmap) -> Result<()> {
+        let mut regions = Vec::new();
+        mem.with_regions_mut(|_, region| -> Result<()> {
+            regions.push(VhostMemoryRegion {
+                guest_phys_addr: region.start_addr().0,
+                memory_size: region.len() as u64,
+                userspace_addr: region.as_ptr() as u64,
+                flags_padding: 0,
+            });
+            Ok(())
+        })?;
+
+        // struct vhost_memory { u32 nregions; u32 padding; struct vhost_memory_region regions[]; }
+        let header_len = 1;
+        let mut buf = vec![0u64; header_len + regions.len() * 4];
+        buf[0] = regions.len() as u64;
+        for (i, region) in regions.iter().enumerate() {
+            let entry = &mut buf[header_len + i * 4..header_len + i * 4 + 4];
+            entry.copy_from_slice(&[
+                region.guest_phys_addr,
+                region.memory_size,
+                region.userspace_addr,
+                region.flags_padding,
+            ]);
+        }
+        // Safe because buf holds the header and as many regions as it announces.
+        let ret = unsafe { ioctl_with_ptr(&self.file, VHOST_SET_MEM_TABLE(), buf.as_ptr()) };
+        self.check(ret)
+    }
+
+    /// Hands `queues` to the backend.
+    ///
+    /// The backend waits on `kick_evts` for notifications from the driver and
+    /// signals `call_evts` when it used buffers.
+    pub fn setup_vrings(
+        &self,
+        mem: &GuestMemoryMmap,
+        queues: &[Queue],
+        kick_evts: &[EventFd],
+        call_evts: &[EventFd],
+    ) -> Result<()> {
+        self.set_mem_table(mem)?;
+
+        let host_addr = |addr: GuestAddress| {
+            mem.get_host_address(addr)
+                .map(|p| p as u64)
+                .map_err(|_| Error::VhostKernel(io::Error::from_raw_os_error(libc::EFAULT)))
+        };
+        for (index, queue) in queues.iter().enumerate() {
+            let index = index as u32;
+            let num = VhostVringState {
+                index,
+                num: u32::from(queue.actual_size()),
+            };
+            let addr = VhostVringAddr {
+                index,
+                flags: 0,
+                desc_user_addr: host_addr(queue.desc_table)?,
+                used_user_addr: host_addr(queue.used_ring)?,
+                avail_user_addr: host_addr(queue.avail_ring)?,
+                log_guest_addr: 0,
+            };
+            let base = VhostVringState {
+                index,
+                num: u32::from(queue.next_avail()),
+            };
+            let call = VhostVringFile {
+                index,
+                fd: call_evts[index as usize].as_raw_fd(),
+            };
+            let kick = VhostVringFile {
+                index,
+                fd: kick_evts[index as usize].as_raw_fd(),
+            };
+            // Safe because each ioctl only reads the structure passed to it.
+            unsafe {
+                self.check(ioctl_with_ref(&self.file, VHOST_SET_VRING_NUM(), &num))?;
+                self.check(ioctl_with_ref(&self.file, VHOST_SET_VRING_ADDR(), &addr))?;
+                self.check(ioctl_with_ref(&self.file, VHOST_SET_VRING_BASE(), &base))?;
+                self.check(ioctl_with_ref(&self.file, VHOST_SET_VRING_CALL(), &call))?;
+                self.check(ioctl_with_ref(&self.file, VHOST_SET_VRING_KICK(), &kick))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Attaches the tap device `fd` to queue `index` of vhost-net.
+    pub fn net_set_backend(&self, index: u32, fd: RawFd) -> Result<()> {
+        let backend = VhostVringFile { index, fd };
+        // Safe because the kernel only reads the structure.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_NET_SET_BACKEND(), &backend) };
+        self.check(ret)
+    }
+
+    /// Sets the context ID the guest has on the host vsock transport.
+    pub fn vsock_set_guest_cid(&self, cid: u64) -> Result<()> {
+        // Safe because the kernel only reads a u64.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_VSOCK_SET_GUEST_CID(), &cid) };
+        self.check(ret)
+    }
+
+    /// Starts or stops vhost-vsock processing the queues.
+    pub fn vsock_set_running(&self, running: bool) -> Result<()> {
+        let running = running as libc::c_int;
+        // Safe because the kernel only reads an int.
+        let ret = unsafe { ioctl_with_ref(&self.file, VHOST_VSOCK_SET_RUNNING(), &running) };
+        self.check(ret)
+    }
+}
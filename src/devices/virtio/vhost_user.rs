@@ -0,0 +1,895 @@
+// Frontend (master) side of the vhost-user protocol.
+// Spec: https://qemu.readthedocs.io/en/latest/interop/vhost-user.html
+
+use super::{call_evts, Queue, VirtioInterrupt};
+use crate::error::*;
+use crate::event_manager::{EventManager, EventOps, Subscriber, Subscription};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::timerfd::TimerFd;
+
+// Request types.
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const GET_VRING_BASE: u32 = 11;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const GET_QUEUE_NUM: u32 = 17;
+const SET_VRING_ENABLE: u32 = 18;
+const GET_CONFIG: u32 = 24;
+
+// Header flags.
+const FLAG_VERSION: u32 = 0x1;
+const FLAG_REPLY: u32 = 0x4;
+
+const HEADER_SIZE: usize = 12;
+const MAX_MEM_REGIONS: usize = 8;
+const MAX_MSG_SIZE: usize = 0x1000;
+
+/// Set in `GET_FEATURES` when the backend supports `GET_PROTOCOL_FEATURES`.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+
+// Protocol features understood by this frontend.
+const PROTOCOL_F_MQ: u32 = 0;
+const PROTOCOL_F_CONFIG: u32 = 9;
+const SUPPORTED_PROTOCOL_FEATURES: u64 = 1 << PROTOCOL_F_MQ | 1 << PROTOCOL_F_CONFIG;
+
+// How long to wait for a backend to create its socket at startup.
+const CONNECT_RETRIES: u32 = 50;
+const CONNECT_INTERVAL: Duration = Duration::from_millis(100);
+// How often to try reconnecting to a backend that went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set in the `SET_VRING_KICK`/`SET_VRING_CALL` payload when no fd is passed.
+const VRING_NOFD_MASK: u64 = 0x100;
+
+/// Connection to a vhost-user backend, over its unix socket.
+pub struct Master {
+    sock: UnixStream,
+}
+
+impl Master {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let sock = UnixStream::connect(path).map_err(Error::VhostUser)?;
+        Ok(Master { sock })
+    }
+
+    pub fn set_owner(&mut self) -> Result<()> {
+        self.send_request(SET_OWNER, &[], &[])
+    }
+
+    pub fn get_features(&mut self) -> Result<u64> {
+        self.send_request(GET_FEATURES, &[], &[])?;
+        self.recv_u64(GET_FEATURES)
+    }
+
+    pub fn set_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    pub fn get_protocol_features(&mut self) -> Result<u64> {
+        self.send_request(GET_PROTOCOL_FEATURES, &[], &[])?;
+        self.recv_u64(GET_PROTOCOL_FEATURES)
+    }
+
+    pub fn set_protocol_features(&mut self, features: u64) -> Result<()> {
+        self.send_request(SET_PROTOCOL_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    /// Shares all guest memory regions with the backend.
+    ///
+    /// Every region must be backed by a file, since the backend maps it through the passed fd.
+    pub fn set_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let mut body = Vec::new();
+        let mut fds = Vec::new();
+
+        mem.with_regions_mut(|_, region| {
+            let file_offset = region
+                .file_offset()
+                .ok_or(Error::VhostUserMemoryNotShared)?;
+            body.extend_from_slice(&region.start_addr().0.to_le_bytes());
+            body.extend_from_slice(&(region.len() as u64).to_le_bytes());
+            body.extend_from_slice(&(region.as_ptr() as u64).to_le_bytes());
+            body.extend_from_slice(&file_offset.start().to_le_bytes());
+            fds.push(file_offset.file().as_raw_fd());
+            Ok(())
+        })?;
+
+        if fds.len() > MAX_MEM_REGIONS {
+            return Err(Error::VhostUserProtocol);
+        }
+
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&(fds.len() as u32).to_le_bytes());
+        msg.extend_from_slice(&0u32.to_le_bytes()); // padding
+        msg.extend_from_slice(&body);
+        self.send_request(SET_MEM_TABLE, &msg, &fds)
+    }
+
+    pub fn set_vring_num(&mut self, index: u32, num: u32) -> Result<()> {
+        self.send_request(SET_VRING_NUM, &vring_state(index, num), &[])
+    }
+
+    /// Sets the vring addresses, as seen by this process.
+    pub fn set_vring_addr(&mut self, index: u32, desc: u64, used: u64, avail: u64) -> Result<()> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&index.to_le_bytes());
+        msg.extend_from_slice(&0u32.to_le_bytes()); // flags
+        msg.extend_from_slice(&desc.to_le_bytes());
+        msg.extend_from_slice(&used.to_le_bytes());
+        msg.extend_from_slice(&avail.to_le_bytes());
+        msg.extend_from_slice(&0u64.to_le_bytes()); // log
+        self.send_request(SET_VRING_ADDR, &msg, &[])
+    }
+
+    pub fn set_vring_base(&mut self, index: u32, base: u32) -> Result<()> {
+        self.send_request(SET_VRING_BASE, &vring_state(index, base), &[])
+    }
+
+    /// Stops the vring and returns the next available index to process.
+    pub fn get_vring_base(&mut self, index: u32) -> Result<u32> {
+        self.send_request(GET_VRING_BASE, &vring_state(index, 0), &[])?;
+        let reply = self.recv_reply(GET_VRING_BASE)?;
+        if reply.len() != 8 {
+            return Err(Error::VhostUserProtocol);
+        }
+        let mut num = [0u8; 4];
+        num.copy_from_slice(&reply[4..8]);
+        Ok(u32::from_le_bytes(num))
+    }
+
+    pub fn set_vring_kick(&mut self, index: u32, fd: Option<RawFd>) -> Result<()> {
+        self.send_vring_fd(SET_VRING_KICK, index, fd)
+    }
+
+    pub fn set_vring_call(&mut self, index: u32, fd: Option<RawFd>) -> Result<()> {
+        self.send_vring_fd(SET_VRING_CALL, index, fd)
+    }
+
+    pub fn set_vring_enable(&mut self, index: u32, enable: bool) -> Result<()> {
+        self.send_request(SET_VRING_ENABLE, &vring_state(index, enable as u32), &[])
+    }
+
+    /// Number of queues the backend supports, needs the MQ protocol feature.
+    pub fn get_queue_num(&mut self) -> Result<u64> {
+        self.send_request(GET_QUEUE_NUM, &[], &[])?;
+        self.recv_u64(GET_QUEUE_NUM)
+    }
+
+    /// Reads `size` bytes of the device configuration space, needs the CONFIG protocol feature.
+    pub fn get_config(&mut self, offset: u32, size: u32) -> Result<Vec<u8>> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&offset.to_le_bytes());
+        msg.extend_from_slice(&size.to_le_bytes());
+        msg.extend_from_slice(&0u32.to_le_bytes()); // flags
+        msg.resize(msg.len() + size as usize, 0);
+        self.send_request(GET_CONFIG, &msg, &[])?;
+
+        let reply = self.recv_reply(GET_CONFIG)?;
+        if reply.len() != msg.len() {
+            return Err(Error::VhostUserProtocol);
+        }
+        Ok(reply[12..].to_vec())
+    }
+
+    fn send_vring_fd(&mut self, req: u32, index: u32, fd: Option<RawFd>) -> Result<()> {
+        match fd {
+            Some(fd) => self.send_request(req, &u64::from(index).to_le_bytes(), &[fd]),
+            None => {
+                let v = u64::from(index) | VRING_NOFD_MASK;
+                self.send_request(req, &v.to_le_bytes(), &[])
+            }
+        }
+    }
+
+    fn send_request(&mut self, req: u32, body: &[u8], fds: &[RawFd]) -> Result<()> {
+        let mut msg = Vec::with_capacity(HEADER_SIZE + body.len());
+        msg.extend_from_slice(&req.to_le_bytes());
+        msg.extend_from_slice(&FLAG_VERSION.to_le_bytes());
+        msg.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        msg.extend_from_slice(body);
+        send_with_fds(self.sock.as_raw_fd(), &msg, fds).map_err(Error::VhostUser)
+    }
+
+    fn recv_reply(&mut self, req: u32) -> Result<Vec<u8>> {
+        let mut hdr = [0u8; HEADER_SIZE];
+        self.sock.read_exact(&mut hdr).map_err(Error::VhostUser)?;
+        let field = |i: usize| {
+            let mut v = [0u8; 4];
+            v.copy_from_slice(&hdr[i * 4..i * 4 + 4]);
+            u32::from_le_bytes(v)
+        };
+        let (reply_req, flags, size) = (field(0), field(1), field(2) as usize);
+        if reply_req != req || flags & FLAG_REPLY == 0 || size > MAX_MSG_SIZE {
+            return Err(Error::VhostUserProtocol);
+        }
+
+        let mut body = vec![0u8; size];
+        self.sock.read_exact(&mut body).map_err(Error::VhostUser)?;
+        Ok(body)
+    }
+
+    fn recv_u64(&mut self, req: u32) -> Result<u64> {
+        let reply = self.recv_reply(req)?;
+        if reply.len() != 8 {
+            return Err(Error::VhostUserProtocol);
+        }
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&reply);
+        Ok(u64::from_le_bytes(v))
+    }
+}
+
+impl AsRawFd for Master {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+// What the backend was handed on activation, to hand it again.
+struct Vrings {
+    mem: GuestMemoryMmap,
+    queues: Vec<Queue>,
+    kick_evts: Vec<EventFd>,
+    call_evts: Vec<EventFd>,
+}
+
+/// A vhost-user backend the frontend negotiated with, able to reconnect when it restarts.
+pub struct Backend {
+    socket: PathBuf,
+    master: Master,
+    // As offered by the backend, including VHOST_USER_F_PROTOCOL_FEATURES.
+    features: u64,
+    acked_features: u64,
+    protocol_features: u64,
+    vrings: Option<Vrings>,
+    // Whether the vrings were stopped by `pause`.
+    paused: bool,
+}
+
+impl Backend {
+    /// Connects to the backend listening on `socket`, waiting for it to come up.
+    pub fn connect(socket: &Path) -> Result<Backend> {
+        let mut retries = CONNECT_RETRIES;
+        let master = loop {
+            match Master::connect(socket) {
+                Ok(master) => break master,
+                Err(e) => {
+                    retries -= 1;
+                    if retries == 0 {
+                        return Err(e);
+                    }
+                    thread::sleep(CONNECT_INTERVAL);
+                }
+            }
+        };
+        Backend::negotiate(socket, master, SUPPORTED_PROTOCOL_FEATURES)
+    }
+
+    // Takes ownership of the backend on `master` and agrees on the protocol
+    // features among `protocol_features`.
+    fn negotiate(socket: &Path, mut master: Master, protocol_features: u64) -> Result<Backend> {
+        master.set_owner()?;
+        let features = master.get_features()?;
+        let mut acked_protocol_features = 0;
+        if features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) != 0 {
+            acked_protocol_features = master.get_protocol_features()? & protocol_features;
+            master.set_protocol_features(acked_protocol_features)?;
+        }
+
+        Ok(Backend {
+            socket: socket.to_path_buf(),
+            master,
+            features,
+            acked_features: 0,
+            protocol_features: acked_protocol_features,
+            vrings: None,
+            paused: false,
+        })
+    }
+
+    /// The virtio features of the device.
+    pub fn features(&self) -> u64 {
+        self.features & !(1 << VHOST_USER_F_PROTOCOL_FEATURES)
+    }
+
+    pub fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.features();
+    }
+
+    /// Number of queues the backend supports, if it tells.
+    pub fn queue_num(&mut self) -> Result<Option<u64>> {
+        if self.protocol_features & (1 << PROTOCOL_F_MQ) == 0 {
+            return Ok(None);
+        }
+        self.master.get_queue_num().map(Some)
+    }
+
+    /// Reads the device configuration space from the backend.
+    ///
+    /// Backends that do not support that have an empty configuration space.
+    pub fn read_config(&mut self, offset: u64, data: &mut [u8]) -> Result<()> {
+        if self.protocol_features & (1 << PROTOCOL_F_CONFIG) == 0 {
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+            return Ok(());
+        }
+        let config = self.master.get_config(offset as u32, data.len() as u32)?;
+        data.copy_from_slice(&config);
+        Ok(())
+    }
+
+    /// Hands the vrings to the backend, which processes `queues` from now on.
+    ///
+    /// The backend waits on `kick_evts` for notifications from the driver and
+    /// signals `call_evts` when it used buffers. They are kept open as long as
+    /// the backend may use them.
+    pub fn setup_vrings(
+        &mut self,
+        mem: GuestMemoryMmap,
+        queues: Vec<Queue>,
+        kick_evts: Vec<EventFd>,
+        call_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.vrings = Some(Vrings {
+            mem,
+            queues,
+            kick_evts,
+            call_evts,
+        });
+        self.paused = false;
+        self.share_vrings()
+    }
+
+    /// Stops the backend processing the vrings, until `resume`.
+    ///
+    /// The backend tells where it stopped in each vring, that is where it
+    /// resumes.
+    pub fn pause(&mut self) -> Result<()> {
+        let vrings = match self.vrings.as_mut() {
+            Some(vrings) if !self.paused => vrings,
+            _ => return Ok(()),
+        };
+        for (index, queue) in vrings.queues.iter_mut().enumerate() {
+            let base = self.master.get_vring_base(index as u32)?;
+            queue.set_next_avail(base as u16);
+        }
+        self.paused = true;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.paused = false;
+        self.start_vrings()
+    }
+
+    // Sets up the backend for the vrings, and starts them unless paused.
+    fn share_vrings(&mut self) -> Result<()> {
+        let mem = match &self.vrings {
+            Some(vrings) => &vrings.mem,
+            None => return Ok(()),
+        };
+        let protocol_bit = self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES);
+        self.master
+            .set_features(self.acked_features | protocol_bit)?;
+        self.master.set_mem_table(mem)?;
+        if self.paused {
+            return Ok(());
+        }
+        self.start_vrings()
+    }
+
+    // Starts each vring at the next available index of its queue.
+    fn start_vrings(&mut self) -> Result<()> {
+        let vrings = match &self.vrings {
+            Some(vrings) => vrings,
+            None => return Ok(()),
+        };
+        let host_addr = |addr: GuestAddress| {
+            vrings
+                .mem
+                .get_host_address(addr)
+                .map(|p| p as u64)
+                .map_err(|_| Error::VhostUserProtocol)
+        };
+        let protocol_bit = self.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES);
+        for (index, queue) in vrings.queues.iter().enumerate() {
+            let index = index as u32;
+            self.master
+                .set_vring_num(index, u32::from(queue.actual_size()))?;
+            self.master.set_vring_addr(
+                index,
+                host_addr(queue.desc_table)?,
+                host_addr(queue.used_ring)?,
+                host_addr(queue.avail_ring)?,
+            )?;
+            self.master
+                .set_vring_base(index, u32::from(queue.next_avail()))?;
+            self.master
+                .set_vring_call(index, Some(vrings.call_evts[index as usize].as_raw_fd()))?;
+            self.master
+                .set_vring_kick(index, Some(vrings.kick_evts[index as usize].as_raw_fd()))?;
+            if protocol_bit != 0 {
+                self.master.set_vring_enable(index, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Connects to a new instance of the backend, keeping the features the
+    // driver acked and the protocol features the device relies on, and hands
+    // it the vrings again.
+    //
+    // The old instance cannot tell where it stopped, so running vrings resume
+    // where the guest sees the used ring; requests in flight are lost.
+    fn reconnect(&mut self) -> Result<()> {
+        let master = Master::connect(&self.socket)?;
+        let backend = Backend::negotiate(&self.socket, master, self.protocol_features)?;
+        if backend.features() & self.acked_features != self.acked_features
+            || backend.protocol_features != self.protocol_features
+        {
+            return Err(Error::VhostUserProtocol);
+        }
+        self.master = backend.master;
+        self.features = backend.features;
+        self.protocol_features = backend.protocol_features;
+        if let Some(vrings) = self.vrings.as_mut() {
+            if !self.paused {
+                for queue in vrings.queues.iter_mut() {
+                    let used_idx: u16 = vrings
+                        .mem
+                        .read_obj(queue.used_ring.unchecked_add(2))
+                        .unwrap_or(0);
+                    queue.set_next_avail(used_idx);
+                }
+            }
+        }
+        self.share_vrings()
+    }
+}
+
+/// Activates a device implemented by `backend`.
+///
+/// Hands the queues to the backend, relays its interrupts to the guest on
+/// `event_manager` and reconnects when it restarts, as long as the returned
+/// subscriptions are kept.
+pub fn activate(
+    backend: &Arc<Mutex<Backend>>,
+    event_manager: &EventManager,
+    mem: GuestMemoryMmap,
+    interrupt: Arc<dyn VirtioInterrupt>,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+) -> Result<Vec<Subscription>> {
+    let (call_evts, relay) = call_evts(event_manager, interrupt, queues.len())?;
+    backend
+        .lock()
+        .unwrap()
+        .setup_vrings(mem, queues, queue_evts, call_evts)?;
+    let mut subscriptions: Vec<Subscription> = relay.into_iter().collect();
+    subscriptions.push(monitor(event_manager, backend)?);
+    Ok(subscriptions)
+}
+
+/// Reconnects to `backend` whenever it goes away, and hands it the vrings again.
+fn monitor(event_manager: &EventManager, backend: &Arc<Mutex<Backend>>) -> Result<Subscription> {
+    let timer = TimerFd::new().map_err(|e| Error::TimerFd(e.into()))?;
+    let timer_fd = timer.as_raw_fd();
+    let handler = ReconnectHandler {
+        backend: backend.clone(),
+        timer,
+    };
+    let mut subscription =
+        Subscription::new(event_manager.subscribe(Arc::new(Mutex::new(handler))));
+    subscription.add(timer_fd, libc::EPOLLIN as u32)?;
+    subscription.add(
+        backend.lock().unwrap().master.as_raw_fd(),
+        libc::EPOLLRDHUP as u32,
+    )?;
+    Ok(subscription)
+}
+
+// Reconnects when the socket of the backend hangs up, and then on a timer
+// until a new instance is there.
+struct ReconnectHandler {
+    backend: Arc<Mutex<Backend>>,
+    timer: TimerFd,
+}
+
+impl Subscriber for ReconnectHandler {
+    fn process(&mut self, fd: RawFd, _events: u32, ops: &EventOps) {
+        let mut backend = self.backend.lock().unwrap();
+        if fd == self.timer.as_raw_fd() {
+            self.timer.wait().ok();
+        } else {
+            println!("vhost-user: backend {:?} went away", backend.socket);
+            // The socket is closed once replaced.
+            ops.remove(fd).ok();
+        }
+
+        let result = backend
+            .reconnect()
+            .and_then(|()| ops.add(backend.master.as_raw_fd(), libc::EPOLLRDHUP as u32));
+        match result {
+            Ok(()) => println!("vhost-user: reconnected to {:?}", backend.socket),
+            Err(e) => {
+                println!("vhost-user: failed to reconnect: {:?}", e);
+                self.timer.reset(RECONNECT_INTERVAL, None).ok();
+            }
+        }
+    }
+}
+
+fn vring_state(index: u32, num: u32) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(8);
+    msg.extend_from_slice(&index.to_le_bytes());
+    msg.extend_from_slice(&num.to_le_bytes());
+    msg
+}
+
+/// Sends `buf` on the socket, passing `fds` as `SCM_RIGHTS` ancillary data.
+fn send_with_fds(sock: RawFd, buf: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = fds.len() * mem::size_of::<RawFd>();
+    // One cmsghdr plus the fd array, rounded up to the alignment of cmsghdr.
+    let mut cmsg_buf = vec![0u64; unsafe { libc::CMSG_SPACE(fds_len as u32) } as usize / 8 + 1];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !fds.is_empty() {
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len as u32) } as _;
+        // Safe because msg_control points to a buffer large enough for one header and the fds.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len,
+            );
+        }
+    }
+
+    // Safe because the kernel only reads from the buffers set up above.
+    let ret = unsafe { libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if ret as usize != buf.len() {
+        return Err(io::Error::from(io::ErrorKind::WriteZero));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+
+    const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+    fn read_msg(sock: &mut UnixStream) -> Option<(u32, Vec<u8>)> {
+        let mut hdr = [0u8; HEADER_SIZE];
+        sock.read_exact(&mut hdr).ok()?;
+        let field = |i: usize| {
+            u32::from_le_bytes([hdr[i * 4], hdr[i * 4 + 1], hdr[i * 4 + 2], hdr[i * 4 + 3]])
+        };
+        assert_eq!(field(1), FLAG_VERSION);
+        let mut body = vec![0u8; field(2) as usize];
+        sock.read_exact(&mut body).unwrap();
+        Some((field(0), body))
+    }
+
+    fn write_msg(sock: &mut UnixStream, req: u32, flags: u32, size: u32, body: &[u8]) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&req.to_le_bytes());
+        msg.extend_from_slice(&flags.to_le_bytes());
+        msg.extend_from_slice(&size.to_le_bytes());
+        msg.extend_from_slice(body);
+        sock.write_all(&msg).unwrap();
+    }
+
+    fn reply(sock: &mut UnixStream, req: u32, body: &[u8]) {
+        write_msg(
+            sock,
+            req,
+            FLAG_VERSION | FLAG_REPLY,
+            body.len() as u32,
+            body,
+        );
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("glue-vhost-user-{}-{}", std::process::id(), name));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    // Answers one frontend like a backend offering `features` and `protocol_features`,
+    // returns the requests it got once the frontend hangs up.
+    fn serve(socket: &Path, features: u64, protocol_features: u64) -> thread::JoinHandle<Vec<u32>> {
+        std::fs::remove_file(socket).ok();
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut requests = Vec::new();
+            while let Some((req, body)) = read_msg(&mut sock) {
+                match req {
+                    GET_FEATURES => reply(&mut sock, req, &features.to_le_bytes()),
+                    GET_PROTOCOL_FEATURES => {
+                        reply(&mut sock, req, &protocol_features.to_le_bytes())
+                    }
+                    // Every vring stopped at 5 plus its index.
+                    GET_VRING_BASE => {
+                        let index = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+                        reply(&mut sock, req, &vring_state(index, index + 5))
+                    }
+                    _ => {}
+                }
+                requests.push(req);
+            }
+            requests
+        })
+    }
+
+    // Answers the negotiation of one frontend, then goes away like a crashed backend.
+    fn serve_and_crash(socket: &Path, features: u64) -> thread::JoinHandle<()> {
+        std::fs::remove_file(socket).ok();
+        let listener = UnixListener::bind(socket).unwrap();
+        thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            assert_eq!(read_msg(&mut sock).unwrap().0, SET_OWNER);
+            assert_eq!(read_msg(&mut sock).unwrap().0, GET_FEATURES);
+            reply(&mut sock, GET_FEATURES, &features.to_le_bytes());
+        })
+    }
+
+    fn is_protocol_error<T>(result: Result<T>) -> bool {
+        match result {
+            Err(Error::VhostUserProtocol) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn request_encoding() {
+        let (sock, mut peer) = UnixStream::pair().unwrap();
+        let mut master = Master { sock };
+
+        master.set_vring_num(1, 256).unwrap();
+        assert_eq!(
+            read_msg(&mut peer),
+            Some((SET_VRING_NUM, vring_state(1, 256)))
+        );
+
+        master.set_vring_call(2, None).unwrap();
+        assert_eq!(
+            read_msg(&mut peer),
+            Some((SET_VRING_CALL, 0x102u64.to_le_bytes().to_vec()))
+        );
+
+        master.set_vring_addr(0, 0x1000, 0x3000, 0x2000).unwrap();
+        let (req, body) = read_msg(&mut peer).unwrap();
+        assert_eq!(req, SET_VRING_ADDR);
+        assert_eq!(body.len(), 40);
+        assert_eq!(&body[8..16], &0x1000u64.to_le_bytes());
+        assert_eq!(&body[16..24], &0x3000u64.to_le_bytes());
+        assert_eq!(&body[24..32], &0x2000u64.to_le_bytes());
+    }
+
+    #[test]
+    fn replies_are_checked() {
+        let (sock, mut peer) = UnixStream::pair().unwrap();
+        let mut master = Master { sock };
+
+        // A reply to another request, not flagged as a reply, too large.
+        reply(&mut peer, GET_PROTOCOL_FEATURES, &[]);
+        assert!(is_protocol_error(master.get_features()));
+        write_msg(&mut peer, GET_FEATURES, FLAG_VERSION, 0, &[]);
+        assert!(is_protocol_error(master.get_features()));
+        write_msg(
+            &mut peer,
+            GET_FEATURES,
+            FLAG_VERSION | FLAG_REPLY,
+            MAX_MSG_SIZE as u32 + 1,
+            &[],
+        );
+        assert!(is_protocol_error(master.get_features()));
+        // A body of the wrong size.
+        reply(&mut peer, GET_FEATURES, &[0; 4]);
+        assert!(is_protocol_error(master.get_features()));
+
+        reply(&mut peer, GET_FEATURES, &VIRTIO_F_VERSION_1.to_le_bytes());
+        assert_eq!(master.get_features().unwrap(), VIRTIO_F_VERSION_1);
+
+        reply(&mut peer, GET_VRING_BASE, &vring_state(1, 7));
+        assert_eq!(master.get_vring_base(1).unwrap(), 7);
+    }
+
+    #[test]
+    fn connect_negotiates_protocol_features() {
+        let socket = socket_path("connect");
+        let server = serve(
+            &socket,
+            VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES,
+            u64::max_value(),
+        );
+
+        let backend = Backend::connect(&socket).unwrap();
+        assert_eq!(backend.features(), VIRTIO_F_VERSION_1);
+        assert_eq!(backend.protocol_features, SUPPORTED_PROTOCOL_FEATURES);
+        drop(backend);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                SET_OWNER,
+                GET_FEATURES,
+                GET_PROTOCOL_FEATURES,
+                SET_PROTOCOL_FEATURES
+            ]
+        );
+        std::fs::remove_file(&socket).ok();
+    }
+
+    #[test]
+    fn connect_without_protocol_features() {
+        let socket = socket_path("no-protocol");
+        let server = serve(&socket, VIRTIO_F_VERSION_1, u64::max_value());
+
+        let mut backend = Backend::connect(&socket).unwrap();
+        assert_eq!(backend.protocol_features, 0);
+        assert_eq!(backend.queue_num().unwrap(), None);
+        let mut config = [0xffu8; 4];
+        backend.read_config(0, &mut config).unwrap();
+        assert_eq!(config, [0; 4]);
+        drop(backend);
+        assert_eq!(server.join().unwrap(), vec![SET_OWNER, GET_FEATURES]);
+        std::fs::remove_file(&socket).ok();
+    }
+
+    #[test]
+    fn reconnect_keeps_acked_features() {
+        let socket = socket_path("reconnect");
+        let server = serve(&socket, VIRTIO_F_VERSION_1 | 1, 0);
+        let mut backend = Backend::connect(&socket).unwrap();
+        backend.ack_features(VIRTIO_F_VERSION_1 | 1 << 1);
+        assert_eq!(backend.acked_features, VIRTIO_F_VERSION_1);
+
+        // The new instance lacks a feature the driver acked.
+        let lacking = serve(&socket, 1, 0);
+        assert!(is_protocol_error(backend.reconnect()));
+        lacking.join().unwrap();
+
+        let restarted = serve(&socket, VIRTIO_F_VERSION_1, 0);
+        backend.reconnect().unwrap();
+        drop(backend);
+        server.join().unwrap();
+        restarted.join().unwrap();
+        std::fs::remove_file(&socket).ok();
+    }
+
+    #[test]
+    fn reconnect_keeps_protocol_features() {
+        let socket = socket_path("reconnect-protocol");
+        let features = VIRTIO_F_VERSION_1 | 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+        let server = serve(&socket, features, 1 << PROTOCOL_F_CONFIG);
+        let mut backend = Backend::connect(&socket).unwrap();
+        assert_eq!(backend.protocol_features, 1 << PROTOCOL_F_CONFIG);
+
+        // The configuration space would suddenly read as zeroes.
+        let lacking = serve(&socket, features, 1 << PROTOCOL_F_MQ);
+        assert!(is_protocol_error(backend.reconnect()));
+        lacking.join().unwrap();
+        let lacking = serve(&socket, VIRTIO_F_VERSION_1, 0);
+        assert!(is_protocol_error(backend.reconnect()));
+        lacking.join().unwrap();
+
+        // The device does not use more than it did.
+        let restarted = serve(&socket, features, u64::max_value());
+        backend.reconnect().unwrap();
+        assert_eq!(backend.protocol_features, 1 << PROTOCOL_F_CONFIG);
+        drop(backend);
+        server.join().unwrap();
+        restarted.join().unwrap();
+        std::fs::remove_file(&socket).ok();
+    }
+
+    #[test]
+    fn pause_stops_and_restarts_the_vrings() {
+        let socket = socket_path("pause");
+        let server = serve(&socket, VIRTIO_F_VERSION_1, 0);
+        let mut backend = Backend::connect(&socket).unwrap();
+        // Nothing to stop before activation.
+        backend.pause().unwrap();
+        assert!(!backend.paused);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(16);
+        queue.desc_table = GuestAddress(0x1000);
+        queue.avail_ring = GuestAddress(0x2000);
+        queue.used_ring = GuestAddress(0x3000);
+        queue.ready = true;
+        let evts = || (0..2).map(|_| EventFd::new(0).unwrap()).collect();
+        // Skips the memory table, which needs file backed memory.
+        backend.vrings = Some(Vrings {
+            mem,
+            queues: vec![queue.clone(), queue],
+            kick_evts: evts(),
+            call_evts: evts(),
+        });
+
+        backend.pause().unwrap();
+        backend.pause().unwrap();
+        let next_avail: Vec<u16> = backend
+            .vrings
+            .as_ref()
+            .unwrap()
+            .queues
+            .iter()
+            .map(|q| q.next_avail())
+            .collect();
+        assert_eq!(next_avail, vec![5, 6]);
+        backend.resume().unwrap();
+        backend.resume().unwrap();
+        drop(backend);
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.iter().filter(|r| **r == GET_VRING_BASE).count(), 2);
+        assert_eq!(requests.iter().filter(|r| **r == SET_VRING_BASE).count(), 2);
+        std::fs::remove_file(&socket).ok();
+    }
+
+    #[test]
+    fn monitor_reconnects() {
+        let socket = socket_path("monitor");
+        let crashed = serve_and_crash(&socket, VIRTIO_F_VERSION_1);
+        let backend = Arc::new(Mutex::new(Backend::connect(&socket).unwrap()));
+        crashed.join().unwrap();
+        let restarted = serve(&socket, VIRTIO_F_VERSION_1, 0);
+
+        let event_manager = EventManager::new(1).unwrap();
+        let subscription = monitor(&event_manager, &backend).unwrap();
+        // Requests fail until the new instance answers them.
+        let mut retries = 50;
+        while backend.lock().unwrap().master.get_features().is_err() {
+            retries -= 1;
+            assert!(retries > 0);
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        drop(subscription);
+        drop(backend);
+        assert_eq!(&restarted.join().unwrap()[..2], &[SET_OWNER, GET_FEATURES]);
+        event_manager.shutdown();
+        std::fs::remove_file(&socket).ok();
+    }
+}
@@ -0,0 +1,100 @@
+// Any virtio device implemented by an external vhost-user backend, like the
+// block and net backends of other VMMs. The backend does all the work, this
+// only relays the configuration space and sets up the vrings.
+
+use super::vhost_user::{self, Backend};
+use super::*;
+use crate::config::VhostUserConfig;
+use crate::error::*;
+use std::sync::Mutex;
+use vm_memory::GuestMemoryMmap;
+
+/// A device whose queues are processed by a vhost-user backend.
+pub struct VhostUserDevice {
+    device_type: u32,
+    queue_sizes: Vec<u16>,
+    backend: Arc<Mutex<Backend>>,
+    event_manager: Arc<EventManager>,
+    subscriptions: Vec<Subscription>,
+}
+
+impl VhostUserDevice {
+    pub fn new(
+        config: &VhostUserConfig,
+        event_manager: Arc<EventManager>,
+    ) -> Result<VhostUserDevice> {
+        let mut backend = Backend::connect(&config.socket)?;
+
+        let num_queues = match config.num_queues {
+            Some(num_queues) => num_queues,
+            None => backend.queue_num()?.unwrap_or(1) as usize,
+        };
+
+        Ok(VhostUserDevice {
+            device_type: config.device_type,
+            queue_sizes: vec![config.queue_size; num_queues],
+            backend: Arc::new(Mutex::new(backend)),
+            event_manager,
+            subscriptions: Vec::new(),
+        })
+    }
+}
+
+impl VirtioDevice for VhostUserDevice {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+
+    fn features(&self) -> u64 {
+        self.backend.lock().unwrap().features()
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.backend.lock().unwrap().ack_features(value)
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        if let Err(e) = self.backend.lock().unwrap().read_config(offset, data) {
+            println!(
+                "vhost-user: failed to read config at {:#x}: {:?}",
+                offset, e
+            );
+            for b in data.iter_mut() {
+                *b = 0;
+            }
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.subscriptions = vhost_user::activate(
+            &self.backend,
+            &self.event_manager,
+            mem,
+            interrupt,
+            queues,
+            queue_evts,
+        )?;
+        Ok(())
+    }
+
+    // Stopping the vrings keeps the backend off guest memory while a snapshot
+    // is taken. A restored device resumes after the used ring like the others,
+    // whatever else the backend keeps, like a disk image, is up to the user.
+    fn pause(&mut self) -> Result<()> {
+        self.backend.lock().unwrap().pause()
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.backend.lock().unwrap().resume()
+    }
+}
@@ -0,0 +1,142 @@
+// virtio-vsock device, with the host side provided by vhost-vsock.
+//
+// Host tools reach the guest with plain AF_VSOCK sockets on its context ID.
+
+use super::vhost_kernel::VhostKernel;
+use super::*;
+use crate::config::VsockConfig;
+use crate::error::*;
+use std::path::Path;
+use vm_memory::{Bytes, GuestMemoryMmap};
+
+const QUEUE_SIZE: u16 = 256;
+// Receive, transmit and event queues. vhost-vsock only handles the first two,
+// the event queue is only used to tell the guest about a migration.
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+const NUM_VHOST_QUEUES: usize = 2;
+const EVENT_INDEX: usize = 2;
+
+// struct virtio_vsock_event { le32 id; }, the guest drops its connections on it.
+const VIRTIO_VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
+// Ring features vhost may offer the guest; others, like VIRTIO_F_ACCESS_PLATFORM,
+// need support from glue.
+const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+const VIRTIO_RING_F_EVENT_IDX: u32 = 29;
+const VHOST_PASSTHROUGH_FEATURES: u64 =
+    1 << VIRTIO_RING_F_INDIRECT_DESC | 1 << VIRTIO_RING_F_EVENT_IDX;
+
+/// A vsock device, the guest has context ID `cid` on the host.
+pub struct Vsock {
+    cid: u64,
+    vhost: VhostKernel,
+    avail_features: u64,
+    acked_features: u64,
+    event_manager: Arc<EventManager>,
+    relay: Option<Subscription>,
+    // Restored from a snapshot: the connections went away with the vhost
+    // device of the old process, the guest has to hear about it.
+    transport_reset: bool,
+}
+
+impl Vsock {
+    pub fn new(config: &VsockConfig, event_manager: Arc<EventManager>) -> Result<Vsock> {
+        let vhost = VhostKernel::open(Path::new("/dev/vhost-vsock"))?;
+        // Fails if another VM already uses the context ID.
+        vhost.vsock_set_guest_cid(config.cid)?;
+        // The transport adds VERSION_1.
+        let avail_features = vhost.get_features()? & VHOST_PASSTHROUGH_FEATURES;
+
+        Ok(Vsock {
+            cid: config.cid,
+            vhost,
+            avail_features,
+            acked_features: 0,
+            event_manager,
+            relay: None,
+            transport_reset: false,
+        })
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn device_type(&self) -> u32 {
+        TYPE_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.acked_features |= value & self.avail_features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        // struct virtio_vsock_config { le64 guest_cid; }
+        let config = self.cid.to_le_bytes();
+        let offset = offset as usize;
+        if offset + data.len() <= config.len() {
+            data.copy_from_slice(&config[offset..offset + data.len()]);
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryMmap,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> Result<()> {
+        self.vhost
+            .set_features(self.acked_features | 1 << VIRTIO_F_VERSION_1)?;
+
+        let (call_evts, relay) =
+            call_evts(&self.event_manager, interrupt.clone(), NUM_VHOST_QUEUES)?;
+        self.relay = relay;
+        self.vhost.setup_vrings(
+            &mem,
+            &queues[..NUM_VHOST_QUEUES],
+            &queue_evts[..NUM_VHOST_QUEUES],
+            &call_evts,
+        )?;
+        self.vhost.vsock_set_running(true)?;
+
+        if self.transport_reset {
+            self.transport_reset = false;
+            let mut queue = queues[EVENT_INDEX].clone();
+            if let Some(desc) = queue.pop(&mem) {
+                let index = desc.index;
+                if desc.is_write_only()
+                    && desc.len >= 4
+                    && mem
+                        .write_obj(VIRTIO_VSOCK_EVENT_TRANSPORT_RESET, desc.addr)
+                        .is_ok()
+                {
+                    queue.add_used(&mem, index, 4);
+                    interrupt
+                        .trigger(VirtioInterruptType::Queue, EVENT_INDEX as u16)
+                        .unwrap_or(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, _state: &[u8]) -> Result<()> {
+        self.transport_reset = true;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.vhost.vsock_set_running(false)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.vhost.vsock_set_running(true)
+    }
+}
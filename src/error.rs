@@ -1,5 +1,135 @@
+use std::io;
+
 #[derive(Debug)]
 pub enum Error {
     // Generic,
+    /// Invalid value in a device option string, like `--fs tag=...,path=...`.
+    InvalidOption(String),
+    /// The address range overlaps with a device already on the bus.
+    BusOverlap,
+    /// Failed to create an eventfd.
+    EventFd(io::Error),
+    /// Failed to create a timerfd.
+    TimerFd(io::Error),
+    /// Failed to create the interrupt controller.
+    CreateGic(kvm_ioctls::Error),
+    /// Failed to create the GICv3 interrupt translation service.
+    CreateIts(kvm_ioctls::Error),
+    /// Failed to save or restore the ITS tables in guest memory.
+    ItsTables(kvm_ioctls::Error),
+    /// Failed to register an irqfd.
+    RegisterIrqfd(kvm_ioctls::Error),
+    /// No more interrupt lines for devices.
+    IrqsExhausted,
+    /// No more MMIO space for devices.
+    MmioExhausted,
+    /// Failed to spawn a device or vcpu thread.
+    SpawnThread(io::Error),
+    /// Failed to start an external backend process.
+    SpawnBackend(io::Error),
+    /// Error talking to a vhost-user backend.
+    VhostUser(io::Error),
+    /// The vhost-user backend sent an unexpected reply.
+    VhostUserProtocol,
+    /// Guest memory is not backed by a file and cannot be shared with the backend.
+    VhostUserMemoryNotShared,
+    /// The device was already activated by the driver.
+    DeviceActivated,
+    /// Failed to open the directory exported over virtio-9p.
+    P9(io::Error),
+    /// Failed to open or map the file backing a virtio-pmem device.
+    Pmem(io::Error),
+    /// The virtio-pmem backing file size is not a multiple of 2 MB.
+    PmemUnaligned(u64),
+    /// No more guest physical space for device memory.
+    DeviceMemoryExhausted,
+    /// Failed to register a KVM memory slot.
+    SetUserMemoryRegion(kvm_ioctls::Error),
+    /// The device tree does not fit in guest memory.
+    FdtTooLarge,
+    /// Error on the control socket of a VM.
+    Control(io::Error),
+    /// No running VM with this name.
+    VmNotFound(String),
+    /// A VM with this name is already running.
+    VmExists(String),
+    /// The VM rejected a control command.
+    Command(String),
+    /// Failed to map the kvm_run structure of a vcpu.
+    MapKvmRun(io::Error),
+    /// Cannot install the signal handler used to kick vcpus out of KVM_RUN.
+    RegisterSignalHandler(vmm_sys_util::errno::Error),
+    /// Cannot write the guest memory dump.
+    Dump(io::Error),
+    /// Failed to read or write a vcpu register.
+    VcpuRegister(kvm_ioctls::Error),
+    /// Saved vcpu registers are truncated or a value has the wrong size.
+    InvalidVcpuState,
+    /// Failed to get or set the power state of a vcpu.
+    VcpuMpState(kvm_ioctls::Error),
+    /// Failed to read or write the registers of the GIC or its ITS.
+    GicState(kvm_ioctls::Error),
+    /// Failed to read or write a snapshot.
+    Snapshot(io::Error),
+    /// The snapshot is truncated or does not match the VM it is restored into.
+    InvalidSnapshot(String),
+    /// The snapshot was written by a glue with another state layout version.
+    SnapshotVersion(u32),
+    /// This host cannot resume the snapshot: another CPU model or missing KVM features.
+    SnapshotIncompatible(String),
+    /// The VM has state snapshots cannot capture.
+    SnapshotUnsupported(String),
+    /// Cannot start the VM again after a reset.
+    Restart(io::Error),
+    /// The BAR has an invalid size, alignment or index.
+    PciBarInvalid(usize),
+    /// No room left for capabilities in the PCI configuration space.
+    PciCapabilitySpace,
+    /// No free slot on the PCI root bus.
+    PciSlotsExhausted,
+    /// No more room in the PCI MMIO windows for BARs.
+    PciMemoryExhausted,
+    /// The guest has no way to find virtio-mmio devices added after boot.
+    HotplugUnsupported,
+    /// All the hotplug slots are taken.
+    HotplugSlotsExhausted,
+    /// No hotplugged device with this id.
+    DeviceNotFound(String),
+    /// The guest did not release the device in time. Once it does, the device is
+    /// removed by the next hotplug, unplug or snapshot command.
+    UnplugTimeout(String),
+    /// No more KVM memory slots.
+    MemSlotsExhausted,
+    /// Failed to read the dirty page log of a KVM memory slot.
+    GetDirtyLog(kvm_ioctls::Error),
+    /// The size asked from virtio-mem is not a multiple of its block size or
+    /// larger than the hotplug region.
+    InvalidMemorySize(u64),
+    /// The VM was started without a hotplug region.
+    NoMemoryHotplug,
+    /// Failed to update the GSI routing table.
+    SetGsiRouting(kvm_ioctls::Error),
+    /// Failed to create the file backing guest memory.
+    Memfd(io::Error),
+    /// Failed to open or grow the file backing guest memory.
+    MemoryFile(io::Error),
+    /// Failed to map guest memory.
+    MmapRam(vm_memory::mmap::MmapRegionError),
+    /// Guest memory is not a multiple of the hugepage size.
+    HugePagesUnaligned(u64),
+    /// Not enough free hugepages on the host: how many are needed, how many are free.
+    NotEnoughHugePages(u64, u64),
+    /// Failed to lock guest memory, RLIMIT_MEMLOCK may be too low.
+    LockMemory(io::Error),
+    /// Failed to bind guest memory to a host NUMA node.
+    BindMemory(io::Error),
+    /// Failed to find the CPUs of a host NUMA node.
+    HostNumaNode(u32),
+    /// Failed to open or set up a tap interface.
+    Tap(io::Error),
+    /// Error setting up an in-kernel vhost backend.
+    VhostKernel(io::Error),
+    /// Failed to add or remove an fd of the device event loop.
+    EventManager(io::Error),
 }
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,434 @@
+// Shared event loop for device work.
+//
+// A few worker threads wait on the eventfds, taps and sockets of all devices
+// and call back into the device owning each fd, instead of every device
+// running a thread of its own.
+
+use crate::error::*;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Events a worker handles per `epoll_wait`.
+const MAX_EVENTS: usize = 32;
+// Tokens of the eventfds stopping and pausing a worker, fds are never negative.
+const STOP_TOKEN: u64 = u64::MAX;
+const PAUSE_TOKEN: u64 = u64::MAX - 1;
+
+/// Something waiting on fds, like the queues of a device.
+pub trait Subscriber: Send {
+    /// Handles `events`, a set of `EPOLL*` flags, on `fd`.
+    ///
+    /// Runs on a worker thread shared with other devices, so it should not block.
+    fn process(&mut self, fd: RawFd, events: u32, ops: &EventOps);
+}
+
+/// Adds and removes the fds a subscriber waits on, at any time.
+#[derive(Clone)]
+pub struct EventOps {
+    worker: Arc<Worker>,
+    subscriber: Arc<Mutex<dyn Subscriber>>,
+}
+
+impl EventOps {
+    /// Waits for `events` on `fd`.
+    ///
+    /// The subscriber keeps owning `fd` and has to remove it before closing it.
+    /// Fails if `fd` is already waited on, by this subscriber or another.
+    pub fn add(&self, fd: RawFd, events: u32) -> Result<()> {
+        // Held until `fd` is in the map, so that its events find the subscriber.
+        let mut subscribers = self.worker.subscribers.lock().unwrap();
+        epoll_ctl(self.worker.epoll_fd, libc::EPOLL_CTL_ADD, fd, events)
+            .map_err(Error::EventManager)?;
+        subscribers.insert(fd, self.subscriber.clone());
+        Ok(())
+    }
+
+    /// Stops waiting on `fd`.
+    pub fn remove(&self, fd: RawFd) -> Result<()> {
+        self.worker.subscribers.lock().unwrap().remove(&fd);
+        epoll_ctl(self.worker.epoll_fd, libc::EPOLL_CTL_DEL, fd, 0).map_err(Error::EventManager)
+    }
+
+    // Stops waiting on all fds of the subscriber.
+    fn remove_all(&self) {
+        // Only the data pointers tell whether two subscribers are the same.
+        let subscriber = Arc::as_ptr(&self.subscriber) as *const u8;
+        let fds: Vec<RawFd> = self
+            .worker
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, s)| Arc::as_ptr(s) as *const u8 == subscriber)
+            .map(|(fd, _)| *fd)
+            .collect();
+        for fd in fds {
+            self.remove(fd).ok();
+        }
+    }
+}
+
+/// The fds a device waits on, removed from the event loop when dropped.
+///
+/// Held by the device, so that the subscriber goes away with it. That
+/// includes the fds the subscriber added from its callbacks.
+pub struct Subscription {
+    ops: EventOps,
+}
+
+impl Subscription {
+    pub fn new(ops: EventOps) -> Self {
+        Subscription { ops }
+    }
+
+    /// Waits for `events` on `fd`, see `EventOps::add`.
+    pub fn add(&mut self, fd: RawFd, events: u32) -> Result<()> {
+        self.ops.add(fd, events)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.ops.remove_all();
+    }
+}
+
+// Whether a worker is asked to pause, and whether it did.
+#[derive(Default)]
+struct PauseState {
+    pause: bool,
+    parked: bool,
+}
+
+struct Worker {
+    epoll_fd: RawFd,
+    stop_evt: EventFd,
+    pause_evt: EventFd,
+    pause_state: Mutex<PauseState>,
+    pause_cond: Condvar,
+    subscribers: Mutex<HashMap<RawFd, Arc<Mutex<dyn Subscriber>>>>,
+}
+
+impl Worker {
+    fn new() -> Result<Worker> {
+        // Safe because the call has no pointer arguments.
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll_fd < 0 {
+            return Err(Error::EventManager(io::Error::last_os_error()));
+        }
+        let worker = Worker {
+            epoll_fd,
+            stop_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            pause_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+            pause_state: Mutex::new(PauseState::default()),
+            pause_cond: Condvar::new(),
+            subscribers: Mutex::new(HashMap::new()),
+        };
+        for (evt, token) in [
+            (&worker.stop_evt, STOP_TOKEN),
+            (&worker.pause_evt, PAUSE_TOKEN),
+        ]
+        .iter()
+        {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: *token,
+            };
+            // Safe because both fds are valid and event outlives the call.
+            let ret = unsafe {
+                libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, evt.as_raw_fd(), &mut event)
+            };
+            if ret < 0 {
+                return Err(Error::EventManager(io::Error::last_os_error()));
+            }
+        }
+        Ok(worker)
+    }
+
+    fn run(self: Arc<Self>) {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        loop {
+            // Safe because events is large enough for the requested number of entries.
+            let n = unsafe {
+                libc::epoll_wait(self.epoll_fd, events.as_mut_ptr(), events.len() as i32, -1)
+            };
+            for event in events.iter().take(n.max(0) as usize) {
+                if event.u64 == STOP_TOKEN {
+                    return;
+                }
+                if event.u64 == PAUSE_TOKEN {
+                    self.park();
+                    continue;
+                }
+                let fd = event.u64 as RawFd;
+                // The fd may have been removed by a callback handling an earlier event.
+                let subscriber = match self.subscribers.lock().unwrap().get(&fd) {
+                    Some(subscriber) => subscriber.clone(),
+                    None => continue,
+                };
+                let ops = EventOps {
+                    worker: self.clone(),
+                    subscriber: subscriber.clone(),
+                };
+                subscriber.lock().unwrap().process(fd, event.events, &ops);
+            }
+        }
+    }
+
+    // Waits for the workers to be resumed, no callback runs meanwhile.
+    fn park(&self) {
+        self.pause_evt.read().ok();
+        let mut state = self.pause_state.lock().unwrap();
+        state.parked = true;
+        self.pause_cond.notify_all();
+        while state.pause {
+            state = self.pause_cond.wait(state).unwrap();
+        }
+        state.parked = false;
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Safe because the worker owns epoll_fd.
+        unsafe { libc::close(self.epoll_fd) };
+    }
+}
+
+fn epoll_ctl(epoll_fd: RawFd, op: i32, fd: RawFd, events: u32) -> io::Result<()> {
+    let mut event = libc::epoll_event {
+        events,
+        u64: fd as u64,
+    };
+    // Safe because event outlives the call, the kernel checks the fds.
+    let ret = unsafe { libc::epoll_ctl(epoll_fd, op, fd, &mut event) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Worker threads running the subscribers of all devices of a VM.
+pub struct EventManager {
+    workers: Vec<Arc<Worker>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    next_worker: AtomicUsize,
+}
+
+impl EventManager {
+    /// Starts `num_workers` worker threads.
+    pub fn new(num_workers: usize) -> Result<EventManager> {
+        let mut workers = Vec::new();
+        let mut handles = Vec::new();
+        for i in 0..num_workers.max(1) {
+            let worker = Arc::new(Worker::new()?);
+            let thread_worker = worker.clone();
+            let handle = thread::Builder::new()
+                .name(format!("event_worker_{}", i))
+                .spawn(move || thread_worker.run())
+                .map_err(Error::SpawnThread)?;
+            workers.push(worker);
+            handles.push(handle);
+        }
+        Ok(EventManager {
+            workers,
+            handles: Mutex::new(handles),
+            next_worker: AtomicUsize::new(0),
+        })
+    }
+
+    /// Hands `subscriber` to one of the workers, which calls it once it added
+    /// fds with the returned ops.
+    ///
+    /// All fds of a subscriber are handled by the same worker, so its callbacks
+    /// never run concurrently.
+    pub fn subscribe(&self, subscriber: Arc<Mutex<dyn Subscriber>>) -> EventOps {
+        let index = self.next_worker.fetch_add(1, Ordering::SeqCst) % self.workers.len();
+        EventOps {
+            worker: self.workers[index].clone(),
+            subscriber,
+        }
+    }
+
+    /// Parks the workers, returns once they are done with the callbacks in
+    /// progress. Events are handled again after `resume`.
+    pub fn pause(&self) {
+        for worker in self.workers.iter() {
+            worker.pause_state.lock().unwrap().pause = true;
+            worker.pause_evt.write(1).ok();
+        }
+        for worker in self.workers.iter() {
+            let mut state = worker.pause_state.lock().unwrap();
+            while !state.parked {
+                state = worker.pause_cond.wait(state).unwrap();
+            }
+        }
+    }
+
+    pub fn resume(&self) {
+        for worker in self.workers.iter() {
+            worker.pause_state.lock().unwrap().pause = false;
+            worker.pause_cond.notify_all();
+        }
+    }
+
+    /// Stops the workers once they are done with the callbacks in progress.
+    pub fn shutdown(&self) {
+        for worker in self.workers.iter() {
+            worker.stop_evt.write(1).ok();
+        }
+        for handle in self.handles.lock().unwrap().drain(..) {
+            handle.join().ok();
+        }
+        // Drops the subscribers, and with them the devices' fds.
+        for worker in self.workers.iter() {
+            worker.subscribers.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::time::Duration;
+
+    // Reports each event on its eventfd, and stops waiting after `limit` of them.
+    struct TestSubscriber {
+        evt: EventFd,
+        events: Sender<RawFd>,
+        limit: usize,
+    }
+
+    impl Subscriber for TestSubscriber {
+        fn process(&mut self, fd: RawFd, _events: u32, ops: &EventOps) {
+            self.evt.read().unwrap();
+            self.events.send(fd).unwrap();
+            self.limit -= 1;
+            if self.limit == 0 {
+                ops.remove(fd).unwrap();
+            }
+        }
+    }
+
+    fn subscribe(
+        event_manager: &EventManager,
+        limit: usize,
+    ) -> (EventFd, EventOps, Receiver<RawFd>) {
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let (tx, rx) = channel();
+        let subscriber = Arc::new(Mutex::new(TestSubscriber {
+            evt: evt.try_clone().unwrap(),
+            events: tx,
+            limit,
+        }));
+        let ops = event_manager.subscribe(subscriber);
+        (evt, ops, rx)
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let event_manager = EventManager::new(2).unwrap();
+        let (evt, ops, rx) = subscribe(&event_manager, usize::MAX);
+        ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+
+        evt.write(1).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            evt.as_raw_fd()
+        );
+
+        ops.remove(evt.as_raw_fd()).unwrap();
+        evt.write(1).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert!(ops.remove(evt.as_raw_fd()).is_err());
+        event_manager.shutdown();
+    }
+
+    #[test]
+    fn remove_from_callback() {
+        let event_manager = EventManager::new(1).unwrap();
+        let (evt, ops, rx) = subscribe(&event_manager, 1);
+        ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+
+        evt.write(1).unwrap();
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        evt.write(1).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        event_manager.shutdown();
+    }
+
+    #[test]
+    fn add_invalid_fd() {
+        let event_manager = EventManager::new(1).unwrap();
+        let (_evt, ops, _rx) = subscribe(&event_manager, 1);
+        assert!(match ops.add(-1, libc::EPOLLIN as u32) {
+            Err(Error::EventManager(_)) => true,
+            _ => false,
+        });
+        assert!(ops.worker.subscribers.lock().unwrap().is_empty());
+        event_manager.shutdown();
+    }
+
+    #[test]
+    fn pause_holds_events() {
+        let event_manager = EventManager::new(2).unwrap();
+        let (evt, ops, rx) = subscribe(&event_manager, usize::MAX);
+        ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+
+        event_manager.pause();
+        evt.write(1).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        event_manager.resume();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            evt.as_raw_fd()
+        );
+        event_manager.shutdown();
+    }
+
+    #[test]
+    fn add_twice() {
+        let event_manager = EventManager::new(1).unwrap();
+        let (evt, ops, rx) = subscribe(&event_manager, usize::MAX);
+        let (_other_evt, other_ops, other_rx) = subscribe(&event_manager, usize::MAX);
+        ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+        assert!(ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).is_err());
+        assert!(other_ops
+            .add(evt.as_raw_fd(), libc::EPOLLIN as u32)
+            .is_err());
+
+        // The fd still goes to the subscriber that added it first.
+        evt.write(1).unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            evt.as_raw_fd()
+        );
+        assert!(other_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        event_manager.shutdown();
+    }
+
+    #[test]
+    fn shutdown_drops_subscribers() {
+        let event_manager = EventManager::new(3).unwrap();
+        let mut subscribers = Vec::new();
+        for _ in 0..3 {
+            let (evt, ops, rx) = subscribe(&event_manager, usize::MAX);
+            ops.add(evt.as_raw_fd(), libc::EPOLLIN as u32).unwrap();
+            subscribers.push((evt, rx));
+        }
+
+        event_manager.shutdown();
+        for (evt, rx) in subscribers.iter() {
+            evt.write(1).unwrap();
+            // The sender went away with the subscriber.
+            assert!(rx.recv_timeout(Duration::from_secs(5)).is_err());
+        }
+    }
+}
@@ -0,0 +1,496 @@
+// Flattened device tree describing the VM to the guest kernel.
+// Format: https://github.com/devicetree-org/devicetree-specification
+
+use crate::config::NumaConfig;
+use crate::device_manager::DeviceManager;
+use crate::error::*;
+use crate::irqchip::{Gic, GicVersion};
+use crate::memory::VmLayout;
+use std::collections::HashMap;
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// The GIC is the only interrupt controller, everything refers to it.
+pub const GIC_PHANDLE: u32 = 1;
+/// The clock of the PrimeCell peripherals.
+pub const APB_PCLK_PHANDLE: u32 = 2;
+pub const GPIO_PHANDLE: u32 = 3;
+/// The GICv3 ITS, MSI parent of the PCI host bridge.
+pub const ITS_PHANDLE: u32 = 4;
+
+pub const APB_PCLK_FREQUENCY: u32 = 24_000_000;
+
+// Interrupt specifier cells, see the arm,gic bindings.
+pub const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+pub const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+pub const IRQ_TYPE_EDGE_RISING: u32 = 1;
+pub const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+// NUMA distances when the configuration gives none, as Linux assumes.
+const LOCAL_DISTANCE: u32 = 10;
+const REMOTE_DISTANCE: u32 = 20;
+
+/// Builds a flattened device tree blob, node by node.
+pub struct FdtWriter {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter {
+            data: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn append_u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.data.len() % 4 != 0 {
+            self.data.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, s: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(s) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(s.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(s.to_string(), offset);
+        offset
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.append_u32(FDT_BEGIN_NODE);
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.append_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.append_u32(FDT_PROP);
+        self.append_u32(value.len() as u32);
+        self.append_u32(name_offset);
+        self.data.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string_list(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    pub fn property_array_u32(&mut self, name: &str, values: &[u32]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| v.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_array_u64(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| v.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    /// Finishes the tree and returns the blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced FDT nodes");
+        self.append_u32(FDT_END);
+
+        // An empty memory reservation block follows the header.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.data.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        for v in &[
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.data.len() as u32,
+        ] {
+            blob.extend_from_slice(&v.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; 16]);
+        blob.extend_from_slice(&self.data);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Interrupt specifier of a shared peripheral interrupt allocated by the device manager.
+pub fn spi(irq: u32, flags: u32) -> [u32; 3] {
+    [GIC_FDT_IRQ_TYPE_SPI, irq - VmLayout::IRQ_BASE, flags]
+}
+
+/// Creates the device tree for the VM.
+pub fn create_fdt(
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &str,
+    vcpu_mpidrs: &[u64],
+    numa: &[NumaConfig],
+    gic: &Gic,
+    devices: &DeviceManager,
+) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("");
+    fdt.property_string("compatible", "linux,dummy-virt");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE);
+
+    create_cpu_nodes(&mut fdt, vcpu_mpidrs, numa);
+    create_memory_nodes(&mut fdt, guest_mem, numa);
+    create_distance_map_node(&mut fdt, numa);
+    create_chosen_node(&mut fdt, cmdline);
+    create_gic_node(&mut fdt, gic);
+    create_timer_node(&mut fdt);
+    create_psci_node(&mut fdt);
+    create_clock_node(&mut fdt);
+    let msi_parent = if gic.has_its() {
+        Some(ITS_PHANDLE)
+    } else {
+        None
+    };
+    devices.create_fdt_nodes(&mut fdt, msi_parent);
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Writes the device tree where the boot vcpu expects it.
+pub fn load_fdt(guest_mem: &GuestMemoryMmap, fdt: &[u8]) -> Result<()> {
+    if fdt.len() > VmLayout::FDT_MAX_SIZE {
+        return Err(Error::FdtTooLarge);
+    }
+    guest_mem
+        .write_slice(fdt, GuestAddress(VmLayout::get_fdt_addr(guest_mem)))
+        .map_err(|_| Error::FdtTooLarge)
+}
+
+fn create_cpu_nodes(fdt: &mut FdtWriter, vcpu_mpidrs: &[u64], numa: &[NumaConfig]) {
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    for (index, mpidr) in vcpu_mpidrs.iter().enumerate() {
+        fdt.begin_node(&format!("cpu@{:x}", index));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_string("compatible", "arm,arm-v8");
+        if vcpu_mpidrs.len() > 1 {
+            fdt.property_string("enable-method", "psci");
+        }
+        // Affinity bits of MPIDR_EL1.
+        fdt.property_u32("reg", (mpidr & 0x7f_ffff) as u32);
+        if let Some(node) = numa.iter().position(|n| n.cpus.contains(&(index as u8))) {
+            fdt.property_u32("numa-node-id", node as u32);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+fn create_memory_nodes(fdt: &mut FdtWriter, guest_mem: &GuestMemoryMmap, numa: &[NumaConfig]) {
+    let mut reg = Vec::new();
+    guest_mem
+        .with_regions_mut(|_, region| -> std::result::Result<(), ()> {
+            // The guest learns about the hotplug region from virtio-mem.
+            if region.start_addr().0 < VmLayout::MEM_HOTPLUG_START {
+                reg.push(region.start_addr().0);
+                reg.push(region.len() as u64);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    if numa.is_empty() {
+        fdt.begin_node("memory");
+        fdt.property_string("device_type", "memory");
+        fdt.property_array_u64("reg", &reg);
+        fdt.end_node();
+        return;
+    }
+    // Each node has a DRAM region of its own, in order.
+    for (node, range) in reg.chunks(2).enumerate() {
+        fdt.begin_node(&format!("memory@{:x}", range[0]));
+        fdt.property_string("device_type", "memory");
+        fdt.property_array_u64("reg", range);
+        fdt.property_u32("numa-node-id", node as u32);
+        fdt.end_node();
+    }
+}
+
+// See the numa-distance-map-v1 binding.
+fn create_distance_map_node(fdt: &mut FdtWriter, numa: &[NumaConfig]) {
+    if numa.is_empty() {
+        return;
+    }
+    let mut matrix = Vec::new();
+    for (from, node) in numa.iter().enumerate() {
+        for to in 0..numa.len() {
+            let distance = match node.distances.get(to) {
+                Some(distance) => u32::from(*distance),
+                None if from == to => LOCAL_DISTANCE,
+                None => REMOTE_DISTANCE,
+            };
+            matrix.extend_from_slice(&[from as u32, to as u32, distance]);
+        }
+    }
+
+    fdt.begin_node("distance-map");
+    fdt.property_string("compatible", "numa-distance-map-v1");
+    fdt.property_array_u32("distance-matrix", &matrix);
+    fdt.end_node();
+}
+
+fn create_chosen_node(fdt: &mut FdtWriter, cmdline: &str) {
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", cmdline);
+    fdt.end_node();
+}
+
+fn create_gic_node(fdt: &mut FdtWriter, gic: &Gic) {
+    let (compatible, reg) = match gic.version() {
+        GicVersion::V2 => (
+            "arm,cortex-a15-gic",
+            [
+                VmLayout::GIC_DIST_BASE,
+                VmLayout::GIC_DIST_SIZE,
+                VmLayout::GIC_CPU_BASE,
+                VmLayout::GIC_CPU_SIZE,
+            ],
+        ),
+        GicVersion::V3 => (
+            "arm,gic-v3",
+            [
+                VmLayout::GIC_DIST_BASE,
+                VmLayout::GIC_DIST_SIZE,
+                VmLayout::get_gic_redist_base(gic.vcpu_count()),
+                VmLayout::GIC_REDIST_SIZE_PER_CPU * gic.vcpu_count(),
+            ],
+        ),
+    };
+
+    fdt.begin_node("intc");
+    fdt.property_string("compatible", compatible);
+    fdt.property_null("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 3);
+    fdt.property_array_u64("reg", &reg);
+    fdt.property_u32("phandle", GIC_PHANDLE);
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_null("ranges");
+    if gic.has_its() {
+        let its_addr = VmLayout::get_gic_its_base(gic.vcpu_count());
+        fdt.begin_node(&format!("msic@{:x}", its_addr));
+        fdt.property_string("compatible", "arm,gic-v3-its");
+        fdt.property_null("msi-controller");
+        fdt.property_array_u64("reg", &[its_addr, VmLayout::GIC_ITS_SIZE]);
+        fdt.property_u32("phandle", ITS_PHANDLE);
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+fn create_timer_node(fdt: &mut FdtWriter) {
+    // Secure, non-secure, virtual and hypervisor physical timer PPIs.
+    let mut interrupts = Vec::new();
+    for irq in &[13, 14, 11, 10] {
+        interrupts.extend_from_slice(&[GIC_FDT_IRQ_TYPE_PPI, *irq, IRQ_TYPE_LEVEL_HIGH]);
+    }
+
+    fdt.begin_node("timer");
+    fdt.property_string("compatible", "arm,armv8-timer");
+    fdt.property_null("always-on");
+    fdt.property_array_u32("interrupts", &interrupts);
+    fdt.end_node();
+}
+
+fn create_clock_node(fdt: &mut FdtWriter) {
+    fdt.begin_node("apb-pclk");
+    fdt.property_string("compatible", "fixed-clock");
+    fdt.property_u32("#clock-cells", 0);
+    fdt.property_u32("clock-frequency", APB_PCLK_FREQUENCY);
+    fdt.property_string("clock-output-names", "clk24mhz");
+    fdt.property_u32("phandle", APB_PCLK_PHANDLE);
+    fdt.end_node();
+}
+
+fn create_psci_node(fdt: &mut FdtWriter) {
+    fdt.begin_node("psci");
+    fdt.property_string("compatible", "arm,psci-0.2");
+    fdt.property_string("method", "hvc");
+    fdt.end_node();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&blob[offset..offset + 4]);
+        u32::from_be_bytes(bytes)
+    }
+
+    #[test]
+    fn empty_tree_header() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 8) as usize, FDT_HEADER_SIZE + 16);
+        assert_eq!(be32(&blob, 16) as usize, FDT_HEADER_SIZE);
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+        assert_eq!(be32(&blob, 24), FDT_LAST_COMP_VERSION);
+        // No strings, the root node name is empty and padded to 4 bytes.
+        assert_eq!(be32(&blob, 32), 0);
+        assert_eq!(be32(&blob, 36), 16);
+
+        let off = be32(&blob, 8) as usize;
+        assert_eq!(be32(&blob, off), FDT_BEGIN_NODE);
+        assert_eq!(be32(&blob, off + 4), 0);
+        assert_eq!(be32(&blob, off + 8), FDT_END_NODE);
+        assert_eq!(be32(&blob, off + 12), FDT_END);
+    }
+
+    #[test]
+    fn properties_are_aligned_and_names_shared() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.begin_node("node");
+        fdt.property_string("compatible", "abc");
+        fdt.property_u32("reg", 7);
+        fdt.end_node();
+        fdt.begin_node("other");
+        fdt.property_array_u64("reg", &[0x1_0000_0002]);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        // Each property name is stored once.
+        let strings = &blob[be32(&blob, 12) as usize..];
+        assert_eq!(strings, b"compatible\0reg\0");
+
+        // "node\0" is padded to 8 bytes.
+        let mut off = be32(&blob, 8) as usize + 8;
+        assert_eq!(be32(&blob, off), FDT_BEGIN_NODE);
+        assert_eq!(&blob[off + 4..off + 12], b"node\0\0\0\0");
+        off += 12;
+
+        // "abc\0" fits in 4 bytes.
+        assert_eq!(be32(&blob, off), FDT_PROP);
+        assert_eq!(be32(&blob, off + 4), 4);
+        assert_eq!(be32(&blob, off + 8), 0);
+        assert_eq!(&blob[off + 12..off + 16], b"abc\0");
+        off += 16;
+
+        assert_eq!(be32(&blob, off), FDT_PROP);
+        assert_eq!(be32(&blob, off + 4), 4);
+        assert_eq!(be32(&blob, off + 8), 11);
+        assert_eq!(be32(&blob, off + 12), 7);
+        off += 16;
+        assert_eq!(be32(&blob, off), FDT_END_NODE);
+        off += 4;
+
+        // "other\0" is padded to 8 bytes, the u64 reg reuses the name.
+        assert_eq!(&blob[off + 4..off + 12], b"other\0\0\0");
+        off += 12;
+        assert_eq!(be32(&blob, off + 4), 8);
+        assert_eq!(be32(&blob, off + 8), 11);
+        assert_eq!(be32(&blob, off + 12), 1);
+        assert_eq!(be32(&blob, off + 16), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unbalanced_nodes() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.finish();
+    }
+
+    #[test]
+    fn load_at_end_of_memory() {
+        let size = 0x400_0000;
+        let guest_mem =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(VmLayout::DRAM_MEM_START), size)])
+                .unwrap();
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        load_fdt(&guest_mem, &blob).unwrap();
+        let addr = VmLayout::get_fdt_addr(&guest_mem);
+        assert_eq!(
+            addr,
+            VmLayout::DRAM_MEM_START + size as u64 - VmLayout::FDT_MAX_SIZE as u64
+        );
+        let magic: u32 = guest_mem.read_obj(GuestAddress(addr)).unwrap();
+        assert_eq!(u32::from_be(magic), FDT_MAGIC);
+
+        let too_large = vec![0u8; VmLayout::FDT_MAX_SIZE + 1];
+        assert!(match load_fdt(&guest_mem, &too_large) {
+            Err(Error::FdtTooLarge) => true,
+            _ => false,
+        });
+    }
+}
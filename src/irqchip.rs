@@ -0,0 +1,503 @@
+// In-kernel GIC emulation, created through the KVM device API.
+
+use crate::allocator::IdAllocator;
+use crate::error::*;
+use crate::memory::VmLayout;
+use crate::snapshot::{StateReader, StateWriter};
+use kvm_bindings::*;
+use kvm_ioctls::{DeviceFd, VmFd};
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::sync::Arc;
+
+/// KVM routes GSI n to SPI n, MSIs get the GSIs above the SPIs.
+const MSI_GSI_BASE: u32 = VmLayout::IRQ_MAX - VmLayout::IRQ_BASE + 1;
+// KVM_MAX_IRQ_ROUTES in the kernel.
+const MAX_IRQ_ROUTES: u32 = 4096;
+
+// GICv3 registers saved in snapshots, as in Documentation/virt/kvm/devices/arm-vgic-v3.rst.
+const GICD_CTLR: u64 = 0x0;
+// Distributor registers with a field per interrupt: offset and field size in
+// bits. The private interrupts are in the redistributors.
+const GICD_IRQ_REGS: &[(u64, u64)] = &[
+    (0x0080, 1),  // GICD_IGROUPR
+    (0x0100, 1),  // GICD_ISENABLER
+    (0x0200, 1),  // GICD_ISPENDR
+    (0x0300, 1),  // GICD_ISACTIVER
+    (0x0400, 8),  // GICD_IPRIORITYR
+    (0x0c00, 2),  // GICD_ICFGR
+    (0x0d00, 1),  // GICD_IGRPMODR
+    (0x6000, 64), // GICD_IROUTER
+];
+// Redistributor registers, by 32 bit word, before GICR_CTLR which enables the
+// LPIs and goes last. The SGI and PPI ones are in the second 64K frame.
+const GICR_REGS: &[u64] = &[
+    0x0070, 0x0074, // GICR_PROPBASER
+    0x0078, 0x007c,  // GICR_PENDBASER
+    0x10080, // GICR_IGROUPR0
+    0x10100, // GICR_ISENABLER0
+    0x10200, // GICR_ISPENDR0
+    0x10300, // GICR_ISACTIVER0
+    0x10400, 0x10404, 0x10408, 0x1040c, 0x10410, 0x10414, 0x10418, 0x1041c, // GICR_IPRIORITYR
+    0x10c00, 0x10c04, // GICR_ICFGR
+    0x10d00, // GICR_IGRPMODR0
+];
+const GICR_CTLR: u64 = 0x0;
+
+// CPU interface registers, by their op0, op1, CRn, CRm and op2 encoding.
+const fn icc_sysreg(op0: u64, op1: u64, crn: u64, crm: u64, op2: u64) -> u64 {
+    op0 << 14 | op1 << 11 | crn << 7 | crm << 3 | op2
+}
+const ICC_SRE_EL1: u64 = icc_sysreg(3, 0, 12, 12, 5);
+const ICC_CTLR_EL1: u64 = icc_sysreg(3, 0, 12, 12, 4);
+const ICC_PMR_EL1: u64 = icc_sysreg(3, 0, 4, 6, 0);
+const ICC_BPR0_EL1: u64 = icc_sysreg(3, 0, 12, 8, 3);
+const ICC_BPR1_EL1: u64 = icc_sysreg(3, 0, 12, 12, 3);
+const ICC_IGRPEN0_EL1: u64 = icc_sysreg(3, 0, 12, 12, 6);
+const ICC_IGRPEN1_EL1: u64 = icc_sysreg(3, 0, 12, 12, 7);
+const ICC_CTLR_PRIBITS_SHIFT: u64 = 8;
+
+// ITS registers, in the order they are restored, GITS_CTLR apart.
+const GITS_CTLR: u64 = 0x0;
+const GITS_REGS: &[u64] = &[
+    0x0004, // GITS_IIDR
+    0x0100, 0x0108, 0x0110, 0x0118, 0x0120, 0x0128, 0x0130, 0x0138, // GITS_BASER
+    0x0080, // GITS_CBASER
+    0x0090, // GITS_CREADR
+    0x0088, // GITS_CWRITER
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GicVersion {
+    V2,
+    V3,
+}
+
+pub struct Gic {
+    fd: DeviceFd,
+    version: GicVersion,
+    vcpu_count: u64,
+    // Translates the MSIs of PCI devices into LPIs, GICv3 only.
+    its: Option<DeviceFd>,
+}
+
+impl Gic {
+    /// Creates a GICv3 with an ITS, falling back to GICv2 on hosts without GICv3 support.
+    ///
+    /// Must be called after all vcpus are created.
+    pub fn new(vm_fd: &VmFd, vcpu_count: u64) -> Result<Gic> {
+        let mut gic = match Gic::create(vm_fd, GicVersion::V3, vcpu_count) {
+            Ok(gic) => gic,
+            Err(_) => Gic::create(vm_fd, GicVersion::V2, vcpu_count)?,
+        };
+
+        gic.set_addresses()?;
+
+        // Number of interrupts, including the 32 private ones.
+        let nr_irqs: u32 = VmLayout::IRQ_MAX + 1;
+        gic.set_attr(
+            KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            0,
+            &nr_irqs as *const u32 as u64,
+        )?;
+
+        // Finalize the GIC, so it can start delivering interrupts.
+        gic.set_attr(
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            0,
+        )?;
+
+        if gic.version == GicVersion::V3 {
+            // Without an ITS, PCI devices fall back to INTx.
+            match Gic::create_its(vm_fd, vcpu_count) {
+                Ok(its) => gic.its = Some(its),
+                Err(e) => println!("gic: no ITS, MSIs are not available: {:?}", e),
+            }
+        }
+
+        Ok(gic)
+    }
+
+    fn create_its(vm_fd: &VmFd, vcpu_count: u64) -> Result<DeviceFd> {
+        let mut its_device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+            fd: 0,
+            flags: 0,
+        };
+        let its = vm_fd
+            .create_device(&mut its_device)
+            .map_err(Error::CreateIts)?;
+
+        let its_addr: u64 = VmLayout::get_gic_its_base(vcpu_count);
+        set_device_attr(
+            &its,
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            u64::from(KVM_VGIC_ITS_ADDR_TYPE),
+            &its_addr as *const u64 as u64,
+        )
+        .map_err(Error::CreateIts)?;
+        set_device_attr(
+            &its,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            0,
+        )
+        .map_err(Error::CreateIts)?;
+        Ok(its)
+    }
+
+    fn create(vm_fd: &VmFd, version: GicVersion, vcpu_count: u64) -> Result<Gic> {
+        let type_ = match version {
+            GicVersion::V2 => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+            GicVersion::V3 => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        };
+        let mut gic_device = kvm_create_device {
+            type_,
+            fd: 0,
+            flags: 0,
+        };
+        let fd = vm_fd
+            .create_device(&mut gic_device)
+            .map_err(Error::CreateGic)?;
+        Ok(Gic {
+            fd,
+            version,
+            vcpu_count,
+            its: None,
+        })
+    }
+
+    fn set_addresses(&self) -> Result<()> {
+        let dist_addr: u64 = VmLayout::GIC_DIST_BASE;
+        match self.version {
+            GicVersion::V2 => {
+                let cpu_addr: u64 = VmLayout::GIC_CPU_BASE;
+                self.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_ADDR,
+                    u64::from(KVM_VGIC_V2_ADDR_TYPE_DIST),
+                    &dist_addr as *const u64 as u64,
+                )?;
+                self.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_ADDR,
+                    u64::from(KVM_VGIC_V2_ADDR_TYPE_CPU),
+                    &cpu_addr as *const u64 as u64,
+                )
+            }
+            GicVersion::V3 => {
+                let redist_addr: u64 = VmLayout::get_gic_redist_base(self.vcpu_count);
+                self.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_ADDR,
+                    u64::from(KVM_VGIC_V3_ADDR_TYPE_DIST),
+                    &dist_addr as *const u64 as u64,
+                )?;
+                self.set_attr(
+                    KVM_DEV_ARM_VGIC_GRP_ADDR,
+                    u64::from(KVM_VGIC_V3_ADDR_TYPE_REDIST),
+                    &redist_addr as *const u64 as u64,
+                )
+            }
+        }
+    }
+
+    fn set_attr(&self, group: u32, attr: u64, addr: u64) -> Result<()> {
+        set_device_attr(&self.fd, group, attr, addr).map_err(Error::CreateGic)
+    }
+
+    pub fn version(&self) -> GicVersion {
+        self.version
+    }
+
+    pub fn vcpu_count(&self) -> u64 {
+        self.vcpu_count
+    }
+
+    pub fn has_its(&self) -> bool {
+        self.its.is_some()
+    }
+
+    /// Flushes the ITS tables (devices, collections, interrupt translations)
+    /// to the guest memory the driver set them up in, so they are part of a snapshot.
+    ///
+    /// The vcpus must be paused.
+    pub fn save_its_tables(&self) -> Result<()> {
+        self.its_ctrl(KVM_DEV_ARM_ITS_SAVE_TABLES)
+    }
+
+    /// Reloads the ITS tables from guest memory after it was restored from a snapshot.
+    pub fn restore_its_tables(&self) -> Result<()> {
+        self.its_ctrl(KVM_DEV_ARM_ITS_RESTORE_TABLES)
+    }
+
+    /// Saves the registers of the GIC and the ITS, and flushes the pending LPIs
+    /// and the ITS tables to guest memory, which then has to be saved too.
+    ///
+    /// The vcpus must be paused. `mpidrs` are those of the vcpus, in order.
+    pub fn save_state(&self, mpidrs: &[u64]) -> Result<Vec<u8>> {
+        if self.version != GicVersion::V3 {
+            return Err(Error::SnapshotUnsupported(
+                "snapshots need a GICv3".to_string(),
+            ));
+        }
+        set_device_attr(
+            &self.fd,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_SAVE_PENDING_TABLES),
+            0,
+        )
+        .map_err(Error::GicState)?;
+        self.save_its_tables()?;
+
+        // The registers, in the order they are restored.
+        let mut regs = Vec::new();
+        regs.push((KVM_DEV_ARM_VGIC_GRP_DIST_REGS, GICD_CTLR));
+        let spis = u64::from(VmLayout::IRQ_MAX + 1 - VmLayout::IRQ_BASE);
+        for (base, bits) in GICD_IRQ_REGS.iter() {
+            let first = base + u64::from(VmLayout::IRQ_BASE) * bits / 8;
+            for word in 0..spis * bits / 32 {
+                regs.push((KVM_DEV_ARM_VGIC_GRP_DIST_REGS, first + word * 4));
+            }
+        }
+        for mpidr in mpidrs {
+            let cpu = mpidr_attr(*mpidr);
+            for offset in GICR_REGS.iter().chain([GICR_CTLR].iter()) {
+                regs.push((KVM_DEV_ARM_VGIC_GRP_REDIST_REGS, cpu | offset));
+            }
+            let ctlr = get_reg(
+                &self.fd,
+                KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS,
+                cpu | ICC_CTLR_EL1,
+            )?;
+            // 1, 2 or 4 active priority registers per group, for 5, 6 or 7 priority bits.
+            let apr_count = match (ctlr >> ICC_CTLR_PRIBITS_SHIFT & 0x7) + 1 {
+                5 => 1,
+                6 => 2,
+                _ => 4,
+            };
+            let mut sysregs = vec![
+                ICC_SRE_EL1,
+                ICC_CTLR_EL1,
+                ICC_PMR_EL1,
+                ICC_BPR0_EL1,
+                ICC_BPR1_EL1,
+            ];
+            sysregs.extend((0..apr_count).map(|n| icc_sysreg(3, 0, 12, 8, 4 + n)));
+            sysregs.extend((0..apr_count).map(|n| icc_sysreg(3, 0, 12, 9, n)));
+            sysregs.push(ICC_IGRPEN0_EL1);
+            sysregs.push(ICC_IGRPEN1_EL1);
+            for sysreg in sysregs {
+                regs.push((KVM_DEV_ARM_VGIC_GRP_CPU_SYSREGS, cpu | sysreg));
+            }
+        }
+
+        let mut state = StateWriter::new();
+        state.u32(regs.len() as u32);
+        for (group, attr) in regs {
+            state.u32(group);
+            state.u64(attr);
+            state.u64(get_reg(&self.fd, group, attr)?);
+        }
+        state.bool(self.its.is_some());
+        if let Some(its) = &self.its {
+            for offset in GITS_REGS.iter().chain([GITS_CTLR].iter()) {
+                state.u64(get_reg(its, KVM_DEV_ARM_VGIC_GRP_ITS_REGS, *offset)?);
+            }
+        }
+        Ok(state.into_inner())
+    }
+
+    /// Writes back the state `save_state` returned, once the vcpus and the
+    /// guest memory are restored.
+    pub fn restore_state(&self, state: &[u8]) -> Result<()> {
+        if self.version != GicVersion::V3 {
+            return Err(Error::SnapshotIncompatible(
+                "KVM on this host has no GICv3".to_string(),
+            ));
+        }
+        let mut state = StateReader::new(state);
+        for _ in 0..state.u32()? {
+            let group = state.u32()?;
+            let attr = state.u64()?;
+            set_reg(&self.fd, group, attr, state.u64()?)?;
+        }
+        if !state.bool()? {
+            return Ok(());
+        }
+        let its = self.its.as_ref().ok_or_else(|| {
+            Error::SnapshotIncompatible("KVM on this host has no ITS".to_string())
+        })?;
+        for offset in GITS_REGS.iter() {
+            set_reg(its, KVM_DEV_ARM_VGIC_GRP_ITS_REGS, *offset, state.u64()?)?;
+        }
+        self.restore_its_tables()?;
+        // Enabling the ITS last, once it has its tables.
+        set_reg(its, KVM_DEV_ARM_VGIC_GRP_ITS_REGS, GITS_CTLR, state.u64()?)
+    }
+
+    fn its_ctrl(&self, ctrl: u32) -> Result<()> {
+        match &self.its {
+            Some(its) => set_device_attr(its, KVM_DEV_ARM_VGIC_GRP_CTRL, u64::from(ctrl), 0)
+                .map_err(Error::ItsTables),
+            None => Ok(()),
+        }
+    }
+}
+
+fn set_device_attr(
+    fd: &DeviceFd,
+    group: u32,
+    attr: u64,
+    addr: u64,
+) -> std::result::Result<(), kvm_ioctls::Error> {
+    let attr = kvm_device_attr {
+        flags: 0,
+        group,
+        attr,
+        addr,
+    };
+    fd.set_device_attr(&attr)
+}
+
+fn get_device_attr(
+    fd: &DeviceFd,
+    group: u32,
+    attr: u64,
+    addr: u64,
+) -> std::result::Result<(), kvm_ioctls::Error> {
+    let mut attr = kvm_device_attr {
+        flags: 0,
+        group,
+        attr,
+        addr,
+    };
+    fd.get_device_attr(&mut attr)
+}
+
+// The distributor and redistributor registers are accessed 32 bits at a time,
+// the CPU interface and ITS ones 64.
+fn is_32bit_group(group: u32) -> bool {
+    group == KVM_DEV_ARM_VGIC_GRP_DIST_REGS || group == KVM_DEV_ARM_VGIC_GRP_REDIST_REGS
+}
+
+fn get_reg(fd: &DeviceFd, group: u32, attr: u64) -> Result<u64> {
+    if is_32bit_group(group) {
+        let mut value = 0u32;
+        get_device_attr(fd, group, attr, &mut value as *mut u32 as u64).map_err(Error::GicState)?;
+        Ok(u64::from(value))
+    } else {
+        let mut value = 0u64;
+        get_device_attr(fd, group, attr, &mut value as *mut u64 as u64).map_err(Error::GicState)?;
+        Ok(value)
+    }
+}
+
+fn set_reg(fd: &DeviceFd, group: u32, attr: u64, value: u64) -> Result<()> {
+    if is_32bit_group(group) {
+        let value = value as u32;
+        set_device_attr(fd, group, attr, &value as *const u32 as u64)
+    } else {
+        set_device_attr(fd, group, attr, &value as *const u64 as u64)
+    }
+    .map_err(Error::GicState)
+}
+
+// The affinity of a vcpu, as the redistributor and CPU interface registers
+// take it in the upper half of their attribute.
+fn mpidr_attr(mpidr: u64) -> u64 {
+    ((mpidr >> 32 & 0xff) << 24 | mpidr & 0xff_ffff) << 32
+}
+
+/// The GSI routing table of the VM.
+///
+/// KVM replaces the whole table on every update, so it keeps the default routes
+/// to the SPIs next to the MSI routes of the devices.
+pub struct GsiRouting {
+    vm_fd: Arc<VmFd>,
+    msi_routes: BTreeMap<u32, kvm_irq_routing_entry>,
+    gsis: IdAllocator,
+}
+
+impl GsiRouting {
+    pub fn new(vm_fd: Arc<VmFd>) -> Self {
+        GsiRouting {
+            vm_fd,
+            msi_routes: BTreeMap::new(),
+            gsis: IdAllocator::new(MSI_GSI_BASE, MAX_IRQ_ROUTES - 1),
+        }
+    }
+
+    pub fn vm_fd(&self) -> &VmFd {
+        &self.vm_fd
+    }
+
+    /// Allocates a GSI for an MSI.
+    pub fn allocate_gsi(&mut self) -> Result<u32> {
+        self.gsis.allocate().ok_or(Error::IrqsExhausted)
+    }
+
+    /// Removes the route of `gsi` and makes it available again.
+    pub fn release_gsi(&mut self, gsi: u32) -> Result<()> {
+        self.gsis.free(gsi);
+        self.remove_route(gsi)
+    }
+
+    /// Routes `gsi` to the MSI `data` written at `addr` by the device `devid`.
+    pub fn set_msi_route(&mut self, gsi: u32, addr: u64, data: u32, devid: u32) -> Result<()> {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_MSI,
+            // The ITS needs to know which device sent the MSI.
+            flags: KVM_MSI_VALID_DEVID,
+            ..Default::default()
+        };
+        entry.u.msi.address_lo = addr as u32;
+        entry.u.msi.address_hi = (addr >> 32) as u32;
+        entry.u.msi.data = data;
+        entry.u.msi.__bindgen_anon_1.devid = devid;
+
+        self.msi_routes.insert(gsi, entry);
+        self.commit()
+    }
+
+    fn remove_route(&mut self, gsi: u32) -> Result<()> {
+        if self.msi_routes.remove(&gsi).is_some() {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        let mut entries: Vec<kvm_irq_routing_entry> = (0..MSI_GSI_BASE)
+            .map(|gsi| {
+                let mut entry = kvm_irq_routing_entry {
+                    gsi,
+                    type_: KVM_IRQ_ROUTING_IRQCHIP,
+                    ..Default::default()
+                };
+                entry.u.irqchip.irqchip = 0;
+                entry.u.irqchip.pin = gsi;
+                entry
+            })
+            .collect();
+        entries.extend(self.msi_routes.values());
+
+        // kvm_irq_routing ends with a flexible array of entries, allocate room for them.
+        let count = (size_of::<kvm_irq_routing>()
+            + entries.len() * size_of::<kvm_irq_routing_entry>())
+            / size_of::<kvm_irq_routing>()
+            + 1;
+        let mut routing = vec![kvm_irq_routing::default(); count];
+        routing[0].nr = entries.len() as u32;
+        // Safe because `routing` has room for all the entries.
+        unsafe {
+            routing[0]
+                .entries
+                .as_mut_slice(entries.len())
+                .copy_from_slice(&entries);
+        }
+
+        self.vm_fd
+            .set_gsi_routing(&routing[0])
+            .map_err(Error::SetGsiRouting)
+    }
+}
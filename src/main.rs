@@ -1,14 +1,23 @@
 #[macro_use]
 extern crate clap;
-use clap::App;
+use clap::{App, ArgMatches};
 use std::path::PathBuf;
 use std::*;
 
+mod allocator;
 mod config;
+mod control;
+mod coredump;
 mod cpu;
+mod device_manager;
+mod devices;
 mod error;
+mod event_manager;
+mod fdt;
+mod irqchip;
 mod memory;
 mod regs;
+mod snapshot;
 mod vm;
 mod vmm;
 
@@ -22,31 +31,167 @@ fn main() {
         ("run", Some(run_matches)) => {
             println!("run");
 
-            let cpus = run_matches.value_of("cpus").unwrap().parse::<u8>().unwrap();
-            let mem = run_matches.value_of("mem").unwrap().parse::<u64>().unwrap();
-            let kernel_path = run_matches.value_of("kernel").unwrap();
-            let kernel_path = PathBuf::from(kernel_path);
-            //let kernel_args = run_matches.value_of("kernel_args").unwrap();
-            let disk_path = run_matches.value_of("disk").unwrap();
-            let disk_path = PathBuf::from(disk_path);
+            let mut vm_config = vm_config(run_matches);
+            vm_config.run_args = env::args().collect();
+            let name = vm_name(run_matches);
 
-            let vm_config = config::VmConfig::new(cpus, mem, kernel_path, disk_path);
-
-            vmm::Vmm::new().unwrap().run_vm(vm_config).unwrap();
+            vmm::Vmm::new().unwrap().run_vm(vm_config, &name).unwrap();
         }
         ("pause", Some(pause_matches)) => {
             let name = pause_matches.value_of("name").unwrap();
-            vmm::Vmm::new().unwrap().pause_vm(name);
+            vmm::Vmm::new().unwrap().pause_vm(name).unwrap();
         }
         ("resume", Some(resume_matches)) => {
             let name = resume_matches.value_of("name").unwrap();
-            vmm::Vmm::new().unwrap().resume_vm(name);
+            vmm::Vmm::new().unwrap().resume_vm(name).unwrap();
+        }
+        ("hotplug", Some(hotplug_matches)) => {
+            let name = hotplug_matches.value_of("name").unwrap();
+            let device = hotplug_matches.value_of("device").unwrap();
+            let options = hotplug_matches.value_of("options").unwrap_or("");
+            vmm::Vmm::new()
+                .unwrap()
+                .hotplug_vm(name, device, options)
+                .unwrap();
+        }
+        ("resize-memory", Some(resize_matches)) => {
+            let name = resize_matches.value_of("name").unwrap();
+            let size = valid(
+                "size",
+                resize_matches.value_of("size").unwrap().parse::<u64>(),
+            );
+            vmm::Vmm::new().unwrap().resize_memory(name, size).unwrap();
+        }
+        ("unplug", Some(unplug_matches)) => {
+            let name = unplug_matches.value_of("name").unwrap();
+            let id = unplug_matches.value_of("id").unwrap();
+            vmm::Vmm::new().unwrap().unplug_vm(name, id).unwrap();
+        }
+        ("snapshot", Some(snapshot_matches)) => {
+            let name = snapshot_matches.value_of("name").unwrap();
+            let dir = PathBuf::from(snapshot_matches.value_of("dir").unwrap());
+            vmm::Vmm::new().unwrap().snapshot_vm(name, &dir).unwrap();
+        }
+        ("restore", Some(restore_matches)) => {
+            let dir = env::current_dir()
+                .unwrap()
+                .join(restore_matches.value_of("dir").unwrap());
+            let state = snapshot::VmState::load(&dir).unwrap();
+
+            // The VM is set up again from the arguments it was run with, where
+            // it was run, so that relative paths still resolve.
+            env::set_current_dir(&state.run_dir).unwrap();
+            let matches = App::from(yaml).get_matches_from(&state.run_args);
+            let run_matches = matches.subcommand_matches("run").unwrap();
+            let mut vm_config = vm_config(run_matches);
+            vm_config.run_args = state.run_args.clone();
+            let name = match restore_matches.value_of("name") {
+                Some(name) => name.to_string(),
+                None => vm_name(run_matches),
+            };
+
+            vmm::Vmm::new()
+                .unwrap()
+                .restore_vm(vm_config, &name, &dir, state)
+                .unwrap();
+        }
+        ("dirty-log", Some(dirty_log_matches)) => {
+            let name = dirty_log_matches.value_of("name").unwrap();
+            let action = dirty_log_matches.value_of("action").unwrap();
+            vmm::Vmm::new().unwrap().dirty_log(name, action).unwrap();
+        }
+        ("dump-memory", Some(dump_matches)) => {
+            let name = dump_matches.value_of("name").unwrap();
+            let file = PathBuf::from(dump_matches.value_of("file").unwrap());
+            vmm::Vmm::new().unwrap().dump_memory(name, &file).unwrap();
         }
         ("stop", Some(stop_matches)) => {
             let name = stop_matches.value_of("name").unwrap();
-            vmm::Vmm::new().unwrap().stop_vm(name);
+            let timeout = valid(
+                "timeout",
+                stop_matches.value_of("timeout").unwrap().parse::<u64>(),
+            );
+            vmm::Vmm::new()
+                .unwrap()
+                .stop_vm(name, time::Duration::from_secs(timeout))
+                .unwrap();
         }
         ("", None) => {}
         _ => {}
     }
 }
+
+// The configuration of the VM the arguments of `glue run` describe.
+fn vm_config(run_matches: &ArgMatches) -> config::VmConfig {
+    let cpus = run_matches.value_of("cpus").unwrap().parse::<u8>().unwrap();
+    let mem = run_matches.value_of("mem").unwrap().parse::<u64>().unwrap();
+    let kernel_path = run_matches.value_of("kernel").unwrap();
+    let kernel_path = PathBuf::from(kernel_path);
+    let kernel_args = run_matches.value_of("params").unwrap_or("").to_string();
+    let disk_path = run_matches.value_of("disk").unwrap();
+    let disk_path = PathBuf::from(disk_path);
+
+    let mut vm_config = config::VmConfig::new(cpus, mem, kernel_path, kernel_args, disk_path);
+    if let Some(memory) = run_matches.value_of("mem-options") {
+        vm_config.memory = valid("mem-options", config::MemoryConfig::parse(memory));
+    }
+    if let Some(fs) = run_matches.values_of("fs") {
+        vm_config.fs = fs
+            .map(|v| valid("fs", config::FsConfig::parse(v)))
+            .collect();
+    }
+    if let Some(p9) = run_matches.values_of("9p") {
+        vm_config.p9 = p9
+            .map(|v| valid("9p", config::P9Config::parse(v)))
+            .collect();
+    }
+    if let Some(pmem) = run_matches.values_of("pmem") {
+        vm_config.pmem = pmem
+            .map(|v| valid("pmem", config::PmemConfig::parse(v)))
+            .collect();
+    }
+    if let Some(net) = run_matches.values_of("net") {
+        vm_config.net = net
+            .map(|v| valid("net", config::NetConfig::parse(v)))
+            .collect();
+    }
+    if let Some(vsock) = run_matches.value_of("vsock") {
+        vm_config.vsock = Some(valid("vsock", config::VsockConfig::parse(vsock)));
+    }
+    if let Some(vhost_user) = run_matches.values_of("vhost-user") {
+        vm_config.vhost_user = vhost_user
+            .map(|v| valid("vhost-user", config::VhostUserConfig::parse(v)))
+            .collect();
+    }
+    if let Some(mem_hotplug) = run_matches.value_of("mem-hotplug") {
+        vm_config.mem_hotplug = Some(valid(
+            "mem-hotplug",
+            config::MemHotplugConfig::parse(mem_hotplug),
+        ));
+    }
+    if let Some(numa) = run_matches.values_of("numa") {
+        vm_config.numa = numa
+            .map(|v| valid("numa", config::NumaConfig::parse(v)))
+            .collect();
+    }
+    if let Some(watchdog) = run_matches.value_of("watchdog") {
+        vm_config.watchdog = Some(valid("watchdog", config::WatchdogConfig::parse(watchdog)));
+    }
+
+    vm_config
+}
+
+// The value of a command line option, exits with a message if it is invalid.
+fn valid<T, E: fmt::Debug>(option: &str, value: result::Result<T, E>) -> T {
+    value.unwrap_or_else(|e| {
+        println!("Invalid --{}: {:?}", option, e);
+        process::exit(1);
+    })
+}
+
+fn vm_name(run_matches: &ArgMatches) -> String {
+    match run_matches.value_of("name") {
+        Some(name) => name.to_string(),
+        None => process::id().to_string(),
+    }
+}
@@ -46,9 +46,25 @@
 //
 // Taken from (http://infocenter.arm.com/help/topic/com.arm.doc.den0001c/DEN0001C_principles_of_arm_memory_maps.pdf).
 
+use crate::allocator::IdAllocator;
+use crate::config::{HugePages, MemoryConfig, NumaConfig};
 use crate::error::*;
-use std::sync::Arc;
-use vm_memory::{GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES, KVM_MEM_READONLY};
+use kvm_ioctls::VmFd;
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use vm_memory::{
+    Address, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MmapRegion,
+};
 
 pub struct VmLayout {}
 
@@ -65,7 +81,7 @@ impl VmLayout {
     // pub const CMDLINE_MAX_SIZE: usize = 2048;
 
     /// Maximum size of the device tree blob as specified in https://www.kernel.org/doc/Documentation/arm64/booting.txt.
-    // pub const FDT_MAX_SIZE: usize = 0x20_0000;
+    pub const FDT_MAX_SIZE: usize = 0x20_0000;
 
     // As per virt/kvm/arm/vgic/vgic-kvm-device.c we need
     // the number of interrupts our GIC will support to be:
@@ -74,26 +90,70 @@ impl VmLayout {
     // * a multiple of 32.
     // We are setting up our interrupt controller to support a maximum of 128 interrupts.
     /// First usable interrupt on aarch64.
-    // pub const IRQ_BASE: u32 = 32;
+    pub const IRQ_BASE: u32 = 32;
 
     /// Last usable interrupt on aarch64.
-    // pub const IRQ_MAX: u32 = 159;
+    pub const IRQ_MAX: u32 = 159;
 
     /// Below this address will reside the GIC, above this address will reside the MMIO devices.
-    // pub const MAPPED_IO_START: u64 = (1 << 30); // 1 GB
+    pub const MAPPED_IO_START: u64 = (1 << 30); // 1 GB
+
+    /// The GIC distributor sits right below the MMIO devices.
+    pub const GIC_DIST_SIZE: u64 = 0x1_0000;
+    pub const GIC_DIST_BASE: u64 = VmLayout::MAPPED_IO_START - VmLayout::GIC_DIST_SIZE;
+
+    /// GICv2 CPU interface, below the distributor.
+    pub const GIC_CPU_SIZE: u64 = 0x2000;
+    pub const GIC_CPU_BASE: u64 = VmLayout::GIC_DIST_BASE - VmLayout::GIC_CPU_SIZE;
+
+    /// Each vcpu has a GICv3 redistributor of two 64K frames.
+    pub const GIC_REDIST_SIZE_PER_CPU: u64 = 0x2_0000;
+
+    /// The GICv3 ITS has a control frame and a translation frame.
+    pub const GIC_ITS_SIZE: u64 = 0x2_0000;
+
+    /// 32-bit BARs of PCI devices, above the MMIO devices.
+    pub const PCI_MMIO32_START: u64 = 0x6000_0000;
+    pub const PCI_MMIO32_SIZE: u64 = 0x1000_0000;
+
+    /// PCI configuration space (ECAM), 1 MB per bus, right below DRAM.
+    pub const PCI_ECAM_START: u64 = 0x7000_0000;
+    pub const PCI_ECAM_SIZE: u64 = 0x1000_0000;
+
+    /// Memory plugged by virtio-mem comes from a region after DRAM, which
+    /// leaves DRAM the 126 GB below it.
+    pub const MEM_HOTPLUG_START: u64 = 128 << 30;
+    pub const MEM_HOTPLUG_SIZE: u64 = 128 << 30;
+
+    /// Device memory, like the ranges of virtio-pmem devices, lives in the
+    /// 256 GB - 512 GB mapped I/O window, away from DRAM.
+    pub const DEVICE_MEM_START: u64 = 256 << 30;
+    pub const DEVICE_MEM_SIZE: u64 = 128 << 30;
+
+    /// 64-bit BARs of PCI devices take the upper half of the same window.
+    pub const PCI_MMIO64_START: u64 = 384 << 30;
+    pub const PCI_MMIO64_SIZE: u64 = 128 << 30;
 
     // Auxiliary function to get the address where the device tree blob is loaded.
-    pub fn get_fdt_addr(_mem: &GuestMemoryMmap) -> u64 {
+    pub fn get_fdt_addr(mem: &GuestMemoryMmap) -> u64 {
+        // At the end of DRAM, the hotplug region is not plugged at boot.
+        let mut end_addr = GuestAddress(VmLayout::DRAM_MEM_START);
+        mem.with_regions_mut(|_, region| -> std::result::Result<(), ()> {
+            if region.start_addr().0 < VmLayout::MEM_HOTPLUG_START {
+                end_addr = end_addr.max(region.last_addr());
+            }
+            Ok(())
+        })
+        .unwrap();
+
         // If the memory allocated is smaller than the size allocated for the FDT,
         // we return the start of the DRAM so that
         // we allow the code to try and load the FDT.
-        /*
-                if let Some(offset) = mem.end_addr().checked_sub(layout::FDT_MAX_SIZE as u64) {
-                    if mem.address_in_range(offset) {
-                        return offset.raw_value();
-                    }
-                }
-        */
+        if let Some(offset) = end_addr.checked_sub(VmLayout::FDT_MAX_SIZE as u64 - 1) {
+            if mem.address_in_range(offset) {
+                return offset.raw_value();
+            }
+        }
         VmLayout::DRAM_MEM_START
     }
 
@@ -101,6 +161,282 @@ impl VmLayout {
     pub fn get_kernel_start() -> u64 {
         VmLayout::DRAM_MEM_START
     }
+
+    /// GICv3 redistributors, below the distributor.
+    pub fn get_gic_redist_base(vcpu_count: u64) -> u64 {
+        VmLayout::GIC_DIST_BASE - VmLayout::GIC_REDIST_SIZE_PER_CPU * vcpu_count
+    }
+
+    /// GICv3 ITS, below the redistributors.
+    pub fn get_gic_its_base(vcpu_count: u64) -> u64 {
+        VmLayout::get_gic_redist_base(vcpu_count) - VmLayout::GIC_ITS_SIZE
+    }
+}
+
+/// KVM memory slots of a VM, KVM_USER_MEM_SLOTS on arm64.
+const MAX_MEM_SLOTS: u32 = 512;
+
+/// Pages of guest memory written by the devices of glue, which KVM does not
+/// log since the writes do not come from a vcpu.
+///
+/// Devices served by vhost backends write guest memory from another process
+/// and are not logged.
+pub struct DeviceDirtyLog {
+    enabled: AtomicBool,
+    page_size: u64,
+    // Start, size and bitmap of each guest memory region, a bit per page.
+    regions: Vec<(u64, u64, Vec<AtomicU64>)>,
+}
+
+impl DeviceDirtyLog {
+    /// A log for the guest memory `regions`, given as start and size.
+    pub fn new(regions: &[(u64, u64)]) -> Self {
+        // Safe because sysconf has no pointer arguments.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let regions = regions
+            .iter()
+            .map(|&(start, size)| {
+                let pages = (size + page_size - 1) / page_size;
+                let words = (pages + 63) / 64;
+                (start, size, (0..words).map(|_| AtomicU64::new(0)).collect())
+            })
+            .collect();
+        DeviceDirtyLog {
+            enabled: AtomicBool::new(false),
+            page_size,
+            regions,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    fn set_enabled(&self, enable: bool) {
+        if enable {
+            for (_, _, bitmap) in self.regions.iter() {
+                for word in bitmap.iter() {
+                    word.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+        self.enabled.store(enable, Ordering::Release);
+    }
+
+    /// Marks the pages of the `len` bytes at `addr` dirty, if logging is on.
+    pub fn mark(&self, addr: GuestAddress, len: usize) {
+        if len == 0 || !self.is_enabled() {
+            return;
+        }
+        let region = self
+            .regions
+            .iter()
+            .find(|(start, size, _)| addr.0 >= *start && addr.0 - start < *size);
+        if let Some((start, size, bitmap)) = region {
+            let first = (addr.0 - start) / self.page_size;
+            let last = (addr.0 - start + len as u64 - 1).min(size - 1) / self.page_size;
+            for page in first..=last {
+                bitmap[(page / 64) as usize].fetch_or(1 << (page % 64), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Adds the pages of the range at `addr` to its `bitmap` from KVM, and clears
+    /// them here. The range starts a region or a multiple of 64 pages into it.
+    fn take(&self, addr: u64, bitmap: &mut [u64]) {
+        let region = self
+            .regions
+            .iter()
+            .find(|(start, size, _)| addr >= *start && addr - start < *size);
+        if let Some((start, _, log)) = region {
+            let first = ((addr - start) / self.page_size / 64) as usize;
+            for (word, logged) in bitmap.iter_mut().zip(log.iter().skip(first)) {
+                *word |= logged.swap(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Ranges of guest memory written since the dirty log was last read, as
+/// guest address and size.
+pub struct DirtyRanges {
+    page_size: u64,
+    // Guest address of each slot and its bitmap, a bit per page.
+    bitmaps: Vec<(u64, Vec<u64>)>,
+    bitmap: usize,
+    page: usize,
+}
+
+impl Iterator for DirtyRanges {
+    type Item = (GuestAddress, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((addr, bitmap)) = self.bitmaps.get(self.bitmap) {
+            let pages = bitmap.len() * 64;
+            let is_dirty = |page: usize| bitmap[page / 64] & (1 << (page % 64)) != 0;
+            while self.page < pages && !is_dirty(self.page) {
+                // Skip clean words at once.
+                if self.page % 64 == 0 && bitmap[self.page / 64] == 0 {
+                    self.page += 64;
+                } else {
+                    self.page += 1;
+                }
+            }
+            if self.page < pages {
+                let first = self.page;
+                while self.page < pages && is_dirty(self.page) {
+                    self.page += 1;
+                }
+                return Some((
+                    GuestAddress(addr + first as u64 * self.page_size),
+                    (self.page - first) as u64 * self.page_size,
+                ));
+            }
+            self.bitmap += 1;
+            self.page = 0;
+        }
+        None
+    }
+}
+
+/// The KVM memory slots of a VM, for guest RAM and device memory alike.
+pub struct MemorySlots {
+    vm_fd: Arc<VmFd>,
+    ids: IdAllocator,
+    // What KVM was told about each slot in use.
+    regions: BTreeMap<u32, kvm_userspace_memory_region>,
+    dirty_logging: bool,
+    device_log: Arc<DeviceDirtyLog>,
+}
+
+impl MemorySlots {
+    /// Slots whose dirty pages are merged with the ones of `device_log`.
+    pub fn new(vm_fd: Arc<VmFd>, device_log: Arc<DeviceDirtyLog>) -> Self {
+        MemorySlots {
+            vm_fd,
+            ids: IdAllocator::new(0, MAX_MEM_SLOTS - 1),
+            regions: BTreeMap::new(),
+            dirty_logging: false,
+            device_log,
+        }
+    }
+
+    /// Where devices mark the guest memory they write to.
+    pub fn device_log(&self) -> Arc<DeviceDirtyLog> {
+        self.device_log.clone()
+    }
+
+    /// Maps the `size` bytes at `host_addr` into the guest at `guest_addr`, in a
+    /// slot of their own, which is returned. Read-only slots, like ROM, exit to
+    /// the vcpu on writes.
+    ///
+    /// # Safety
+    ///
+    /// The host range must stay mapped until the slot is removed.
+    pub unsafe fn add(
+        &mut self,
+        guest_addr: u64,
+        size: u64,
+        host_addr: u64,
+        readonly: bool,
+    ) -> Result<u32> {
+        let slot = self.ids.allocate().ok_or(Error::MemSlotsExhausted)?;
+        let flags = if readonly {
+            KVM_MEM_READONLY
+        } else if self.dirty_logging {
+            KVM_MEM_LOG_DIRTY_PAGES
+        } else {
+            0
+        };
+        let region = kvm_userspace_memory_region {
+            slot,
+            flags,
+            guest_phys_addr: guest_addr,
+            memory_size: size,
+            userspace_addr: host_addr,
+        };
+        if let Err(e) = self.vm_fd.set_user_memory_region(region) {
+            self.ids.free(slot);
+            return Err(Error::SetUserMemoryRegion(e));
+        }
+        self.regions.insert(slot, region);
+        Ok(slot)
+    }
+
+    /// How many more slots can be added.
+    pub fn available(&self) -> u32 {
+        self.ids.available()
+    }
+
+    /// Deletes `slot`, the guest cannot reach its range anymore.
+    pub fn remove(&mut self, slot: u32) {
+        let mut region = match self.regions.remove(&slot) {
+            Some(region) => region,
+            None => return,
+        };
+        region.memory_size = 0;
+        // Safe because a region of size 0 only deletes the slot.
+        if let Err(e) = unsafe { self.vm_fd.set_user_memory_region(region) } {
+            println!("Failed to delete KVM memory slot {}: {:?}", slot, e);
+        }
+        self.ids.free(slot);
+    }
+
+    /// Guest address and size of each slot, in address order.
+    pub fn ranges(&self) -> Vec<(GuestAddress, u64)> {
+        let mut ranges: Vec<(GuestAddress, u64)> = self
+            .regions
+            .values()
+            .map(|r| (GuestAddress(r.guest_phys_addr), r.memory_size))
+            .collect();
+        ranges.sort_by_key(|r| r.0);
+        ranges
+    }
+
+    /// Whether KVM logs the pages the guest writes to.
+    pub fn dirty_logging(&self) -> bool {
+        self.dirty_logging
+    }
+
+    /// Turns logging of the pages the guest and devices write to on or off,
+    /// for the writable slots and the ones added later.
+    pub fn set_dirty_logging(&mut self, enable: bool) -> Result<()> {
+        self.dirty_logging = enable;
+        self.device_log.set_enabled(enable);
+        for region in self.regions.values_mut() {
+            if region.flags & KVM_MEM_READONLY != 0 {
+                continue;
+            }
+            region.flags = if enable { KVM_MEM_LOG_DIRTY_PAGES } else { 0 };
+            // Safe because only the flags of an existing slot change.
+            unsafe { self.vm_fd.set_user_memory_region(*region) }
+                .map_err(Error::SetUserMemoryRegion)?;
+        }
+        Ok(())
+    }
+
+    /// The pages written since logging was turned on or this was last called,
+    /// leaving the logs clear. Empty while logging is off.
+    pub fn dirty_ranges(&self) -> Result<DirtyRanges> {
+        let mut bitmaps = Vec::new();
+        for region in self.regions.values() {
+            if region.flags & KVM_MEM_LOG_DIRTY_PAGES == 0 {
+                continue;
+            }
+            let mut bitmap = self
+                .vm_fd
+                .get_dirty_log(region.slot, region.memory_size as usize)
+                .map_err(Error::GetDirtyLog)?;
+            self.device_log.take(region.guest_phys_addr, &mut bitmap);
+            bitmaps.push((region.guest_phys_addr, bitmap));
+        }
+        Ok(DirtyRanges {
+            page_size: self.device_log.page_size,
+            bitmaps,
+            bitmap: 0,
+            page: 0,
+        })
+    }
 }
 
 #[derive(PartialEq)]
@@ -110,35 +446,491 @@ pub enum RegionType {
 }
 
 pub struct VmMemory {
+    /// DRAM, followed by the hotplug region if there is one.
     pub guest_mem: GuestMemoryMmap,
+    /// Start and size of the region virtio-mem plugs memory from.
+    pub hotplug_region: Option<(GuestAddress, usize)>,
+    /// KVM slots of DRAM, shared with the devices that map memory into the guest.
+    pub slots: Arc<Mutex<MemorySlots>>,
 }
 
 impl VmMemory {
-    pub fn new(mem_size_mib: usize) -> Result<Self> {
+    /// Allocates the guest RAM, backed as `config` says, and reserves
+    /// `hotplug_size` bytes of address space for memory plugged later.
+    ///
+    /// With `shared`, RAM is mapped shared and backed by memfds unless `config`
+    /// names a file, so vhost-user backends can map it too.
+    ///
+    /// DRAM is registered with KVM right away, the hotplug region block by
+    /// block as the guest plugs it.
+    ///
+    /// Each of the `numa` nodes gets a DRAM region of its own, bound to its host
+    /// node if it has one.
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        mem_size_mib: usize,
+        hotplug_size: usize,
+        shared: bool,
+        config: &MemoryConfig,
+        numa: &[NumaConfig],
+    ) -> Result<Self> {
         let mem_size_bytes = mem_size_mib << 20;
+        let node_sizes: Vec<usize> = if numa.is_empty() {
+            vec![mem_size_bytes]
+        } else {
+            numa.iter().map(|n| (n.mem_size as usize) << 20).collect()
+        };
 
-        let arch_mem_regions = VmMemory::arch_memory_regions(mem_size_bytes);
+        let page_size = config.hugepages.and_then(HugePages::page_size);
+        if let Some(page_size) = page_size {
+            if node_sizes.iter().any(|s| *s as u64 % page_size != 0)
+                || hotplug_size as u64 % page_size != 0
+            {
+                return Err(Error::HugePagesUnaligned(page_size));
+            }
+            // Hugetlbfs mappings are not reserved up front, the guest would crash
+            // on the first page missing instead.
+            check_hugepages(page_size, (mem_size_bytes + hotplug_size) as u64, None)?;
+            // Nodes bound to the same host node share its pool.
+            let mut host_node_sizes: BTreeMap<u32, u64> = BTreeMap::new();
+            for (node, size) in numa.iter().zip(&node_sizes) {
+                if let Some(host_node) = node.host_node {
+                    *host_node_sizes.entry(host_node).or_insert(0) += *size as u64;
+                }
+            }
+            for (host_node, size) in host_node_sizes {
+                check_hugepages(page_size, size, Some(host_node))?;
+            }
+        }
 
-        let ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
+        let arch_mem_regions = VmMemory::arch_memory_regions(&node_sizes);
+
+        let mut ram_regions: Vec<(GuestAddress, usize)> = arch_mem_regions
             .iter()
             .filter(|r| r.2 == RegionType::Ram)
             .map(|r| (r.0, r.1))
             .collect();
+        // The region is part of guest memory so devices can reach plugged blocks,
+        // but KVM only maps the blocks that are plugged.
+        let hotplug_region = if hotplug_size > 0 {
+            Some((GuestAddress(VmLayout::MEM_HOTPLUG_START), hotplug_size))
+        } else {
+            None
+        };
+        ram_regions.extend(hotplug_region);
+
+        let backing_file = match &config.file {
+            Some(path) => {
+                let size = ram_regions.iter().map(|r| r.1 as u64).sum();
+                Some(open_backing_file(path, size)?)
+            }
+            None => None,
+        };
+        let mmap_flags = libc::MAP_NORESERVE
+            | if shared {
+                libc::MAP_SHARED
+            } else {
+                libc::MAP_PRIVATE
+            };
 
         let mut mem_regions = Vec::new();
-        for region in ram_regions.iter() {
-            let mem_region = Arc::new(
-                GuestRegionMmap::new(MmapRegion::new(region.1).unwrap(), region.0).unwrap(),
-            );
+        // Offset of the region in the backing file.
+        let mut offset = 0;
+        for (index, region) in ram_regions.iter().enumerate() {
+            let file_offset = match (&backing_file, page_size, &config.hugetlbfs) {
+                (Some(file), _, _) => Some(FileOffset::new(
+                    file.try_clone().map_err(Error::MemoryFile)?,
+                    offset,
+                )),
+                (None, Some(_), Some(dir)) => Some(FileOffset::new(
+                    hugetlbfs_file(dir, region.0, region.1 as u64)?,
+                    0,
+                )),
+                (None, Some(page_size), None) => Some(FileOffset::new(
+                    memfd(region.1 as u64, hugetlb_flags(page_size))?,
+                    0,
+                )),
+                (None, None, _) if shared => Some(FileOffset::new(memfd(region.1 as u64, 0)?, 0)),
+                (None, None, _) => None,
+            };
+            offset += region.1 as u64;
+            let mmap_region = match file_offset {
+                Some(file_offset) => MmapRegion::build(
+                    Some(file_offset),
+                    region.1,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    mmap_flags,
+                ),
+                None => MmapRegion::new(region.1),
+            }
+            .map_err(Error::MmapRam)?;
+            if config.hugepages == Some(HugePages::Transparent) {
+                advise(&mmap_region, libc::MADV_HUGEPAGE, "Transparent hugepages");
+            }
+            if config.merge {
+                advise(&mmap_region, libc::MADV_MERGEABLE, "Page merging");
+            }
+            if config.dontdump {
+                advise(
+                    &mmap_region,
+                    libc::MADV_DONTDUMP,
+                    "Leaving out of core dumps",
+                );
+            }
+            // DRAM regions follow the nodes, the hotplug region comes last.
+            if let Some(host_node) = numa.get(index).and_then(|n| n.host_node) {
+                bind_to_host_node(mmap_region.as_ptr(), region.1, host_node)?;
+            }
+            let mem_region = Arc::new(GuestRegionMmap::new(mmap_region, region.0).unwrap());
             mem_regions.push(mem_region);
         }
 
+        // Host ranges of DRAM. Unplugged blocks of the hotplug region must stay
+        // unpopulated and unlocked, so the host can take them back.
+        let dram: Vec<(u64, usize)> = mem_regions
+            .iter()
+            .filter(|r| Some(r.start_addr()) != hotplug_region.map(|r| r.0))
+            .map(|r| (r.as_ptr() as u64, r.len() as usize))
+            .collect();
+        if config.prefault {
+            let start = Instant::now();
+            // Safe because sysconf has no pointer arguments.
+            let page_size =
+                page_size.unwrap_or_else(|| unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 });
+            prefault(&dram, page_size as usize)?;
+            println!(
+                "Prefaulted {} MB of guest memory in {:?}",
+                mem_size_mib,
+                start.elapsed()
+            );
+        }
+        if config.mlock {
+            for (addr, len) in dram.iter() {
+                // Safe because the range is a mapping of ours.
+                if unsafe { libc::mlock(*addr as *const libc::c_void, *len) } != 0 {
+                    return Err(Error::LockMemory(std::io::Error::last_os_error()));
+                }
+            }
+        }
+
+        let device_log = DeviceDirtyLog::new(
+            &mem_regions
+                .iter()
+                .map(|r| (r.start_addr().0, r.len()))
+                .collect::<Vec<_>>(),
+        );
+        let mut slots = MemorySlots::new(vm_fd, Arc::new(device_log));
+        for region in mem_regions.iter() {
+            if Some(region.start_addr()) == hotplug_region.map(|r| r.0) {
+                continue;
+            }
+            // Safe because guest memory stays mapped as long as the VM exists.
+            unsafe {
+                slots.add(
+                    region.start_addr().0,
+                    region.len(),
+                    region.as_ptr() as u64,
+                    false,
+                )
+            }?;
+        }
+
         let guest_mem = GuestMemoryMmap::from_arc_regions(mem_regions.clone()).unwrap();
 
-        Ok(VmMemory { guest_mem })
+        Ok(VmMemory {
+            guest_mem,
+            hotplug_region,
+            slots: Arc::new(Mutex::new(slots)),
+        })
+    }
+
+    /// DRAM regions of `sizes`, one after the other.
+    pub fn arch_memory_regions(sizes: &[usize]) -> Vec<(GuestAddress, usize, RegionType)> {
+        let mut addr = GuestAddress(VmLayout::DRAM_MEM_START);
+        let mut regions = Vec::new();
+        for size in sizes {
+            regions.push((addr, *size, RegionType::Ram));
+            addr = addr.unchecked_add(*size as u64);
+        }
+        regions
+    }
+}
+
+/// Gives `advice` about the whole of `region` to the host, warning if it is
+/// not taken since guest memory works without it.
+fn advise(region: &MmapRegion, advice: libc::c_int, what: &str) {
+    // Safe because the range is a mapping of ours and advice does not change its content.
+    let ret = unsafe { libc::madvise(region.as_ptr() as *mut libc::c_void, region.size(), advice) };
+    if ret != 0 {
+        println!(
+            "{} unavailable for guest memory: {}",
+            what,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Opens the file at `path` to back guest memory, growing it to `size` bytes.
+fn open_backing_file(path: &Path, size: u64) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)
+        .map_err(Error::MemoryFile)?;
+    if file.metadata().map_err(Error::MemoryFile)?.len() < size {
+        file.set_len(size).map_err(Error::MemoryFile)?;
+    }
+    Ok(file)
+}
+
+// memfd_create flags selecting the hugetlbfs page size, see linux/memfd.h.
+const MFD_HUGE_SHIFT: u32 = 26;
+
+fn hugetlb_flags(page_size: u64) -> u32 {
+    libc::MFD_HUGETLB | page_size.trailing_zeros() << MFD_HUGE_SHIFT
+}
+
+/// Most threads faulting guest memory in, past which they mostly wait on each other.
+const PREFAULT_THREADS: usize = 16;
+
+/// Touches every page of the host `ranges`, the pages spread over one thread
+/// per host CPU.
+fn prefault(ranges: &[(u64, usize)], page_size: usize) -> Result<()> {
+    // Safe because sysconf has no pointer arguments.
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    let threads = (cpus.max(1) as usize).min(PREFAULT_THREADS);
+
+    let mut handles = Vec::new();
+    for index in 0..threads {
+        let ranges = ranges.to_vec();
+        let handle = thread::Builder::new()
+            .name(format!("prefault{}", index))
+            .spawn(move || {
+                for (addr, len) in ranges {
+                    let pages = (len + page_size - 1) / page_size;
+                    let per_thread = (pages + threads - 1) / threads;
+                    let end = ((index + 1) * per_thread).min(pages);
+                    for page in index * per_thread..end {
+                        let ptr = (addr as usize + page * page_size) as *mut u8;
+                        // Safe because the page is part of guest memory, which
+                        // nothing uses yet, and is written back unchanged.
+                        unsafe { ptr.write_volatile(ptr.read_volatile()) };
+                    }
+                }
+            })
+            .map_err(Error::SpawnThread)?;
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
+// Memory policy of mbind(2), see linux/mempolicy.h.
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// Makes the `len` bytes at `addr` come from host NUMA node `node` only.
+fn bind_to_host_node(addr: *mut u8, len: usize, node: u32) -> Result<()> {
+    let mut nodemask = vec![0u64; node as usize / 64 + 1];
+    nodemask[node as usize / 64] |= 1 << (node % 64);
+    // The kernel looks at one bit less than maxnode.
+    let max_node = nodemask.len() as u64 * 64 + 1;
+    // Safe because the range is a mapping of ours and nodemask outlives the call.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            addr,
+            len,
+            MPOL_BIND,
+            nodemask.as_ptr(),
+            max_node,
+            MPOL_MF_STRICT | MPOL_MF_MOVE,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::BindMemory(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Fails unless the host, or its NUMA `node`, has `size` bytes of free hugepages
+/// of `page_size`.
+fn check_hugepages(page_size: u64, size: u64, node: Option<u32>) -> Result<()> {
+    let dir = match node {
+        Some(node) => format!(
+            "/sys/devices/system/node/node{}/hugepages/hugepages-{}kB",
+            node,
+            page_size >> 10
+        ),
+        None => format!("/sys/kernel/mm/hugepages/hugepages-{}kB", page_size >> 10),
+    };
+    let read = |name: &str| {
+        fs::read_to_string(format!("{}/{}", dir, name))
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    // Reserved pages are promised to mappings that did not touch them yet. Nodes
+    // do not count them, they are only known for the host as a whole.
+    let available = read("free_hugepages").saturating_sub(read("resv_hugepages"));
+    let needed = size / page_size;
+    if available < needed {
+        return Err(Error::NotEnoughHugePages(needed, available));
+    }
+    Ok(())
+}
+
+/// Creates a file of `size` bytes for the region at `addr` in the hugetlbfs
+/// mount `dir`, gone once closed.
+fn hugetlbfs_file(dir: &Path, addr: GuestAddress, size: u64) -> Result<File> {
+    let path = dir.join(format!("glue_ram_{}_{:x}", process::id(), addr.0));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(Error::Memfd)?;
+    fs::remove_file(&path).map_err(Error::Memfd)?;
+    file.set_len(size).map_err(Error::Memfd)?;
+    Ok(file)
+}
+
+/// Creates an anonymous file of `size` bytes to back guest memory, with the
+/// memfd_create `flags` on top of close-on-exec.
+fn memfd(size: u64, flags: u32) -> Result<File> {
+    let name = CString::new("glue_ram").unwrap();
+    // Safe because name is a valid C string and the returned fd is checked.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | flags) };
+    if fd < 0 {
+        return Err(Error::Memfd(std::io::Error::last_os_error()));
+    }
+    // Safe because fd was just created and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size).map_err(Error::Memfd)?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gic_layout() {
+        for vcpu_count in &[1u64, 8, u64::from(u8::max_value())] {
+            let redist_base = VmLayout::get_gic_redist_base(*vcpu_count);
+            let its_base = VmLayout::get_gic_its_base(*vcpu_count);
+            // ITS, redistributors and distributor are back to back below the MMIO devices.
+            assert_eq!(its_base + VmLayout::GIC_ITS_SIZE, redist_base);
+            assert_eq!(
+                redist_base + VmLayout::GIC_REDIST_SIZE_PER_CPU * vcpu_count,
+                VmLayout::GIC_DIST_BASE
+            );
+            assert_eq!(
+                VmLayout::GIC_DIST_BASE + VmLayout::GIC_DIST_SIZE,
+                VmLayout::MAPPED_IO_START
+            );
+            // KVM wants 64K aligned frames.
+            assert_eq!(its_base % 0x1_0000, 0);
+            assert_eq!(redist_base % 0x1_0000, 0);
+        }
+    }
+
+    const PAGE_SIZE: u64 = 0x1000;
+
+    fn ranges(bitmaps: Vec<(u64, Vec<u64>)>) -> Vec<(u64, u64)> {
+        DirtyRanges {
+            page_size: PAGE_SIZE,
+            bitmaps,
+            bitmap: 0,
+            page: 0,
+        }
+        .map(|(addr, size)| (addr.0, size / PAGE_SIZE))
+        .collect()
+    }
+
+    #[test]
+    fn dirty_ranges_across_words() {
+        // Pages 62 to 66, then page 127 alone.
+        let bitmap = vec![3 << 62, 0b111 | 1 << 63];
+        assert_eq!(
+            ranges(vec![(0x4000_0000, bitmap)]),
+            vec![
+                (0x4000_0000 + 62 * PAGE_SIZE, 5),
+                (0x4000_0000 + 127 * PAGE_SIZE, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn dirty_ranges_all_dirty_word() {
+        assert_eq!(ranges(vec![(0, vec![!0])]), vec![(0, 64)]);
+        assert_eq!(
+            ranges(vec![(0, vec![0, !0, 1])]),
+            vec![(64 * PAGE_SIZE, 65)]
+        );
+        assert_eq!(ranges(vec![(0, vec![0, 0])]), vec![]);
+    }
+
+    #[test]
+    fn dirty_ranges_several_bitmaps() {
+        // The last page of a slot and the first of the next one are not merged.
+        let bitmaps = vec![
+            (0, vec![0, 1 << 63]),
+            (128 * PAGE_SIZE, vec![1]),
+            (0x10_0000_0000, vec![]),
+            (0x20_0000_0000, vec![0b1010]),
+        ];
+        assert_eq!(
+            ranges(bitmaps),
+            vec![
+                (127 * PAGE_SIZE, 1),
+                (128 * PAGE_SIZE, 1),
+                (0x20_0000_0000 + PAGE_SIZE, 1),
+                (0x20_0000_0000 + 3 * PAGE_SIZE, 1),
+            ]
+        );
     }
 
-    pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize, RegionType)> {
-        vec![(GuestAddress(0), size, RegionType::Ram)]
+    #[test]
+    fn device_log_take_merges() {
+        // Safe because sysconf has no pointer arguments.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let log = DeviceDirtyLog::new(&[(0x4000_0000, 256 * page_size), (0x8000_0000, page_size)]);
+        let page = |n: u64| GuestAddress(0x4000_0000 + n * page_size);
+
+        // Nothing is logged while logging is off.
+        log.mark(page(0), 1);
+        log.set_enabled(true);
+        log.mark(page(1), 1);
+        // Pages 63 and 64, straddling two words.
+        log.mark(page(63), page_size as usize + 1);
+        log.mark(page(200), 1);
+
+        let mut bitmap = vec![1 << 5, 0, 0, 0];
+        log.take(0x4000_0000, &mut bitmap);
+        assert_eq!(
+            bitmap,
+            vec![1 << 5 | 1 << 1 | 1 << 63, 1, 0, 1 << (200 - 192)]
+        );
+        // Taken pages are clear.
+        let mut bitmap = vec![0; 4];
+        log.take(0x4000_0000, &mut bitmap);
+        assert_eq!(bitmap, vec![0; 4]);
+
+        // A slot 128 pages into the region only gets its own words.
+        log.mark(page(1), 1);
+        log.mark(page(130), 1);
+        let mut bitmap = vec![0; 2];
+        log.take(0x4000_0000 + 128 * page_size, &mut bitmap);
+        assert_eq!(bitmap, vec![1 << 2, 0]);
+
+        // Ranges out of the logged regions are left as KVM gave them.
+        let mut bitmap = vec![1];
+        log.take(0xc000_0000, &mut bitmap);
+        assert_eq!(bitmap, vec![1]);
     }
 }
@@ -4,8 +4,18 @@ use crate::error::*;
 use crate::memory::VmLayout;
 use kvm_bindings::*;
 use kvm_ioctls::VcpuFd;
+use std::convert::TryInto;
 use std::mem;
 use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_ref};
+use vmm_sys_util::{ioctl_iow_nr, ioctl_iowr_nr};
+
+// Register ioctls of a vcpu, see linux/kvm.h. They take registers of any size,
+// unlike the ones of kvm-ioctls.
+const KVMIO: u32 = 0xae;
+ioctl_iow_nr!(KVM_GET_ONE_REG, KVMIO, 0xab, kvm_one_reg);
+ioctl_iow_nr!(KVM_SET_ONE_REG, KVMIO, 0xac, kvm_one_reg);
+ioctl_iowr_nr!(KVM_GET_REG_LIST, KVMIO, 0xb0, kvm_reg_list);
 
 #[allow(non_upper_case_globals)]
 // PSR (Processor State Register) bits.
@@ -17,6 +27,7 @@ const PSR_A_BIT: u64 = 0x0000_0100;
 const PSR_D_BIT: u64 = 0x0000_0200;
 // Taken from arch/arm64/kvm/inject_fault.c.
 const PSTATE_FAULT_BITS_64: u64 = (PSR_MODE_EL1h | PSR_A_BIT | PSR_F_BIT | PSR_I_BIT | PSR_D_BIT);
+const PSR_MODE_MASK: u64 = 0x0000_000f;
 
 // Following are macros that help with getting the ID of a aarch64 core register.
 // The core register are represented by the user_pt_regs structure. Look for it in
@@ -91,6 +102,14 @@ macro_rules! arm64_sys_reg {
 // Constant imported from the Linux kernel:
 // https://elixir.bootlin.com/linux/v4.20.17/source/arch/arm64/include/asm/sysreg.h#L135
 arm64_sys_reg!(MPIDR_EL1, 3, 0, 0, 0, 5);
+// Implementer, part number and revision of the host CPU.
+arm64_sys_reg!(MIDR_EL1, 3, 0, 0, 0, 0);
+
+// The virtual timer, as in arch/arm64/include/uapi/asm/kvm.h, where the
+// encodings of CNT and CVAL are swapped for compatibility.
+arm64_sys_reg!(TIMER_CTL, 3, 3, 14, 3, 1);
+arm64_sys_reg!(TIMER_CNT, 3, 3, 14, 3, 2);
+arm64_sys_reg!(TIMER_CVAL, 3, 3, 14, 0, 2);
 
 /// Configure core registers for a given CPU.
 ///
@@ -120,6 +139,40 @@ pub fn setup_regs(vcpu: &VcpuFd, cpu_id: u8, boot_ip: u64, mem: &GuestMemoryMmap
     Ok(())
 }
 
+/// Read x0 to x30, SP, PC and PSTATE, laid out as `user_pt_regs`.
+///
+/// SP is the stack pointer in use, SP_EL1 when the vcpu runs the kernel on it.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+pub fn read_core_regs(vcpu: &VcpuFd) -> Result<[u64; 34]> {
+    let mut regs = [0u64; 34];
+    // Registers are 64 bit, their ids count 32 bit words.
+    for (i, reg) in regs.iter_mut().take(31).enumerate() {
+        *reg = vcpu
+            .get_one_reg(arm64_core_reg!(regs) + i as u64 * 2)
+            .map_err(Error::VcpuRegister)?;
+    }
+    let pstate = vcpu
+        .get_one_reg(arm64_core_reg!(pstate))
+        .map_err(Error::VcpuRegister)?;
+    let sp_id = if pstate & PSR_MODE_MASK == PSR_MODE_EL1h {
+        KVM_REG_ARM64 as u64
+            | KVM_REG_SIZE_U64 as u64
+            | u64::from(KVM_REG_ARM_CORE)
+            | ((offset__of!(kvm_regs, sp_el1) / mem::size_of::<u32>()) as u64)
+    } else {
+        arm64_core_reg!(sp)
+    };
+    regs[31] = vcpu.get_one_reg(sp_id).map_err(Error::VcpuRegister)?;
+    regs[32] = vcpu
+        .get_one_reg(arm64_core_reg!(pc))
+        .map_err(Error::VcpuRegister)?;
+    regs[33] = pstate;
+    Ok(regs)
+}
+
 /// Read the MPIDR - Multiprocessor Affinity Register.
 ///
 /// # Arguments
@@ -129,3 +182,249 @@ pub fn read_mpidr(vcpu: &VcpuFd) -> Result<u64> {
     let mpidr = vcpu.get_one_reg(MPIDR_EL1).unwrap();
     Ok(mpidr)
 }
+
+/// Every register of a vcpu KVM gives access to: core, FP/SIMD, system, timer
+/// and firmware registers.
+#[derive(Clone, Default)]
+pub struct VcpuState {
+    /// Id and value of each register, the value little endian and as long as the id says.
+    pub regs: Vec<(u64, Vec<u8>)>,
+}
+
+impl VcpuState {
+    /// The number of registers, then the id and value of each.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(self.regs.len() as u64).to_le_bytes());
+        for (id, value) in self.regs.iter() {
+            data.extend_from_slice(&id.to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    /// Reads the state `to_bytes` wrote from the start of `data`, returns it
+    /// and how many bytes it took.
+    pub fn from_bytes(data: &[u8]) -> Result<(Self, usize)> {
+        let read_u64 = |offset: usize| -> Result<u64> {
+            data.get(offset..offset + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+                .ok_or(Error::InvalidVcpuState)
+        };
+        let count = read_u64(0)?;
+        let mut offset = 8;
+        let mut regs = Vec::new();
+        for _ in 0..count {
+            let id = read_u64(offset)?;
+            offset += 8;
+            let value = data
+                .get(offset..offset + reg_size(id))
+                .ok_or(Error::InvalidVcpuState)?;
+            offset += value.len();
+            regs.push((id, value.to_vec()));
+        }
+        Ok((VcpuState { regs }, offset))
+    }
+}
+
+// Size in bytes of the register `id`.
+fn reg_size(id: u64) -> usize {
+    1 << ((id & KVM_REG_SIZE_MASK as u64) >> KVM_REG_SIZE_SHIFT as u64)
+}
+
+/// Ids of all the registers of the vcpu.
+pub fn get_reg_list(vcpu: &VcpuFd) -> Result<Vec<u64>> {
+    // The number of registers comes first, then their ids. With no room for
+    // any, KVM fails with E2BIG and says how many there are.
+    let mut list = vec![0u64; 1];
+    // Safe because KVM writes no more ids than list[0] leaves room for.
+    let ret = unsafe { ioctl_with_mut_ptr(vcpu, KVM_GET_REG_LIST(), list.as_mut_ptr()) };
+    if ret < 0 {
+        let e = kvm_ioctls::Error::last();
+        if e.errno() != libc::E2BIG {
+            return Err(Error::VcpuRegister(e));
+        }
+        list.resize(list[0] as usize + 1, 0);
+        // Safe because list now has room for all the ids.
+        let ret = unsafe { ioctl_with_mut_ptr(vcpu, KVM_GET_REG_LIST(), list.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(Error::VcpuRegister(kvm_ioctls::Error::last()));
+        }
+    }
+    list.truncate(list[0] as usize + 1);
+    list.remove(0);
+    Ok(list)
+}
+
+fn get_reg(vcpu: &VcpuFd, id: u64) -> Result<Vec<u8>> {
+    let mut value = vec![0u8; reg_size(id)];
+    let reg = kvm_one_reg {
+        id,
+        addr: value.as_mut_ptr() as u64,
+    };
+    // Safe because value has room for a register of the size the id gives.
+    let ret = unsafe { ioctl_with_ref(vcpu, KVM_GET_ONE_REG(), &reg) };
+    if ret < 0 {
+        return Err(Error::VcpuRegister(kvm_ioctls::Error::last()));
+    }
+    Ok(value)
+}
+
+fn set_reg(vcpu: &VcpuFd, id: u64, value: &[u8]) -> Result<()> {
+    if value.len() != reg_size(id) {
+        return Err(Error::InvalidVcpuState);
+    }
+    let reg = kvm_one_reg {
+        id,
+        addr: value.as_ptr() as u64,
+    };
+    // Safe because KVM only reads a register of the size the id gives from value.
+    let ret = unsafe { ioctl_with_ref(vcpu, KVM_SET_ONE_REG(), &reg) };
+    if ret < 0 {
+        return Err(Error::VcpuRegister(kvm_ioctls::Error::last()));
+    }
+    Ok(())
+}
+
+/// Reads every register of a vcpu that is not running.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+pub fn save_vcpu_state(vcpu: &VcpuFd) -> Result<VcpuState> {
+    let regs = get_reg_list(vcpu)?
+        .into_iter()
+        .map(|id| get_reg(vcpu, id).map(|value| (id, value)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(VcpuState { regs })
+}
+
+/// Writes back the registers of `state` to a vcpu that is not running.
+///
+/// MPIDR goes first, since KVM derives other registers from it. The timer
+/// goes last and its counter after the rest of it: writing the counter sets
+/// the offset of the virtual counter, which should not move afterwards.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `state` - Registers saved by `save_vcpu_state`.
+pub fn restore_vcpu_state(vcpu: &VcpuFd, state: &VcpuState) -> Result<()> {
+    let rank = |id: u64| match id {
+        MPIDR_EL1 => 0,
+        TIMER_CTL | TIMER_CVAL => 2,
+        TIMER_CNT => 3,
+        _ => 1,
+    };
+    let mut regs: Vec<&(u64, Vec<u8>)> = state.regs.iter().collect();
+    // Stable, the other registers keep the order KVM listed them in.
+    regs.sort_by_key(|(id, _)| rank(*id));
+    for (id, value) in regs {
+        set_reg(vcpu, *id, value)?;
+    }
+    Ok(())
+}
+
+/// Checks a vcpu of this host can take the registers of `state`: it has all
+/// of them and runs on the same kind of CPU.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `state` - Registers saved by `save_vcpu_state`, maybe on another host.
+pub fn check_vcpu_state(vcpu: &VcpuFd, state: &VcpuState) -> Result<()> {
+    let ids = get_reg_list(vcpu)?;
+    if let Some((id, _)) = state.regs.iter().find(|(id, _)| !ids.contains(id)) {
+        return Err(Error::SnapshotIncompatible(format!(
+            "the vcpus of this host have no register {:#x}",
+            id
+        )));
+    }
+    let midr = get_reg(vcpu, MIDR_EL1)?;
+    if let Some((_, saved)) = state.regs.iter().find(|(id, _)| *id == MIDR_EL1) {
+        if *saved != midr {
+            return Err(Error::SnapshotIncompatible(format!(
+                "the snapshot was taken on CPU {:#x}, this host has CPU {:#x}",
+                u64::from_le_bytes(saved.as_slice().try_into().unwrap()),
+                u64::from_le_bytes(midr.as_slice().try_into().unwrap())
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg_id(size: u64, index: u64) -> u64 {
+        KVM_REG_ARM64 as u64 | size | index
+    }
+
+    fn state() -> VcpuState {
+        VcpuState {
+            regs: vec![
+                (reg_id(KVM_REG_SIZE_U64 as u64, 1), vec![1; 8]),
+                (reg_id(KVM_REG_SIZE_U128 as u64, 2), (0..16).collect()),
+                (reg_id(KVM_REG_SIZE_U32 as u64, 3), vec![3; 4]),
+            ],
+        }
+    }
+
+    #[test]
+    fn reg_sizes() {
+        assert_eq!(reg_size(reg_id(KVM_REG_SIZE_U32 as u64, 0)), 4);
+        assert_eq!(reg_size(reg_id(KVM_REG_SIZE_U64 as u64, 0)), 8);
+        assert_eq!(reg_size(reg_id(KVM_REG_SIZE_U128 as u64, 0)), 16);
+        assert_eq!(reg_size(MPIDR_EL1), 8);
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = state();
+        let mut data = state.to_bytes();
+        assert_eq!(data.len(), 8 + 3 * 8 + 8 + 16 + 4);
+        // Whatever follows the state is left alone.
+        data.extend_from_slice(&[0xaa; 5]);
+
+        let (restored, len) = VcpuState::from_bytes(&data).unwrap();
+        assert_eq!(len, data.len() - 5);
+        assert_eq!(restored.regs, state.regs);
+
+        let (empty, len) = VcpuState::from_bytes(&VcpuState::default().to_bytes()).unwrap();
+        assert!(empty.regs.is_empty());
+        assert_eq!(len, 8);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = state().to_bytes();
+        for len in 0..data.len() {
+            assert!(match VcpuState::from_bytes(&data[..len]) {
+                Err(Error::InvalidVcpuState) => true,
+                _ => false,
+            });
+        }
+    }
+
+    #[test]
+    fn bad_register_sizes() {
+        // A 64-bit register id followed by only 4 bytes of value.
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&reg_id(KVM_REG_SIZE_U64 as u64, 1).to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        assert!(VcpuState::from_bytes(&data).is_err());
+
+        // Far more registers than there is data for.
+        let mut data = state().to_bytes();
+        data[..8].copy_from_slice(&u64::max_value().to_le_bytes());
+        assert!(VcpuState::from_bytes(&data).is_err());
+
+        // The largest size an id can claim.
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&u64::max_value().to_le_bytes());
+        assert!(VcpuState::from_bytes(&data).is_err());
+    }
+}
@@ -0,0 +1,441 @@
+// Snapshots of a paused VM, resumed later by `glue restore` in a new process.
+//
+// A snapshot is a directory: `state` holds the configuration, the vcpu
+// registers and the GIC and device state, `memory` the guest RAM ranges listed
+// in the state, one after the other.
+
+use crate::error::*;
+use crate::regs::VcpuState;
+use kvm_ioctls::{Cap, Kvm};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// Version of the layout of the state, bumped on every change to it. Snapshots
+/// of other versions are refused.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 8] = b"GLUESNAP";
+const STATE_FILE: &str = "state";
+const MEMORY_FILE: &str = "memory";
+
+// KVM features restoring needs on top of those booting a VM needs.
+const REQUIRED_CAPS: &[(Cap, &str)] = &[
+    (Cap::OneReg, "KVM_CAP_ONE_REG"),
+    (Cap::MpState, "KVM_CAP_MP_STATE"),
+    (Cap::ArmPsci02, "KVM_CAP_ARM_PSCI_0_2"),
+    (Cap::IrqRouting, "KVM_CAP_IRQ_ROUTING"),
+];
+
+/// Appends the fields of a saved state, little endian.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    /// The length of `value`, then its bytes.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back the fields a `StateWriter` appended, in the same order.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let value = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| Error::InvalidSnapshot("truncated state".to_string()))?;
+        self.offset += len;
+        Ok(value)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+/// Everything about a VM but the content of its memory.
+pub struct VmState {
+    /// Directory and arguments of the `glue run` that started the VM, which
+    /// give its configuration.
+    pub run_dir: PathBuf,
+    pub run_args: Vec<String>,
+    /// Power state and registers of each vcpu.
+    pub vcpus: Vec<(u32, VcpuState)>,
+    /// State of the GIC and its ITS, see `Gic::save_state`.
+    pub gic: Vec<u8>,
+    /// State of each device, by the address it sits at on the MMIO bus.
+    pub devices: Vec<(u64, Vec<u8>)>,
+    /// Guest address and size of the ranges in the memory file, in order.
+    pub memory: Vec<(GuestAddress, u64)>,
+}
+
+impl VmState {
+    /// Writes the state and the content of its memory ranges in `guest_mem` to
+    /// the snapshot directory `dir`, created if missing.
+    pub fn save(&self, guest_mem: &GuestMemoryMmap, dir: &Path) -> Result<()> {
+        let mut state = StateWriter::new();
+        state.bytes(self.run_dir.as_os_str().as_bytes());
+        state.u32(self.run_args.len() as u32);
+        for arg in self.run_args.iter() {
+            state.bytes(arg.as_bytes());
+        }
+        state.u32(self.vcpus.len() as u32);
+        for (mp_state, regs) in self.vcpus.iter() {
+            state.u32(*mp_state);
+            state.bytes(&regs.to_bytes());
+        }
+        state.bytes(&self.gic);
+        state.u32(self.devices.len() as u32);
+        for (addr, device) in self.devices.iter() {
+            state.u64(*addr);
+            state.bytes(device);
+        }
+        state.u32(self.memory.len() as u32);
+        for (addr, size) in self.memory.iter() {
+            state.u64(addr.0);
+            state.u64(*size);
+        }
+
+        fs::create_dir_all(dir).map_err(Error::Snapshot)?;
+        let mut file = File::create(dir.join(STATE_FILE)).map_err(Error::Snapshot)?;
+        file.write_all(MAGIC).map_err(Error::Snapshot)?;
+        file.write_all(&SNAPSHOT_VERSION.to_le_bytes())
+            .map_err(Error::Snapshot)?;
+        file.write_all(&state.into_inner())
+            .map_err(Error::Snapshot)?;
+
+        let mut file = File::create(dir.join(MEMORY_FILE)).map_err(Error::Snapshot)?;
+        for (addr, size) in self.memory.iter() {
+            guest_mem
+                .write_all_to(*addr, &mut file, *size as usize)
+                .map_err(|e| Error::Snapshot(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the state of the snapshot in `dir`.
+    pub fn load(dir: &Path) -> Result<VmState> {
+        let data = fs::read(dir.join(STATE_FILE)).map_err(Error::Snapshot)?;
+        if data.len() < MAGIC.len() + 4 || &data[..MAGIC.len()] != MAGIC {
+            return Err(Error::InvalidSnapshot(format!(
+                "{} is not a glue snapshot",
+                dir.display()
+            )));
+        }
+        let mut state = StateReader::new(&data[MAGIC.len()..]);
+        let version = state.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(Error::SnapshotVersion(version));
+        }
+
+        let run_dir = PathBuf::from(OsStr::from_bytes(state.bytes()?));
+        let run_args = (0..state.u32()?)
+            .map(|_| Ok(String::from_utf8_lossy(state.bytes()?).into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        let vcpus = (0..state.u32()?)
+            .map(|_| {
+                let mp_state = state.u32()?;
+                let (regs, _) = VcpuState::from_bytes(state.bytes()?)?;
+                Ok((mp_state, regs))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let gic = state.bytes()?.to_vec();
+        let devices = (0..state.u32()?)
+            .map(|_| Ok((state.u64()?, state.bytes()?.to_vec())))
+            .collect::<Result<Vec<_>>>()?;
+        let memory = (0..state.u32()?)
+            .map(|_| Ok((GuestAddress(state.u64()?), state.u64()?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(VmState {
+            run_dir,
+            run_args,
+            vcpus,
+            gic,
+            devices,
+            memory,
+        })
+    }
+
+    /// Copies the memory saved in the snapshot directory `dir` into `guest_mem`.
+    pub fn load_memory(&self, guest_mem: &GuestMemoryMmap, dir: &Path) -> Result<()> {
+        let mut file = File::open(dir.join(MEMORY_FILE)).map_err(Error::Snapshot)?;
+        for (addr, size) in self.memory.iter() {
+            guest_mem
+                .read_exact_from(*addr, &mut file, *size as usize)
+                .map_err(|e| Error::Snapshot(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the KVM of this host has what restoring a snapshot takes.
+///
+/// Whether the vcpus and the GIC match those of the snapshot is only known
+/// once they are created, see `regs::check_vcpu_state` and `Gic::restore_state`.
+pub fn check_host(kvm: &Kvm) -> Result<()> {
+    check_caps(|cap| kvm.check_extension(cap))
+}
+
+fn check_caps<F: Fn(Cap) -> bool>(has_cap: F) -> Result<()> {
+    for (cap, name) in REQUIRED_CAPS.iter() {
+        if !has_cap(*cap) {
+            return Err(Error::SnapshotIncompatible(format!(
+                "KVM on this host has no {}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_invalid<T>(result: Result<T>) -> bool {
+        match result {
+            Err(Error::InvalidSnapshot(_)) | Err(Error::InvalidVcpuState) => true,
+            _ => false,
+        }
+    }
+
+    fn snapshot_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("glue-snapshot-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn guest_mem() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0x8000_0000), 0x4000)]).unwrap()
+    }
+
+    fn vm_state() -> VmState {
+        VmState {
+            run_dir: PathBuf::from("/var/run/glue"),
+            run_args: vec!["glue".to_string(), "run".to_string()],
+            vcpus: vec![
+                (0, VcpuState::default()),
+                (
+                    1,
+                    VcpuState {
+                        regs: vec![(0x6030_0000_0010_0000, vec![7; 8])],
+                    },
+                ),
+            ],
+            gic: vec![1, 2, 3],
+            devices: vec![(0x4000_0000, vec![4; 10]), (0x4000_1000, vec![])],
+            memory: vec![
+                (GuestAddress(0x8000_0000), 0x1000),
+                (GuestAddress(0x8000_3000), 0x1000),
+            ],
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0xab);
+        writer.u16(0x1234);
+        writer.u32(0xdead_beef);
+        writer.u64(u64::max_value());
+        writer.bool(true);
+        writer.bytes(b"glue");
+        writer.bytes(&[]);
+        let data = writer.into_inner();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 0xab);
+        assert_eq!(reader.u16().unwrap(), 0x1234);
+        assert_eq!(reader.u32().unwrap(), 0xdead_beef);
+        assert_eq!(reader.u64().unwrap(), u64::max_value());
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.bytes().unwrap(), b"glue");
+        assert!(reader.bytes().unwrap().is_empty());
+        assert!(is_invalid(reader.u8()));
+    }
+
+    #[test]
+    fn state_truncated() {
+        let mut writer = StateWriter::new();
+        writer.u64(1);
+        writer.bytes(b"glue");
+        let data = writer.into_inner();
+
+        for len in 0..data.len() {
+            let mut reader = StateReader::new(&data[..len]);
+            let result = reader.u64().and_then(|_| reader.bytes());
+            assert!(is_invalid(result));
+        }
+
+        // A length far past the end.
+        let data = u32::max_value().to_le_bytes();
+        assert!(is_invalid(StateReader::new(&data).bytes()));
+    }
+
+    #[test]
+    fn vm_state_round_trip() {
+        let dir = snapshot_dir("round-trip");
+        let mem = guest_mem();
+        mem.write_slice(&[0x11; 0x1000], GuestAddress(0x8000_0000))
+            .unwrap();
+        mem.write_slice(&[0x22; 0x1000], GuestAddress(0x8000_3000))
+            .unwrap();
+        let state = vm_state();
+        state.save(&mem, &dir).unwrap();
+
+        let loaded = VmState::load(&dir).unwrap();
+        assert_eq!(loaded.run_dir, state.run_dir);
+        assert_eq!(loaded.run_args, state.run_args);
+        assert_eq!(loaded.vcpus.len(), 2);
+        for ((mp_state, regs), (saved_mp_state, saved_regs)) in
+            loaded.vcpus.iter().zip(state.vcpus.iter())
+        {
+            assert_eq!(mp_state, saved_mp_state);
+            assert_eq!(regs.regs, saved_regs.regs);
+        }
+        assert_eq!(loaded.gic, state.gic);
+        assert_eq!(loaded.devices, state.devices);
+        assert_eq!(loaded.memory, state.memory);
+
+        let restored = guest_mem();
+        loaded.load_memory(&restored, &dir).unwrap();
+        let mut data = [0u8; 0x1000];
+        restored
+            .read_slice(&mut data, GuestAddress(0x8000_0000))
+            .unwrap();
+        assert!(data.iter().all(|b| *b == 0x11));
+        restored
+            .read_slice(&mut data, GuestAddress(0x8000_3000))
+            .unwrap();
+        assert!(data.iter().all(|b| *b == 0x22));
+        // Only the listed ranges are saved.
+        restored
+            .read_slice(&mut data, GuestAddress(0x8000_1000))
+            .unwrap();
+        assert!(data.iter().all(|b| *b == 0));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn load_rejects_bad_state() {
+        let dir = snapshot_dir("bad-state");
+        assert!(match VmState::load(&dir) {
+            Err(Error::Snapshot(_)) => true,
+            _ => false,
+        });
+
+        vm_state().save(&guest_mem(), &dir).unwrap();
+        let state_file = dir.join(STATE_FILE);
+        let data = fs::read(&state_file).unwrap();
+
+        let mut other_version = data.clone();
+        other_version[MAGIC.len()..MAGIC.len() + 4]
+            .copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        fs::write(&state_file, &other_version).unwrap();
+        assert!(match VmState::load(&dir) {
+            Err(Error::SnapshotVersion(v)) => v == SNAPSHOT_VERSION + 1,
+            _ => false,
+        });
+
+        let mut not_a_snapshot = data.clone();
+        not_a_snapshot[0] = b'X';
+        fs::write(&state_file, &not_a_snapshot).unwrap();
+        assert!(is_invalid(VmState::load(&dir)));
+
+        for len in 0..data.len() {
+            fs::write(&state_file, &data[..len]).unwrap();
+            assert!(is_invalid(VmState::load(&dir)));
+        }
+
+        // Corrupt bytes give an error or a different state, never a panic.
+        for i in MAGIC.len() + 4..data.len() {
+            let mut corrupt = data.clone();
+            corrupt[i] = 0xff;
+            fs::write(&state_file, &corrupt).unwrap();
+            VmState::load(&dir).ok();
+        }
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn required_caps() {
+        assert!(check_caps(|_| true).is_ok());
+        for (missing, name) in REQUIRED_CAPS.iter() {
+            let result = check_caps(|cap| cap as u32 != *missing as u32);
+            assert!(match result {
+                Err(Error::SnapshotIncompatible(msg)) => msg.contains(name),
+                _ => false,
+            });
+        }
+    }
+}
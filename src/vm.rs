@@ -1,37 +1,108 @@
-use crate::config::VmConfig;
-use crate::cpu::VmCpu;
+use crate::config::{
+    NetConfig, PmemConfig, VhostUserConfig, VmConfig, VsockConfig, WatchdogAction,
+};
+use crate::coredump;
+use crate::cpu::{self, VmCpu};
+use crate::device_manager::DeviceManager;
+use crate::devices::sp805::Expiry;
+use crate::devices::virtio;
 use crate::error::*;
+use crate::event_manager::EventManager;
+use crate::fdt;
+use crate::irqchip::Gic;
 use crate::memory::VmLayout;
-use crate::memory::VmMemory;
+use crate::memory::{MemorySlots, VmMemory};
+use crate::snapshot::VmState;
 use kvm_ioctls::Kvm;
 use kvm_ioctls::VmFd;
 use linux_loader::loader;
 use linux_loader::loader::KernelLoader;
+use std::env;
 use std::fs::File;
-use vm_memory::GuestAddress;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
 
 pub struct Vm {
-    fd: VmFd,
+    fd: Arc<VmFd>,
     memory: VmMemory,
     cpus: VmCpu,
     config: VmConfig,
+    gic: Option<Gic>,
+    devices: DeviceManager,
+    exit_evt: EventFd,
+    // Set when the VM exits to be started again.
+    reset: Arc<AtomicBool>,
+    exited: Arc<Exited>,
 }
 
+// Tells the threads helping the VM run once it is gone.
+#[derive(Default)]
+struct Exited {
+    exited: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Exited {
+    fn set(&self) {
+        *self.exited.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    // Waits up to `timeout` for the VM to exit, returns whether it did.
+    fn wait_timeout(&self, timeout: Duration) -> bool {
+        let exited = self.exited.lock().unwrap();
+        let (exited, _) = self
+            .cond
+            .wait_timeout_while(exited, timeout, |exited| !*exited)
+            .unwrap();
+        *exited
+    }
+}
+
+/// Threads running the event loops of all devices.
+const DEVICE_WORKERS: usize = 2;
+
+/// How often the watchdog counter is brought up to date.
+const WATCHDOG_TICK: Duration = Duration::from_millis(100);
+
 impl Vm {
     pub fn new(kvm: &Kvm, vm_config: VmConfig) -> Result<Self> {
+        vm_config.check_numa()?;
+
         // Create VM.
-        let vm_fd = kvm.create_vm().unwrap();
+        let vm_fd = Arc::new(kvm.create_vm().unwrap());
 
         // Setup memory.
-        let vm_memory = VmMemory::new(vm_config.memory_size as usize)?;
+        let hotplug_size = vm_config.mem_hotplug.as_ref().map_or(0, |c| c.size);
+        let vm_memory = VmMemory::new(
+            vm_fd.clone(),
+            vm_config.memory_size as usize,
+            hotplug_size as usize,
+            vm_config.shares_memory(),
+            &vm_config.memory,
+            &vm_config.numa,
+        )?;
 
         let vm_cpu = VmCpu::new()?;
+        let exit_evt = EventFd::new(0).map_err(Error::EventFd)?;
+        let event_manager = Arc::new(EventManager::new(DEVICE_WORKERS)?);
+        let devices = DeviceManager::new(vm_fd.clone(), vm_memory.slots.clone(), event_manager);
 
         Ok(Vm {
-            fd: vm_fd,
+            fd: vm_fd.clone(),
             memory: vm_memory,
             cpus: vm_cpu,
             config: vm_config,
+            gic: None,
+            devices,
+            exit_evt,
+            reset: Arc::new(AtomicBool::new(false)),
+            exited: Arc::new(Exited::default()),
         })
     }
 
@@ -46,17 +117,423 @@ impl Vm {
                 &self.memory.guest_mem,
             )
             .unwrap();
-        self.cpus.start_vcpus().unwrap();
 
-        /*
-        self.setup_irqchip()?;
+        self.pin_vcpus()?;
+
+        // The GIC can only be finalized once all vcpus exist.
+        self.gic = Some(Gic::new(&self.fd, self.config.boot_vcpus as u64)?);
 
         // Setup devices.
+        self.setup_devices()?;
+
+        // Describe the VM to the kernel.
+        let fdt = fdt::create_fdt(
+            &self.memory.guest_mem,
+            &self.config.kernel_args,
+            &self.cpus.mpidrs(),
+            &self.config.numa,
+            self.gic.as_ref().unwrap(),
+            &self.devices,
+        );
+        fdt::load_fdt(&self.memory.guest_mem, &fdt)?;
 
         // Start.
-        self.start_vcpus(vcpus)
-         */
+        self.cpus
+            .start_vcpus(self.devices.mmio_bus(), &self.exit_evt)?;
+        self.start_watchdog()?;
+
+        Ok(())
+    }
+
+    /// Sets the VM up like `boot`, then puts it in the state saved in the
+    /// snapshot directory `dir` instead of loading a kernel, and starts it.
+    ///
+    /// The VM must have the configuration of the one the snapshot was taken of.
+    pub fn restore(&mut self, state: &VmState, dir: &Path) -> Result<()> {
+        // The registers come from the snapshot, the entry point is overwritten.
+        self.cpus.create_vcpus(
+            &self.fd,
+            self.config.boot_vcpus as u64,
+            GuestAddress(VmLayout::get_kernel_start()),
+            &self.memory.guest_mem,
+        )?;
+        self.pin_vcpus()?;
+        self.gic = Some(Gic::new(&self.fd, self.config.boot_vcpus as u64)?);
+        self.setup_devices()?;
+
+        // Devices and the ITS find their rings and tables in guest memory, and
+        // the GIC needs the vcpus to have their MPIDR back.
+        state.load_memory(&self.memory.guest_mem, dir)?;
+        self.cpus.restore_state(&state.vcpus)?;
+        self.gic.as_ref().unwrap().restore_state(&state.gic)?;
+        self.devices.restore(&state.devices)?;
+
+        self.cpus
+            .start_vcpus(self.devices.mmio_bus(), &self.exit_evt)?;
+        self.start_watchdog()?;
+        Ok(())
+    }
+
+    // The vcpus of a NUMA node run on the CPUs of its host node.
+    fn pin_vcpus(&mut self) -> Result<()> {
+        for node in self.config.numa.iter() {
+            if let Some(host_node) = node.host_node {
+                let host_cpus = cpu::host_node_cpus(host_node)?;
+                self.cpus.set_affinity(&node.cpus, &host_cpus);
+            }
+        }
+        Ok(())
+    }
+
+    /// Signaled when the VM should go away, by the guest or by `glue stop`.
+    pub fn exit_evt(&self) -> Result<EventFd> {
+        self.exit_evt.try_clone().map_err(Error::EventFd)
+    }
+
+    /// Whether the VM exited because it has to be started again.
+    pub fn reset_requested(&self) -> bool {
+        self.reset.load(Ordering::SeqCst)
+    }
 
+    /// Stops the vcpu and device threads once the guest is gone.
+    pub fn shutdown(&mut self) {
+        self.exited.set();
+        self.cpus.join_vcpus();
+        self.devices.shutdown();
+    }
+
+    /// Asks the guest to power off, killing it if it is still running after `timeout`.
+    pub fn stop(&self, timeout: Duration) -> Result<()> {
+        let exit_evt = self.exit_evt()?;
+        let power_button = self.devices.power_button();
+        let exited = self.exited.clone();
+        thread::Builder::new()
+            .name("stop".to_string())
+            .spawn(move || {
+                if let Some(power_button) = power_button {
+                    power_button.press();
+                }
+                // Also gone when the guest reset instead, to be started again.
+                if !exited.wait_timeout(timeout) {
+                    println!("Guest did not shut down in time, killing it");
+                    exit_evt.write(1).ok();
+                }
+            })
+            .map_err(Error::SpawnThread)?;
+        Ok(())
+    }
+
+    /// Handles a request from the control socket and returns the reply.
+    pub fn handle_request(&mut self, request: &str) -> String {
+        let mut args = request.split_whitespace();
+        let result = match args.next() {
+            Some("stop") => match args.next().map(|t| t.parse::<u64>()) {
+                Some(Ok(timeout)) => self
+                    .stop(Duration::from_secs(timeout))
+                    .map(|()| "ok".to_string()),
+                _ => Err(Error::Command(request.to_string())),
+            },
+            Some("pause") => {
+                self.cpus.control().pause();
+                Ok("ok".to_string())
+            }
+            Some("resume") => {
+                self.cpus.control().resume();
+                Ok("ok".to_string())
+            }
+            Some("hotplug") => match args.next() {
+                Some(kind) => self
+                    .hotplug(kind, args.next().unwrap_or(""))
+                    .map(|id| format!("ok {}", id)),
+                None => Err(Error::Command(request.to_string())),
+            },
+            Some("resize-memory") => match args.next().map(|s| s.parse::<u64>()) {
+                Some(Ok(size_mib)) => self
+                    .devices
+                    .resize_memory(size_mib << 20)
+                    .map(|()| "ok".to_string()),
+                _ => Err(Error::Command(request.to_string())),
+            },
+            Some("dump-memory") => match request.splitn(2, ' ').nth(1) {
+                Some(path) => self
+                    .dump_memory(Path::new(path.trim()))
+                    .map(|()| "ok".to_string()),
+                None => Err(Error::Command(request.to_string())),
+            },
+            Some("snapshot") => match request.splitn(2, ' ').nth(1) {
+                Some(dir) => self
+                    .snapshot(Path::new(dir.trim()))
+                    .map(|()| "ok".to_string()),
+                None => Err(Error::Command(request.to_string())),
+            },
+            Some("unplug") => match args.next() {
+                Some(id) => self.devices.unplug(id).map(|()| "ok".to_string()),
+                None => Err(Error::Command(request.to_string())),
+            },
+            Some("dirty-log") => match args.next() {
+                Some("start") => self.set_dirty_logging(true).map(|()| "ok".to_string()),
+                Some("stop") => self.set_dirty_logging(false).map(|()| "ok".to_string()),
+                Some("read") => self.dirty_ranges().map(|ranges| format!("ok {}", ranges)),
+                _ => Err(Error::Command(request.to_string())),
+            },
+            Some("plugged") => match args.next() {
+                Some(id) => Ok(format!("ok {}", self.devices.is_plugged(id))),
+                None => Err(Error::Command(request.to_string())),
+            },
+            _ => Err(Error::Command(request.to_string())),
+        };
+        match result {
+            Ok(reply) => reply,
+            Err(e) => format!("error: {:?}", e),
+        }
+    }
+
+    /// Writes guest RAM and the vcpu registers to an ELF core at `path`, with
+    /// the vcpus paused for the time it takes.
+    fn dump_memory(&self, path: &Path) -> Result<()> {
+        let control = self.cpus.control();
+        let was_paused = control.is_paused();
+        control.pause();
+        let result = self.cpus.core_regs().and_then(|regs| {
+            coredump::write_core(&self.memory.guest_mem, &self.ram_ranges(), &regs, path)
+        });
+        if !was_paused {
+            control.resume();
+        }
+        result
+    }
+
+    /// Writes a snapshot of the VM to the directory `dir`, with the vcpus and
+    /// devices paused for the time it takes.
+    fn snapshot(&mut self, dir: &Path) -> Result<()> {
+        let control = self.cpus.control();
+        let was_paused = control.is_paused();
+        control.pause();
+        let result = self
+            .devices
+            .pause()
+            .and_then(|()| self.save_state())
+            .and_then(|state| state.save(&self.memory.guest_mem, dir));
+        // Also resumes the devices that were paused before one failed to.
+        let resumed = self.devices.resume();
+        if !was_paused {
+            control.resume();
+        }
+        result.and(resumed)
+    }
+
+    fn save_state(&mut self) -> Result<VmState> {
+        // Flushes the pending interrupts and the ITS tables to guest memory,
+        // which is saved last.
+        let gic = self.gic.as_ref().unwrap().save_state(&self.cpus.mpidrs())?;
+        Ok(VmState {
+            run_dir: env::current_dir().map_err(Error::Snapshot)?,
+            run_args: self.config.run_args.clone(),
+            vcpus: self.cpus.save_state()?,
+            gic,
+            devices: self.devices.snapshot()?,
+            memory: self.ram_ranges(),
+        })
+    }
+
+    fn set_dirty_logging(&self, enable: bool) -> Result<()> {
+        self.memory.slots.lock().unwrap().set_dirty_logging(enable)
+    }
+
+    // The guest memory written since the last read, as `<addr>+<size>` in hex.
+    fn dirty_ranges(&self) -> Result<String> {
+        let slots = self.memory.slots.lock().unwrap();
+        if !slots.dirty_logging() {
+            return Err(Error::Command("dirty logging is off".to_string()));
+        }
+        let ranges: Vec<String> = slots
+            .dirty_ranges()?
+            .map(|(addr, size)| format!("{:#x}+{:#x}", addr.0, size))
+            .collect();
+        Ok(ranges.join(" "))
+    }
+
+    fn ram_ranges(&self) -> Vec<(GuestAddress, u64)> {
+        ram_ranges(&self.memory.slots, &self.memory.guest_mem)
+    }
+
+    /// Adds a device of `kind` set up by `options` while the guest runs, returns its id.
+    fn hotplug(&mut self, kind: &str, options: &str) -> Result<String> {
+        // The guest only finds devices added after boot on PCI.
+        let options = if options.contains("transport=") {
+            options.to_string()
+        } else {
+            format!("{},transport=pci", options)
+        };
+        let event_manager = self.devices.event_manager();
+        let (fd, mem) = (&self.fd, &self.memory.guest_mem);
+        match kind {
+            "net" => {
+                let config = NetConfig::parse(&options)?;
+                let net = virtio::net::Net::new(&config, event_manager)?;
+                self.devices
+                    .hotplug_virtio(fd, mem, kind, Box::new(net), config.transport)
+            }
+            "vsock" => {
+                let config = VsockConfig::parse(&options)?;
+                let vsock = virtio::vsock::Vsock::new(&config, event_manager)?;
+                self.devices
+                    .hotplug_virtio(fd, mem, kind, Box::new(vsock), config.transport)
+            }
+            "pmem" => {
+                let config = PmemConfig::parse(&options)?;
+                self.devices.hotplug_pmem(fd, mem, &config)
+            }
+            "vhost-user" => {
+                let config = VhostUserConfig::parse(&options)?;
+                let device =
+                    virtio::vhost_user_device::VhostUserDevice::new(&config, event_manager)?;
+                self.devices
+                    .hotplug_virtio(fd, mem, kind, Box::new(device), config.transport)
+            }
+            _ => Err(Error::Command(format!("hotplug {}", kind))),
+        }
+    }
+
+    fn setup_devices(&mut self) -> Result<()> {
+        self.devices.register_gpio(&self.fd)?;
+
+        for fs_config in self.config.fs.iter() {
+            let fs = virtio::fs::Fs::new(fs_config, self.devices.event_manager())?;
+            self.devices.register_virtio(
+                &self.fd,
+                &self.memory.guest_mem,
+                Box::new(fs),
+                fs_config.transport,
+            )?;
+        }
+
+        for p9_config in self.config.p9.iter() {
+            let p9 = virtio::p9::P9::new(p9_config, self.devices.event_manager())?;
+            self.devices.register_virtio(
+                &self.fd,
+                &self.memory.guest_mem,
+                Box::new(p9),
+                p9_config.transport,
+            )?;
+        }
+
+        for pmem_config in self.config.pmem.iter() {
+            self.devices
+                .register_pmem(&self.fd, &self.memory.guest_mem, pmem_config)?;
+        }
+
+        if let (Some(config), Some(region)) = (&self.config.mem_hotplug, self.memory.hotplug_region)
+        {
+            self.devices
+                .register_virtio_mem(&self.fd, &self.memory.guest_mem, config, region)?;
+        }
+
+        for net_config in self.config.net.iter() {
+            let net = virtio::net::Net::new(net_config, self.devices.event_manager())?;
+            self.devices.register_virtio(
+                &self.fd,
+                &self.memory.guest_mem,
+                Box::new(net),
+                net_config.transport,
+            )?;
+        }
+
+        if let Some(vsock_config) = &self.config.vsock {
+            let vsock = virtio::vsock::Vsock::new(vsock_config, self.devices.event_manager())?;
+            self.devices.register_virtio(
+                &self.fd,
+                &self.memory.guest_mem,
+                Box::new(vsock),
+                vsock_config.transport,
+            )?;
+        }
+
+        for vhost_user_config in self.config.vhost_user.iter() {
+            let device = virtio::vhost_user_device::VhostUserDevice::new(
+                vhost_user_config,
+                self.devices.event_manager(),
+            )?;
+            self.devices.register_virtio(
+                &self.fd,
+                &self.memory.guest_mem,
+                Box::new(device),
+                vhost_user_config.transport,
+            )?;
+        }
+
+        if self.config.watchdog.is_some() {
+            self.devices.register_watchdog(&self.fd)?;
+        }
+
+        self.devices.register_hotplug_ports(&self.fd)?;
+
+        Ok(())
+    }
+
+    /// Runs the watchdog counter and carries out the configured action when it
+    /// expires, until the VM exits.
+    fn start_watchdog(&self) -> Result<()> {
+        let (watchdog, config) = match (self.devices.watchdog(), &self.config.watchdog) {
+            (Some(watchdog), Some(config)) => (watchdog, config),
+            _ => return Ok(()),
+        };
+        let action = config.action.clone();
+        let vcpus = self.cpus.control();
+        let exit_evt = self.exit_evt()?;
+        let reset = self.reset.clone();
+        let guest_mem = self.memory.guest_mem.clone();
+        let slots = self.memory.slots.clone();
+        let running = self.cpus.running();
+        let exited = self.exited.clone();
+
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || loop {
+                if exited.wait_timeout(WATCHDOG_TICK) {
+                    return;
+                }
+                let expiry = {
+                    let mut watchdog = watchdog.lock().unwrap();
+                    // The guest cannot feed the watchdog while it is paused.
+                    if vcpus.is_paused() {
+                        watchdog.reload();
+                        continue;
+                    }
+                    match watchdog.tick() {
+                        Some(expiry) => expiry,
+                        None => continue,
+                    }
+                };
+
+                if expiry == Expiry::Interrupt {
+                    println!("event: watchdog expired, action: interrupt");
+                    continue;
+                }
+                println!("event: watchdog expired, action: {:?}", action);
+                match &action {
+                    WatchdogAction::Reset => reset.store(true, Ordering::SeqCst),
+                    WatchdogAction::Poweroff => {}
+                    WatchdogAction::Pause => {
+                        vcpus.pause();
+                        continue;
+                    }
+                    WatchdogAction::Dump(path) => {
+                        vcpus.pause();
+                        let dumped = cpu::core_regs(&running).and_then(|regs| {
+                            let ranges = ram_ranges(&slots, &guest_mem);
+                            coredump::write_core(&guest_mem, &ranges, &regs, path)
+                        });
+                        match dumped {
+                            Ok(()) => println!("VM dumped to {}", path.display()),
+                            Err(e) => println!("Failed to dump the VM: {:?}", e),
+                        }
+                    }
+                }
+                exit_evt.write(1).ok();
+                return;
+            })
+            .map_err(Error::SpawnThread)?;
         Ok(())
     }
 
@@ -75,3 +552,14 @@ impl Vm {
         Ok(load_addr)
     }
 }
+
+// Plugged blocks of the hotplug region and DRAM in `slots`, not device memory.
+fn ram_ranges(slots: &Mutex<MemorySlots>, guest_mem: &GuestMemoryMmap) -> Vec<(GuestAddress, u64)> {
+    slots
+        .lock()
+        .unwrap()
+        .ranges()
+        .into_iter()
+        .filter(|(addr, _)| guest_mem.address_in_range(*addr))
+        .collect()
+}
@@ -1,7 +1,20 @@
 use crate::config::VmConfig;
+use crate::control::{self, ControlServer};
 use crate::error::*;
+use crate::snapshot::{self, VmState};
 use crate::vm::Vm;
 use kvm_ioctls::Kvm;
+use std::env;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the guest gets to release a device being unplugged. Linux waits 5
+/// seconds after the attention button is pressed, in case it is pressed again.
+const UNPLUG_TIMEOUT: Duration = Duration::from_secs(15);
 
 /*
 pub enum Error {
@@ -22,26 +35,162 @@ impl Vmm {
         Ok(Vmm { kvm })
     }
 
-    pub fn run_vm(&self, vm_config: VmConfig) -> Result<()> {
+    pub fn run_vm(&self, vm_config: VmConfig, name: &str) -> Result<()> {
         println!(
-            "run_vm: cpus: {}, mem: {} MB",
-            vm_config.boot_vcpus, vm_config.memory_size
+            "run_vm: {}, cpus: {}, mem: {} MB",
+            name, vm_config.boot_vcpus, vm_config.memory_size
         );
 
+        let run_args = vm_config.run_args.clone();
         let mut vm = Vm::new(&self.kvm, vm_config)?;
 
-        vm.boot()
+        vm.boot()?;
+        Vmm::serve(vm, name, &run_args)
+    }
+
+    /// Resumes the VM of the snapshot in `dir`, whose state is `state`, with
+    /// `vm_config` being the configuration it ran with.
+    pub fn restore_vm(
+        &self,
+        vm_config: VmConfig,
+        name: &str,
+        dir: &Path,
+        state: VmState,
+    ) -> Result<()> {
+        println!("restore_vm: {} from {}", name, dir.display());
+        snapshot::check_host(&self.kvm)?;
+
+        let run_args = vm_config.run_args.clone();
+        let mut vm = Vm::new(&self.kvm, vm_config)?;
+
+        vm.restore(&state, dir)?;
+        Vmm::serve(vm, name, &run_args)
+    }
+
+    // Handles requests for the running `vm` until it exits.
+    fn serve(vm: Vm, name: &str, run_args: &[String]) -> Result<()> {
+        let exit_evt = vm.exit_evt()?;
+
+        let vm = Arc::new(Mutex::new(vm));
+        let control_vm = vm.clone();
+        let _control = ControlServer::start(name, move |request| {
+            control_vm.lock().unwrap().handle_request(request)
+        })?;
+
+        // Either the guest powered off or it was killed.
+        exit_evt.read().map_err(Error::EventFd)?;
+        vm.lock().unwrap().shutdown();
+        if vm.lock().unwrap().reset_requested() {
+            println!("VM {} resetting", name);
+            drop(_control);
+            return Vmm::restart(run_args);
+        }
+        println!("VM {} exited", name);
+        Ok(())
     }
 
-    pub fn pause_vm(&self, name: &str) {
+    /// Starts over in a fresh process running `run_args`, nothing of the old VM survives.
+    ///
+    /// A restored VM boots anew, from the arguments of the `glue run` it came from.
+    fn restart(run_args: &[String]) -> Result<()> {
+        let err = Command::new("/proc/self/exe")
+            .args(run_args.iter().skip(1))
+            .exec();
+        Err(Error::Restart(err))
+    }
+
+    pub fn pause_vm(&self, name: &str) -> Result<()> {
+        Vmm::send_command(name, "pause")?;
         println!("VM {} paused", name);
+        Ok(())
     }
 
-    pub fn resume_vm(&self, name: &str) {
+    pub fn resume_vm(&self, name: &str) -> Result<()> {
+        Vmm::send_command(name, "resume")?;
         println!("VM {} resumed", name);
+        Ok(())
+    }
+
+    /// Asks the guest of the running VM to plug `size_mib` MB of its hotplug region.
+    pub fn resize_memory(&self, name: &str, size_mib: u64) -> Result<()> {
+        Vmm::send_command(name, &format!("resize-memory {}", size_mib))?;
+        println!("VM {} asked to plug {} MB", name, size_mib);
+        Ok(())
     }
 
-    pub fn stop_vm(&self, name: &str) {
+    /// Adds a device to the running VM, see `Vm::handle_request`.
+    pub fn hotplug_vm(&self, name: &str, kind: &str, options: &str) -> Result<()> {
+        let id = Vmm::send_command(name, &format!("hotplug {} {}", kind, options))?;
+        println!("Device {} added to VM {}", id, name);
+        Ok(())
+    }
+
+    /// Removes the hotplugged device `id` from the running VM, once the guest released it.
+    pub fn unplug_vm(&self, name: &str, id: &str) -> Result<()> {
+        Vmm::send_command(name, &format!("unplug {}", id))?;
+
+        let start = Instant::now();
+        while Vmm::send_command(name, &format!("plugged {}", id))? == "true" {
+            if start.elapsed() > UNPLUG_TIMEOUT {
+                return Err(Error::UnplugTimeout(id.to_string()));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        println!("Device {} removed from VM {}", id, name);
+        Ok(())
+    }
+
+    /// Starts or stops logging the memory the VM writes to, or prints what it
+    /// wrote since the last read, see `Vm::handle_request`.
+    pub fn dirty_log(&self, name: &str, action: &str) -> Result<()> {
+        let reply = Vmm::send_command(name, &format!("dirty-log {}", action))?;
+        for range in reply.split_whitespace() {
+            println!("{}", range);
+        }
+        Ok(())
+    }
+
+    /// Writes the memory and vcpu registers of the VM to an ELF core at `path`.
+    pub fn dump_memory(&self, name: &str, path: &Path) -> Result<()> {
+        // The VM runs in another directory.
+        let path = env::current_dir().map_err(Error::Dump)?.join(path);
+        Vmm::send_command(name, &format!("dump-memory {}", path.display()))?;
+        println!("VM {} dumped to {}", name, path.display());
+        Ok(())
+    }
+
+    /// Writes a snapshot of the VM to the directory `dir`, see `glue restore`.
+    pub fn snapshot_vm(&self, name: &str, dir: &Path) -> Result<()> {
+        // The VM runs in another directory.
+        let dir = env::current_dir().map_err(Error::Snapshot)?.join(dir);
+        Vmm::send_command(name, &format!("snapshot {}", dir.display()))?;
+        println!("VM {} saved to {}", name, dir.display());
+        Ok(())
+    }
+
+    // Sends `command` and returns whatever follows "ok" in the reply.
+    fn send_command(name: &str, command: &str) -> Result<String> {
+        let reply = control::send_command(name, command)?;
+        if reply != "ok" && !reply.starts_with("ok ") {
+            return Err(Error::Command(reply));
+        }
+        Ok(reply[2..].trim().to_string())
+    }
+
+    /// Presses the power button of the VM and waits for it to go away.
+    ///
+    /// The VM kills the guest itself if it does not shut down within `timeout`.
+    pub fn stop_vm(&self, name: &str, timeout: Duration) -> Result<()> {
+        Vmm::send_command(name, &format!("stop {}", timeout.as_secs()))?;
+
+        let start = Instant::now();
+        while control::is_running(name) {
+            if start.elapsed() > timeout + Duration::from_secs(5) {
+                return Err(Error::Command(format!("VM {} did not stop", name)));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
         println!("VM {} stopped", name);
+        Ok(())
     }
 }